@@ -0,0 +1,204 @@
+
+use std::io::IoSlice;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::Ordering;
+
+use super::{arena, copy_vectored, Consumer, Producer};
+
+// every frame starts with its length as a little endian u32
+const PREFIX : usize = 4;
+
+// in place of a length: the frame did not fit before the end of the ring
+// and starts over at the front. with less than PREFIX bytes left there is
+// no room for a marker, the reader skips those without one
+const PAD : u32 = u32::MAX;
+
+// writing end of a framed byte ring, see framed()
+pub struct FrameSender {
+  bytes : Producer,
+}
+
+// reading end, frames come out whole and in order
+pub struct FrameReceiver {
+  bytes : Consumer,
+}
+
+// a received frame, read in place. dropping it frees its bytes
+pub struct Frame<'a> {
+  rx  : &'a mut Consumer,
+  at  : *const u8,
+  len : usize,
+  end : u64,
+}
+
+// a byte ring of `capacity` bytes carrying length prefixed frames of up
+// to capacity-4 bytes each
+pub fn framed(capacity : usize) -> (FrameSender, FrameReceiver) {
+  if capacity <= PREFIX { panic!("capacity must be more than {} bytes", PREFIX); }
+  let (tx, rx) = arena(capacity);
+  (FrameSender { bytes : tx }, FrameReceiver { bytes : rx })
+}
+
+impl FrameSender {
+  // the largest frame there is room for
+  pub fn max_frame(&self) -> usize {
+    self.bytes.capacity() - PREFIX
+  }
+
+  // writes `payload` as one frame, false if it does not fit until the
+  // receiver frees some. panics for frames over max_frame()
+  pub fn send_frame(&mut self, payload : &[u8]) -> bool {
+    self.send_frame_vectored(&[IoSlice::new(payload)])
+  }
+
+  // send_frame() of `bufs` one after the other as a single frame, e.g. a
+  // header and a body kept apart
+  pub fn send_frame_vectored(&mut self, bufs : &[IoSlice<'_>]) -> bool {
+    let len : usize = bufs.iter().map(|b| b.len()).sum();
+    if len > self.max_frame() {
+      panic!("a {} byte frame does not fit a {} byte ring", len, self.bytes.capacity());
+    }
+
+    let head = self.bytes.shared.head.load(Ordering::Relaxed);
+    let mut region = match self.bytes.reserve(PREFIX + len) {
+      Some(region) => region,
+      None         => return false,
+    };
+    // the bytes skipped before a wrap are free as well, the marker is
+    // published with the frame
+    if region.start - head >= PREFIX as u64 {
+      let shared = &region.producer.shared;
+      let at = shared.at(head, PREFIX).expect("pad marker wraps");
+      unsafe { ptr::copy_nonoverlapping(PAD.to_le_bytes().as_ptr(), at, PREFIX); }
+    }
+
+    let bytes = region.as_mut();
+    bytes[..PREFIX].copy_from_slice(&(len as u32).to_le_bytes());
+    copy_vectored(&mut bytes[PREFIX..], bufs);
+    region.commit();
+    true
+  }
+}
+
+impl FrameReceiver {
+  // the oldest frame not received yet, None if there is none. the next
+  // one can only be had once this is dropped
+  pub fn recv_frame(&mut self) -> Option<Frame<'_>> {
+    let s    = &self.bytes.shared;
+    let cap  = s.capacity();
+    let head = s.head.load(Ordering::Acquire);
+    let mut pos = s.tail.load(Ordering::Relaxed);
+    loop {
+      if pos == head { return None; }
+
+      let left = cap - pos % cap;
+      if left < PREFIX as u64 { pos += left; continue; }
+
+      let mut prefix = [0u8; PREFIX];
+      let at = s.at(pos, PREFIX).expect("frame prefix wraps");
+      unsafe { ptr::copy_nonoverlapping(at, prefix.as_mut_ptr(), PREFIX); }
+      let len = u32::from_le_bytes(prefix);
+      if len == PAD { pos += left; continue; }
+
+      let start = pos + PREFIX as u64;
+      let at = s.at(start, len as usize).expect("frame wraps");
+      return Some(Frame { rx : &mut self.bytes, at, len : len as usize, end : start + len as u64 });
+    }
+  }
+
+  // recv_frame() copied out, so the bytes are free right away
+  pub fn recv_frame_vec(&mut self) -> Option<Vec<u8>> {
+    self.recv_frame().map(|frame| frame.to_vec())
+  }
+}
+
+impl <'a> Deref for Frame<'a> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    unsafe { slice::from_raw_parts(self.at, self.len) }
+  }
+}
+
+impl <'a> Drop for Frame<'a> {
+  fn drop(&mut self) {
+    // our reads of the frame happen before the sender writes there again
+    self.rx.shared.tail.store(self.end, Ordering::Release);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::framed;
+  use std::thread;
+
+  #[test]
+  fn frames_keep_their_boundaries() {
+    let (mut tx, mut rx) = framed(16);
+    assert_eq!(tx.max_frame(), 12);
+    assert!(rx.recv_frame().is_none());
+
+    assert!(tx.send_frame(b"abc"));
+    assert!(tx.send_frame(b""));
+    assert!(!tx.send_frame(b"12345"));
+    assert_eq!(&*rx.recv_frame().unwrap(), b"abc");
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"");
+
+    // at 11 there is no room for 9 bytes before the end, they start over
+    // at the front behind a marker
+    assert!(tx.send_frame(b"12345"));
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"12345");
+    assert!(rx.recv_frame().is_none());
+
+    // 1 byte left at the end, too little for a marker
+    assert!(tx.send_frame(b"ab"));
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"ab");
+    assert!(tx.send_frame(b"wrapped"));
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"wrapped");
+    assert!(rx.recv_frame().is_none());
+  }
+
+  #[test]
+  fn vectored_frames() {
+    use std::io::IoSlice;
+    let (mut tx, mut rx) = framed(16);
+    assert!(tx.send_frame_vectored(&[IoSlice::new(b"hdr:"), IoSlice::new(b"body")]));
+    assert!(tx.send_frame_vectored(&[]));
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"hdr:body");
+    assert_eq!(rx.recv_frame_vec().unwrap(), b"");
+  }
+
+  #[test]
+  #[should_panic(expected = "does not fit")]
+  fn oversized_frame() {
+    let (mut tx, _rx) = framed(16);
+    tx.send_frame(&[0; 13]);
+  }
+
+  #[test]
+  fn frames_across_threads() {
+    const FRAMES : usize = 20_000;
+    let (mut tx, mut rx) = framed(100);
+    let t = thread::spawn(move || {
+      for i in 0..FRAMES {
+        let payload : Vec<u8> = (0..i % 40).map(|j| (i + j) as u8).collect();
+        while !tx.send_frame(&payload) { thread::yield_now(); }
+      }
+    });
+
+    let mut seen = 0;
+    while seen < FRAMES {
+      match rx.recv_frame() {
+        Some(frame) => {
+          assert_eq!(frame.len(), seen % 40);
+          for (j, b) in frame.iter().enumerate() { assert_eq!(*b, (seen + j) as u8); }
+          seen += 1;
+        },
+        None => thread::yield_now(),
+      }
+    }
+    t.join().unwrap();
+  }
+}
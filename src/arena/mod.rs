@@ -0,0 +1,303 @@
+
+// byte ring for variable size messages, sent through a channel by
+// Descriptor
+//
+// the producer reserves a contiguous region, writes the payload in place
+// and commits it, which hands out a Descriptor (position and length, Copy)
+// to put on an spsc channel. the consumer reads the bytes where they are
+// and releases the region when done. nothing is copied or allocated on the
+// way.
+//
+// regions are released in order: releasing one also frees everything
+// committed before it, so descriptors the channel dropped (Overwrite) or
+// the consumer skipped come back too. positions count bytes since the
+// start and never wrap, a descriptor that is released already, or not
+// committed yet, reads as None. a region that does not fit before the end
+// of the ring starts over at the front, the bytes skipped are freed with
+// the next release. framed.rs puts length prefixed frames on the same
+// ring, for a stream of messages that needs no channel next to it
+
+mod framed;
+
+pub use self::framed::{framed, Frame, FrameReceiver, FrameSender};
+
+use std::cell::UnsafeCell;
+use std::io::IoSlice;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use storage::CachePadded;
+
+struct Shared {
+  bytes : Box<[UnsafeCell<u8>]>,
+  head  : CachePadded<AtomicU64>, // end of the last commit, producer only
+  tail  : CachePadded<AtomicU64>, // end of the last release, consumer only
+}
+
+// bytes are only touched by the end that owns them between the positions
+unsafe impl Sync for Shared { }
+
+pub struct Producer {
+  shared : Arc<Shared>,
+}
+
+pub struct Consumer {
+  shared : Arc<Shared>,
+}
+
+// a reserved region, see Producer::reserve(). dropping it commits nothing
+pub struct Region<'a> {
+  producer : &'a mut Producer,
+  start    : u64,
+  len      : usize,
+}
+
+// where a committed payload is, what travels through the channel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Descriptor {
+  start : u64,
+  len   : usize,
+}
+
+// a ring of `capacity` bytes, the largest payload that fits
+pub fn arena(capacity : usize) -> (Producer, Consumer) {
+  if capacity == 0 { panic!("capacity cannot be zero"); }
+  let shared = Arc::new(Shared {
+    bytes : (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+    head  : CachePadded::new(AtomicU64::new(0)),
+    tail  : CachePadded::new(AtomicU64::new(0)),
+  });
+  (Producer { shared : shared.clone() }, Consumer { shared })
+}
+
+impl Shared {
+  fn capacity(&self) -> u64 {
+    self.bytes.len() as u64
+  }
+
+  // the region at `start` if it is contiguous in the ring
+  fn at(&self, start : u64, len : usize) -> Option<*mut u8> {
+    let offset = (start % self.capacity()) as usize;
+    if offset + len > self.bytes.len() { return None; }
+    Some(unsafe { (self.bytes.as_ptr() as *mut u8).add(offset) })
+  }
+}
+
+impl Producer {
+  pub fn capacity(&self) -> usize {
+    self.shared.bytes.len()
+  }
+
+  // bytes not reserved by anything unreleased. a payload this long may
+  // still not fit if it would wrap around the end of the ring
+  pub fn free(&self) -> usize {
+    let head = self.shared.head.load(Ordering::Relaxed);
+    let tail = self.shared.tail.load(Ordering::Acquire);
+    self.capacity() - (head - tail) as usize
+  }
+
+  // a region of `len` bytes holding whatever was there before, None
+  // until the consumer released enough (or if `len` is over capacity())
+  pub fn reserve(&mut self, len : usize) -> Option<Region<'_>> {
+    let s    = &self.shared;
+    let head = s.head.load(Ordering::Relaxed);
+    // pairs with the release in Consumer::release(), its reads of the
+    // bytes happen before we write there
+    let tail = s.tail.load(Ordering::Acquire);
+
+    let cap    = s.capacity();
+    let offset = head % cap;
+    let start  = if offset + len as u64 > cap { head + cap - offset } else { head };
+    if start + len as u64 - tail > cap { return None; }
+    Some(Region { producer : self, start, len })
+  }
+
+  // a region holding `bufs` one after the other, e.g. a header and a
+  // body kept apart, ready to commit
+  pub fn reserve_vectored(&mut self, bufs : &[IoSlice<'_>]) -> Option<Region<'_>> {
+    let mut region = self.reserve(bufs.iter().map(|b| b.len()).sum())?;
+    copy_vectored(region.as_mut(), bufs);
+    Some(region)
+  }
+}
+
+// `bufs` into `to`, which holds exactly as many bytes
+fn copy_vectored(to : &mut [u8], bufs : &[IoSlice<'_>]) {
+  let mut at = 0;
+  for b in bufs {
+    to[at..at + b.len()].copy_from_slice(b);
+    at += b.len();
+  }
+}
+
+impl <'a> Region<'a> {
+  // publishes the region as it is
+  pub fn commit(self) -> Descriptor {
+    let len = self.len;
+    self.commit_len(len)
+  }
+
+  // publishes only the first `len` bytes, for payloads that came out
+  // shorter than reserved. the rest is free again
+  pub fn commit_len(self, len : usize) -> Descriptor {
+    if len > self.len { panic!("committing {} bytes of a {} byte region", len, self.len); }
+    // the payload written happens before the consumer reads it (acquire
+    // in Consumer::read())
+    self.producer.shared.head.store(self.start + len as u64, Ordering::Release);
+    Descriptor { start : self.start, len }
+  }
+}
+
+impl <'a> AsRef<[u8]> for Region<'a> {
+  fn as_ref(&self) -> &[u8] {
+    let at = self.producer.shared.at(self.start, self.len).expect("reserved region wraps");
+    unsafe { slice::from_raw_parts(at, self.len) }
+  }
+}
+
+impl <'a> AsMut<[u8]> for Region<'a> {
+  fn as_mut(&mut self) -> &mut [u8] {
+    let at = self.producer.shared.at(self.start, self.len).expect("reserved region wraps");
+    unsafe { slice::from_raw_parts_mut(at, self.len) }
+  }
+}
+
+impl Consumer {
+  pub fn capacity(&self) -> usize {
+    self.shared.bytes.len()
+  }
+
+  // bytes committed and not released yet, including skipped ones
+  pub fn used(&self) -> usize {
+    let tail = self.shared.tail.load(Ordering::Relaxed);
+    let head = self.shared.head.load(Ordering::Acquire);
+    (head - tail) as usize
+  }
+
+  // the payload of `desc`, None if it was released already. the bytes
+  // stay put until release() frees them
+  pub fn read(&self, desc : Descriptor) -> Option<&[u8]> {
+    let s    = &self.shared;
+    let tail = s.tail.load(Ordering::Relaxed);
+    let head = s.head.load(Ordering::Acquire);
+    if desc.start < tail || desc.end() > head { return None; }
+    let at = s.at(desc.start, desc.len)?;
+    Some(unsafe { slice::from_raw_parts(at, desc.len) })
+  }
+
+  // frees `desc` and everything committed before it. returns false if
+  // it was released already
+  pub fn release(&mut self, desc : Descriptor) -> bool {
+    let s    = &self.shared;
+    let tail = s.tail.load(Ordering::Relaxed);
+    let head = s.head.load(Ordering::Acquire);
+    if desc.end() <= tail || desc.end() > head { return false; }
+    s.tail.store(desc.end(), Ordering::Release);
+    true
+  }
+}
+
+impl Descriptor {
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  fn end(&self) -> u64 {
+    self.start + self.len as u64
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{arena, Descriptor};
+  use spsc::{Builder, Policy};
+  use std::thread;
+
+  fn send(tx : &mut super::Producer, payload : &[u8]) -> Descriptor {
+    let mut region = tx.reserve(payload.len()).unwrap();
+    region.as_mut().copy_from_slice(payload);
+    region.commit()
+  }
+
+  #[test]
+  fn regions_wrap_and_release_in_order() {
+    let (mut tx, mut rx) = arena(10);
+    let a = send(&mut tx, b"hello");
+    let b = send(&mut tx, b"abc");
+    assert!(tx.reserve(3).is_none());
+    assert_eq!((rx.read(a), rx.read(b)), (Some(&b"hello"[..]), Some(&b"abc"[..])));
+
+    // releasing b frees a as well, a reads as gone
+    assert!(rx.release(b));
+    assert!(!rx.release(a));
+    assert_eq!(rx.read(a), None);
+    assert_eq!(tx.free(), 10);
+
+    // 4 bytes do not fit behind position 8, they start over at 10
+    let c = send(&mut tx, b"wxyz");
+    assert_eq!(rx.read(c), Some(&b"wxyz"[..]));
+    assert_eq!(rx.used(), 6);
+    assert!(tx.reserve(7).is_none());
+    assert!(tx.reserve(11).is_none());
+
+    assert!(rx.release(c));
+
+    // dropped and shortened regions
+    tx.reserve(5).unwrap().as_mut()[0] = 1;
+    assert_eq!(rx.used(), 0);
+    let mut region = tx.reserve(6).unwrap();
+    region.as_mut()[..2].copy_from_slice(b"ok");
+    let d = region.commit_len(2);
+    assert_eq!(rx.read(d), Some(&b"ok"[..]));
+    assert_eq!(rx.read(Descriptor::default()), None);
+    assert!(rx.release(d));
+    assert_eq!(rx.used(), 0);
+  }
+
+  #[test]
+  fn vectored_regions() {
+    use std::io::IoSlice;
+    let (mut tx, rx) = arena(16);
+    let parts = [IoSlice::new(b"hdr:"), IoSlice::new(b""), IoSlice::new(b"body")];
+    let desc = tx.reserve_vectored(&parts).unwrap().commit();
+    assert_eq!(rx.read(desc), Some(&b"hdr:body"[..]));
+    assert!(tx.reserve_vectored(&parts[..1]).is_some());
+    assert!(tx.reserve_vectored(&[IoSlice::new(&[0; 9])]).is_none());
+  }
+
+  #[test]
+  fn payloads_through_a_channel() {
+    const MESSAGES : usize = 20_000;
+    let (mut arena_tx, mut arena_rx) = arena(256);
+    let (tx, rx) = Builder::new().capacity(8).overwrite(Policy::Block).build::<Descriptor>();
+
+    let t = thread::spawn(move || {
+      for i in 0..MESSAGES {
+        let len = i % 50;
+        while arena_tx.free() < len + 50 { thread::yield_now(); }
+        let mut region = arena_tx.reserve(len).unwrap();
+        for (j, b) in region.as_mut().iter_mut().enumerate() { *b = (i + j) as u8; }
+        let desc = region.commit();
+        tx.put(|v| *v = desc);
+      }
+    });
+
+    let mut seen = 0;
+    for desc in rx.iter() {
+      {
+        let payload = arena_rx.read(desc).unwrap();
+        assert_eq!(payload.len(), seen % 50);
+        for (j, b) in payload.iter().enumerate() { assert_eq!(*b, (seen + j) as u8); }
+      }
+      arena_rx.release(desc);
+      seen += 1;
+    }
+    t.join().unwrap();
+    assert_eq!((seen, arena_rx.used()), (MESSAGES, 0));
+  }
+}
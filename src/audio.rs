@@ -0,0 +1,236 @@
+
+// spsc ring of audio frames for handing samples between a device
+// callback and a processing thread
+//
+// channel() is the only place that allocates. after that both ends only
+// copy frames and touch two atomic cursors: no locks, no allocation, no
+// syscalls, and no panics (the lints below keep indexing, unwrapping and
+// unchecked arithmetic out of this file). a full ring does not overwrite
+// anything, the frames that did not fit are counted as overrun. a
+// consumer that wants more than there is can have the rest filled with
+// silence, counted as underrun. the counters are shared, either end can
+// look at both.
+//
+// frames are Copy, e.g. [f32; 2] for interleaved stereo
+
+#![deny(clippy::indexing_slicing, clippy::panic, clippy::unwrap_used, clippy::expect_used, clippy::arithmetic_side_effects)]
+
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use storage::CachePadded;
+
+struct Shared<F : Copy> {
+  frames    : Box<[UnsafeCell<F>]>,
+  mask      : usize,                    // capacity-1, the capacity is a power of two
+  head      : CachePadded<AtomicUsize>, // frames written, producer only
+  tail      : CachePadded<AtomicUsize>, // frames read, consumer only
+  overruns  : AtomicU64,
+  underruns : AtomicU64,
+}
+
+// a frame is only ever touched by the end that owns it between the cursors
+unsafe impl<F : Copy + Send> Sync for Shared<F> { }
+
+pub struct Producer<F : Copy> {
+  shared : Arc<Shared<F>>,
+}
+
+pub struct Consumer<F : Copy> {
+  shared : Arc<Shared<F>>,
+  silence : F,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Xruns {
+  pub overruns  : u64,          // frames the producer had to drop
+  pub underruns : u64,          // frames the consumer filled with silence
+}
+
+// a ring for at least `capacity` frames, rounded up to a power of two.
+// `silence` is what underruns are filled with. this is the one call that
+// allocates, and the only one that panics (for a zero capacity)
+#[allow(clippy::panic)]
+pub fn channel<F : Copy + Send>(capacity : usize, silence : F) -> (Producer<F>, Consumer<F>) {
+  if capacity == 0 { panic!("capacity cannot be zero"); }
+
+  let capacity = capacity.checked_next_power_of_two().unwrap_or_else(|| panic!("capacity too large"));
+  let shared = Arc::new(Shared {
+    frames    : (0..capacity).map(|_| UnsafeCell::new(silence)).collect(),
+    mask      : capacity.wrapping_sub(1),
+    head      : CachePadded::new(AtomicUsize::new(0)),
+    tail      : CachePadded::new(AtomicUsize::new(0)),
+    overruns  : AtomicU64::new(0),
+    underruns : AtomicU64::new(0),
+  });
+  (Producer { shared : shared.clone() }, Consumer { shared, silence })
+}
+
+impl <F : Copy> Shared<F> {
+  fn capacity(&self) -> usize {
+    self.frames.len()
+  }
+
+  // copies between `frames` at ring positions from `pos` on and `other`,
+  // in up to two runs around the end of the ring
+  //
+  // unsafe: the caller must own those positions
+  unsafe fn copy(&self, pos : usize, other : *mut F, count : usize, to_ring : bool) {
+    let base  = self.frames.as_ptr() as *mut F;
+    let start = pos & self.mask;
+    let first = count.min(self.capacity().wrapping_sub(start));
+    let runs  = [(start, 0, first), (0, first, count.wrapping_sub(first))];
+    for &(at, off, len) in runs.iter() {
+      let (ring, other) = (base.add(at), other.add(off));
+      if to_ring { ptr::copy_nonoverlapping(other, ring, len); }
+      else       { ptr::copy_nonoverlapping(ring, other, len); }
+    }
+  }
+
+  fn xruns(&self) -> Xruns {
+    Xruns {
+      overruns  : self.overruns.load(Ordering::Relaxed),
+      underruns : self.underruns.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl <F : Copy + Send> Producer<F> {
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+
+  // frames that fit right now
+  pub fn free(&self) -> usize {
+    let s    = &self.shared;
+    let head = s.head.load(Ordering::Relaxed);
+    let tail = s.tail.load(Ordering::Acquire);
+    s.capacity().wrapping_sub(head.wrapping_sub(tail))
+  }
+
+  // copies in as many frames as fit, oldest first, and counts the rest
+  // as overrun (check free() first to retry them later instead). returns
+  // how many were written
+  pub fn write(&mut self, frames : &[F]) -> usize {
+    let count = frames.len().min(self.free());
+    let s     = &self.shared;
+    let head  = s.head.load(Ordering::Relaxed);
+
+    // the consumer released these positions (acquire in free())
+    unsafe { s.copy(head, frames.as_ptr() as *mut F, count, true); }
+    s.head.store(head.wrapping_add(count), Ordering::Release);
+
+    let dropped = frames.len().wrapping_sub(count);
+    if dropped > 0 { s.overruns.fetch_add(dropped as u64, Ordering::Relaxed); }
+    count
+  }
+
+  pub fn xruns(&self) -> Xruns {
+    self.shared.xruns()
+  }
+}
+
+impl <F : Copy + Send> Consumer<F> {
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+
+  // frames that can be read right now
+  pub fn available(&self) -> usize {
+    let s    = &self.shared;
+    let tail = s.tail.load(Ordering::Relaxed);
+    let head = s.head.load(Ordering::Acquire);
+    head.wrapping_sub(tail)
+  }
+
+  // copies out up to `out.len()` frames, oldest first, returns how many.
+  // the rest of `out` is left as it was and nothing counts as underrun
+  pub fn read(&mut self, out : &mut [F]) -> usize {
+    let count = out.len().min(self.available());
+    let s     = &self.shared;
+    let tail  = s.tail.load(Ordering::Relaxed);
+
+    // the producer published these positions (acquire in available())
+    unsafe { s.copy(tail, out.as_mut_ptr(), count, false); }
+    s.tail.store(tail.wrapping_add(count), Ordering::Release);
+    count
+  }
+
+  // read() for a device callback that has to deliver `out.len()` frames
+  // now: what is missing is filled with silence and counted as underrun
+  pub fn read_or_silence(&mut self, out : &mut [F]) -> usize {
+    let count = self.read(out);
+    let mut missing = 0u64;
+    for f in out.iter_mut().skip(count) {
+      *f = self.silence;
+      missing = missing.wrapping_add(1);
+    }
+    if missing > 0 { self.shared.underruns.fetch_add(missing, Ordering::Relaxed); }
+    count
+  }
+
+  pub fn xruns(&self) -> Xruns {
+    self.shared.xruns()
+  }
+}
+
+#[cfg(test)]
+#[allow(clippy::indexing_slicing, clippy::unwrap_used, clippy::arithmetic_side_effects)]
+mod tests {
+  use super::{channel, Xruns};
+  use std::thread;
+
+  #[test]
+  fn overrun_and_underrun() {
+    let (mut tx, mut rx) = channel(3, [0f32; 2]);
+    assert_eq!(tx.capacity(), 4);
+    assert_eq!(tx.write(&[[1.0, 1.0], [2.0, 2.0]]), 2);
+    assert_eq!(tx.write(&[[3.0, 3.0], [4.0, 4.0], [5.0, 5.0]]), 2);
+    assert_eq!(rx.xruns(), Xruns { overruns : 1, underruns : 0 });
+
+    let mut out = [[9f32; 2]; 3];
+    assert_eq!(rx.read(&mut out), 3);
+    assert_eq!(out, [[1.0, 1.0], [2.0, 2.0], [3.0, 3.0]]);
+    // wraps around the end of the ring
+    assert_eq!(tx.write(&[[6.0, 6.0], [7.0, 7.0]]), 2);
+    assert_eq!(rx.read_or_silence(&mut out), 3);
+    assert_eq!(out, [[4.0, 4.0], [6.0, 6.0], [7.0, 7.0]]);
+    assert_eq!(rx.read_or_silence(&mut out), 0);
+    assert_eq!(out, [[0.0, 0.0]; 3]);
+    assert_eq!(tx.xruns(), Xruns { overruns : 1, underruns : 3 });
+  }
+
+  #[test]
+  fn frames_arrive_in_order() {
+    const FRAMES : u32 = 200_000;
+    let (mut tx, mut rx) = channel(256, 0u32);
+    let t = thread::spawn(move || {
+      let mut next = 0;
+      let chunk : Vec<u32> = (0..64).collect();
+      while next < FRAMES {
+        let n = (FRAMES - next).min(64) as usize;
+        let frames : Vec<u32> = chunk[..n].iter().map(|i| next + i).collect();
+        let mut sent = 0;
+        while sent < n {
+          sent += tx.write(&frames[sent..]);
+          thread::yield_now();
+        }
+        next += n as u32;
+      }
+    });
+
+    let mut expected = 0;
+    let mut out = [0u32; 100];
+    while expected < FRAMES {
+      let n = rx.read(&mut out);
+      for f in &out[..n] {
+        assert_eq!(*f, expected);
+        expected += 1;
+      }
+      if n == 0 { thread::yield_now(); }
+    }
+    t.join().unwrap();
+  }
+}
@@ -0,0 +1,476 @@
+
+// throughput and latency measurements for the channels
+//
+// results can be rendered as a table for humans or as csv/json so
+// numbers can be tracked across commits by external tooling
+//
+// besides the channel stream there are workloads generic over RingQueue,
+// so a new queue flavor can be compared with the others as soon as it
+// implements the trait, see queues()
+//
+// events() puts the dispatch pattern of the events module against a
+// channel per event type
+//
+// with the perf feature on linux every result also carries the hardware
+// counters of its run, see perf
+
+use std::cell::Cell;
+use std::hint;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use events::{self, Market};
+use queue::RingQueue;
+use simple;
+use spsc::{self, Policy};
+use wait;
+
+mod perf;
+
+pub use self::perf::Counters;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+  Table,
+  Csv,
+  Json,
+}
+
+impl Format {
+  pub fn parse(name : &str) -> Option<Format> {
+    match name {
+      "table" => Some(Format::Table),
+      "csv"   => Some(Format::Csv),
+      "json"  => Some(Format::Json),
+      _       => None,
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+  pub name        : String,
+  pub capacity    : usize,
+  pub sent        : usize,
+  pub received    : usize,
+  pub elapsed     : Duration,
+  pub median_ns   : u64,
+  pub p99_ns      : u64,
+  pub cas_retries : usize,
+  pub counters    : Option<Counters>,
+}
+
+impl BenchResult {
+  // received items per second
+  pub fn throughput(&self) -> f64 {
+    let secs = self.elapsed.as_secs_f64();
+    if secs > 0.0 { self.received as f64 / secs } else { 0.0 }
+  }
+}
+
+// value at quantile q of already sorted samples
+fn quantile(sorted : &[u64], q : f64) -> u64 {
+  if sorted.is_empty() { return 0; }
+  let at = ((sorted.len() - 1) as f64 * q).round() as usize;
+  sorted[at]
+}
+
+// one producer thread putting `items` timestamps as fast as it can, the
+// calling thread drains and measures put-to-read latency of what it sees
+pub fn spsc_stream(capacity : usize, items : usize) -> BenchResult {
+  let (tx, rx) = spsc::channel(capacity, Instant::now());
+  let done = Arc::new(AtomicBool::new(false));
+  let producer_done = done.clone();
+
+  let start = Instant::now();
+  let producer = thread::spawn(move || {
+    for _ in 0..items {
+      tx.put(|v| *v = Instant::now());
+    }
+    producer_done.store(true, Ordering::SeqCst);
+    tx.cas_retries()
+  });
+
+  let mut latencies : Vec<u64> = Vec::with_capacity(items);
+  loop {
+    let finished = done.load(Ordering::SeqCst);
+    let mut got = 0;
+    for sent in rx.try_iter() {
+      latencies.push(sent.elapsed().as_nanos() as u64);
+      got += 1;
+    }
+    if finished && got == 0 { break; }
+  }
+  let elapsed = start.elapsed();
+  let put_retries = producer.join().unwrap();
+
+  latencies.sort_unstable();
+  BenchResult {
+    name        : "spsc_stream".to_string(),
+    capacity,
+    sent        : items,
+    received    : latencies.len(),
+    elapsed,
+    median_ns   : quantile(&latencies, 0.5),
+    p99_ns      : quantile(&latencies, 0.99),
+    cas_retries : put_retries + rx.cas_retries(),
+    counters    : None,
+  }
+}
+
+// one benchmark with the counters around it, threads it starts are
+// counted as long as it joins them
+fn counted<F : FnOnce() -> BenchResult>(bench : F) -> BenchResult {
+  let (mut result, counters) = perf::measure(bench);
+  result.counters = counters;
+  result
+}
+
+// runs every benchmark once per capacity
+pub fn run(capacities : &[usize], items : usize) -> Vec<BenchResult> {
+  capacities.iter().map(|c| counted(|| spsc_stream(*c, items))).collect()
+}
+
+// timings in `samples` are per item
+fn queue_result(name : String, capacity : usize, sent : usize, received : usize,
+                elapsed : Duration, samples : Vec<u64>) -> BenchResult {
+  let mut samples = samples;
+  samples.sort_unstable();
+  BenchResult {
+    name,
+    capacity,
+    sent,
+    received,
+    elapsed,
+    median_ns   : quantile(&samples, 0.5),
+    p99_ns      : quantile(&samples, 0.99),
+    cas_retries : 0,
+    counters    : None,
+  }
+}
+
+// one item goes to an echo thread and back before the next one is sent,
+// the samples are round trips. the queues are single owner, so both sit
+// behind a Mutex, that cost is the same for every flavor. the sides spin
+// on a counter of pushes, not on the lock, or the spinner would keep
+// grabbing the lock from the other side. backing off ends in yields,
+// which keeps it usable on a single core
+pub fn ping_pong<Q, F>(name : &str, make : F, capacity : usize, items : usize) -> BenchResult
+  where Q : RingQueue<u64> + Send + 'static,
+        F : Fn(usize) -> Q
+{
+  let ping  = Arc::new((Mutex::new(make(capacity)), AtomicUsize::new(0)));
+  let pong  = Arc::new((Mutex::new(make(capacity)), AtomicUsize::new(0)));
+
+  let echo = {
+    let (ping, pong) = (ping.clone(), pong.clone());
+    thread::spawn(move || {
+      for i in 0..items {
+        let mut attempt = 0;
+        while ping.1.load(Ordering::Acquire) == i { wait::backoff(attempt); attempt += 1; }
+        let mut got = vec![];
+        ping.0.lock().unwrap().drain(|v| got.push(v));
+        let mut queue = pong.0.lock().unwrap();
+        for v in got { queue.push(v); }
+        pong.1.store(i + 1, Ordering::Release);
+      }
+    })
+  };
+
+  let mut samples = Vec::with_capacity(items);
+  let mut received = 0;
+  let start = Instant::now();
+  for i in 0..items {
+    let at = Instant::now();
+    ping.0.lock().unwrap().push(i as u64);
+    ping.1.store(i + 1, Ordering::Release);
+    let mut attempt = 0;
+    while pong.1.load(Ordering::Acquire) == i { wait::backoff(attempt); attempt += 1; }
+    received += pong.0.lock().unwrap().drain(|_| ());
+    samples.push(at.elapsed().as_nanos() as u64);
+  }
+  let elapsed = start.elapsed();
+  echo.join().unwrap();
+  queue_result(format!("{}/ping_pong", name), capacity, items, received, elapsed, samples)
+}
+
+// fills the queue to capacity and drains it in one go, samples are the
+// time per item of every burst
+pub fn burst_drain<Q : RingQueue<u64>>(name : &str, queue : Q, items : usize) -> BenchResult {
+  let mut queue    = queue;
+  let capacity     = queue.capacity();
+  let mut samples  = Vec::with_capacity(items / capacity + 1);
+  let mut sent     = 0;
+  let mut received = 0;
+
+  let start = Instant::now();
+  while sent < items {
+    let burst = capacity.min(items - sent);
+    let at = Instant::now();
+    for i in 0..burst { queue.push((sent + i) as u64); }
+    received += queue.drain(|v| { hint::black_box(v); });
+    samples.push(at.elapsed().as_nanos() as u64 / burst as u64);
+    sent += burst;
+  }
+  queue_result(format!("{}/burst_drain", name), capacity, sent, received, start.elapsed(), samples)
+}
+
+// producer and consumer at the same rate, one push and one drain per
+// item so the queue never holds more than one
+pub fn steady_state<Q : RingQueue<u64>>(name : &str, queue : Q, items : usize) -> BenchResult {
+  let mut queue    = queue;
+  let capacity     = queue.capacity();
+  let mut samples  = Vec::with_capacity(items);
+  let mut received = 0;
+
+  let start = Instant::now();
+  for i in 0..items {
+    let at = Instant::now();
+    queue.push(i as u64);
+    received += queue.drain(|v| { hint::black_box(v); });
+    samples.push(at.elapsed().as_nanos() as u64);
+  }
+  queue_result(format!("{}/steady_state", name), capacity, items, received, start.elapsed(), samples)
+}
+
+// every generic workload against one flavor
+pub fn queue_workloads<Q, F>(name : &str, make : F, capacity : usize, items : usize) -> Vec<BenchResult>
+  where Q : RingQueue<u64> + Send + 'static,
+        F : Fn(usize) -> Q
+{
+  vec![
+    counted(|| ping_pong(name, &make, capacity, items)),
+    counted(|| burst_drain(name, make(capacity), items)),
+    counted(|| steady_state(name, make(capacity), items)),
+  ]
+}
+
+// the generic workloads against every RingQueue flavor, per capacity
+pub fn queues(capacities : &[usize], items : usize) -> Vec<BenchResult> {
+  let mut out = vec![];
+  for capacity in capacities {
+    out.extend(queue_workloads("simple", |c| simple::CircularBuffer::new(c, 0u64), *capacity, items));
+    out.extend(queue_workloads("spsc", |c| spsc::CircularBuffer::new(c, 0u64), *capacity, items));
+  }
+  out
+}
+
+// the market event of item i, trades and quotes alternate with a halt
+// every 16th
+fn market_event(i : usize) -> Market {
+  match i % 16 {
+    15              => Market::Halt,
+    n if n % 2 == 0 => Market::Trade { price : i as f64, qty : n as u32 },
+    _               => Market::Quote { bid : i as f64, ask : i as f64 + 0.5 },
+  }
+}
+
+// one producer sending `items` market events over one channel of enums to
+// a Dispatcher, samples are the time per event of every dispatch() that got
+// some. nothing is lost, the ring blocks
+pub fn events_one_channel(capacity : usize, items : usize) -> BenchResult {
+  let (tx, rx) = spsc::Builder::new().capacity(capacity).overwrite(Policy::Block).build_with(Market::Halt);
+  let mut events = events::Dispatcher::new(rx);
+  let handled = Rc::new(Cell::new(0usize));
+  for kind in 0..3 {
+    let handled = handled.clone();
+    events.on(kind, move |e| { hint::black_box(e); handled.set(handled.get() + 1); });
+  }
+
+  let start = Instant::now();
+  let producer = thread::spawn(move || {
+    for i in 0..items { tx.put(|e| *e = market_event(i)); }
+  });
+  let mut samples = vec![];
+  loop {
+    let closed = events.receiver().is_closed();
+    let at = Instant::now();
+    let got = events.dispatch();
+    if got > 0 { samples.push(at.elapsed().as_nanos() as u64 / got as u64); } else if closed { break; }
+  }
+  let elapsed = start.elapsed();
+  producer.join().unwrap();
+  queue_result("events/one_channel".to_string(), capacity, items, handled.get(), elapsed, samples)
+}
+
+// the same events over a channel per type, each carrying just its payload,
+// the consumer polls them in turn. the order between types is lost
+pub fn events_per_type(capacity : usize, items : usize) -> BenchResult {
+  let build = || spsc::Builder::new().capacity(capacity).overwrite(Policy::Block);
+  let (trades_tx, trades) = build().build_with((0.0f64, 0u32));
+  let (quotes_tx, quotes) = build().build_with((0.0f64, 0.0f64));
+  let (halts_tx, halts)   = build().build_with(());
+
+  let start = Instant::now();
+  let producer = thread::spawn(move || {
+    for i in 0..items {
+      match market_event(i) {
+        Market::Trade { price, qty } => { trades_tx.put(|v| *v = (price, qty)); },
+        Market::Quote { bid, ask }   => { quotes_tx.put(|v| *v = (bid, ask)); },
+        Market::Halt                 => { halts_tx.put(|_| ()); },
+      }
+    }
+  });
+  let mut samples = vec![];
+  let mut received = 0;
+  loop {
+    let closed = trades.is_closed() && quotes.is_closed() && halts.is_closed();
+    let at = Instant::now();
+    let got = trades.try_iter().map(|v| { hint::black_box(v); }).count() +
+              quotes.try_iter().map(|v| { hint::black_box(v); }).count() +
+              halts.try_iter().count();
+    received += got;
+    if got > 0 { samples.push(at.elapsed().as_nanos() as u64 / got as u64); } else if closed { break; }
+  }
+  let elapsed = start.elapsed();
+  producer.join().unwrap();
+  queue_result("events/per_type".to_string(), capacity, items, received, elapsed, samples)
+}
+
+// both ways of sending the market events, per capacity
+pub fn events(capacities : &[usize], items : usize) -> Vec<BenchResult> {
+  let mut out = vec![];
+  for capacity in capacities {
+    out.push(counted(|| events_one_channel(*capacity, items)));
+    out.push(counted(|| events_per_type(*capacity, items)));
+  }
+  out
+}
+
+// the counter columns are there once any result has counters, a result
+// without them shows zeros
+pub fn render(results : &[BenchResult], format : Format) -> String {
+  let mut out = String::new();
+  let with_counters = results.iter().any(|r| r.counters.is_some());
+  match format {
+    Format::Table => {
+      out.push_str(&format!("{:<20} {:>9} {:>10} {:>10} {:>14} {:>11} {:>11} {:>11}",
+                            "name", "capacity", "sent", "received", "items/s",
+                            "median ns", "p99 ns", "cas retries"));
+      if with_counters { out.push_str(&format!(" {:>14} {:>12} {:>13}", "cycles", "cache misses", "branch misses")); }
+      out.push('\n');
+      for r in results {
+        out.push_str(&format!("{:<20} {:>9} {:>10} {:>10} {:>14.0} {:>11} {:>11} {:>11}",
+                              r.name, r.capacity, r.sent, r.received, r.throughput(),
+                              r.median_ns, r.p99_ns, r.cas_retries));
+        if with_counters {
+          let c = r.counters.unwrap_or_default();
+          out.push_str(&format!(" {:>14} {:>12} {:>13}", c.cycles, c.cache_misses, c.branch_misses));
+        }
+        out.push('\n');
+      }
+    },
+    Format::Csv => {
+      out.push_str("name,capacity,sent,received,seconds,throughput,median_ns,p99_ns,cas_retries");
+      if with_counters { out.push_str(",cycles,cache_misses,branch_misses"); }
+      out.push('\n');
+      for r in results {
+        out.push_str(&format!("{},{},{},{},{:.6},{:.1},{},{},{}",
+                              r.name, r.capacity, r.sent, r.received, r.elapsed.as_secs_f64(),
+                              r.throughput(), r.median_ns, r.p99_ns, r.cas_retries));
+        if with_counters {
+          let c = r.counters.unwrap_or_default();
+          out.push_str(&format!(",{},{},{}", c.cycles, c.cache_misses, c.branch_misses));
+        }
+        out.push('\n');
+      }
+    },
+    Format::Json => {
+      out.push('[');
+      for (i, r) in results.iter().enumerate() {
+        if i > 0 { out.push(','); }
+        out.push_str(&format!("\n  {{\"name\": \"{}\", \"capacity\": {}, \"sent\": {}, \"received\": {}, \
+                               \"seconds\": {:.6}, \"throughput\": {:.1}, \"median_ns\": {}, \
+                               \"p99_ns\": {}, \"cas_retries\": {}",
+                              r.name, r.capacity, r.sent, r.received, r.elapsed.as_secs_f64(),
+                              r.throughput(), r.median_ns, r.p99_ns, r.cas_retries));
+        if let Some(c) = r.counters {
+          out.push_str(&format!(", \"cycles\": {}, \"cache_misses\": {}, \"branch_misses\": {}",
+                                c.cycles, c.cache_misses, c.branch_misses));
+        }
+        out.push('}');
+      }
+      out.push_str("\n]\n");
+    },
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{render, BenchResult, Counters, Format};
+  use std::time::Duration;
+
+  fn sample() -> BenchResult {
+    BenchResult {
+      name        : "x".to_string(),
+      capacity    : 8,
+      sent        : 100,
+      received    : 50,
+      elapsed     : Duration::from_millis(500),
+      median_ns   : 10,
+      p99_ns      : 90,
+      cas_retries : 3,
+      counters    : None,
+    }
+  }
+
+  #[test]
+  fn csv_output() {
+    let out = render(&[sample()], Format::Csv);
+    let lines : Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1], "x,8,100,50,0.500000,100.0,10,90,3");
+  }
+
+  #[test]
+  fn json_output() {
+    let out = render(&[sample(), sample()], Format::Json);
+    assert!(out.starts_with("[\n  {\"name\": \"x\", \"capacity\": 8,"));
+    assert_eq!(out.matches("\"cas_retries\": 3}").count(), 2);
+    assert!(out.ends_with("}\n]\n"));
+  }
+
+  #[test]
+  fn counter_columns() {
+    let mut counted = sample();
+    counted.counters = Some(Counters { cycles : 1000, cache_misses : 20, branch_misses : 5 });
+    let out = render(&[counted.clone(), sample()], Format::Csv);
+    let lines : Vec<&str> = out.lines().collect();
+    assert!(lines[0].ends_with(",cas_retries,cycles,cache_misses,branch_misses"));
+    assert_eq!(lines[1], "x,8,100,50,0.500000,100.0,10,90,3,1000,20,5");
+    assert_eq!(lines[2], "x,8,100,50,0.500000,100.0,10,90,3,0,0,0");
+    let out = render(&[counted], Format::Json);
+    assert!(out.contains("\"cas_retries\": 3, \"cycles\": 1000, \"cache_misses\": 20, \"branch_misses\": 5}"));
+  }
+
+  #[test]
+  fn queues_run_every_workload() {
+    let results = super::queues(&[4], 50);
+    assert_eq!(results.len(), 6);
+    assert_eq!(results[0].name, "simple/ping_pong");
+    assert_eq!(results[5].name, "spsc/steady_state");
+    for r in &results {
+      assert_eq!(r.capacity, 4);
+      assert_eq!(r.received, 50, "{}", r.name);
+    }
+  }
+
+  #[test]
+  fn events_both_ways() {
+    let results = super::events(&[4], 500);
+    assert_eq!(results.iter().map(|r| r.name.as_str()).collect::<Vec<&str>>(), vec!["events/one_channel", "events/per_type"]);
+    for r in &results { assert_eq!(r.received, 500, "{}", r.name); }
+  }
+
+  #[test]
+  fn stream_counts_items() {
+    let r = super::spsc_stream(16, 1000);
+    assert_eq!(r.sent, 1000);
+    assert!(r.received > 0 && r.received <= 1000);
+    assert!(r.median_ns <= r.p99_ns);
+  }
+}
@@ -0,0 +1,162 @@
+
+// hardware counters around a benchmark, through perf_event_open
+//
+// the counters follow the calling thread and, inherited, every thread it
+// spawns while they run, as long as those are joined before the end. only
+// user space is counted, which is what an unprivileged process may open
+// with the default perf_event_paranoid. without the perf feature, off
+// linux, or if the kernel refuses, there simply are no counters
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+  pub cycles        : u64,
+  pub cache_misses  : u64,
+  pub branch_misses : u64,
+}
+
+// runs `f`, with the counters it took if there are any
+pub fn measure<R, F : FnOnce() -> R>(f : F) -> (R, Option<Counters>) {
+  let group = imp::Group::open();
+  let ret = f();
+  (ret, group.and_then(|g| g.finish()))
+}
+
+#[cfg(all(feature = "perf", target_os = "linux"))]
+mod imp {
+  use std::mem;
+  use std::os::raw::{c_int, c_long, c_ulong, c_void};
+
+  use super::Counters;
+
+  #[cfg(target_arch = "x86_64")]
+  const SYS_PERF_EVENT_OPEN : c_long = 298;
+  #[cfg(target_arch = "x86")]
+  const SYS_PERF_EVENT_OPEN : c_long = 336;
+  #[cfg(target_arch = "arm")]
+  const SYS_PERF_EVENT_OPEN : c_long = 364;
+  #[cfg(any(target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64"))]
+  const SYS_PERF_EVENT_OPEN : c_long = 241;
+
+  const PERF_TYPE_HARDWARE            : u32 = 0;
+  const PERF_COUNT_HW_CPU_CYCLES      : u64 = 0;
+  const PERF_COUNT_HW_CACHE_MISSES    : u64 = 3;
+  const PERF_COUNT_HW_BRANCH_MISSES   : u64 = 5;
+
+  // perf_event_attr flag bits
+  const DISABLED       : u64 = 1 << 0;
+  const INHERIT        : u64 = 1 << 1;
+  const EXCLUDE_KERNEL : u64 = 1 << 5;
+  const EXCLUDE_HV     : u64 = 1 << 6;
+
+  const PERF_EVENT_IOC_ENABLE  : c_ulong = 0x2400;
+  const PERF_EVENT_IOC_DISABLE : c_ulong = 0x2401;
+
+  // the first PERF_ATTR_SIZE_VER0 bytes of perf_event_attr, the kernel
+  // takes the rest as zero
+  #[repr(C)]
+  struct Attr {
+    kind          : u32,
+    size          : u32,
+    config        : u64,
+    sample_period : u64,
+    sample_type   : u64,
+    read_format   : u64,
+    flags         : u64,
+    wakeup_events : u32,
+    bp_type       : u32,
+    bp_addr       : u64,
+  }
+
+  extern "C" {
+    fn syscall(num : c_long, ...) -> c_long;
+    fn ioctl(fd : c_int, request : c_ulong, ...) -> c_int;
+    fn read(fd : c_int, buf : *mut c_void, count : usize) -> isize;
+    fn close(fd : c_int) -> c_int;
+  }
+
+  struct Counter(c_int);
+
+  impl Counter {
+    fn open(config : u64) -> Option<Counter> {
+      let attr = Attr {
+        kind          : PERF_TYPE_HARDWARE,
+        size          : mem::size_of::<Attr>() as u32,
+        config,
+        sample_period : 0,
+        sample_type   : 0,
+        read_format   : 0,
+        flags         : DISABLED | INHERIT | EXCLUDE_KERNEL | EXCLUDE_HV,
+        wakeup_events : 0,
+        bp_type       : 0,
+        bp_addr       : 0,
+      };
+      // this thread on any cpu, no group, no flags
+      let fd = unsafe { syscall(SYS_PERF_EVENT_OPEN, &attr as *const Attr, 0 as c_int, -1 as c_int, -1 as c_int, 0 as c_ulong) };
+      if fd < 0 { None } else { Some(Counter(fd as c_int)) }
+    }
+
+    fn value(&self) -> Option<u64> {
+      let mut v : u64 = 0;
+      let n = unsafe { read(self.0, &mut v as *mut u64 as *mut c_void, mem::size_of::<u64>()) };
+      if n == mem::size_of::<u64>() as isize { Some(v) } else { None }
+    }
+  }
+
+  impl Drop for Counter {
+    fn drop(&mut self) {
+      unsafe { close(self.0); }
+    }
+  }
+
+  pub struct Group {
+    counters : [Counter; 3],
+  }
+
+  impl Group {
+    // all three or none, enabled right away
+    pub fn open() -> Option<Group> {
+      let counters = [
+        Counter::open(PERF_COUNT_HW_CPU_CYCLES)?,
+        Counter::open(PERF_COUNT_HW_CACHE_MISSES)?,
+        Counter::open(PERF_COUNT_HW_BRANCH_MISSES)?,
+      ];
+      for c in counters.iter() { unsafe { ioctl(c.0, PERF_EVENT_IOC_ENABLE, 0 as c_int); } }
+      Some(Group { counters })
+    }
+
+    pub fn finish(self) -> Option<Counters> {
+      for c in self.counters.iter() { unsafe { ioctl(c.0, PERF_EVENT_IOC_DISABLE, 0 as c_int); } }
+      Some(Counters {
+        cycles        : self.counters[0].value()?,
+        cache_misses  : self.counters[1].value()?,
+        branch_misses : self.counters[2].value()?,
+      })
+    }
+  }
+}
+
+#[cfg(not(all(feature = "perf", target_os = "linux")))]
+mod imp {
+  use super::Counters;
+
+  pub struct Group;
+
+  impl Group {
+    pub fn open() -> Option<Group> { None }
+    pub fn finish(self) -> Option<Counters> { None }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::measure;
+
+  #[test]
+  fn counts_or_stays_out_of_the_way() {
+    let (sum, counters) = measure(|| (0..100_000u64).sum::<u64>());
+    assert_eq!(sum, 4_999_950_000);
+    if cfg!(not(all(feature = "perf", target_os = "linux"))) { assert_eq!(counters, None); }
+    // the kernel may still refuse, e.g. inside a container
+    if let Some(c) = counters { assert!(c.cycles > 0); }
+  }
+}
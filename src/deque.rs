@@ -0,0 +1,279 @@
+
+// Chase-Lev work-stealing deque
+//
+// the owning Worker pushes and pops at the bottom, LIFO, which keeps the
+// freshest (cache hot) task local. any number of Stealers take from the
+// top, FIFO, and only they and the worker's pop of the very last task race
+// for it with a CAS on top. orderings follow Lê, Pop, Cohen, Zappa Nardelli
+// (PPoPP 2013), "Correct and efficient work-stealing for weak memory
+// models".
+//
+// the buffer doubles when full. a stealer may still be reading the old
+// one, so retired buffers are kept until the deque itself goes away, which
+// costs at most as much memory again as the largest buffer
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicIsize, AtomicPtr, Ordering};
+
+use fence;
+use wait;
+
+const MIN_CAPACITY : usize = 16;
+
+struct Buffer<T> {
+  slots : Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+struct Inner<T> {
+  top     : AtomicIsize,                // next to steal
+  bottom  : AtomicIsize,                // next to push, worker only
+  buffer  : AtomicPtr<Buffer<T>>,
+  retired : UnsafeCell<Vec<Buffer<T>>>, // worker only, the slots stay put
+}
+
+// the owner's end, one per deque
+pub struct Worker<T : Send> {
+  inner : Arc<Inner<T>>,
+  _not_sync : PhantomData<*mut ()>,
+}
+
+// the other end, clone one for every thread that steals
+pub struct Stealer<T : Send> {
+  inner : Arc<Inner<T>>,
+}
+
+unsafe impl<T : Send> Send for Worker<T> { }
+unsafe impl<T : Send> Send for Stealer<T> { }
+unsafe impl<T : Send> Sync for Stealer<T> { }
+
+impl <T> Buffer<T> {
+  fn new(capacity : usize) -> Box<Buffer<T>> {
+    Box::new(Buffer { slots : (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect() })
+  }
+
+  fn at(&self, index : isize) -> *mut MaybeUninit<T> {
+    self.slots[index as usize & (self.slots.len() - 1)].get()
+  }
+
+  unsafe fn write(&self, index : isize, value : T) {
+    ptr::write(self.at(index), MaybeUninit::new(value));
+  }
+
+  // a bitwise copy, only one of the copies may be kept. volatile as a
+  // stealer can race with the worker reusing the slot, it then throws
+  // the copy away because its CAS fails
+  unsafe fn read(&self, index : isize) -> MaybeUninit<T> {
+    ptr::read_volatile(self.at(index))
+  }
+}
+
+pub fn new<T : Send>() -> (Worker<T>, Stealer<T>) {
+  let inner = Arc::new(Inner {
+    top     : AtomicIsize::new(0),
+    bottom  : AtomicIsize::new(0),
+    buffer  : AtomicPtr::new(Box::into_raw(Buffer::new(MIN_CAPACITY))),
+    retired : UnsafeCell::new(Vec::new()),
+  });
+  (Worker { inner : inner.clone(), _not_sync : PhantomData }, Stealer { inner })
+}
+
+impl <T> Inner<T> {
+  fn len(&self) -> usize {
+    let b = self.bottom.load(Ordering::Relaxed);
+    let t = self.top.load(Ordering::Relaxed);
+    (b - t).max(0) as usize
+  }
+}
+
+impl <T : Send> Worker<T> {
+  pub fn push(&self, value : T) {
+    let inner = &*self.inner;
+    let b = inner.bottom.load(Ordering::Relaxed);
+    let t = inner.top.load(Ordering::Acquire);
+    let mut buffer = unsafe { &*inner.buffer.load(Ordering::Relaxed) };
+    if b - t >= buffer.slots.len() as isize {
+      buffer = self.grow(buffer, t, b);
+    }
+    unsafe { buffer.write(b, value); }
+    // the task is written before a stealer can see the new bottom
+    fence::release();
+    inner.bottom.store(b + 1, fence::on(Ordering::Relaxed, Ordering::Release));
+  }
+
+  // the most recently pushed task
+  pub fn pop(&self) -> Option<T> {
+    let inner = &*self.inner;
+    let b = inner.bottom.load(Ordering::Relaxed) - 1;
+    let buffer = unsafe { &*inner.buffer.load(Ordering::Relaxed) };
+    inner.bottom.store(b, Ordering::Relaxed);
+    // orders the bottom store before the top load, against steal()
+    atomic::fence(Ordering::SeqCst);
+    let t = inner.top.load(Ordering::Relaxed);
+
+    if t > b {
+      inner.bottom.store(b + 1, Ordering::Relaxed);
+      return None;
+    }
+    let value = unsafe { buffer.read(b) };
+    if t < b {
+      return Some(unsafe { value.assume_init() });
+    }
+    // the last task, a stealer may be after it as well
+    let won = inner.top.compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::Relaxed).is_ok();
+    inner.bottom.store(b + 1, Ordering::Relaxed);
+    if won { Some(unsafe { value.assume_init() }) } else { None }
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn stealer(&self) -> Stealer<T> {
+    Stealer { inner : self.inner.clone() }
+  }
+
+  // moves tasks t..b into a buffer twice the size
+  fn grow(&self, old : &Buffer<T>, t : isize, b : isize) -> &Buffer<T> {
+    let new = Buffer::new(old.slots.len() * 2);
+    for i in t..b {
+      unsafe { ptr::copy_nonoverlapping(old.at(i), new.at(i), 1); }
+    }
+    let new = Box::into_raw(new);
+    // stealers that load the new buffer see the tasks moved into it
+    let old = self.inner.buffer.swap(new, Ordering::Release);
+    unsafe {
+      (*self.inner.retired.get()).push(*Box::from_raw(old));
+      &*new
+    }
+  }
+}
+
+impl <T : Send> Stealer<T> {
+  // the oldest task, None if there is none. retries while it loses the
+  // race for a task to the worker or other stealers
+  pub fn steal(&self) -> Option<T> {
+    let mut attempt = 0;
+    loop {
+      match self.try_steal() {
+        Ok(value) => return value,
+        Err(())   => { wait::backoff(attempt); attempt += 1; },
+      }
+    }
+  }
+
+  // one attempt, Err if another thread took the task first
+  fn try_steal(&self) -> Result<Option<T>, ()> {
+    let inner = &*self.inner;
+    let t = inner.top.load(Ordering::Acquire);
+    // orders the top load before the bottom load, against pop()
+    atomic::fence(Ordering::SeqCst);
+    let b = inner.bottom.load(Ordering::Acquire);
+    if t >= b { return Ok(None); }
+
+    let buffer = unsafe { &*inner.buffer.load(Ordering::Acquire) };
+    let value = unsafe { buffer.read(t) };
+    match inner.top.compare_exchange(t, t + 1, Ordering::SeqCst, Ordering::Relaxed) {
+      Ok(_)  => Ok(Some(unsafe { value.assume_init() })),
+      Err(_) => Err(()),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl <T : Send> Clone for Stealer<T> {
+  fn clone(&self) -> Stealer<T> {
+    Stealer { inner : self.inner.clone() }
+  }
+}
+
+// the last handle drops the tasks still queued
+impl <T> Drop for Inner<T> {
+  fn drop(&mut self) {
+    let b = *self.bottom.get_mut();
+    let t = *self.top.get_mut();
+    let buffer = unsafe { Box::from_raw(*self.buffer.get_mut()) };
+    for i in t..b {
+      unsafe { ptr::drop_in_place((*buffer.at(i)).as_mut_ptr()); }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::new;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  #[test]
+  fn lifo_for_the_owner_fifo_for_thieves() {
+    let (w, s) = new();
+    for i in 0..100 { w.push(i); }
+    assert_eq!(w.len(), 100);
+    assert_eq!(w.pop(), Some(99));
+    assert_eq!(s.steal(), Some(0));
+    assert_eq!(s.clone().steal(), Some(1));
+    let mut rest = vec![];
+    while let Some(i) = w.pop() { rest.push(i); }
+    assert_eq!(rest, (2..99).rev().collect::<Vec<i32>>());
+    assert!(w.is_empty() && s.steal().is_none() && w.pop().is_none());
+  }
+
+  #[test]
+  fn queued_tasks_are_dropped() {
+    let task = Arc::new(());
+    {
+      let (w, _s) = new();
+      for _ in 0..40 { w.push(task.clone()); }
+      w.pop();
+    }
+    assert_eq!(Arc::strong_count(&task), 1);
+  }
+
+  #[test]
+  fn every_task_runs_once() {
+    const TASKS   : usize = 100_000;
+    const THIEVES : usize = 3;
+    let (w, s) = new::<usize>();
+    let runs : Arc<Vec<AtomicUsize>> = Arc::new((0..TASKS).map(|_| AtomicUsize::new(0)).collect());
+    let done = Arc::new(AtomicUsize::new(0));
+
+    let thieves : Vec<_> = (0..THIEVES).map(|_| {
+      let (s, runs, done) = (s.clone(), runs.clone(), done.clone());
+      thread::spawn(move || {
+        while done.load(Ordering::Acquire) < TASKS {
+          match s.steal() {
+            Some(i) => { runs[i].fetch_add(1, Ordering::Relaxed); done.fetch_add(1, Ordering::Release); },
+            None    => thread::yield_now(),
+          }
+        }
+      })
+    }).collect();
+
+    // pushes in bursts so the buffer grows while thieves are at it
+    for chunk in 0..TASKS / 100 {
+      for i in 0..100 { w.push(chunk * 100 + i); }
+      for _ in 0..30 {
+        if let Some(i) = w.pop() { runs[i].fetch_add(1, Ordering::Relaxed); done.fetch_add(1, Ordering::Release); }
+      }
+    }
+    while let Some(i) = w.pop() { runs[i].fetch_add(1, Ordering::Relaxed); done.fetch_add(1, Ordering::Release); }
+    for t in thieves { t.join().unwrap(); }
+    assert!(runs.iter().all(|r| r.load(Ordering::Relaxed) == 1));
+  }
+}
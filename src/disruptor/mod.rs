@@ -0,0 +1,442 @@
+
+// one ring, several consumer stages processing it in dependency order
+//
+// every stage owns a cursor (the next seqno it will look at). a stage only
+// sees slots all of its dependencies released, and the producer only
+// reuses a slot once every stage is past it. this gives e.g. a
+// journal -> replicate -> apply pipeline over a single allocation, without
+// copying items from one channel into the next.
+//
+// Producer::pause() halts every stage at one seqno, e.g. for a consistent
+// snapshot of what the stages keep: each finishes everything before it,
+// none gets to anything after it, until the pause is lifted. the stage
+// cursors tell when they are all there
+
+use std::cell::UnsafeCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use seq::{self, Seqno};
+use wait::{CancelToken, Canceled, SpinThenPark, WaitStrategy};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StageId(usize);
+
+struct Shared<T : Clone> {
+  slots    : Box<[UnsafeCell<T>]>,
+  cursor   : AtomicU64,            // next seqno the producer publishes
+  stages   : Vec<AtomicU64>,       // next seqno each stage processes
+  deps     : Vec<Vec<usize>>,      // stage -> stages it waits for
+  pause    : AtomicU64,            // stages halt here, RUNNING if not
+  wait     : Arc<dyn WaitStrategy>,
+}
+
+const RUNNING : Seqno = Seqno::MAX;
+
+// each slot is touched either by the producer or by the stages, never both
+unsafe impl<T : Clone + Send + Sync> Sync for Shared<T> { }
+
+impl <T : Clone> Shared<T> {
+  // how far `stage` may read
+  fn barrier(&self, stage : usize) -> Seqno {
+    let deps = &self.deps[stage];
+    let to = if deps.is_empty() {
+      self.cursor.load(Ordering::Acquire)
+    } else {
+      deps.iter().map(|d| self.stages[*d].load(Ordering::Acquire)).min().unwrap()
+    };
+    // a pause is in place before anything past it is published
+    let pause = self.pause.load(Ordering::Acquire);
+    if pause != RUNNING && seq::before(pause, to) { pause } else { to }
+  }
+
+  // the slowest stage, the producer must not lap it
+  fn gate(&self) -> Seqno {
+    self.stages.iter().map(|s| s.load(Ordering::Acquire)).min().unwrap()
+  }
+
+  // true if the producer may write `seqno` without lapping a stage
+  fn has_room(&self, seqno : Seqno) -> bool {
+    seq::distance(self.gate(), seqno) < self.slots.len() as Seqno
+  }
+
+  fn slot(&self, seqno : Seqno) -> &UnsafeCell<T> {
+    &self.slots[(seqno % self.slots.len() as Seqno) as usize]
+  }
+}
+
+pub struct Builder {
+  deps  : Vec<Vec<usize>>,
+  wait  : Arc<dyn WaitStrategy>,
+}
+
+pub struct Producer<T : Clone> {
+  shared : Arc<Shared<T>>,
+}
+
+pub struct Consumer<T : Clone> {
+  shared : Arc<Shared<T>>,
+  stage  : usize,
+}
+
+// holds the stages at one seqno until dropped, see Producer::pause()
+pub struct Pause<T : Clone> {
+  shared : Arc<Shared<T>>,
+  at     : Seqno,
+}
+
+unsafe impl<T : Clone + Send + Sync> Send for Producer<T> { }
+unsafe impl<T : Clone + Send + Sync> Send for Consumer<T> { }
+
+impl Default for Builder {
+  fn default() -> Builder {
+    Builder::new()
+  }
+}
+
+impl Builder {
+  pub fn new() -> Builder {
+    Builder {
+      deps : vec![],
+      wait : Arc::new(SpinThenPark::default()),
+    }
+  }
+
+  pub fn wait<W : WaitStrategy + 'static>(mut self, strategy : W) -> Builder {
+    self.wait = Arc::new(strategy);
+    self
+  }
+
+  // adds a stage that only sees what all of `after` released,
+  // an empty list means it follows the producer directly
+  pub fn stage(&mut self, after : &[StageId]) -> StageId {
+    let id = self.deps.len();
+    self.deps.push(after.iter().map(|s| s.0).collect());
+    StageId(id)
+  }
+
+  // the consumers are returned in the order the stages were added
+  pub fn build<T : Clone + Send>(self, size : usize, default_value : T) -> (Producer<T>, Vec<Consumer<T>>) {
+    if size == 0 { panic!("size cannot be zero"); }
+    if self.deps.is_empty() { panic!("at least one stage is needed"); }
+
+    let stages = self.deps.len();
+    let shared = Arc::new(Shared {
+      slots  : (0..size).map(|_| UnsafeCell::new(default_value.clone())).collect(),
+      cursor : AtomicU64::new(0),
+      stages : (0..stages).map(|_| AtomicU64::new(0)).collect(),
+      deps   : self.deps,
+      pause  : AtomicU64::new(RUNNING),
+      wait   : self.wait,
+    });
+
+    let consumers = (0..stages).map(|stage| Consumer { shared : shared.clone(), stage }).collect();
+    (Producer { shared }, consumers)
+  }
+}
+
+impl <T : Clone + Send> Producer<T> {
+  pub fn capacity(&self) -> usize {
+    self.shared.slots.len()
+  }
+
+  // None if the slowest stage still holds every slot
+  pub fn try_put<F>(&mut self, setter : F) -> Option<Seqno>
+    where F : FnMut(&mut T)
+  {
+    let seqno = self.shared.cursor.load(Ordering::Relaxed);
+    if !self.shared.has_room(seqno) { return None; }
+    Some(self.publish(seqno, setter))
+  }
+
+  // blocks while the ring is full
+  pub fn put<F>(&mut self, setter : F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    let seqno = self.shared.cursor.load(Ordering::Relaxed);
+    let shared = &self.shared;
+    shared.wait.wait_for(&|| shared.has_room(seqno), None);
+    self.publish(seqno, setter)
+  }
+
+  // put() that another thread can abort while the ring is full
+  pub fn put_cancelable<F>(&mut self, setter : F, token : &CancelToken) -> Result<Seqno, Canceled>
+    where F : FnMut(&mut T)
+  {
+    let seqno = self.shared.cursor.load(Ordering::Relaxed);
+    let shared = &self.shared;
+    token.wait_for(&shared.wait, &|| shared.has_room(seqno), None)?;
+    Ok(self.publish(seqno, setter))
+  }
+
+  // stops every stage at the next seqno to be put. the producer can go on
+  // putting until the ring is full, the stages get to that once the Pause
+  // is gone
+  pub fn pause(&mut self) -> Pause<T> {
+    if self.shared.pause.load(Ordering::Relaxed) != RUNNING { panic!("the stages are paused already"); }
+    let at = self.shared.cursor.load(Ordering::Relaxed);
+    self.shared.pause.store(at, Ordering::Release);
+    Pause { shared : self.shared.clone(), at }
+  }
+
+  fn publish<F>(&mut self, seqno : Seqno, setter : F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    let mut setter = setter;
+    unsafe { setter(&mut *self.shared.slot(seqno).get()); }
+    self.shared.cursor.store(seqno + 1, Ordering::Release);
+    self.shared.wait.notify();
+    seqno
+  }
+}
+
+impl <T : Clone + Send> Consumer<T> {
+  pub fn stage(&self) -> StageId {
+    StageId(self.stage)
+  }
+
+  // items released by the dependencies but not processed here yet
+  pub fn available(&self) -> usize {
+    seq::distance(self.shared.stages[self.stage].load(Ordering::Relaxed), self.shared.barrier(self.stage)) as usize
+  }
+
+  // returns false if nothing became available before the timeout
+  pub fn wait(&self, timeout : Option<Duration>) -> bool {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    self.shared.wait.wait_for(&|| self.available() > 0, deadline)
+  }
+
+  pub fn wait_cancelable(&self, timeout : Option<Duration>, token : &CancelToken) -> Result<bool, Canceled> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    token.wait_for(&self.shared.wait, &|| self.available() > 0, deadline)
+  }
+
+  // hands every available (seqno, item) to `handler`, then releases them
+  // to the dependent stages in one go. returns the number of items.
+  //
+  // if the handler panics the items before the failing one are released
+  // and the panic goes on, a later process() starts at the failing item
+  pub fn process<F>(&mut self, handler : F) -> usize
+    where F : FnMut(Seqno, &T)
+  {
+    let mut handler = handler;
+    let from = self.shared.stages[self.stage].load(Ordering::Relaxed);
+    let to   = self.shared.barrier(self.stage);
+
+    let count = seq::distance(from, to);
+    for seqno in (0..count).map(|i| from.wrapping_add(i)) {
+      let item = unsafe { &*self.shared.slot(seqno).get() };
+      if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| handler(seqno, item))) {
+        self.release(from, seqno);
+        panic::resume_unwind(panic);
+      }
+    }
+
+    self.release(from, to);
+    count as usize
+  }
+
+  // true if this stage got to a pause and waits for it to end
+  pub fn is_paused(&self) -> bool {
+    self.shared.pause.load(Ordering::Acquire) == self.shared.stages[self.stage].load(Ordering::Relaxed)
+  }
+
+  fn release(&self, from : Seqno, to : Seqno) {
+    if seq::before(from, to) {
+      self.shared.stages[self.stage].store(to, Ordering::Release);
+      self.shared.wait.notify();
+    }
+  }
+}
+
+impl <T : Clone> Pause<T> {
+  // where the stages stop, everything before it is processed by all of them
+  pub fn at(&self) -> Seqno {
+    self.at
+  }
+
+  // true once every stage is at the pause
+  pub fn is_reached(&self) -> bool {
+    self.shared.gate() == self.at
+  }
+
+  // waits for every stage to get to the pause, false on timeout
+  pub fn wait(&self, timeout : Option<Duration>) -> bool {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    self.shared.wait.wait_for(&|| self.is_reached(), deadline)
+  }
+
+  // lets the stages go on, same as dropping it
+  pub fn resume(self) { }
+}
+
+impl <T : Clone> Drop for Pause<T> {
+  fn drop(&mut self) {
+    self.shared.pause.store(RUNNING, Ordering::Release);
+    self.shared.wait.notify();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Builder;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicU64, Ordering};
+  use std::thread;
+  use std::time::Duration;
+  use wait::Blocking;
+
+  #[test]
+  fn stages_see_items_in_order() {
+    let mut b = Builder::new();
+    let first  = b.stage(&[]);
+    let second = b.stage(&[first]);
+    assert_eq!(second.0, 1);
+    let (mut tx, mut rx) = b.build(2, 0i32);
+
+    tx.put(|v| *v = 1);
+    tx.put(|v| *v = 2);
+    assert!(tx.try_put(|v| *v = 3).is_none());
+
+    let mut seen = vec![];
+    assert_eq!(rx[1].process(|_, v| seen.push(*v)), 0);
+    assert_eq!(rx[0].process(|_, v| seen.push(*v)), 2);
+    assert_eq!(rx[1].available(), 2);
+    assert!(tx.try_put(|v| *v = 3).is_none());
+    assert_eq!(rx[1].process(|_, v| seen.push(10 * *v)), 2);
+    assert_eq!(seen, vec![1, 2, 10, 20]);
+    assert_eq!(tx.try_put(|v| *v = 3), Some(2));
+  }
+
+  #[test]
+  fn panicking_handler_releases_handled_items() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    let mut b = Builder::new();
+    let first = b.stage(&[]);
+    b.stage(&[first]);
+    let (mut tx, mut rx) = b.build(4, 0i32);
+    for i in 0..4 { tx.put(|v| *v = i); }
+
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+      rx[0].process(|_, v| if *v == 2 { panic!("handler failed") });
+    }));
+    assert!(ret.is_err());
+    assert_eq!(rx[1].available(), 2);
+
+    let mut seen = vec![];
+    assert_eq!(rx[0].process(|_, v| seen.push(*v)), 2);
+    assert_eq!(seen, vec![2, 3]);
+  }
+
+  #[test]
+  fn cancel_full_producer() {
+    use wait::{CancelToken, Canceled};
+
+    let mut b = Builder::new();
+    b.stage(&[]);
+    let (mut tx, _rx) = b.build(1, 0i32);
+    tx.put(|v| *v = 1);
+
+    let token = CancelToken::new();
+    token.cancel();
+    assert_eq!(tx.put_cancelable(|v| *v = 2, &token), Err(Canceled));
+  }
+
+  #[test]
+  fn stages_halt_at_a_pause() {
+    let mut b = Builder::new();
+    let first = b.stage(&[]);
+    b.stage(&[first]);
+    let (mut tx, mut rx) = b.build(8, 0i32);
+    for i in 0..3 { tx.put(|v| *v = i); }
+
+    let pause = tx.pause();
+    assert_eq!(pause.at(), 3);
+    for i in 3..5 { tx.put(|v| *v = i); }
+    assert!(!pause.wait(Some(Duration::from_millis(0))));
+    assert_eq!(rx[0].process(|_, _| ()), 3);
+    assert!(rx[0].is_paused() && !pause.is_reached());
+    assert_eq!(rx[1].process(|_, _| ()), 3);
+    assert!(pause.wait(Some(Duration::from_millis(0))));
+    assert_eq!(rx[0].available(), 0);
+
+    pause.resume();
+    assert!(!rx[0].is_paused());
+    let mut seen = vec![];
+    assert_eq!(rx[0].process(|_, v| seen.push(*v)), 2);
+    assert_eq!(seen, vec![3, 4]);
+  }
+
+  #[test]
+  fn pauses_while_the_stages_run() {
+    const ITEMS : u64 = 10000;
+
+    let mut b = Builder::new();
+    let first = b.stage(&[]);
+    b.stage(&[first]);
+    let (mut tx, rx) = b.build(16, 0u64);
+
+    let done : Arc<Vec<AtomicU64>> = Arc::new((0..2).map(|_| AtomicU64::new(0)).collect());
+    let workers : Vec<_> = rx.into_iter().enumerate().map(|(i, mut c)| {
+      let done = done.clone();
+      thread::spawn(move || {
+        while done[i].load(Ordering::SeqCst) < ITEMS {
+          c.wait(None);
+          c.process(|seqno, _| done[i].store(seqno + 1, Ordering::SeqCst));
+        }
+      })
+    }).collect();
+
+    for i in 0..ITEMS {
+      tx.put(|v| *v = i);
+      if i % 1000 == 999 {
+        let pause = tx.pause();
+        pause.wait(None);
+        // a consistent cut: both stages handled exactly what came before
+        for d in done.iter() { assert_eq!(d.load(Ordering::SeqCst), pause.at()); }
+      }
+    }
+    for w in workers { w.join().unwrap(); }
+  }
+
+  #[test]
+  fn pipeline_respects_dependencies() {
+    const ITEMS : u64 = 10000;
+
+    let mut b = Builder::new().wait(Blocking::default());
+    let journal   = b.stage(&[]);
+    let replicate = b.stage(&[journal]);
+    let apply     = b.stage(&[replicate, journal]);
+    let (mut tx, rx) = b.build(16, 0u64);
+    assert_eq!(rx[2].stage(), apply);
+
+    let done : Arc<Vec<AtomicU64>> = Arc::new((0..3).map(|_| AtomicU64::new(0)).collect());
+    let workers : Vec<_> = rx.into_iter().enumerate().map(|(i, mut c)| {
+      let done = done.clone();
+      thread::spawn(move || {
+        let mut next = 0;
+        while next < ITEMS {
+          c.wait(None);
+          c.process(|seqno, v| {
+            assert_eq!(seqno, next);
+            assert_eq!(*v, seqno * 3);
+            // everything upstream must be done with this item already
+            for up in 0..i {
+              assert!(done[up].load(Ordering::SeqCst) > seqno);
+            }
+            next += 1;
+            done[i].store(next, Ordering::SeqCst);
+          });
+        }
+      })
+    }).collect();
+
+    for i in 0..ITEMS {
+      tx.put(|v| *v = i * 3);
+    }
+    for w in workers { w.join().unwrap(); }
+  }
+}
@@ -0,0 +1,164 @@
+
+// several kinds of events over one spsc channel, dispatched by kind
+//
+// the producer puts variants of one enum, the consumer registers a
+// handler per variant and a Dispatcher calls the right one for every
+// event it takes. the enum says which variant is which through Event, so
+// dispatching is an index into the handlers, not a chain of matches.
+//
+// compared with a channel per event type this keeps the order between
+// kinds and needs one ring, one wakeup and one poll for all of them. the
+// price is that every slot is as big as the biggest variant, and a burst
+// of one kind can evict the others from an overwriting ring. bench::events()
+// measures both ways, Market is the enum it uses and an example of one
+
+use std::fmt;
+
+use spsc;
+
+// an enum whose variants are told apart by number, 0 to KINDS-1
+pub trait Event : Clone + Send {
+  const KINDS : usize;
+
+  fn kind(&self) -> usize;
+}
+
+type Handler<E> = Box<dyn FnMut(E)>;
+
+// the receiving end with a handler per kind, events of kinds without one
+// are counted and dropped
+pub struct Dispatcher<E : Event> {
+  rx        : spsc::Receiver<E>,
+  handlers  : Vec<Option<Handler<E>>>,
+  unhandled : u64,
+}
+
+// a channel of `capacity` events, the dispatcher has no handlers yet
+pub fn channel<E : Event>(capacity : usize, default_value : E) -> (spsc::Sender<E>, Dispatcher<E>) {
+  let (tx, rx) = spsc::channel(capacity, default_value);
+  (tx, Dispatcher::new(rx))
+}
+
+impl <E : Event> Dispatcher<E> {
+  pub fn new(rx : spsc::Receiver<E>) -> Dispatcher<E> {
+    Dispatcher { rx, handlers : (0..E::KINDS).map(|_| None).collect(), unhandled : 0 }
+  }
+
+  // replaces whatever handled `kind` before
+  pub fn on<F : FnMut(E) + 'static>(&mut self, kind : usize, handler : F) -> &mut Dispatcher<E> {
+    if kind >= E::KINDS { panic!("kind {} is out of range for {} kinds", kind, E::KINDS); }
+    self.handlers[kind] = Some(Box::new(handler));
+    self
+  }
+
+  // hands every event waiting right now to its handler, returns how many
+  // there were
+  pub fn dispatch(&mut self) -> usize {
+    let mut count = 0;
+    for event in self.rx.try_iter() {
+      Dispatcher::handle(&mut self.handlers, &mut self.unhandled, event);
+      count += 1;
+    }
+    count
+  }
+
+  // dispatches until the sender is gone and everything was handled
+  pub fn run(&mut self) {
+    for event in self.rx.iter() {
+      Dispatcher::handle(&mut self.handlers, &mut self.unhandled, event);
+    }
+  }
+
+  // events of a kind nobody registered for
+  pub fn unhandled(&self) -> u64 {
+    self.unhandled
+  }
+
+  pub fn receiver(&self) -> &spsc::Receiver<E> {
+    &self.rx
+  }
+
+  fn handle(handlers : &mut [Option<Handler<E>>], unhandled : &mut u64, event : E) {
+    match handlers[event.kind()] {
+      Some(ref mut handler) => handler(event),
+      None                  => *unhandled += 1,
+    }
+  }
+}
+
+// the usual market data feed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Market {
+  Trade { price : f64, qty : u32 },
+  Quote { bid : f64, ask : f64 },
+  Halt,
+}
+
+impl Market {
+  pub const TRADE : usize = 0;
+  pub const QUOTE : usize = 1;
+  pub const HALT  : usize = 2;
+}
+
+impl Event for Market {
+  const KINDS : usize = 3;
+
+  fn kind(&self) -> usize {
+    match *self {
+      Market::Trade { .. } => Market::TRADE,
+      Market::Quote { .. } => Market::QUOTE,
+      Market::Halt         => Market::HALT,
+    }
+  }
+}
+
+impl fmt::Display for Market {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      Market::Trade { price, qty } => write!(f, "trade {} at {}", qty, price),
+      Market::Quote { bid, ask }   => write!(f, "quote {} / {}", bid, ask),
+      Market::Halt                 => f.write_str("halt"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{channel, Market};
+  use std::cell::RefCell;
+  use std::rc::Rc;
+  use std::thread;
+
+  #[test]
+  fn every_kind_to_its_handler() {
+    let (tx, mut events) = channel(8, Market::Halt);
+    let trades = Rc::new(RefCell::new(vec![]));
+    let quotes = Rc::new(RefCell::new(0));
+    {
+      let (trades, quotes) = (trades.clone(), quotes.clone());
+      events.on(Market::TRADE, move |e| if let Market::Trade { qty, .. } = e { trades.borrow_mut().push(qty); })
+            .on(Market::QUOTE, move |_| *quotes.borrow_mut() += 1);
+    }
+
+    tx.put(|e| *e = Market::Trade { price : 1.5, qty : 10 });
+    tx.put(|e| *e = Market::Quote { bid : 1.4, ask : 1.6 });
+    tx.put(|e| *e = Market::Halt);
+    tx.put(|e| *e = Market::Trade { price : 1.5, qty : 20 });
+    assert_eq!(events.dispatch(), 4);
+    assert_eq!((trades.borrow().clone(), *quotes.borrow(), events.unhandled()), (vec![10, 20], 1, 1));
+
+    let producer = thread::spawn(move || {
+      for qty in 0..100 { tx.put(|e| *e = Market::Trade { price : 2.0, qty }); }
+    });
+    events.run();
+    producer.join().unwrap();
+    assert_eq!(trades.borrow().last(), Some(&99));
+  }
+
+  #[test]
+  #[should_panic(expected = "kind 3 is out of range for 3 kinds")]
+  fn unknown_kind() {
+    let (_tx, mut events) = channel(2, Market::Halt);
+    events.on(3, |_| ());
+  }
+}
@@ -0,0 +1,295 @@
+
+// a tiny single threaded executor, its tasks woken by channel notifications
+//
+// Wakers is a WaitStrategy that wakes the registered tasks on notify()
+// instead of unparking threads, so a spsc channel built with it can be
+// awaited through AsyncReceiver. the executor polls woken tasks only and
+// parks its thread while none is, which is all the end to end Waker
+// plumbing there is to it, without pulling in a runtime. blocking waits
+// on such a channel (Policy::Block puts, Receiver::wait()) still work,
+// they yield in a loop.
+//
+// the crate has no async blocks (edition 2015), tasks are futures written
+// out by hand around poll_next(), or built with recv() and for_each()
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+use spsc;
+use wait::{WaitStrategy, Yield};
+
+// clones share the registered wakers
+#[derive(Clone, Default)]
+pub struct Wakers {
+  wakers : Arc<Mutex<Vec<Waker>>>,
+}
+
+impl Wakers {
+  pub fn new() -> Wakers {
+    Wakers::default()
+  }
+
+  // wakes `waker` on the next notify(), once
+  pub fn register(&self, waker : &Waker) {
+    let mut wakers = self.wakers.lock().unwrap();
+    if !wakers.iter().any(|w| w.will_wake(waker)) { wakers.push(waker.clone()); }
+  }
+}
+
+impl WaitStrategy for Wakers {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    Yield.wait_for(ready, deadline)
+  }
+
+  // the lock orders this against register() and the check after it, a
+  // task either sees the change or gets woken
+  fn notify(&self) {
+    let wakers : Vec<Waker> = self.wakers.lock().unwrap().drain(..).collect();
+    for w in wakers { w.wake(); }
+  }
+}
+
+// a spsc receiver to await items on
+pub struct AsyncReceiver<T : Clone + Send> {
+  rx      : spsc::Receiver<T>,
+  wakers  : Wakers,
+  pending : VecDeque<T>,    // taken from the ring, not handed out yet
+}
+
+// a channel of `capacity` items whose receiver is awaited
+pub fn channel<T : Clone + Send>(capacity : usize, default_value : T) -> (spsc::Sender<T>, AsyncReceiver<T>) {
+  let wakers = Wakers::new();
+  let (tx, rx) = spsc::Builder::new().capacity(capacity).wait(wakers.clone()).build_with(default_value);
+  (tx, AsyncReceiver { rx, wakers, pending : VecDeque::new() })
+}
+
+impl <T : Clone + Send> AsyncReceiver<T> {
+  // the next item, None once the sender is gone and everything read.
+  // Pending registers the task to be woken by the sender
+  pub fn poll_next(&mut self, cx : &mut Context<'_>) -> Poll<Option<T>> {
+    if let Some(v) = self.pending.pop_front() { return Poll::Ready(Some(v)); }
+    // closed is checked first, as in Receiver::iter()
+    let closed = self.rx.is_closed();
+    if self.take() { return Poll::Ready(self.pending.pop_front()); }
+    if closed { return Poll::Ready(None); }
+
+    self.wakers.register(cx.waker());
+    // whatever came before the registration did not wake us
+    let closed = self.rx.is_closed();
+    if self.take() { return Poll::Ready(self.pending.pop_front()); }
+    if closed { return Poll::Ready(None); }
+    Poll::Pending
+  }
+
+  // poll_next() as a future
+  pub fn recv(&mut self) -> Recv<'_, T> {
+    Recv { rx : self }
+  }
+
+  // a future calling f(item) for every item, done once the sender is gone
+  pub fn for_each<F : FnMut(T)>(self, f : F) -> ForEach<T, F> {
+    ForEach { rx : self, f }
+  }
+
+  fn take(&mut self) -> bool {
+    self.pending.extend(self.rx.try_iter());
+    !self.pending.is_empty()
+  }
+}
+
+pub struct Recv<'a, T : 'a + Clone + Send> {
+  rx : &'a mut AsyncReceiver<T>,
+}
+
+impl <'a, T : Clone + Send> Future for Recv<'a, T> {
+  type Output = Option<T>;
+
+  fn poll(self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<Option<T>> {
+    self.get_mut().rx.poll_next(cx)
+  }
+}
+
+pub struct ForEach<T : Clone + Send, F> {
+  rx : AsyncReceiver<T>,
+  f  : F,
+}
+
+// nothing in there is pinned to its place
+impl <T : Clone + Send, F> Unpin for ForEach<T, F> { }
+
+impl <T : Clone + Send, F : FnMut(T)> Future for ForEach<T, F> {
+  type Output = ();
+
+  fn poll(self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<()> {
+    let this = self.get_mut();
+    loop {
+      match this.rx.poll_next(cx) {
+        Poll::Ready(Some(v)) => (this.f)(v),
+        Poll::Ready(None)    => return Poll::Ready(()),
+        Poll::Pending        => return Poll::Pending,
+      }
+    }
+  }
+}
+
+// the ids of the woken tasks plus the thread to unpark for them
+struct Ready {
+  queue  : Mutex<VecDeque<usize>>,
+  thread : Thread,
+}
+
+struct TaskWaker {
+  id    : usize,
+  ready : Arc<Ready>,
+}
+
+impl Wake for TaskWaker {
+  fn wake(self : Arc<Self>) {
+    self.wake_by_ref();
+  }
+
+  fn wake_by_ref(self : &Arc<Self>) {
+    self.ready.queue.lock().unwrap().push_back(self.id);
+    self.ready.thread.unpark();
+  }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+// tasks need not be Send, so the executor stays on the thread that made it
+pub struct Executor {
+  tasks : Vec<Option<(Task, Waker)>>,
+  ready : Arc<Ready>,
+}
+
+impl Default for Executor {
+  fn default() -> Executor {
+    Executor::new()
+  }
+}
+
+impl Executor {
+  pub fn new() -> Executor {
+    Executor {
+      tasks : vec![],
+      ready : Arc::new(Ready { queue : Mutex::new(VecDeque::new()), thread : thread::current() }),
+    }
+  }
+
+  // polled for the first time by run()
+  pub fn spawn<F : Future<Output = ()> + 'static>(&mut self, task : F) {
+    let id = self.tasks.len();
+    let waker = Waker::from(Arc::new(TaskWaker { id, ready : self.ready.clone() }));
+    self.tasks.push(Some((Box::pin(task), waker.clone())));
+    waker.wake();
+  }
+
+  // unfinished tasks
+  pub fn pending(&self) -> usize {
+    self.tasks.iter().filter(|t| t.is_some()).count()
+  }
+
+  // polls woken tasks until all of them finished, parking while none is
+  // woken. returns how many polls that took
+  pub fn run(&mut self) -> usize {
+    let mut polls = 0;
+    while self.pending() > 0 {
+      let next = self.ready.queue.lock().unwrap().pop_front();
+      let id = match next {
+        Some(id) => id,
+        None     => { thread::park(); continue; },
+      };
+      // a task woken twice may be done by its second turn
+      let done = match self.tasks[id] {
+        Some((ref mut task, ref waker)) => {
+          polls += 1;
+          task.as_mut().poll(&mut Context::from_waker(waker)).is_ready()
+        },
+        None => false,
+      };
+      if done { self.tasks[id] = None; }
+    }
+    polls
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{channel, AsyncReceiver, Executor};
+  use std::cell::Cell;
+  use std::future::Future;
+  use std::pin::Pin;
+  use std::rc::Rc;
+  use std::task::{Context, Poll};
+  use std::thread;
+  use std::time::Duration;
+
+  // adds up everything, by hand the way an async block would
+  struct Sum {
+    rx  : AsyncReceiver<u64>,
+    out : Rc<Cell<u64>>,
+  }
+
+  impl Future for Sum {
+    type Output = ();
+
+    fn poll(self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<()> {
+      let this = self.get_mut();
+      loop {
+        match Pin::new(&mut this.rx.recv()).poll(cx) {
+          Poll::Ready(Some(v)) => this.out.set(this.out.get() + v),
+          Poll::Ready(None)    => return Poll::Ready(()),
+          Poll::Pending        => return Poll::Pending,
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn sums_what_another_thread_sends() {
+    let (tx, rx) = channel(4, 0u64);
+    let producer = thread::spawn(move || {
+      for i in 1..=100 {
+        while tx.is_full() { thread::yield_now(); }
+        tx.put(|v| *v = i);
+        if i % 10 == 0 { thread::sleep(Duration::from_millis(1)); }
+      }
+    });
+
+    let sum = Rc::new(Cell::new(0));
+    let mut ex = Executor::new();
+    ex.spawn(Sum { rx, out : sum.clone() });
+    ex.run();
+    producer.join().unwrap();
+    assert_eq!(sum.get(), 5050);
+  }
+
+  #[test]
+  fn tasks_wake_each_other() {
+    let (to_b, at_b) = channel(2, 0u32);
+    let (to_a, at_a) = channel(2, 0u32);
+    to_b.put(|v| *v = 1);
+    let last = Rc::new(Cell::new(0));
+
+    let mut ex = Executor::new();
+    // a ends the exchange by dropping its sender, which ends b and with it a
+    let mut to_b = Some(to_b);
+    let seen = last.clone();
+    ex.spawn(at_a.for_each(move |v| {
+      seen.set(v);
+      if v >= 10 { to_b = None; } else if let Some(ref tx) = to_b { tx.put(|x| *x = v + 1); }
+    }));
+    ex.spawn(at_b.for_each(move |v| { to_a.put(|x| *x = v + 1); }));
+    assert_eq!(ex.pending(), 2);
+
+    // every hop is one wakeup and one poll, no busy polling
+    let polls = ex.run();
+    assert!(polls <= 2 + 12, "{} polls", polls);
+    assert_eq!((ex.pending(), last.get()), (0, 10));
+  }
+}
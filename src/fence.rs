@@ -0,0 +1,32 @@
+
+// the fences that order data around the lock-free structures
+//
+// ThreadSanitizer does not see standalone fences, so it reports races on
+// slots that a fence plus a relaxed flag update hand over correctly. with
+// the tsan feature the fences go away and the atomic next to each one
+// takes its ordering instead, through on(): slower, but every hand-over
+// is an acquire/release pair it understands. the SeqCst fences in wait,
+// deque and segqueue stay as they are, they order a store against a
+// later load and hand over no data
+
+use std::sync::atomic::Ordering;
+#[cfg(not(feature = "tsan"))]
+use std::sync::atomic;
+
+#[cfg(not(feature = "tsan"))]
+pub(crate) fn release() { atomic::fence(Ordering::Release); }
+#[cfg(not(feature = "tsan"))]
+pub(crate) fn acquire() { atomic::fence(Ordering::Acquire); }
+
+#[cfg(feature = "tsan")]
+pub(crate) fn release() { }
+#[cfg(feature = "tsan")]
+pub(crate) fn acquire() { }
+
+// the ordering of an atomic operation that one of the fences above would
+// order otherwise: `order` under tsan, `relaxed` (what the fenced code
+// uses) in normal builds
+#[cfg(not(feature = "tsan"))]
+pub(crate) const fn on(relaxed : Ordering, _order : Ordering) -> Ordering { relaxed }
+#[cfg(feature = "tsan")]
+pub(crate) const fn on(_relaxed : Ordering, order : Ordering) -> Ordering { order }
@@ -0,0 +1,37 @@
+
+// plain table driven CRC-32 (IEEE 802.3, reflected, poly 0xedb88320)
+
+const TABLE : [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+  let mut table = [0u32; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut c = i as u32;
+    let mut k = 0;
+    while k < 8 {
+      c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+      k += 1;
+    }
+    table[i] = c;
+    i += 1;
+  }
+  table
+}
+
+pub fn checksum(bytes : &[u8]) -> u32 {
+  let mut c = !0u32;
+  for b in bytes {
+    c = TABLE[((c ^ (*b as u32)) & 0xff) as usize] ^ (c >> 8);
+  }
+  !c
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn known_values() {
+    assert_eq!(super::checksum(b""), 0);
+    assert_eq!(super::checksum(b"123456789"), 0xcbf4_3926);
+  }
+}
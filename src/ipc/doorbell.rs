@@ -0,0 +1,67 @@
+
+// 1 byte "data available" notifications over a unix datagram socket
+//
+// the consumer binds the socket and can hand its fd to poll/epoll next
+// to other fds, the producer rings it after every put. both ends are non
+// blocking: a full socket buffer already means a pending wakeup, and a
+// missing consumer is not the producer's problem
+
+use std::fs;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+pub struct Bell {
+  sock : UnixDatagram,
+}
+
+impl Bell {
+  pub fn connect(path : &Path) -> io::Result<Bell> {
+    let sock = UnixDatagram::unbound()?;
+    sock.connect(path)?;
+    sock.set_nonblocking(true)?;
+    Ok(Bell { sock })
+  }
+
+  pub fn ring(&self) {
+    let _ = self.sock.send(&[1]);
+  }
+}
+
+pub struct Doorbell {
+  sock  : UnixDatagram,
+  path  : PathBuf,
+}
+
+impl Doorbell {
+  pub fn bind(path : &Path) -> io::Result<Doorbell> {
+    // a stale socket file from an earlier run would make bind() fail
+    let _ = fs::remove_file(path);
+    let sock = UnixDatagram::bind(path)?;
+    sock.set_nonblocking(true)?;
+    Ok(Doorbell { sock, path : path.to_path_buf() })
+  }
+
+  // drains the pending rings, returns how many there were
+  pub fn clear(&self) -> usize {
+    let mut buf = [0u8; 64];
+    let mut count = 0;
+    while self.sock.recv(&mut buf).is_ok() {
+      count += 1;
+    }
+    count
+  }
+}
+
+impl AsRawFd for Doorbell {
+  fn as_raw_fd(&self) -> RawFd {
+    self.sock.as_raw_fd()
+  }
+}
+
+impl Drop for Doorbell {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
@@ -0,0 +1,91 @@
+
+// process shared wait/wake on a 32 bit word in the mapping
+//
+// a futex on the linux targets whose syscall number is known here, the
+// crate has no libc to ask. everywhere else, other linux targets such
+// as mips or riscv32 included, the waiting side polls the word
+
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+#[cfg(all(target_os = "linux",
+          any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm",
+              target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64",
+              target_arch = "powerpc", target_arch = "powerpc64", target_arch = "s390x")))]
+mod imp {
+  use std::os::raw::{c_int, c_long};
+  use std::ptr;
+  use std::sync::atomic::AtomicU32;
+  use std::time::Duration;
+
+  #[cfg(target_arch = "x86_64")]
+  const SYS_FUTEX : c_long = 202;
+  #[cfg(any(target_arch = "x86", target_arch = "arm"))]
+  const SYS_FUTEX : c_long = 240;
+  #[cfg(any(target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64"))]
+  const SYS_FUTEX : c_long = 98;
+  #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+  const SYS_FUTEX : c_long = 221;
+  #[cfg(target_arch = "s390x")]
+  const SYS_FUTEX : c_long = 238;
+
+  // no FUTEX_PRIVATE_FLAG, the word is shared with another process
+  const FUTEX_WAIT : c_int = 0;
+  const FUTEX_WAKE : c_int = 1;
+
+  #[repr(C)]
+  struct Timespec {
+    tv_sec   : c_long,
+    tv_nsec  : c_long,
+  }
+
+  extern "C" {
+    fn syscall(num : c_long, ...) -> c_long;
+  }
+
+  pub fn wait(word : &AtomicU32, expected : u32, timeout : Option<Duration>) {
+    let ts = timeout.map(|d| Timespec {
+      tv_sec  : d.as_secs() as c_long,
+      tv_nsec : d.subsec_nanos() as c_long,
+    });
+    let ts_ptr = match ts {
+      Some(ref ts) => ts as *const Timespec,
+      None         => ptr::null(),
+    };
+    // spurious wakeups, EAGAIN and EINTR are all fine, callers recheck
+    unsafe { syscall(SYS_FUTEX, word as *const AtomicU32, FUTEX_WAIT, expected, ts_ptr); }
+  }
+
+  pub fn wake(word : &AtomicU32, n : u32) {
+    unsafe { syscall(SYS_FUTEX, word as *const AtomicU32, FUTEX_WAKE, n as c_int); }
+  }
+}
+
+#[cfg(not(all(target_os = "linux",
+              any(target_arch = "x86_64", target_arch = "x86", target_arch = "arm",
+                  target_arch = "aarch64", target_arch = "riscv64", target_arch = "loongarch64",
+                  target_arch = "powerpc", target_arch = "powerpc64", target_arch = "s390x"))))]
+mod imp {
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  // no process shared futex to call, poll the word instead
+  pub fn wait(word : &AtomicU32, expected : u32, timeout : Option<Duration>) {
+    let nap = Duration::from_micros(50);
+    let nap = timeout.map_or(nap, |t| t.min(nap));
+    if word.load(Ordering::Acquire) == expected {
+      thread::sleep(nap);
+    }
+  }
+
+  pub fn wake(_word : &AtomicU32, _n : u32) { }
+}
+
+pub fn wait(word : &AtomicU32, expected : u32, timeout : Option<Duration>) {
+  imp::wait(word, expected, timeout)
+}
+
+pub fn wake(word : &AtomicU32, n : u32) {
+  imp::wake(word, n)
+}
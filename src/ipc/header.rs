@@ -0,0 +1,116 @@
+
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use super::Layout;
+
+pub const MAGIC   : u64 = 0x0067_6e69_7267_7072; // "rpgring\0" little endian
+pub const VERSION : u32 = 4;
+
+// first bytes of every ring file, the field order and widths are fixed
+// for a given VERSION, anything that changes them must bump it
+#[repr(C)]
+pub struct Header {
+  magic        : AtomicU64,   // written last, once the ring is usable
+  version      : u32,
+  checksum     : u32,
+  word_size    : u64,
+  elem_size    : u64,
+  capacity     : u64,
+  ctrl_offset  : u64,
+  crc_offset   : u64,
+  data_offset  : u64,
+  wakeups      : AtomicU32,   // bumped on every put, futex word
+  waiters      : AtomicU32,   // consumers parked on `wakeups`
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderError {
+  NotReady,
+  BadMagic(u64),
+  Version { found : u32, expected : u32 },
+  WordSize { found : u64, expected : u64 },
+  ElementSize { found : u64, expected : u64 },
+  Layout,
+}
+
+impl fmt::Display for HeaderError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      HeaderError::NotReady =>
+        write!(f, "ring is not initialized yet"),
+      HeaderError::BadMagic(m) =>
+        write!(f, "not a ring file (magic {:#x})", m),
+      HeaderError::Version { found, expected } =>
+        write!(f, "ring layout version {} is not supported, expected {}", found, expected),
+      HeaderError::WordSize { found, expected } =>
+        write!(f, "ring was created with {} byte control words, this build uses {}", found, expected),
+      HeaderError::ElementSize { found, expected } =>
+        write!(f, "ring holds {} byte elements, expected {}", found, expected),
+      HeaderError::Layout =>
+        write!(f, "ring region offsets do not match the layout version"),
+    }
+  }
+}
+
+impl Error for HeaderError { }
+
+impl Header {
+  pub fn write<T>(&mut self, capacity : usize, checksum : u32) {
+    let layout = Layout::new(capacity);
+    self.version     = VERSION;
+    self.checksum    = checksum;
+    self.word_size   = mem::size_of::<AtomicUsize>() as u64;
+    self.elem_size   = mem::size_of::<T>() as u64;
+    self.capacity    = capacity as u64;
+    self.ctrl_offset = layout.ctrl;
+    self.crc_offset  = layout.crc;
+    self.data_offset = layout.data;
+    self.magic.store(MAGIC, Ordering::Release);
+  }
+
+  pub fn validate<T>(&self) -> Result<(), HeaderError> {
+    match self.magic.load(Ordering::Acquire) {
+      0     => return Err(HeaderError::NotReady),
+      MAGIC => {},
+      m     => return Err(HeaderError::BadMagic(m)),
+    }
+    if self.version != VERSION {
+      return Err(HeaderError::Version { found : self.version, expected : VERSION });
+    }
+    let word_size = mem::size_of::<AtomicUsize>() as u64;
+    if self.word_size != word_size {
+      return Err(HeaderError::WordSize { found : self.word_size, expected : word_size });
+    }
+    let elem_size = mem::size_of::<T>() as u64;
+    if self.elem_size != elem_size {
+      return Err(HeaderError::ElementSize { found : self.elem_size, expected : elem_size });
+    }
+    let layout = Layout::new(self.capacity as usize);
+    if self.capacity == 0 ||
+       self.ctrl_offset != layout.ctrl ||
+       self.crc_offset != layout.crc ||
+       self.data_offset != layout.data {
+      return Err(HeaderError::Layout);
+    }
+    Ok(())
+  }
+
+  pub fn wakeups(&self) -> &AtomicU32 {
+    &self.wakeups
+  }
+
+  pub fn waiters(&self) -> &AtomicU32 {
+    &self.waiters
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity as usize
+  }
+
+  pub fn checksum(&self) -> u32 {
+    self.checksum
+  }
+}
@@ -0,0 +1,512 @@
+
+// spsc ring shared between two processes through a file mapping
+//
+// the file holds a versioned header, the control words and the data slots of
+// an spsc::CircularBuffer, so both processes run the same algorithm over
+// the same memory. the producer creates the file, the consumer attaches
+// to it afterwards and can sleep while the ring is empty, on a futex in
+// the header (linux) or a named event (windows). on windows both map
+// views of one named mapping over the file, the consumer opens it by
+// its name. on unix the consumer can
+// also bind a doorbell socket to fold the ring into its own poll loop. each side keeps its private slot positions in
+// process memory, so a side cannot re-attach once it went away.
+
+mod crc32;
+#[cfg(unix)]
+mod doorbell;
+#[cfg(unix)]
+mod futex;
+mod header;
+mod notify;
+
+pub use self::header::HeaderError;
+
+use std::error::Error;
+#[cfg(windows)]
+use std::ffi::OsStr;
+use std::fmt;
+#[cfg(unix)]
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::ffi::OsStrExt;
+use std::path::Path;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use seq::Seqno;
+use spsc::{flag, CircularBuffer, CircularBufferIterator};
+#[cfg(unix)]
+use self::doorbell::{Bell, Doorbell};
+use self::header::Header;
+use self::notify::Notifier;
+use storage::{MmapRegion, RingStorage};
+#[cfg(windows)]
+use storage::NamedMapping;
+
+// every region starts on its own 64k boundary, which is a multiple of
+// the page size on all supported platforms and the windows allocation
+// granularity
+const SEGMENT : u64 = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+  Off,
+  Crc32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ChecksumError {
+  pub slot      : usize,
+  pub expected  : u32,
+  pub actual    : u32,
+}
+
+impl fmt::Display for ChecksumError {
+  fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "slot {} fails its checksum, expected {:#010x}, found {:#010x}", self.slot, self.expected, self.actual)
+  }
+}
+
+impl Error for ChecksumError { }
+
+// offsets of the regions for a ring of `size` elements of T
+struct Layout {
+  ctrl  : u64,
+  crc   : u64,
+  data  : u64,
+}
+
+impl Layout {
+  fn new(size : usize) -> Layout {
+    let align = |x : u64| (x + SEGMENT - 1) & !(SEGMENT - 1);
+    let ctrl  = SEGMENT;
+    let crc   = align(ctrl + (flag::ctrl_words(size) * mem::size_of::<AtomicUsize>()) as u64);
+    let data  = align(crc + (((size*2)+1) * mem::size_of::<u32>()) as u64);
+    Layout { ctrl, crc, data }
+  }
+}
+
+type Ring<T> = CircularBuffer<T, MmapRegion<T>, MmapRegion<AtomicUsize>>;
+
+// what the regions are mapped from: the file itself on unix, on windows
+// the named mapping over it
+#[cfg(unix)]
+type Backing = File;
+#[cfg(windows)]
+type Backing = NamedMapping;
+
+#[cfg(unix)]
+unsafe fn region<T>(backing : &File, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+  MmapRegion::from_file(backing, offset, len)
+}
+
+#[cfg(windows)]
+unsafe fn region<T>(backing : &NamedMapping, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+  backing.view(offset, len)
+}
+
+// a kernel object name both processes derive from the ring file, FNV-1a
+// over its canonical path. events and mappings share one namespace,
+// `kind` keeps them apart
+#[cfg(windows)]
+fn object_name(path : &Path, kind : &str) -> io::Result<Vec<u16>> {
+  let path = path.canonicalize()?;
+  let mut h : u64 = 0xcbf2_9ce4_8422_2325;
+  for b in path.to_string_lossy().bytes() {
+    h = (h ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+  }
+  let name = format!("Local\\rpg-{}-{:016x}", kind, h);
+  Ok(OsStr::new(&name).encode_wide().chain(Some(0)).collect())
+}
+
+pub struct Producer<T : Copy> {
+  ring     : Ring<T>,
+  crc      : Option<MmapRegion<u32>>,
+  header   : MmapRegion<Header>,
+  notify   : Notifier,
+  #[cfg(unix)]
+  bell     : Option<Bell>,
+}
+
+pub struct Consumer<T : Copy> {
+  ring     : Ring<T>,
+  crc      : Option<MmapRegion<u32>>,
+  header   : MmapRegion<Header>,
+  notify   : Notifier,
+  #[cfg(unix)]
+  doorbell : Option<Doorbell>,
+}
+
+pub struct Iter<'a, T : 'a + Copy> {
+  inner  : CircularBufferIterator<'a, T>,
+  crc    : Option<&'a [u32]>,
+}
+
+// the checksum covers the raw slot memory, so T should not have padding
+fn slot_bytes<T>(v : &T) -> &[u8] {
+  unsafe { slice::from_raw_parts(v as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+unsafe fn map_ring<T : Copy>(backing : &Backing, size : usize, checksum : Checksum, init : bool)
+  -> io::Result<(Ring<T>, Option<MmapRegion<u32>>)>
+{
+  let layout = Layout::new(size);
+  let data   = region(backing, layout.data, (size*2)+1)?;
+  let ctrl   = region(backing, layout.ctrl, flag::ctrl_words(size))?;
+  let crc    = match checksum {
+    Checksum::Off   => None,
+    Checksum::Crc32 => Some(region(backing, layout.crc, (size*2)+1)?),
+  };
+  Ok((CircularBuffer::with_parts(data, ctrl, init), crc))
+}
+
+impl <T : Copy + Send> Producer<T> {
+  // creates the ring file. fails with ErrorKind::AlreadyExists if there
+  // is one, a consumer may still have it mapped and truncating it would
+  // pull the pages out from under it. a stale file has to be removed
+  // first
+  pub fn create<P : AsRef<Path>>(path : P,
+                                 size : usize,
+                                 default_value : T,
+                                 checksum : Checksum) -> io::Result<Producer<T>> {
+    if size == 0 { panic!("size cannot be zero"); }
+
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    let notify = Notifier::open(path.as_ref())?;
+    let layout = Layout::new(size);
+    #[cfg(unix)]
+    let backing = file;
+    // the whole file up front, the mapping takes its size from it
+    #[cfg(windows)]
+    let backing = NamedMapping::create(&file, layout.data + ((size*2+1) * mem::size_of::<T>()) as u64,
+                                       &object_name(path.as_ref(), "map")?)?;
+    let mut header : MmapRegion<Header> = unsafe { region(&backing, 0, 1)? };
+
+    // slots are zero bytes in a fresh file, make them valid values
+    {
+      let mut data : MmapRegion<T> = unsafe { region(&backing, layout.data, (size*2)+1)? };
+      for v in data.slots_mut() {
+        *v = default_value;
+      }
+      if checksum == Checksum::Crc32 {
+        let mut crc : MmapRegion<u32> = unsafe { region(&backing, layout.crc, (size*2)+1)? };
+        for (c, v) in crc.slots_mut().iter_mut().zip(data.slots()) {
+          *c = crc32::checksum(slot_bytes(v));
+        }
+      }
+    }
+
+    // the header goes last, attach() refuses the file until it is there
+    let (ring, crc) = unsafe { map_ring::<T>(&backing, size, checksum, true)? };
+    header.slots_mut()[0].write::<T>(size, checksum as u32);
+    Ok(Producer {
+      ring,
+      crc,
+      header,
+      notify,
+      #[cfg(unix)]
+      bell : None,
+    })
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.ring.capacity()
+  }
+
+  pub fn put<F>(&mut self, setter: F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    let mut setter = setter;
+    let seqno = match self.crc {
+      Some(ref mut crc) => {
+        let crc = crc.slots_mut();
+        self.ring.put_at(|at, v| {
+          setter(v);
+          crc[at] = crc32::checksum(slot_bytes(v));
+        })
+      },
+      None => self.ring.put_at(|_, v| setter(v)),
+    };
+
+    // only pay for the syscall when the consumer is actually parked
+    let h = &self.header.slots()[0];
+    h.wakeups().fetch_add(1, Ordering::SeqCst);
+    if h.waiters().load(Ordering::SeqCst) > 0 {
+      self.notify.wake(h.wakeups());
+    }
+    #[cfg(unix)]
+    {
+      if let Some(ref bell) = self.bell { bell.ring(); }
+    }
+    seqno
+  }
+
+  // rings the consumer's doorbell socket at `path` after every put
+  #[cfg(unix)]
+  pub fn connect_doorbell<P : AsRef<Path>>(&mut self, path : P) -> io::Result<()> {
+    self.bell = Some(Bell::connect(path.as_ref())?);
+    Ok(())
+  }
+}
+
+impl <T : Copy + Send> Consumer<T> {
+  /// Attaches to a ring created by `Producer::create()`. Fails with
+  /// `ErrorKind::InvalidData` wrapping a `HeaderError` when the file
+  /// was not written by a compatible producer.
+  ///
+  /// # Safety
+  ///
+  /// The slots are reinterpreted as `T`, the producer must have created
+  /// the ring with the same element type. Only the element size can be
+  /// checked here.
+  pub unsafe fn attach<P : AsRef<Path>>(path : P) -> io::Result<Consumer<T>> {
+    #[cfg(unix)]
+    let backing = {
+      let file = OpenOptions::new().read(true).write(true).open(&path)?;
+      if file.metadata()?.len() < SEGMENT {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, HeaderError::NotReady));
+      }
+      file
+    };
+    // the producer's mapping, by name. none yet, or none any more
+    #[cfg(windows)]
+    let backing = match NamedMapping::open(&object_name(path.as_ref(), "map")?) {
+      Ok(mapping) => mapping,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound =>
+        return Err(io::Error::new(io::ErrorKind::InvalidData, HeaderError::NotReady)),
+      Err(e) => return Err(e),
+    };
+
+    let header : MmapRegion<Header> = region(&backing, 0, 1)?;
+    let (capacity, checksum) = {
+      let h = &header.slots()[0];
+      if let Err(e) = h.validate::<T>() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+      }
+      (h.capacity(), h.checksum())
+    };
+
+    let checksum = if checksum == Checksum::Crc32 as u32 { Checksum::Crc32 } else { Checksum::Off };
+    let (ring, crc) = map_ring::<T>(&backing, capacity, checksum, false)?;
+    let notify = Notifier::open(path.as_ref())?;
+    Ok(Consumer {
+      ring,
+      crc,
+      header,
+      notify,
+      #[cfg(unix)]
+      doorbell : None,
+    })
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.ring.capacity()
+  }
+
+  // blocks until the producer published something not read yet,
+  // returns false if the timeout expired first
+  pub fn wait(&self, timeout : Option<Duration>) -> bool {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let h = &self.header.slots()[0];
+
+    loop {
+      let seen = h.wakeups().load(Ordering::SeqCst);
+      if self.ring.has_unread() { return true; }
+
+      let left = match deadline {
+        Some(d) => {
+          let now = Instant::now();
+          if now >= d { return false; }
+          Some(d - now)
+        },
+        None => None,
+      };
+
+      h.waiters().fetch_add(1, Ordering::SeqCst);
+      self.notify.wait(h.wakeups(), seen, left);
+      h.waiters().fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  // binds a datagram socket the producer can ring, see doorbell_fd()
+  #[cfg(unix)]
+  pub fn bind_doorbell<P : AsRef<Path>>(&mut self, path : P) -> io::Result<()> {
+    self.doorbell = Some(Doorbell::bind(path.as_ref())?);
+    Ok(())
+  }
+
+  // readable whenever the producer put something since the last
+  // clear_doorbell(), meant for poll/epoll
+  #[cfg(unix)]
+  pub fn doorbell_fd(&self) -> Option<RawFd> {
+    self.doorbell.as_ref().map(|d| d.as_raw_fd())
+  }
+
+  #[cfg(unix)]
+  pub fn clear_doorbell(&self) -> usize {
+    self.doorbell.as_ref().map_or(0, |d| d.clear())
+  }
+
+  pub fn iter(&mut self) -> Iter<'_, T> {
+    Iter {
+      inner  : self.ring.iter(),
+      crc    : self.crc.as_ref().map(|c| c.slots()),
+    }
+  }
+}
+
+impl <'a, T : 'a + Copy> Iterator for Iter<'a, T> {
+  type Item = Result<T, ChecksumError>;
+
+  fn next(&mut self) -> Option<Result<T, ChecksumError>> {
+    if self.inner.count == 0 { return None; }
+
+    let slot = self.inner.revpos[self.inner.count-1];
+    if let Some(crc) = self.crc {
+      let actual = crc32::checksum(slot_bytes(self.inner.slot(slot)));
+      if actual != crc[slot] {
+        self.inner.count -= 1;
+        self.inner.seqno = self.inner.seqno.wrapping_add(1);
+        return Some(Err(ChecksumError { slot, expected : crc[slot], actual }));
+      }
+    }
+    self.inner.next().map(Ok)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Checksum, Consumer, HeaderError, Layout, Producer};
+  use super::header::VERSION;
+  use std::env;
+  use std::fs;
+  use std::io::ErrorKind;
+  use std::path::PathBuf;
+  use std::process;
+  use std::thread;
+  use std::time::Duration;
+
+  fn ring_path(name : &str) -> PathBuf {
+    env::temp_dir().join(format!("rpg-ipc-{}-{}", process::id(), name))
+  }
+
+  fn scribble(path : &PathBuf, bytes : &[u8], offset : u64) {
+    let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+    #[cfg(unix)]
+    { use std::os::unix::fs::FileExt; file.write_at(bytes, offset).unwrap(); }
+    #[cfg(windows)]
+    { use std::os::windows::fs::FileExt; file.seek_write(bytes, offset).unwrap(); }
+  }
+
+  #[test]
+  fn put_and_read() {
+    let path = ring_path("put_and_read");
+    let mut tx = Producer::create(&path, 4, 0u64, Checksum::Off).unwrap();
+    let mut rx = unsafe { Consumer::<u64>::attach(&path).unwrap() };
+    assert_eq!(rx.capacity(), 4);
+    for i in 1..7 { tx.put(|v| *v = i); }
+    let got : Vec<u64> = rx.iter().map(|v| v.unwrap()).collect();
+    assert_eq!(got, vec![3, 4, 5, 6]);
+    assert_eq!(rx.iter().count(), 0);
+    drop((tx, rx));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn leaves_a_ring_in_use_alone() {
+    let path = ring_path("in_use");
+    let mut tx = Producer::create(&path, 4, 0u64, Checksum::Off).unwrap();
+    let mut rx = unsafe { Consumer::<u64>::attach(&path).unwrap() };
+    tx.put(|v| *v = 1);
+
+    let err = Producer::create(&path, 4, 0u64, Checksum::Off).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    tx.put(|v| *v = 2);
+    assert_eq!(rx.iter().map(|v| v.unwrap()).collect::<Vec<u64>>(), vec![1, 2]);
+    drop((tx, rx));
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn wait_for_other_process() {
+    let path = ring_path("wait");
+    let mut tx = Producer::create(&path, 4, 0u64, Checksum::Off).unwrap();
+    let mut rx = unsafe { Consumer::<u64>::attach(&path).unwrap() };
+    assert!(!rx.wait(Some(Duration::from_millis(10))));
+
+    let t = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      tx.put(|v| *v = 7);
+    });
+    assert!(rx.wait(None));
+    assert_eq!(rx.iter().next(), Some(Ok(7)));
+    t.join().unwrap();
+    drop(rx);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn doorbell_rings_on_put() {
+    let path = ring_path("doorbell");
+    let bell = ring_path("doorbell.sock");
+    let mut tx = Producer::create(&path, 4, 0u64, Checksum::Off).unwrap();
+    let mut rx = unsafe { Consumer::<u64>::attach(&path).unwrap() };
+    rx.bind_doorbell(&bell).unwrap();
+    tx.connect_doorbell(&bell).unwrap();
+
+    assert!(rx.doorbell_fd().is_some());
+    assert_eq!(rx.clear_doorbell(), 0);
+    tx.put(|v| *v = 1);
+    tx.put(|v| *v = 2);
+    assert_eq!(rx.clear_doorbell(), 2);
+    assert_eq!(rx.iter().count(), 2);
+    drop((tx, rx));
+    fs::remove_file(&path).unwrap();
+    assert!(!bell.exists());
+  }
+
+  #[test]
+  fn rejects_mismatched_header() {
+    let path = ring_path("mismatch");
+    let tx = Producer::create(&path, 4, 0u64, Checksum::Off).unwrap();
+
+    let err = unsafe { Consumer::<u32>::attach(&path).err().unwrap() };
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<HeaderError>(),
+               Some(&HeaderError::ElementSize { found : 8, expected : 4 }));
+
+    // bump the version field, which sits right after the magic
+    scribble(&path, &99u32.to_ne_bytes(), 8);
+    let err = unsafe { Consumer::<u64>::attach(&path).err().unwrap() };
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<HeaderError>(),
+               Some(&HeaderError::Version { found : 99, expected : VERSION }));
+    drop(tx);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn detects_corrupted_slot() {
+    let path = ring_path("corrupted");
+    let mut tx = Producer::create(&path, 2, 0u64, Checksum::Crc32).unwrap();
+    let mut rx = unsafe { Consumer::<u64>::attach(&path).unwrap() };
+    tx.put(|v| *v = 42);
+    tx.put(|v| *v = 43);
+
+    // the first put lands in slot 0, scribble over it behind the ring's back
+    scribble(&path, &[0xff; 8], Layout::new(2).data);
+
+    let got : Vec<_> = rx.iter().collect();
+    assert_eq!(got.len(), 2);
+    assert_eq!(got[0].as_ref().unwrap_err().slot, 0);
+    assert_eq!(got[1], Ok(43));
+    drop((tx, rx));
+    fs::remove_file(&path).unwrap();
+  }
+}
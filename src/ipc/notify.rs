@@ -0,0 +1,92 @@
+
+// wakes a consumer parked on the header's wakeup word
+//
+// linux uses a process shared futex on the word itself, windows a named
+// auto-reset event derived from the ring path, other unixes poll
+
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::sync::atomic::AtomicU32;
+#[cfg(unix)]
+use std::time::Duration;
+
+#[cfg(unix)]
+pub struct Notifier;
+
+#[cfg(unix)]
+impl Notifier {
+  pub fn open(_path : &Path) -> io::Result<Notifier> {
+    Ok(Notifier)
+  }
+
+  pub fn wait(&self, word : &AtomicU32, expected : u32, timeout : Option<Duration>) {
+    super::futex::wait(word, expected, timeout)
+  }
+
+  pub fn wake(&self, word : &AtomicU32) {
+    super::futex::wake(word, 1)
+  }
+}
+
+#[cfg(windows)]
+pub use self::windows::Notifier;
+
+#[cfg(windows)]
+mod windows {
+  use std::io;
+  use std::os::raw::c_void;
+  use std::path::Path;
+  use std::ptr;
+  use std::sync::atomic::{AtomicU32, Ordering};
+  use std::time::Duration;
+
+  type Handle = *mut c_void;
+
+  const INFINITE : u32 = 0xffff_ffff;
+
+  #[link(name = "kernel32")]
+  extern "system" {
+    fn CreateEventW(attributes : *mut c_void, manual_reset : i32,
+                    initial_state : i32, name : *const u16) -> Handle;
+    fn SetEvent(event : Handle) -> i32;
+    fn WaitForSingleObject(handle : Handle, millis : u32) -> u32;
+    fn CloseHandle(handle : Handle) -> i32;
+  }
+
+  pub struct Notifier {
+    event  : Handle,
+  }
+
+  unsafe impl Send for Notifier { }
+
+  impl Notifier {
+    pub fn open(path : &Path) -> io::Result<Notifier> {
+      let name  = super::super::object_name(path, "ring")?;
+      // opens the event if the other side created it already
+      let event = unsafe { CreateEventW(ptr::null_mut(), 0, 0, name.as_ptr()) };
+      if event.is_null() {
+        return Err(io::Error::last_os_error());
+      }
+      Ok(Notifier { event })
+    }
+
+    pub fn wait(&self, word : &AtomicU32, expected : u32, timeout : Option<Duration>) {
+      if word.load(Ordering::SeqCst) != expected { return; }
+      let millis = timeout.map_or(INFINITE, |t| t.as_millis().min((INFINITE - 1) as u128) as u32);
+      unsafe { WaitForSingleObject(self.event, millis); }
+    }
+
+    pub fn wake(&self, _word : &AtomicU32) {
+      unsafe { SetEvent(self.event); }
+    }
+  }
+
+  impl Drop for Notifier {
+    fn drop(&mut self) {
+      unsafe { CloseHandle(self.event); }
+    }
+  }
+}
@@ -1,2 +1,32 @@
+pub mod arena;
+pub mod audio;
+pub mod bench;
+pub mod deque;
+pub mod disruptor;
+pub mod events;
+pub mod executor;
+mod fence;
+#[cfg(any(unix, windows))]
+pub mod ipc;
+pub mod log;
+pub mod merge;
+pub mod metrics;
+pub mod mpsc;
+#[cfg(test)]
+mod portability;
+pub mod pool;
+pub mod prelude;
+pub mod pubsub;
+pub mod queue;
+pub mod rate;
+pub mod runtime;
+pub mod segqueue;
+pub mod seq;
+pub mod sharded;
 pub mod simple;
 pub mod spsc;
+pub mod stack;
+pub mod storage;
+pub mod video;
+pub mod wait;
+pub mod workers;
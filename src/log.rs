@@ -0,0 +1,251 @@
+
+// append-only journal of fixed size entries
+//
+// the newest `capacity` entries live in a simple::CircularBuffer, so recent
+// reads never touch the disk. an entry about to be evicted goes to the
+// file first, together with everything else in memory that is not there
+// yet, so the file always holds a prefix of the log and grows by one write
+// per `capacity` appends. sync() and drop write out the rest.
+//
+// entries are stored as their raw bytes, entry n at n*size_of::<T>(), so
+// T should not have padding and must be valid for whatever bytes were
+// written (see open())
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::iter::Skip;
+use std::mem::{self, MaybeUninit};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use seq::Seqno;
+use simple::{CircularBuffer, CircularBufferIterator};
+
+pub struct Log<T : Copy> {
+  recent  : CircularBuffer<T>,
+  file    : File,
+  path    : PathBuf,
+  next    : Seqno,                  // entries appended so far
+  spilled : Seqno,                  // entries in the file
+  scratch : Vec<T>,                 // the memory part in order, for spilling
+}
+
+// entries oldest first, from the file until the memory part starts
+pub struct Iter<'a, T : 'a + Copy> {
+  file   : Option<BufReader<File>>,
+  left   : Seqno,                   // entries still to read from the file
+  recent : Skip<CircularBufferIterator<'a, T>>,
+}
+
+fn entry_size<T>() -> u64 {
+  let size = mem::size_of::<T>() as u64;
+  if size == 0 { panic!("zero sized entries cannot be logged"); }
+  size
+}
+
+impl <T : Copy> Log<T> {
+  // starts an empty log at `path`, truncating whatever was there
+  pub fn create<P : AsRef<Path>>(path : P, capacity : usize, default_value : T) -> io::Result<Log<T>> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path.as_ref())?;
+    Ok(Log::with_file(file, path.as_ref(), 0, capacity, default_value))
+  }
+
+  /// Continues the log at `path`, creating it if needed. A partly written
+  /// last entry (e.g. after a crash) is cut off. The memory part starts
+  /// out empty, older entries are read from the file.
+  ///
+  /// # Safety
+  ///
+  /// The existing bytes are reinterpreted as `T`, so `T` must be valid
+  /// for any bit pattern stored there.
+  pub unsafe fn open<P : AsRef<Path>>(path : P, capacity : usize, default_value : T) -> io::Result<Log<T>> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.as_ref())?;
+    let entries = file.metadata()?.len() / entry_size::<T>();
+    file.set_len(entries * entry_size::<T>())?;
+    Ok(Log::with_file(file, path.as_ref(), entries, capacity, default_value))
+  }
+
+  fn with_file(file : File, path : &Path, entries : Seqno, capacity : usize, default_value : T) -> Log<T> {
+    let _ = entry_size::<T>();        // panics for zero sized entries
+    let mut file = file;
+    // appending the spilled entries is all the writer ever does
+    let _ = file.seek(SeekFrom::End(0));
+    Log {
+      recent  : CircularBuffer::new(capacity, default_value),
+      file,
+      path    : path.to_path_buf(),
+      next    : entries,
+      spilled : entries,
+      scratch : vec![default_value; capacity],
+    }
+  }
+
+  // adds an entry, returns its seqno counting from the first entry ever
+  // written to the file. fails only if spilling to the file failed, the
+  // entry is not added then
+  pub fn append(&mut self, entry : T) -> io::Result<Seqno> {
+    if self.recent.len() == self.recent.capacity() && self.first_in_memory() >= self.spilled {
+      self.spill()?;
+    }
+    self.recent.put(|v| *v = entry);
+    self.next += 1;
+    Ok(self.next - 1)
+  }
+
+  // writes everything only held in memory to the file and waits for the
+  // disk. the entries stay in memory for reading
+  pub fn sync(&mut self) -> io::Result<()> {
+    self.spill()?;
+    self.file.sync_data()
+  }
+
+  fn first_in_memory(&self) -> Seqno {
+    self.next - self.recent.len() as Seqno
+  }
+
+  fn spill(&mut self) -> io::Result<()> {
+    if self.spilled == self.next { return Ok(()); }
+
+    let held  = self.recent.read_into(&mut self.scratch);
+    let from  = (self.spilled - self.first_in_memory()) as usize;
+    let items = &self.scratch[from..held];
+    let bytes = unsafe {
+      slice::from_raw_parts(items.as_ptr() as *const u8, mem::size_of_val(items))
+    };
+    self.file.write_all(bytes)?;
+    self.spilled = self.next;
+    Ok(())
+  }
+
+  // entries in the whole log
+  pub fn len(&self) -> Seqno {
+    self.next
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.next == 0
+  }
+
+  // how many of the newest entries are read from memory
+  pub fn in_memory(&self) -> usize {
+    self.recent.len()
+  }
+
+  // every entry, oldest first
+  pub fn iter(&self) -> io::Result<Iter<'_, T>> {
+    self.iter_from(0)
+  }
+
+  // entries from `seqno` on, oldest first. only opens the file if some
+  // of them are not in memory
+  pub fn iter_from(&self, seqno : Seqno) -> io::Result<Iter<'_, T>> {
+    let first = self.first_in_memory();
+    let mut file = None;
+    if seqno < first {
+      let mut f = File::open(&self.path)?;
+      f.seek(SeekFrom::Start(seqno * entry_size::<T>()))?;
+      file = Some(BufReader::new(f));
+    }
+    Ok(Iter {
+      file,
+      left   : first.saturating_sub(seqno),
+      recent : self.recent.iter().skip(seqno.saturating_sub(first) as usize),
+    })
+  }
+}
+
+// a clean shutdown loses nothing, errors can only be seen through sync()
+impl <T : Copy> Drop for Log<T> {
+  fn drop(&mut self) {
+    let _ = self.spill();
+  }
+}
+
+impl <'a, T : 'a + Copy> Iterator for Iter<'a, T> {
+  type Item = io::Result<T>;
+
+  fn next(&mut self) -> Option<io::Result<T>> {
+    if self.left == 0 {
+      return self.recent.next().map(Ok);
+    }
+
+    let file = self.file.as_mut().expect("file entries without a file");
+    let mut entry = MaybeUninit::<T>::uninit();
+    let bytes = unsafe { slice::from_raw_parts_mut(entry.as_mut_ptr() as *mut u8, mem::size_of::<T>()) };
+    match file.read_exact(bytes) {
+      Ok(()) => {
+        self.left -= 1;
+        Some(Ok(unsafe { entry.assume_init() }))
+      },
+      Err(e) => {
+        // the memory part would not follow on from here
+        self.left = 0;
+        self.recent.by_ref().for_each(drop);
+        Some(Err(e))
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Log;
+  use std::env;
+  use std::fs;
+  use std::path::PathBuf;
+  use std::process;
+
+  fn log_path(name : &str) -> PathBuf {
+    env::temp_dir().join(format!("rpg-log-{}-{}", process::id(), name))
+  }
+
+  fn entries(log : &Log<u64>, from : u64) -> Vec<u64> {
+    log.iter_from(from).unwrap().map(|e| e.unwrap()).collect()
+  }
+
+  #[test]
+  fn spills_in_whole_rings() {
+    let path = log_path("spill");
+    let mut log = Log::create(&path, 4, 0u64).unwrap();
+    for i in 0..4 { log.append(i).unwrap(); }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    assert_eq!(log.append(4).unwrap(), 4);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 4 * 8);
+
+    for i in 5..11 { log.append(i).unwrap(); }
+    assert_eq!((log.len(), log.in_memory()), (11, 4));
+    assert_eq!(entries(&log, 0), (0..11).collect::<Vec<u64>>());
+    assert_eq!(entries(&log, 6), (6..11).collect::<Vec<u64>>());
+    assert_eq!(entries(&log, 9), vec![9, 10]);
+    assert_eq!(entries(&log, 20), vec![]);
+
+    log.sync().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 11 * 8);
+    assert_eq!(entries(&log, 0), (0..11).collect::<Vec<u64>>());
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn reopened_log_continues() {
+    let path = log_path("reopen");
+    {
+      let mut log = Log::create(&path, 3, 0u64).unwrap();
+      for i in 0..5 { log.append(i).unwrap(); }
+    }
+    // a torn last entry is dropped
+    {
+      use std::io::Write;
+      let mut f = fs::OpenOptions::new().append(true).open(&path).unwrap();
+      f.write_all(&[1, 2, 3]).unwrap();
+    }
+
+    let mut log = unsafe { Log::open(&path, 3, 0u64).unwrap() };
+    assert_eq!((log.len(), log.in_memory()), (5, 0));
+    assert_eq!(log.append(5).unwrap(), 5);
+    assert_eq!(entries(&log, 0), (0..6).collect::<Vec<u64>>());
+    assert_eq!(entries(&log, 4), vec![4, 5]);
+    drop(log);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 6 * 8);
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -1,25 +1,123 @@
 extern crate rpg;
 
+use std::env;
+use std::process;
+
+fn usage() -> ! {
+  eprintln!("usage: rpg [bench [--workload stream|queues|events] [--format table|csv|json] [--items N] [--capacity N[,N...]]]");
+  process::exit(2);
+}
+
+fn bench(args : &[String]) {
+  use rpg::bench::{self, Format};
+
+  let mut workload   = "stream";
+  let mut format     = Format::Table;
+  let mut items      = 1_000_000;
+  let mut capacities = vec![8, 64, 1024];
+
+  let mut it = args.iter();
+  while let Some(arg) = it.next() {
+    let value = it.next().unwrap_or_else(|| usage());
+    match arg.as_str() {
+      "--workload" => {
+        workload = match value.as_str() {
+          w @ "stream" | w @ "queues" | w @ "events" => w,
+          _ => usage(),
+        };
+      },
+      "--format"   => { format = Format::parse(value).unwrap_or_else(|| usage()); },
+      "--items"    => { items = value.parse().unwrap_or_else(|_| usage()); },
+      "--capacity" => {
+        capacities = value.split(',')
+                          .map(|c| c.parse().unwrap_or_else(|_| usage()))
+                          .collect();
+      },
+      _ => usage(),
+    }
+  }
+  if items == 0 || capacities.contains(&0) { usage(); }
+
+  let results = match workload {
+    "queues" => bench::queues(&capacities, items),
+    "events" => bench::events(&capacities, items),
+    _        => bench::run(&capacities, items),
+  };
+  print!("{}", bench::render(&results, format));
+}
+
+// what the two rings keep and hand out, printed
+fn simple_demo() {
+  use rpg::simple::CircularBuffer;
+
+  let mut x = CircularBuffer::new(2, 0i32);
+  x.put(|v| *v = 1);
+  let mut y : i32 = 2;
+  x.put(|v| { *v = y; y += 1; });
+  x.put(|v| { *v = y; y += 1; });
+
+  for (seqno, i) in x.iter_enumerated() {
+    println!("CB: #{} {}", seqno, i);
+  }
+
+  x.put_slice(&[4, 5, 6]);
+  let mut out = [0i32; 4];
+  let n = x.read_into(&mut out);
+  println!("CB bulk: {:?}", &out[..n]);
+}
+
+fn spsc_demo() {
+  use rpg::spsc::CircularBuffer;
+
+  let mut x = CircularBuffer::new(4, 0i32);
+
+  {
+    x.put(|v| *v = 1);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 3);
+    x.put(|v| *v = 4);
+    x.put(|v| *v = 5);
+    x.put(|v| *v = 6);
+    x.put(|v| *v = 7);
+  }
+
+  {
+    for i in x.iter() {
+      println!("--: {}", i);
+    }
+  }
+
+  println!("{}", x.dump_state().to_json());
+}
+
 fn main() {
   use std::thread;
   use rpg::*;
 
-  simple::tests();
-  spsc::tests();
+  let args : Vec<String> = env::args().skip(1).collect();
+  match args.first().map(|a| a.as_str()) {
+    Some("bench") => { bench(&args[1..]); return; },
+    Some(_)       => usage(),
+    None          => {},
+  }
+
+  simple_demo();
+  spsc_demo();
 
-  let (mut tx, mut rx) = spsc::channel(7, 0 as i32);
+  let (tx, rx) = spsc::channel(7, 0i32);
   let t = thread::spawn(move|| {
     for i in 1..1000000 {
       tx.put(|v| *v = i);
     }
   });
 
-  for _k in 1..1000 {
-    let mut prev = 0;
-    for i in rx.iter() {
-      if i < prev { panic!("invalid value read!"); }
-      prev = i;
-    }
+  // item i went out as seqno i-1, see tests/oracle.rs for the full check.
+  // the loop ends once the sender thread is done
+  let mut prev = None;
+  for (seqno, i) in rx.iter_enumerated() {
+    if i as u64 != seqno + 1 { panic!("item {} read as seqno {}", i, seqno); }
+    if prev.is_some_and(|p| p >= seqno) { panic!("seqno {} read after {:?}", seqno, prev); }
+    prev = Some(seqno);
   }
 
   t.join().unwrap();
@@ -0,0 +1,238 @@
+
+// puts a stream that was split over several receivers back in order
+//
+// every item carries its place in the original stream, seqno_of() reads
+// it. items are handed out strictly by that seqno, those that arrive
+// early wait in a small reorder buffer. a missing seqno is given up on
+// once `window` later items are buffered, after `patience` without it
+// showing up, or when every input is closed: the merge then yields a Gap
+// and carries on after it. whatever comes in behind the merge's position
+// (a duplicate, or an item given up on that showed up after all) is
+// dropped and counted as late
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::iter::FusedIterator;
+use std::time::{Duration, Instant};
+
+use seq::{self, Seqno};
+use spsc;
+use wait;
+
+// seqnos from..to (exclusive) never arrived
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Gap {
+  pub from : Seqno,
+  pub to   : Seqno,
+}
+
+impl fmt::Display for Gap {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.to - self.from == 1 { write!(f, "seqno {} never arrived", self.from) }
+    else { write!(f, "seqnos {} to {} never arrived", self.from, self.to - 1) }
+  }
+}
+
+impl Error for Gap { }
+
+pub struct Merge<T : Clone + Send, F : Fn(&T) -> Seqno> {
+  inputs   : Vec<spsc::Receiver<T>>,
+  seqno_of : F,
+  pending  : BTreeMap<Seqno, T>,
+  next     : Seqno,
+  window   : usize,
+  patience : Option<Duration>,
+  stalled  : Option<Instant>,       // since when `next` is missing
+  late     : u64,
+  turn     : usize,                 // input to wait on when idle
+}
+
+// merges `inputs` starting at seqno 0, with a window of 64 items and no
+// time limit
+pub fn merge<T, F>(inputs : Vec<spsc::Receiver<T>>, seqno_of : F) -> Merge<T, F>
+  where T : Clone + Send,
+        F : Fn(&T) -> Seqno
+{
+  if inputs.is_empty() { panic!("nothing to merge"); }
+  Merge {
+    inputs,
+    seqno_of,
+    pending  : BTreeMap::new(),
+    next     : 0,
+    window   : 64,
+    patience : None,
+    stalled  : None,
+    late     : 0,
+    turn     : 0,
+  }
+}
+
+impl <T : Clone + Send, F : Fn(&T) -> Seqno> Merge<T, F> {
+  // the first seqno expected
+  pub fn start_at(mut self, seqno : Seqno) -> Merge<T, F> {
+    self.next = seqno;
+    self
+  }
+
+  // how many items past a missing one are buffered before it is given up
+  pub fn window(mut self, window : usize) -> Merge<T, F> {
+    if window == 0 { panic!("window cannot be zero"); }
+    self.window = window;
+    self
+  }
+
+  // how long a missing seqno is waited for while later ones are there
+  pub fn patience(mut self, patience : Duration) -> Merge<T, F> {
+    self.patience = Some(patience);
+    self
+  }
+
+  // the seqno the merge waits for
+  pub fn next_seqno(&self) -> Seqno {
+    self.next
+  }
+
+  // items dropped because the merge was already past them
+  pub fn late(&self) -> u64 {
+    self.late
+  }
+
+  // items waiting in the reorder buffer
+  pub fn buffered(&self) -> usize {
+    self.pending.len()
+  }
+
+  // the next item or gap if there is one already, without waiting
+  pub fn try_next(&mut self) -> Option<Result<(Seqno, T), Gap>> {
+    let closed = self.inputs.iter().all(|rx| rx.is_closed());
+    self.poll();
+    self.ready(Instant::now(), closed)
+  }
+
+  fn poll(&mut self) {
+    for rx in self.inputs.iter() {
+      for item in rx.try_iter() {
+        let seqno = (self.seqno_of)(&item);
+        if seq::before(seqno, self.next) || self.pending.contains_key(&seqno) {
+          self.late += 1;
+        } else {
+          self.pending.insert(seqno, item);
+        }
+      }
+    }
+  }
+
+  // `closed` must be from before the last poll(), so nothing can follow
+  fn ready(&mut self, now : Instant, closed : bool) -> Option<Result<(Seqno, T), Gap>> {
+    if let Some(item) = self.pending.remove(&self.next) {
+      self.stalled = None;
+      self.next += 1;
+      return Some(Ok((self.next - 1, item)));
+    }
+
+    let first = *self.pending.keys().next()?;
+    let since = *self.stalled.get_or_insert(now);
+    let timed_out = self.patience.is_some_and(|p| now.duration_since(since) >= p);
+    if closed || timed_out || self.pending.len() >= self.window {
+      let gap = Gap { from : self.next, to : first };
+      self.next = first;
+      self.stalled = None;
+      return Some(Err(gap));
+    }
+    None
+  }
+
+  // spins and yields for a bit, then waits on the inputs in turn
+  fn idle(&mut self, attempt : usize) {
+    if attempt < 16 {
+      wait::backoff(attempt);
+    } else {
+      self.turn = (self.turn + 1) % self.inputs.len();
+      self.inputs[self.turn].wait(Some(Duration::from_millis(1)));
+    }
+  }
+}
+
+// blocks for the next item or gap, ends once every input is closed and
+// everything was handed out
+impl <T : Clone + Send, F : Fn(&T) -> Seqno> Iterator for Merge<T, F> {
+  type Item = Result<(Seqno, T), Gap>;
+
+  fn next(&mut self) -> Option<Result<(Seqno, T), Gap>> {
+    let mut attempt = 0;
+    loop {
+      let closed = self.inputs.iter().all(|rx| rx.is_closed());
+      self.poll();
+      if let Some(next) = self.ready(Instant::now(), closed) { return Some(next); }
+      if closed { return None; }
+      self.idle(attempt);
+      attempt += 1;
+    }
+  }
+}
+
+impl <T : Clone + Send, F : Fn(&T) -> Seqno> FusedIterator for Merge<T, F> { }
+
+#[cfg(test)]
+mod tests {
+  use super::{merge, Gap};
+  use sharded;
+  use spsc;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn reorders_and_reports_gaps() {
+    let (a, ra) = spsc::channel(16, 0u64);
+    let (b, rb) = spsc::channel(16, 0u64);
+    let mut m = merge(vec![ra, rb], |v| *v).window(3);
+    assert_eq!(m.try_next(), None);
+
+    for &i in [1, 2].iter() { a.put(|v| *v = i); }
+    b.put(|v| *v = 0);
+    assert_eq!(m.try_next(), Some(Ok((0, 0))));
+    assert_eq!(m.try_next(), Some(Ok((1, 1))));
+    assert_eq!(m.try_next(), Some(Ok((2, 2))));
+
+    // 3 went missing, three items later it is given up
+    for &i in [5, 4].iter() { b.put(|v| *v = i); }
+    assert_eq!(m.try_next(), None);
+    a.put(|v| *v = 6);
+    assert_eq!(m.try_next(), Some(Err(Gap { from : 3, to : 4 })));
+    assert_eq!(Gap { from : 3, to : 4 }.to_string(), "seqno 3 never arrived");
+    assert_eq!(Gap { from : 3, to : 6 }.to_string(), "seqnos 3 to 5 never arrived");
+    assert_eq!(m.try_next(), Some(Ok((4, 4))));
+
+    // too late now, and duplicates
+    a.put(|v| *v = 3);
+    b.put(|v| *v = 6);
+    assert_eq!(m.try_next(), Some(Ok((5, 5))));
+    assert_eq!(m.late(), 2);
+    drop((a, b));
+    assert_eq!(m.map(|r| r.map(|(s, _)| s)).collect::<Vec<_>>(), vec![Ok(6)]);
+  }
+
+  #[test]
+  fn patience_gives_up_on_stragglers() {
+    let (tx, rx) = spsc::channel(4, 0u64);
+    let mut m = merge(vec![rx], |v| *v).patience(Duration::from_millis(10));
+    tx.put(|v| *v = 1);
+    assert_eq!(m.try_next(), None);
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(m.try_next(), Some(Err(Gap { from : 0, to : 1 })));
+    assert_eq!(m.try_next(), Some(Ok((1, 1))));
+  }
+
+  #[test]
+  fn sharded_stream_comes_back_in_order() {
+    const ITEMS : u64 = 30_000;
+    let (tx, rxs) = sharded::channel::<u64>(4, ITEMS as usize);
+    let t = thread::spawn(move || {
+      for i in 0..ITEMS { tx.send(&(i * 7919 % 13), i); }
+    });
+    let items : Vec<u64> = merge(rxs, |v| *v).window(ITEMS as usize).map(|r| r.unwrap().1).collect();
+    t.join().unwrap();
+    assert_eq!(items, (0..ITEMS).collect::<Vec<u64>>());
+  }
+}
@@ -0,0 +1,141 @@
+
+// means in O(1) per sample. MovingAvg keeps a running sum over its
+// window, adding what comes in and taking out what the ring evicts. the
+// sum is recomputed from the window once per lap, so float rounding
+// cannot pile up over a long run. Ewma needs no window at all
+
+use simple::CircularBuffer;
+
+pub struct MovingAvg {
+  samples : CircularBuffer<f64>,
+  sum     : f64,
+  evicted : usize,            // since the sum was last recomputed
+}
+
+impl MovingAvg {
+  // the mean of the last `window` samples, panics if that is zero
+  pub fn new(window : usize) -> MovingAvg {
+    MovingAvg {
+      samples : CircularBuffer::new(window, 0.0),
+      sum     : 0.0,
+      evicted : 0,
+    }
+  }
+
+  pub fn record(&mut self, value : f64) {
+    if self.samples.len() == self.samples.capacity() {
+      // the oldest sample is the one put() overwrites
+      let oldest = self.samples.iter().next().unwrap_or(0.0);
+      self.sum  -= oldest;
+      self.evicted += 1;
+    }
+    self.samples.put(|v| *v = value);
+    self.sum += value;
+
+    if self.evicted >= self.samples.capacity() {
+      self.sum     = self.samples.iter().sum();
+      self.evicted = 0;
+    }
+  }
+
+  // None before the first sample
+  pub fn mean(&self) -> Option<f64> {
+    if self.samples.is_empty() { None } else { Some(self.sum / self.samples.len() as f64) }
+  }
+
+  pub fn sum(&self) -> f64 {
+    self.sum
+  }
+
+  pub fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  pub fn clear(&mut self) {
+    self.samples.clear();
+    self.sum     = 0.0;
+    self.evicted = 0;
+  }
+}
+
+// exponentially weighted mean, every sample moves it `alpha` of the way
+// towards itself. the first sample is taken as is
+#[derive(Clone, Copy, Debug)]
+pub struct Ewma {
+  alpha : f64,
+  value : Option<f64>,
+}
+
+impl Ewma {
+  // panics unless 0 < alpha <= 1
+  pub fn new(alpha : f64) -> Ewma {
+    if !(alpha > 0.0 && alpha <= 1.0) { panic!("alpha must be in (0, 1], got {}", alpha); }
+    Ewma { alpha, value : None }
+  }
+
+  // the alpha that halves a sample's weight every `samples` samples
+  pub fn with_half_life(samples : f64) -> Ewma {
+    Ewma::new(1.0 - 0.5f64.powf(1.0 / samples))
+  }
+
+  pub fn record(&mut self, value : f64) {
+    self.value = Some(match self.value {
+      Some(v) => v + self.alpha * (value - v),
+      None    => value,
+    });
+  }
+
+  // None before the first sample
+  pub fn value(&self) -> Option<f64> {
+    self.value
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Ewma, MovingAvg};
+
+  #[test]
+  fn moving_average_over_window() {
+    let mut m = MovingAvg::new(4);
+    assert_eq!(m.mean(), None);
+    m.record(2.0);
+    m.record(4.0);
+    assert_eq!(m.mean(), Some(3.0));
+    for v in [6.0, 8.0, 10.0, 12.0].iter() { m.record(*v); }
+    assert_eq!((m.len(), m.sum()), (4, 36.0));
+    assert_eq!(m.mean(), Some(9.0));
+
+    // rounding does not drift over many laps
+    for i in 0..100_000 { m.record(0.1 * (i % 7) as f64); }
+    let exact = [99_996, 99_997, 99_998, 99_999].iter().map(|i| 0.1 * (i % 7) as f64).sum::<f64>() / 4.0;
+    assert!((m.mean().unwrap() - exact).abs() < 1e-12);
+
+    m.clear();
+    assert_eq!(m.mean(), None);
+  }
+
+  #[test]
+  fn ewma_moves_towards_samples() {
+    let mut e = Ewma::new(0.5);
+    assert_eq!(e.value(), None);
+    e.record(10.0);
+    assert_eq!(e.value(), Some(10.0));
+    e.record(20.0);
+    e.record(20.0);
+    assert_eq!(e.value(), Some(17.5));
+
+    let h = Ewma::with_half_life(1.0);
+    assert!((h.alpha - 0.5).abs() < 1e-12);
+  }
+
+  #[test]
+  #[should_panic]
+  fn ewma_rejects_zero_alpha() {
+    Ewma::new(0.0);
+  }
+}
@@ -0,0 +1,9 @@
+
+// rolling statistics over the newest samples, the windowed ones keep
+// them in a simple::CircularBuffer
+
+mod average;
+mod quantiles;
+
+pub use self::average::{Ewma, MovingAvg};
+pub use self::quantiles::Quantiles;
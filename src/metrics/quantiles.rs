@@ -0,0 +1,118 @@
+
+// percentiles over the last `window` samples, optionally only those that
+// are at most `max_age` old. recording is a single put, a query copies
+// the window out and selects in it, so O(window) per query
+
+use std::time::{Duration, Instant};
+
+use simple::CircularBuffer;
+
+#[derive(Clone, Copy)]
+struct Sample {
+  at    : Instant,
+  value : f64,
+}
+
+pub struct Quantiles {
+  samples : CircularBuffer<Sample>,
+  max_age : Option<Duration>,
+}
+
+impl Quantiles {
+  // keeps the last `window` samples, panics if that is zero
+  pub fn new(window : usize) -> Quantiles {
+    Quantiles {
+      samples : CircularBuffer::new(window, Sample { at : Instant::now(), value : 0.0 }),
+      max_age : None,
+    }
+  }
+
+  // ignores samples older than `max_age`, on top of the window
+  pub fn max_age(mut self, max_age : Duration) -> Quantiles {
+    self.max_age = Some(max_age);
+    self
+  }
+
+  pub fn record(&mut self, value : f64) {
+    self.record_at(value, Instant::now());
+  }
+
+  // for samples taken earlier, `at` must not be before the previous one
+  pub fn record_at(&mut self, value : f64, at : Instant) {
+    self.samples.put(|s| *s = Sample { at, value });
+  }
+
+  // the number of samples a query would look at right now
+  pub fn len(&self) -> usize {
+    self.window(Instant::now()).len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn clear(&mut self) {
+    self.samples.clear();
+  }
+
+  // the value below which a `q` share of the samples lie (nearest rank),
+  // None without samples. q is clamped to [0, 1], NaNs sort last
+  pub fn quantile(&self, q : f64) -> Option<f64> {
+    self.quantile_at(q, Instant::now())
+  }
+
+  pub fn quantile_at(&self, q : f64, now : Instant) -> Option<f64> {
+    let mut values = self.window(now);
+    if values.is_empty() { return None; }
+
+    let q    = if q.is_nan() { 0.0 } else { q.clamp(0.0, 1.0) };
+    let rank = ((q * values.len() as f64).ceil() as usize).max(1) - 1;
+    Some(*values.select_nth_unstable_by(rank, |a, b| a.total_cmp(b)).1)
+  }
+
+  pub fn p50(&self) -> Option<f64> { self.quantile(0.50) }
+  pub fn p95(&self) -> Option<f64> { self.quantile(0.95) }
+  pub fn p99(&self) -> Option<f64> { self.quantile(0.99) }
+
+  // values of the samples that count at `now`, oldest first
+  fn window(&self, now : Instant) -> Vec<f64> {
+    let fresh = |s : &Sample| match self.max_age {
+      Some(age) => now.saturating_duration_since(s.at) <= age,
+      None      => true,
+    };
+    self.samples.iter().filter(fresh).map(|s| s.value).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Quantiles;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn nearest_rank() {
+    let mut q = Quantiles::new(100);
+    assert_eq!(q.p50(), None);
+    for v in (1..=100).rev() { q.record(v as f64); }
+    assert_eq!((q.p50(), q.p95(), q.p99()), (Some(50.0), Some(95.0), Some(99.0)));
+    assert_eq!((q.quantile(0.0), q.quantile(1.0)), (Some(1.0), Some(100.0)));
+
+    // only the last 100 count
+    for _ in 0..50 { q.record(1000.0); }
+    assert_eq!(q.len(), 100);
+    assert_eq!(q.p50(), Some(50.0));
+    assert_eq!(q.quantile(0.51), Some(1000.0));
+  }
+
+  #[test]
+  fn old_samples_drop_out() {
+    let start = Instant::now();
+    let mut q = Quantiles::new(10).max_age(Duration::from_secs(5));
+    for i in 0..10 { q.record_at(i as f64, start + Duration::from_secs(i)); }
+
+    let at = |secs| start + Duration::from_secs(secs);
+    assert_eq!(q.quantile_at(0.0, at(9)), Some(4.0));
+    assert_eq!(q.quantile_at(0.5, at(12)), Some(8.0));
+    assert_eq!(q.quantile_at(0.5, at(20)), None);
+  }
+}
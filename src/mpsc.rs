@@ -0,0 +1,382 @@
+
+// many producers into one spsc ring
+//
+// channel(): the simple version. every DirectSender puts into the shared
+// ring itself, taking turns through one flag word they all CAS, so that
+// word and the ring's flags bounce between the producers' cores on every
+// put. fine for a few producers that put now and then
+//
+// channel_combining(): every Sender (every clone) writes into a staging
+// ring of its own, so producers never CAS the same flag words. whoever
+// gets the combiner lock right after a put drains all staging rings into
+// the shared ring in one batch, with one wake for the receiver. a producer
+// that finds the lock taken leaves its items to the holder, which looks
+// at the put counter once more before it lets go, so nothing is stranded
+// in a staging ring. the receiving end is a plain spsc::Receiver.
+//
+// both kinds of ring overwrite their oldest item when full, as
+// spsc::channel() does. a staging ring that fills up is combined before
+// the next put instead, so only the shared ring ever evicts
+//
+// a WeakSender does not count as a sender, the channel closes when the
+// last real one goes, whatever weak handles are left. upgrading one
+// after that fails
+
+use std::cell::UnsafeCell;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use spsc;
+use wait;
+
+struct Direct<T : Clone + Send> {
+  busy    : AtomicBool,                           // a sender is putting
+  out     : UnsafeCell<Option<spsc::Sender<T>>>,  // only touched while busy, gone with the last sender
+  senders : AtomicUsize,
+}
+
+// the out sender is only used by whoever set `busy`
+unsafe impl<T : Clone + Send> Sync for Direct<T> { }
+
+pub struct DirectSender<T : Clone + Send> {
+  shared : Arc<Direct<T>>,
+}
+
+struct Combiner<T : Clone + Send> {
+  out     : Option<spsc::Sender<T>>,    // gone with the last Sender
+  staging : Vec<spsc::Receiver<T>>,     // one per Sender
+  default : T,                          // for new staging rings
+}
+
+struct Shared<T : Clone + Send> {
+  combiner  : Mutex<Combiner<T>>,
+  puts      : AtomicUsize,              // staged so far, see combine()
+  senders   : AtomicUsize,
+  size      : usize,
+}
+
+pub struct Sender<T : Clone + Send> {
+  staging : spsc::Sender<T>,
+  shared  : Arc<Shared<T>>,
+}
+
+// see Sender::downgrade()
+pub struct WeakSender<T : Clone + Send> {
+  shared : Weak<Shared<T>>,
+}
+
+// a channel for `size` items, shared by every clone of the sender
+pub fn channel<T : Clone + Send>(size : usize, default_value : T) -> (DirectSender<T>, spsc::Receiver<T>) {
+  let (out, rx) = spsc::channel(size, default_value);
+  let shared = Arc::new(Direct {
+    busy    : AtomicBool::new(false),
+    out     : UnsafeCell::new(Some(out)),
+    senders : AtomicUsize::new(1),
+  });
+  (DirectSender { shared }, rx)
+}
+
+// a channel for `size` items, the staging rings hold `size` each as well
+pub fn channel_combining<T : Clone + Send>(size : usize, default_value : T) -> (Sender<T>, spsc::Receiver<T>) {
+  let (out, rx) = spsc::channel(size, default_value.clone());
+  let shared = Arc::new(Shared {
+    combiner : Mutex::new(Combiner { out : Some(out), staging : Vec::new(), default : default_value }),
+    puts     : AtomicUsize::new(0),
+    senders  : AtomicUsize::new(0),
+    size,
+  });
+  shared.senders.fetch_add(1, Ordering::Relaxed);
+  (Sender::register(shared), rx)
+}
+
+impl <T : Clone + Send> Direct<T> {
+  // runs f with the out sender, waiting for whoever has it now. the flag
+  // is dropped again even if f panics
+  fn with_out<F : FnOnce(&mut Option<spsc::Sender<T>>)>(&self, f : F) {
+    struct Done<'a>(&'a AtomicBool);
+    impl<'a> Drop for Done<'a> {
+      fn drop(&mut self) { self.0.store(false, Ordering::Release); }
+    }
+
+    let mut attempt = 0;
+    while self.busy.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+      wait::backoff(attempt);
+      attempt += 1;
+    }
+    let _done = Done(&self.busy);
+    f(unsafe { &mut *self.out.get() })
+  }
+}
+
+impl <T : Clone + Send> DirectSender<T> {
+  // true for clones of the same sender, which share one receiver
+  pub fn same_channel(&self, other : &DirectSender<T>) -> bool {
+    Arc::ptr_eq(&self.shared, &other.shared)
+  }
+
+  // puts into the shared ring once no other sender is in the middle of
+  // it. the setter must not put into the same channel, it would wait
+  // for itself
+  pub fn put<F>(&self, setter : F)
+    where F : FnMut(&mut T)
+  {
+    self.shared.with_out(|out| if let Some(ref out) = *out { out.put(setter); });
+  }
+}
+
+impl <T : Clone + Send> Clone for DirectSender<T> {
+  fn clone(&self) -> DirectSender<T> {
+    self.shared.senders.fetch_add(1, Ordering::Relaxed);
+    DirectSender { shared : self.shared.clone() }
+  }
+}
+
+// the last sender closes the ring, after every other one's puts
+impl <T : Clone + Send> Drop for DirectSender<T> {
+  fn drop(&mut self) {
+    if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+      self.shared.with_out(|out| *out = None);
+    }
+  }
+}
+
+impl <T : Clone + Send> Shared<T> {
+  // a panicking producer leaves the staging rings as they were, the
+  // next combine can carry on
+  fn lock(&self) -> MutexGuard<'_, Combiner<T>> {
+    self.combiner.lock().unwrap_or_else(|e| e.into_inner())
+  }
+
+  // drains the staging rings unless someone else is already at it. the
+  // holder re-checks `puts` after letting go, so a put that raced with
+  // its drain gets combined by it or the next holder
+  //
+  // that takes sequential consistency: a producer counts its put, then
+  // looks at the lock, the holder lets go of the lock, then looks at the
+  // count. with acquire/release only both could read the old value, the
+  // producer seeing the lock still taken and the holder no new put, and
+  // the item would sit in its staging ring until the next put. the fences
+  // make the lock word part of the same total order as the count
+  fn combine(&self) {
+    loop {
+      atomic::fence(Ordering::SeqCst);
+      let mut c = match self.combiner.try_lock() {
+        Ok(c)  => c,
+        Err(_) => return,
+      };
+      let seen = self.puts.load(Ordering::SeqCst);
+      c.drain();
+      drop(c);
+      atomic::fence(Ordering::SeqCst);
+      if self.puts.load(Ordering::SeqCst) == seen { return; }
+    }
+  }
+}
+
+impl <T : Clone + Send> Combiner<T> {
+  fn drain(&mut self) {
+    let out = match self.out {
+      Some(ref out) => out,
+      None          => return,
+    };
+    for rx in self.staging.iter() {
+      out.put_all(rx.try_iter());
+    }
+    // senders that are gone and fully drained
+    self.staging.retain(|rx| !(rx.is_closed() && rx.is_empty()));
+  }
+}
+
+impl <T : Clone + Send> Sender<T> {
+  // a staging ring for a sender already counted in `senders`
+  fn register(shared : Arc<Shared<T>>) -> Sender<T> {
+    let staging = {
+      let mut c = shared.lock();
+      let (staging, rx) = spsc::channel(shared.size, c.default.clone());
+      c.staging.push(rx);
+      staging
+    };
+    Sender { staging, shared }
+  }
+
+  // true for clones of the same sender, which share one receiver
+  pub fn same_channel(&self, other : &Sender<T>) -> bool {
+    Arc::ptr_eq(&self.shared, &other.shared)
+  }
+
+  // a handle that does not keep the channel open, e.g. for a registry
+  // that outlives the producers
+  pub fn downgrade(&self) -> WeakSender<T> {
+    WeakSender { shared : Arc::downgrade(&self.shared) }
+  }
+
+  // stages an item and combines if nobody else does. the receiver gets
+  // every producer's items in the order they were put
+  pub fn put<F>(&self, setter : F)
+    where F : FnMut(&mut T)
+  {
+    // only we fill the staging ring, once it has room it keeps it
+    while self.staging.is_full() {
+      self.shared.combine();
+      if self.staging.is_full() { thread::yield_now(); }
+    }
+    self.staging.put(setter);
+    // see combine()
+    self.shared.puts.fetch_add(1, Ordering::SeqCst);
+    self.shared.combine();
+  }
+}
+
+// every clone gets a staging ring of its own
+impl <T : Clone + Send> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    self.shared.senders.fetch_add(1, Ordering::Relaxed);
+    Sender::register(self.shared.clone())
+  }
+}
+
+impl <T : Clone + Send> WeakSender<T> {
+  // a new sender while some other one is still around, None once the
+  // channel closed. counting it in before the last one can drop to zero
+  // rules out reopening a closed channel
+  pub fn upgrade(&self) -> Option<Sender<T>> {
+    let shared = self.shared.upgrade()?;
+    shared.senders.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| if n == 0 { None } else { Some(n + 1) }).ok()?;
+    Some(Sender::register(shared))
+  }
+}
+
+impl <T : Clone + Send> Clone for WeakSender<T> {
+  fn clone(&self) -> WeakSender<T> {
+    WeakSender { shared : self.shared.clone() }
+  }
+}
+
+// the last sender closes the shared ring, after a final drain under the
+// lock so the receiver sees everything before it sees the end
+impl <T : Clone + Send> Drop for Sender<T> {
+  fn drop(&mut self) {
+    if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+      let mut c = self.shared.lock();
+      c.drain();
+      c.out = None;
+    } else {
+      self.shared.combine();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{channel, channel_combining};
+  use spsc;
+  use std::thread;
+
+  #[test]
+  fn items_from_every_sender() {
+    let (tx, rx) = channel_combining(8, 0i32);
+    let tx2 = tx.clone();
+    tx.put(|v| *v = 1);
+    tx2.put(|v| *v = 2);
+    tx.put(|v| *v = 3);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+    assert!(tx.same_channel(&tx2) && !tx.same_channel(&channel_combining(8, 0i32).0));
+
+    // the shared ring keeps the newest 8
+    for i in 0..20 { tx2.put(|v| *v = i); }
+    drop(tx);
+    assert!(!rx.is_closed());
+    drop(tx2);
+    assert_eq!(rx.iter().collect::<Vec<i32>>(), (12..20).collect::<Vec<i32>>());
+  }
+
+  #[test]
+  fn weak_senders_do_not_keep_it_open() {
+    let (tx, rx) = channel_combining(8, 0i32);
+    let weak = tx.downgrade();
+    let tx2 = weak.upgrade().unwrap();
+    tx2.put(|v| *v = 1);
+    drop(tx);
+    assert!(!rx.is_closed());
+    drop(tx2);
+    assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![1]);
+    assert!(weak.clone().upgrade().is_none());
+  }
+
+  #[test]
+  fn per_producer_order_survives() {
+    const PRODUCERS : usize = 4;
+    const ITEMS     : usize = 20_000;
+    let (tx, rx) = channel_combining(64, (0usize, 0usize));
+
+    let threads : Vec<_> = (0..PRODUCERS).map(|p| {
+      let tx = tx.clone();
+      thread::spawn(move || {
+        for i in 0..ITEMS { tx.put(|v| *v = (p, i)); }
+      })
+    }).collect();
+    drop(tx);
+
+    // the ring overwrites, so items may be missing, but never out of order
+    let mut last : Vec<Option<usize>> = vec![None; PRODUCERS];
+    for (p, i) in rx.iter() {
+      assert!(last[p].is_none_or(|prev| prev < i), "producer {} went from {:?} to {}", p, last[p], i);
+      last[p] = Some(i);
+    }
+    for t in threads { t.join().unwrap(); }
+    assert!(last.contains(&Some(ITEMS - 1)));
+  }
+
+  // what each producer's items reached the receiver as
+  fn per_producer(rx : spsc::Receiver<(usize, usize)>, producers : usize) -> Vec<Vec<usize>> {
+    let mut got = vec![Vec::new(); producers];
+    for (p, i) in rx.iter() { got[p].push(i); }
+    got
+  }
+
+  #[test]
+  fn direct_and_combining_agree() {
+    const PRODUCERS : usize = 4;
+    const ITEMS     : usize = 2_000;
+
+    // one thread, alternating between two senders: the same items in the
+    // same order, also once the ring wrapped
+    for &n in &[5, 20] {
+      let (a, ra) = channel(8, 0usize);
+      let (b, rb) = channel_combining(8, 0usize);
+      let (a2, b2) = (a.clone(), b.clone());
+      assert!(a.same_channel(&a2) && !a.same_channel(&channel(8, 0usize).0));
+      for i in 0..n {
+        if i % 2 == 0 { a.put(|v| *v = i); b.put(|v| *v = i); } else { a2.put(|v| *v = i); b2.put(|v| *v = i); }
+      }
+      drop((a, a2, b, b2));
+      let direct = ra.iter().collect::<Vec<usize>>();
+      assert_eq!(direct, (n.saturating_sub(8)..n).collect::<Vec<usize>>());
+      assert_eq!(direct, rb.iter().collect::<Vec<usize>>());
+    }
+
+    // threads, with room for everything: both deliver every item, each
+    // producer's in order
+    let (tx, rx) = channel(PRODUCERS * ITEMS, (0usize, 0usize));
+    let threads : Vec<_> = (0..PRODUCERS).map(|p| {
+      let tx = tx.clone();
+      thread::spawn(move || for i in 0..ITEMS { tx.put(|v| *v = (p, i)); })
+    }).collect();
+    drop(tx);
+    for t in threads { t.join().unwrap(); }
+    let direct = per_producer(rx, PRODUCERS);
+
+    let (tx, rx) = channel_combining(PRODUCERS * ITEMS, (0usize, 0usize));
+    let threads : Vec<_> = (0..PRODUCERS).map(|p| {
+      let tx = tx.clone();
+      thread::spawn(move || for i in 0..ITEMS { tx.put(|v| *v = (p, i)); })
+    }).collect();
+    drop(tx);
+    for t in threads { t.join().unwrap(); }
+    let combining = per_producer(rx, PRODUCERS);
+
+    assert_eq!(direct, vec![(0..ITEMS).collect::<Vec<usize>>(); PRODUCERS]);
+    assert_eq!(direct, combining);
+  }
+}
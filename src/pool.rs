@@ -0,0 +1,285 @@
+
+// fixed set of preallocated objects for messages that are not Copy
+//
+// the producer checks an object out, fills it in place and sends its
+// Handle (two words, Copy) through a channel instead of the object. the
+// consumer redeems the handle for the object and dropping that puts it
+// back on the free list, so nothing is allocated or cloned on the way.
+//
+// the free list is a stack::IndexStack of slot indices. every slot has a
+// state word as well, in the style of the spsc flags, packing a
+// generation that counts checkouts with where the object is. redeeming a
+// handle swaps its slot from in flight to checked out, so a handle works
+// once and stale copies of it (rings keep those around) get None.
+//
+// a handle that is never redeemed keeps its object out of the pool. on a
+// ring that evicts, send with put_replace() and redeem what it hands back
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use stack::{self, IndexStack};
+
+// the low bits of a slot's state, the generation is above them
+const FREE       : usize = 0;
+const OUT        : usize = 1;               // held by a Pooled
+const IN_FLIGHT  : usize = 2;               // behind a Handle
+const STATE_BITS : u32   = 2;
+
+fn pack_state(gen : usize, state : usize) -> usize {
+  (gen << STATE_BITS) | state
+}
+
+struct Slot<T> {
+  object : UnsafeCell<T>,
+  state  : AtomicUsize,                     // generation and FREE/OUT/IN_FLIGHT
+}
+
+struct Shared<T> {
+  slots     : Box<[Slot<T>]>,
+  free_list : IndexStack,
+  free      : AtomicUsize,                  // objects on the free list
+}
+
+// an object is only touched by the one Pooled that owns its slot
+unsafe impl<T : Send> Sync for Shared<T> { }
+
+// the pool, clones share the objects
+pub struct Pool<T : Send> {
+  shared : Arc<Shared<T>>,
+}
+
+// a checked out object, goes back to the pool on drop
+pub struct Pooled<'a, T : 'a + Send> {
+  pool  : &'a Shared<T>,
+  index : usize,
+}
+
+// what travels through the channel for a sent object
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Handle {
+  index : usize,
+  gen   : usize,
+}
+
+impl <T : Send> Pool<T> {
+  // `capacity` objects, each a clone of `default_value`
+  pub fn new(capacity : usize, default_value : T) -> Pool<T>
+    where T : Clone
+  {
+    if capacity == 0 { panic!("capacity cannot be zero"); }
+    if capacity > stack::MAX_LEN { panic!("at most {} objects fit the free list on this target, got {}", stack::MAX_LEN, capacity); }
+
+    // every slot starts on the free list
+    let slots = (0..capacity).map(|_| Slot {
+      object : UnsafeCell::new(default_value.clone()),
+      state  : AtomicUsize::new(pack_state(0, FREE)),
+    }).collect();
+    Pool {
+      shared : Arc::new(Shared {
+        slots,
+        free_list : IndexStack::new(capacity, true),
+        free      : AtomicUsize::new(capacity),
+      }),
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.shared.slots.len()
+  }
+
+  // objects that can be checked out right now
+  pub fn available(&self) -> usize {
+    self.shared.free.load(Ordering::Relaxed)
+  }
+
+  // an object from the free list, None if all of them are out. it holds
+  // whatever its last user left in it
+  pub fn checkout(&self) -> Option<Pooled<'_, T>> {
+    let s = &*self.shared;
+    let index = s.free_list.pop()?;
+    s.free.fetch_sub(1, Ordering::Relaxed);
+    let slot = &s.slots[index];
+    let gen  = (slot.state.load(Ordering::Relaxed) >> STATE_BITS).wrapping_add(1);
+    slot.state.store(pack_state(gen, OUT), Ordering::Relaxed);
+    Some(Pooled { pool : s, index })
+  }
+
+  // the object `handle` was sent for. None if it was redeemed already or
+  // comes from another pool (or is the default handle)
+  pub fn take(&self, handle : Handle) -> Option<Pooled<'_, T>> {
+    let s = &*self.shared;
+    let slot = s.slots.get(handle.index)?;
+    // pairs with the release in into_handle(), in case the handle did
+    // not come through a channel that orders the object already
+    slot.state.compare_exchange(pack_state(handle.gen, IN_FLIGHT), pack_state(handle.gen, OUT),
+                                Ordering::Acquire, Ordering::Relaxed).ok()?;
+    Some(Pooled { pool : s, index : handle.index })
+  }
+}
+
+impl <T : Send> Clone for Pool<T> {
+  fn clone(&self) -> Pool<T> {
+    Pool { shared : self.shared.clone() }
+  }
+}
+
+impl <T> Shared<T> {
+  fn put_back(&self, index : usize) {
+    let slot = &self.slots[index];
+    let gen  = slot.state.load(Ordering::Relaxed) >> STATE_BITS;
+    slot.state.store(pack_state(gen, FREE), Ordering::Relaxed);
+    // the object's last writes happen before the next checkout of it
+    self.free_list.push(index);
+    self.free.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl <'a, T : Send> Pooled<'a, T> {
+  // gives the object up for sending, it stays out of the pool until the
+  // handle is redeemed with take()
+  pub fn into_handle(self) -> Handle {
+    let slot = &self.pool.slots[self.index];
+    let gen  = slot.state.load(Ordering::Relaxed) >> STATE_BITS;
+    slot.state.store(pack_state(gen, IN_FLIGHT), Ordering::Release);
+    let handle = Handle { index : self.index, gen };
+    mem::forget(self);
+    handle
+  }
+}
+
+impl <'a, T : Send> Deref for Pooled<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { &*self.pool.slots[self.index].object.get() }
+  }
+}
+
+impl <'a, T : Send> DerefMut for Pooled<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { &mut *self.pool.slots[self.index].object.get() }
+  }
+}
+
+impl <'a, T : Send> Drop for Pooled<'a, T> {
+  fn drop(&mut self) {
+    self.pool.put_back(self.index);
+  }
+}
+
+impl Handle {
+  pub fn index(&self) -> usize {
+    self.index
+  }
+}
+
+// for the slots of a channel before anything was sent, no pool takes it
+impl Default for Handle {
+  fn default() -> Handle {
+    Handle { index : usize::MAX, gen : 0 }
+  }
+}
+
+impl fmt::Debug for Handle {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Handle({}@{})", self.index, self.gen)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Handle, Pool};
+  use spsc::{self, Builder, Policy};
+  use std::thread;
+
+  #[test]
+  fn checkout_until_empty() {
+    let pool = Pool::new(2, String::new());
+    let mut a = pool.checkout().unwrap();
+    a.push('a');
+    let b = pool.checkout().unwrap();
+    assert!(pool.checkout().is_none());
+    assert_eq!(pool.available(), 0);
+
+    drop(a);
+    assert_eq!(pool.available(), 1);
+    // objects come back as they were left
+    assert_eq!(&*pool.checkout().unwrap(), "a");
+    drop(b);
+    assert_eq!(pool.available(), 2);
+  }
+
+  #[test]
+  fn handles_redeem_once() {
+    let pool = Pool::new(1, vec![0u8; 4]);
+    let handle = pool.checkout().unwrap().into_handle();
+    assert!(pool.checkout().is_none());
+    assert!(pool.take(Handle::default()).is_none());
+
+    let obj = pool.take(handle).unwrap();
+    assert!(pool.take(handle).is_none());
+    drop(obj);
+    // a later checkout of the same slot does not revive the old handle
+    let again = pool.checkout().unwrap().into_handle();
+    assert_eq!(again.index(), handle.index());
+    assert!(pool.take(handle).is_none());
+    assert!(pool.take(again).is_some());
+    assert!(Pool::new(1, 0u8).take(again).is_none());
+  }
+
+  #[test]
+  fn objects_make_the_round_trip() {
+    const MESSAGES : usize = 20_000;
+    let pool = Pool::new(8, vec![0u8; 64]);
+    let (tx, rx) = Builder::new().capacity(4).overwrite(Policy::Block).build::<Handle>();
+
+    let producer = pool.clone();
+    let t = thread::spawn(move || {
+      for i in 0..MESSAGES {
+        let mut msg = loop {
+          match producer.checkout() {
+            Some(msg) => break msg,
+            None      => thread::yield_now(),
+          }
+        };
+        msg.clear();
+        msg.extend((0..i % 64).map(|b| b as u8));
+        let handle = msg.into_handle();
+        tx.put(|v| *v = handle);
+      }
+    });
+
+    let mut seen = 0;
+    for handle in rx.iter() {
+      let msg = pool.take(handle).unwrap();
+      assert_eq!(msg.len(), seen % 64);
+      // nothing grew past the preallocated capacity
+      assert_eq!(msg.capacity(), 64);
+      seen += 1;
+    }
+    t.join().unwrap();
+    assert_eq!((seen, pool.available()), (MESSAGES, 8));
+  }
+
+  #[test]
+  fn evicted_handles_go_back() {
+    let pool = Pool::new(3, 0u64);
+    let (tx, rx) = spsc::channel(1, Handle::default());
+    for i in 0..3 {
+      let mut obj = pool.checkout().unwrap();
+      *obj = i;
+      if let Some(evicted) = tx.put_replace(obj.into_handle()) {
+        drop(pool.take(evicted).unwrap());
+      }
+    }
+    assert_eq!(pool.available(), 2);
+    let got : Vec<u64> = rx.try_iter().map(|h| *pool.take(h).unwrap()).collect();
+    assert_eq!(got, vec![2]);
+    assert_eq!(pool.available(), 3);
+  }
+}
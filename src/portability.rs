@@ -0,0 +1,71 @@
+
+// checks for the assumptions that differ between targets: pointer width
+// (flag layout, seqno wrap) and byte order (nothing may depend on it)
+//
+// all of it runs natively, to cover the other layouts run it under qemu:
+//
+//   cross test --target armv7-unknown-linux-gnueabihf portability
+//   cross test --target powerpc64-unknown-linux-gnu portability   (big endian)
+
+use std::thread;
+
+use spsc;
+use spsc::flag;
+
+#[test]
+fn flag_layout_follows_pointer_width() {
+  #[cfg(target_pointer_width = "32")]
+  assert_eq!((flag::MAX_SLOTS, flag::MAX_SIZE), (1 << 15, (1 << 14) - 1));
+  #[cfg(target_pointer_width = "64")]
+  assert_eq!((flag::MAX_SLOTS, flag::MAX_SIZE), (1 << 47, 1 << 15));
+
+  assert_eq!(flag::TAKEN.count_ones(), 1);
+  assert_eq!(flag::TAKEN.leading_zeros(), 0);
+  assert_eq!(flag::pack(flag::MAX_SLOTS - 1, flag::SEQ_MASK) | flag::TAKEN, usize::MAX);
+}
+
+#[test]
+fn flags_are_plain_integers() {
+  // the packing is arithmetic, so its bytes follow the native order
+  // and unpacking from them gives the same fields on either endianness
+  let f = flag::pack(0x1234, 0xabcd);
+  let bytes = f.to_ne_bytes();
+  let back = usize::from_ne_bytes(bytes);
+  assert_eq!((flag::pos(back), flag::seq(back)), (0x1234, 0xabcd));
+
+  #[cfg(target_endian = "little")]
+  assert_eq!(bytes[0], 0xcd);
+  #[cfg(target_endian = "big")]
+  assert_eq!(bytes[bytes.len() - 1], 0xcd);
+}
+
+#[test]
+fn largest_ring_of_a_32_bit_target() {
+  // (MAX_SLOTS on 32 bit - 1) / 2 positions, fine everywhere
+  let size = ((1 << 15) - 1) / 2;
+  let (tx, rx) = spsc::channel(size, 0u32);
+  for i in 0..(size as u32 * 2) { tx.put(|v| *v = i); }
+  let got : Vec<(u64, u32)> = rx.try_iter_enumerated().collect();
+  assert_eq!(got.len(), size);
+  assert!(got.iter().all(|&(seqno, v)| seqno as u32 == v));
+}
+
+#[test]
+fn seqno_wraps_the_flag_bits() {
+  // more than 2^SEQ_BITS puts, the seqnos must stay in order
+  const ITEMS : u64 = 3 << flag::SEQ_BITS;
+  let (tx, rx) = spsc::channel(8, 0u64);
+  let t = thread::spawn(move || {
+    for i in 0..ITEMS { tx.put(|v| *v = i); }
+  });
+
+  let mut last = None;
+  while last != Some(ITEMS - 1) {
+    for (seqno, v) in rx.try_iter_enumerated() {
+      assert_eq!(seqno, v);
+      assert!(last.is_none_or(|l| l < seqno));
+      last = Some(seqno);
+    }
+  }
+  t.join().unwrap();
+}
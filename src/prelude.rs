@@ -0,0 +1,45 @@
+
+// the blessed api in one import, `use rpg::prelude::*;`
+//
+// the spsc channel and its halves, the errors the Result returning calls
+// give back and the traits storage, wait strategies and queue flavors
+// plug in through. the other flavors stay in their modules
+
+pub use spsc::{channel, channel_aligned, channel_pow2, channel_with_storage, Builder, Policy, Profile, Receiver, Sender};
+#[cfg(target_os = "linux")]
+pub use spsc::channel_eventfd;
+
+pub use merge::Gap;
+pub use simple::EmptyStorage;
+pub use spsc::{Poisoned, TryPutError};
+pub use wait::Canceled;
+#[cfg(any(unix, windows))]
+pub use ipc::{ChecksumError, HeaderError};
+
+pub use queue::RingQueue;
+pub use seq::Seqno;
+pub use storage::RingStorage;
+pub use wait::{CancelToken, Deadline, WaitStrategy};
+
+#[cfg(test)]
+mod tests {
+  use prelude::*;
+  use std::error::Error;
+  use std::thread;
+
+  // the usual round trip without reaching into a module
+  #[test]
+  fn covers_a_channel() {
+    let (tx, rx) : (Sender<u32>, Receiver<u32>) = Builder::new().capacity(4).overwrite(Policy::Block).build();
+    let t = thread::spawn(move || { for i in 0..10 { tx.put(|v| *v = i); } });
+    assert_eq!(rx.iter().sum::<u32>(), 45);
+    t.join().unwrap();
+    assert!(rx.checked_iter().is_ok());
+
+    let (tx, _rx) = Builder::new().capacity(1).overwrite(Policy::Block).build::<u32>();
+    tx.put(|v| *v = 1);
+    let err : Box<dyn Error> = Box::new(tx.try_put(|v| *v = 2, 0).unwrap_err());
+    assert_eq!(err.to_string(), TryPutError::Full.to_string());
+    assert_ne!(err.to_string(), TryPutError::Contended.to_string());
+  }
+}
@@ -0,0 +1,242 @@
+
+// topic based fan-out over spsc rings
+//
+// every subscription is a ring of its own that the Publisher fills with a
+// clone of each message published to its topic. the Publisher is the one
+// writer of all those rings, several publishing threads feed one through
+// e.g. mpsc::channel_combining(). subscriptions come and go at runtime:
+// Subscriptions handles add them from any thread (picked up with the next
+// publish), dropping a Subscriber removes it.
+//
+// what happens to a subscriber that falls behind is up to its Slow policy,
+// chosen per subscription, so one slow consumer need not cost the others
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use spsc::{self, Builder, Policy};
+
+// what publish() does for a subscriber whose ring is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slow {
+  // evict its oldest unread message, as spsc::channel() does
+  Overwrite,
+  // wait until it took something, holding up everyone after it
+  Block,
+  // skip the new message for it
+  DropNewest,
+  // end the subscription, its receiver sees the channel closed
+  Disconnect,
+}
+
+struct Subscription<T : Clone + Send> {
+  tx     : spsc::Sender<T>,
+  slow   : Slow,
+  missed : Arc<AtomicU64>,
+}
+
+struct Pending<T : Clone + Send> {
+  default : T,                          // for new rings
+  added   : Vec<(String, Subscription<T>)>,
+}
+
+struct Requests<T : Clone + Send> {
+  pending : Mutex<Pending<T>>,
+  any     : AtomicBool,                 // added is not empty
+}
+
+pub struct Publisher<T : Clone + Send> {
+  topics   : HashMap<String, Vec<Subscription<T>>>,
+  requests : Arc<Requests<T>>,
+}
+
+// subscribes from other threads, see Publisher::subscriptions()
+pub struct Subscriptions<T : Clone + Send> {
+  requests : Arc<Requests<T>>,
+}
+
+// the receiving end of one subscription
+pub struct Subscriber<T : Clone + Send> {
+  rx     : spsc::Receiver<T>,
+  topic  : String,
+  missed : Arc<AtomicU64>,
+}
+
+impl <T : Clone + Send> Requests<T> {
+  fn subscribe(&self, topic : &str, capacity : usize, slow : Slow) -> Subscriber<T> {
+    let policy = if slow == Slow::Block { Policy::Block } else { Policy::Overwrite };
+    let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+    let (tx, rx) = Builder::new().capacity(capacity).overwrite(policy).build_with(pending.default.clone());
+    let missed = Arc::new(AtomicU64::new(0));
+    pending.added.push((topic.to_string(), Subscription { tx, slow, missed : missed.clone() }));
+    self.any.store(true, Ordering::Release);
+    Subscriber { rx, topic : topic.to_string(), missed }
+  }
+}
+
+impl <T : Clone + Send> Publisher<T> {
+  // every ring slot starts as a clone of `default_value`
+  pub fn new(default_value : T) -> Publisher<T> {
+    Publisher {
+      topics   : HashMap::new(),
+      requests : Arc::new(Requests {
+        pending : Mutex::new(Pending { default : default_value, added : Vec::new() }),
+        any     : AtomicBool::new(false),
+      }),
+    }
+  }
+
+  // a handle for subscribing from other threads
+  pub fn subscriptions(&self) -> Subscriptions<T> {
+    Subscriptions { requests : self.requests.clone() }
+  }
+
+  // a subscription to `topic` with a ring of `capacity` messages
+  pub fn subscribe(&mut self, topic : &str, capacity : usize, slow : Slow) -> Subscriber<T> {
+    let sub = self.requests.subscribe(topic, capacity, slow);
+    self.adopt();
+    sub
+  }
+
+  // hands a clone of `msg` to every subscriber of `topic`, returns how
+  // many got it
+  pub fn publish(&mut self, topic : &str, msg : &T) -> usize {
+    self.adopt();
+    let subs = match self.topics.get_mut(topic) {
+      Some(subs) => subs,
+      None       => return 0,
+    };
+
+    let mut delivered = 0;
+    subs.retain(|sub| {
+      if sub.tx.is_closed() { return false; }
+      let full = sub.tx.is_full();
+      match sub.slow {
+        Slow::Overwrite => {
+          if sub.tx.put_replace(msg.clone()).is_some() { sub.missed.fetch_add(1, Ordering::Relaxed); }
+        },
+        Slow::Block => { sub.tx.put(|v| v.clone_from(msg)); },
+        Slow::DropNewest if full => {
+          sub.missed.fetch_add(1, Ordering::Relaxed);
+          return true;
+        },
+        Slow::Disconnect if full => return false,
+        Slow::DropNewest | Slow::Disconnect => { sub.tx.put(|v| v.clone_from(msg)); },
+      }
+      delivered += 1;
+      true
+    });
+    if subs.is_empty() { self.topics.remove(topic); }
+    delivered
+  }
+
+  // live subscriptions to `topic`
+  pub fn subscribers(&mut self, topic : &str) -> usize {
+    self.adopt();
+    self.topics.get(topic).map_or(0, |subs| subs.iter().filter(|sub| !sub.tx.is_closed()).count())
+  }
+
+  // takes over what Subscriptions handles added since the last call
+  fn adopt(&mut self) {
+    if !self.requests.any.swap(false, Ordering::Acquire) { return; }
+    let added : Vec<_> = {
+      let mut pending = self.requests.pending.lock().unwrap_or_else(|e| e.into_inner());
+      pending.added.drain(..).collect()
+    };
+    for (topic, sub) in added {
+      self.topics.entry(topic).or_default().push(sub);
+    }
+  }
+}
+
+impl <T : Clone + Send> Subscriptions<T> {
+  // see Publisher::subscribe(), the publisher takes it on with its next
+  // publish, nothing published before that reaches the subscriber
+  pub fn subscribe(&self, topic : &str, capacity : usize, slow : Slow) -> Subscriber<T> {
+    self.requests.subscribe(topic, capacity, slow)
+  }
+}
+
+impl <T : Clone + Send> Clone for Subscriptions<T> {
+  fn clone(&self) -> Subscriptions<T> {
+    Subscriptions { requests : self.requests.clone() }
+  }
+}
+
+impl <T : Clone + Send> Subscriber<T> {
+  pub fn topic(&self) -> &str {
+    &self.topic
+  }
+
+  // messages lost to the Slow policy, evicted or skipped
+  pub fn missed(&self) -> u64 {
+    self.missed.load(Ordering::Relaxed)
+  }
+}
+
+impl <T : Clone + Send> Deref for Subscriber<T> {
+  type Target = spsc::Receiver<T>;
+
+  fn deref(&self) -> &spsc::Receiver<T> {
+    &self.rx
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Publisher, Slow};
+  use std::thread;
+
+  #[test]
+  fn messages_go_to_their_topic() {
+    let mut p = Publisher::new(0i32);
+    let a1 = p.subscribe("a", 8, Slow::Overwrite);
+    let a2 = p.subscribe("a", 8, Slow::Overwrite);
+    let b  = p.subscribe("b", 8, Slow::Overwrite);
+    assert_eq!(p.publish("a", &1), 2);
+    assert_eq!(p.publish("b", &2), 1);
+    assert_eq!(p.publish("c", &3), 0);
+    assert_eq!(a1.try_iter().collect::<Vec<i32>>(), vec![1]);
+    assert_eq!(a2.try_iter().collect::<Vec<i32>>(), vec![1]);
+    assert_eq!((b.topic(), b.try_iter().collect::<Vec<i32>>()), ("b", vec![2]));
+
+    // dropping a subscriber unsubscribes it
+    drop(a1);
+    assert_eq!(p.subscribers("a"), 1);
+    assert_eq!(p.publish("a", &4), 1);
+    assert_eq!(a2.try_iter().collect::<Vec<i32>>(), vec![4]);
+  }
+
+  #[test]
+  fn slow_subscriber_policies() {
+    let mut p = Publisher::new(0i32);
+    let over = p.subscribe("t", 2, Slow::Overwrite);
+    let skip = p.subscribe("t", 2, Slow::DropNewest);
+    let gone = p.subscribe("t", 2, Slow::Disconnect);
+    for i in 0..3 { p.publish("t", &i); }
+    assert_eq!(p.subscribers("t"), 2);
+
+    assert_eq!((over.try_iter().collect::<Vec<i32>>(), over.missed()), (vec![1, 2], 1));
+    assert_eq!((skip.try_iter().collect::<Vec<i32>>(), skip.missed()), (vec![0, 1], 1));
+    assert!(gone.is_closed());
+    assert_eq!(gone.try_iter().collect::<Vec<i32>>(), vec![0, 1]);
+  }
+
+  #[test]
+  fn subscribe_from_another_thread() {
+    let mut p = Publisher::new(0u64);
+    let subs = p.subscriptions();
+    let t = thread::spawn(move || {
+      let sub = subs.subscribe("t", 4, Slow::Block);
+      sub.iter().collect::<Vec<u64>>()
+    });
+
+    // nothing reaches it before the publisher took the subscription on
+    while p.subscribers("t") == 0 { thread::yield_now(); }
+    for i in 0..100 { assert_eq!(p.publish("t", &i), 1); }
+    drop(p);
+    assert_eq!(t.join().unwrap(), (0..100).collect::<Vec<u64>>());
+  }
+}
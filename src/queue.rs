@@ -0,0 +1,54 @@
+
+// what every ring buffer flavor offers when one owner both puts and
+// takes, so code and benchmarks can be written once and get the
+// implementation as a type parameter
+//
+// push() never fails, a full ring evicts its oldest item. try_push()
+// hands the item back instead of evicting anything
+
+pub trait RingQueue<T> {
+  fn push(&mut self, item : T);
+  fn try_push(&mut self, item : T) -> Result<(), T>;
+
+  // hands every held item to `f`, oldest first, and forgets it.
+  // returns the number of items
+  fn drain<F : FnMut(T)>(&mut self, f : F) -> usize;
+
+  fn len(&self) -> usize;
+  fn capacity(&self) -> usize;
+
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RingQueue;
+  use simple;
+  use spsc;
+
+  fn fill_and_drain<Q : RingQueue<i32>>(mut q : Q) {
+    let cap = q.capacity();
+    assert!(q.is_empty());
+    for i in 0..cap as i32 { assert_eq!(q.try_push(i), Ok(())); }
+    assert_eq!(q.len(), cap);
+    assert_eq!(q.try_push(-1), Err(-1));
+
+    // evicts 0
+    q.push(cap as i32);
+    let mut seen = vec![];
+    assert_eq!(q.drain(|v| seen.push(v)), cap);
+    assert_eq!(seen, (1..cap as i32 + 1).collect::<Vec<i32>>());
+    assert!(q.is_empty());
+    assert_eq!(q.drain(|_| ()), 0);
+    assert_eq!(q.try_push(7), Ok(()));
+    assert_eq!(q.len(), 1);
+  }
+
+  #[test]
+  fn all_flavors_behave_the_same() {
+    fill_and_drain(simple::CircularBuffer::new(3, 0i32));
+    fill_and_drain(spsc::CircularBuffer::new(3, 0i32));
+  }
+}
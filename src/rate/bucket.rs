@@ -0,0 +1,88 @@
+
+// refills `rate` tokens per second up to `burst`, a permit costs a token
+
+use std::time::{Duration, Instant};
+
+use super::Limiter;
+
+pub struct TokenBucket {
+  rate   : f64,
+  burst  : f64,
+  tokens : f64,
+  last   : Instant,      // when tokens was brought up to date
+}
+
+impl TokenBucket {
+  // starts full, so the first `burst` permits come right away
+  pub fn new(rate : f64, burst : usize) -> TokenBucket {
+    if !(rate > 0.0 && rate.is_finite()) { panic!("rate must be positive, got {}", rate); }
+    if burst == 0 { panic!("burst cannot be zero"); }
+    TokenBucket {
+      rate,
+      burst  : burst as f64,
+      tokens : burst as f64,
+      last   : Instant::now(),
+    }
+  }
+
+  // the tokens there are at `now`, after refilling
+  pub fn available_at(&mut self, now : Instant) -> f64 {
+    if now > self.last {
+      let refill  = now.duration_since(self.last).as_secs_f64() * self.rate;
+      self.tokens = (self.tokens + refill).min(self.burst);
+      self.last   = now;
+    }
+    self.tokens
+  }
+}
+
+impl Limiter for TokenBucket {
+  fn try_acquire_at(&mut self, n : usize, now : Instant) -> Result<(), Duration> {
+    if n as f64 > self.burst { panic!("asking for {} permits, the bucket holds {}", n, self.burst); }
+
+    let tokens = self.available_at(now);
+    if tokens >= n as f64 {
+      self.tokens -= n as f64;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((n as f64 - tokens) / self.rate))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::TokenBucket;
+  use rate::Limiter;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn burst_then_rate() {
+    let start = Instant::now();
+    let mut b = TokenBucket::new(10.0, 5);
+    b.last = start;
+    let at = |ms| start + Duration::from_millis(ms);
+
+    assert_eq!(b.try_acquire_at(5, at(0)), Ok(()));
+    assert_eq!(b.try_acquire_at(1, at(0)), Err(Duration::from_millis(100)));
+    assert_eq!(b.try_acquire_at(1, at(100)), Ok(()));
+    assert_eq!(b.try_acquire_at(2, at(150)), Err(Duration::from_millis(150)));
+    // a long pause refills up to the burst only
+    assert_eq!(b.try_acquire_at(5, at(10_000)), Ok(()));
+    assert!(b.try_acquire_at(1, at(10_000)).is_err());
+  }
+
+  #[test]
+  fn acquire_waits() {
+    let mut b = TokenBucket::new(100.0, 1);
+    let started = Instant::now();
+    for _ in 0..4 { b.acquire(1); }
+    assert!(started.elapsed() >= Duration::from_millis(29));
+  }
+
+  #[test]
+  #[should_panic]
+  fn more_than_the_burst() {
+    TokenBucket::new(1.0, 2).try_acquire(3);
+  }
+}
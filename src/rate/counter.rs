@@ -0,0 +1,123 @@
+
+// events over the last `window`, counted in `buckets` slices of it. the
+// slices are kept in a ring, each holding how many events fell into it,
+// and the oldest one only counts with the part still inside the window,
+// so the count slides along smoothly instead of jumping by whole buckets
+
+use std::time::{Duration, Instant};
+
+use simple::CircularBuffer;
+
+#[derive(Clone, Copy)]
+struct Bucket {
+  index : u64,            // slices since origin
+  count : u64,
+}
+
+pub struct WindowCounter {
+  done    : CircularBuffer<Bucket>,       // the last slices before current
+  current : Bucket,
+  origin  : Instant,
+  width   : Duration,
+  window  : Duration,
+}
+
+impl WindowCounter {
+  pub fn new(window : Duration, buckets : usize) -> WindowCounter {
+    if buckets == 0 { panic!("buckets cannot be zero"); }
+    let width = window / buckets as u32;
+    if width.is_zero() { panic!("{:?} is too short for {} buckets", window, buckets); }
+    WindowCounter {
+      done    : CircularBuffer::new(buckets, Bucket { index : 0, count : 0 }),
+      current : Bucket { index : 0, count : 0 },
+      origin  : Instant::now(),
+      width,
+      window,
+    }
+  }
+
+  fn index(&self, at : Instant) -> u64 {
+    (at.saturating_duration_since(self.origin).as_nanos() / self.width.as_nanos()) as u64
+  }
+
+  pub fn record(&mut self, n : u64) {
+    self.record_at(n, Instant::now());
+  }
+
+  // `at` must not be before the previous event
+  pub fn record_at(&mut self, n : u64, at : Instant) {
+    let index = self.index(at);
+    if index != self.current.index {
+      let current = self.current;
+      if current.count > 0 { self.done.put(|b| *b = current); }
+      self.current = Bucket { index, count : 0 };
+    }
+    self.current.count += n;
+  }
+
+  // events in the whole window
+  pub fn count(&self) -> u64 {
+    self.count_over_at(self.window, Instant::now())
+  }
+
+  // events in the last `span`, at most the window
+  pub fn count_over(&self, span : Duration) -> u64 {
+    self.count_over_at(span, Instant::now())
+  }
+
+  pub fn count_over_at(&self, span : Duration, now : Instant) -> u64 {
+    let width = self.width.as_nanos() as f64;
+    let end   = now.saturating_duration_since(self.origin).as_nanos() as f64;
+    let start = end - span.min(self.window).as_nanos() as f64;
+
+    let current = if self.current.index <= self.index(now) { Some(self.current) } else { None };
+    let total : f64 = self.done.iter().chain(current).map(|b| {
+      let from = b.index as f64 * width;
+      let to   = (from + width).min(end);
+      if to <= start || to <= from { 0.0 }
+      else if from >= start { b.count as f64 }
+      // events are taken as spread evenly over their slice
+      else { b.count as f64 * (to - start) / (to - from) }
+    }).sum();
+    total.round() as u64
+  }
+
+  // events per second over the whole window
+  pub fn rate(&self) -> f64 {
+    self.count() as f64 / self.window.as_secs_f64()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::WindowCounter;
+  use std::time::Duration;
+
+  #[test]
+  fn slides_within_buckets() {
+    let mut c = WindowCounter::new(Duration::from_secs(1), 10);
+    let origin = c.origin;
+    let at = |ms| origin + Duration::from_millis(ms);
+
+    c.record_at(10, at(0));
+    c.record_at(10, at(50));
+    c.record_at(4, at(550));
+    assert_eq!(c.count_over_at(Duration::from_secs(1), at(600)), 24);
+    assert_eq!(c.count_over_at(Duration::from_millis(100), at(600)), 4);
+
+    // the first slice leaves the window bit by bit
+    assert_eq!(c.count_over_at(Duration::from_secs(1), at(1000)), 24);
+    assert_eq!(c.count_over_at(Duration::from_secs(1), at(1050)), 14);
+    assert_eq!(c.count_over_at(Duration::from_secs(1), at(1099)), 4);
+    assert_eq!(c.count_over_at(Duration::from_secs(1), at(1600)), 0);
+  }
+
+  #[test]
+  fn old_buckets_fall_off_the_ring() {
+    let mut c = WindowCounter::new(Duration::from_millis(100), 4);
+    let origin = c.origin;
+    let at = |ms| origin + Duration::from_millis(ms);
+    for i in 0..20 { c.record_at(1, at(i * 25)); }
+    assert_eq!(c.count_over_at(Duration::from_millis(100), at(490)), 4);
+  }
+}
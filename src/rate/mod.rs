@@ -0,0 +1,36 @@
+
+// limits on how often something may happen, e.g. a producer capping its
+// publish rate into a channel. both limiters hand out permits, the token
+// bucket smooths bursts out to a steady rate (what a leaky bucket used as
+// a meter does as well), the sliding window allows at most `limit`
+// permits in any `window` exactly, at the price of a timestamp per permit.
+// WindowCounter only counts what happened, with fixed memory
+
+mod bucket;
+mod counter;
+mod window;
+
+pub use self::bucket::TokenBucket;
+pub use self::counter::WindowCounter;
+pub use self::window::SlidingWindow;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub trait Limiter {
+  // takes `n` permits if they are available at `now`, otherwise takes
+  // nothing and tells how long until they would be. panics if `n` is
+  // more than the limiter ever holds at once
+  fn try_acquire_at(&mut self, n : usize, now : Instant) -> Result<(), Duration>;
+
+  fn try_acquire(&mut self, n : usize) -> bool {
+    self.try_acquire_at(n, Instant::now()).is_ok()
+  }
+
+  // sleeps until `n` permits are available and takes them
+  fn acquire(&mut self, n : usize) {
+    while let Err(wait) = self.try_acquire_at(n, Instant::now()) {
+      thread::sleep(wait);
+    }
+  }
+}
@@ -0,0 +1,70 @@
+
+// at most `limit` permits in any `window`, remembering when each of the
+// last `limit` permits was taken
+
+use std::time::{Duration, Instant};
+
+use super::Limiter;
+use simple::CircularBuffer;
+
+pub struct SlidingWindow {
+  taken  : CircularBuffer<Instant>,
+  window : Duration,
+}
+
+impl SlidingWindow {
+  pub fn new(limit : usize, window : Duration) -> SlidingWindow {
+    if limit == 0 { panic!("limit cannot be zero"); }
+    SlidingWindow {
+      taken : CircularBuffer::new(limit, Instant::now()),
+      window,
+    }
+  }
+
+  // when the permits still inside the window at `now` were taken, oldest first
+  fn inside(&self, now : Instant) -> impl Iterator<Item = Instant> + '_ {
+    let window = self.window;
+    self.taken.iter().filter(move |t| now.saturating_duration_since(*t) < window)
+  }
+}
+
+impl Limiter for SlidingWindow {
+  fn try_acquire_at(&mut self, n : usize, now : Instant) -> Result<(), Duration> {
+    let limit = self.taken.capacity();
+    if n > limit { panic!("asking for {} permits, the window allows {}", n, limit); }
+
+    let inside = self.inside(now).count();
+    if inside + n <= limit {
+      for _ in 0..n { self.taken.put(|t| *t = now); }
+      Ok(())
+    } else {
+      // the oldest ones have to leave the window first
+      let leaving = inside + n - limit;
+      let last    = self.inside(now).nth(leaving - 1).unwrap_or(now);
+      Err((last + self.window).saturating_duration_since(now))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::SlidingWindow;
+  use rate::Limiter;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn limit_per_window() {
+    let start = Instant::now();
+    let at = |ms| start + Duration::from_millis(ms);
+    let mut w = SlidingWindow::new(3, Duration::from_millis(100));
+
+    assert_eq!(w.try_acquire_at(2, at(0)), Ok(()));
+    assert_eq!(w.try_acquire_at(1, at(50)), Ok(()));
+    assert_eq!(w.try_acquire_at(1, at(60)), Err(Duration::from_millis(40)));
+    // both of the first ones have to go
+    assert_eq!(w.try_acquire_at(3, at(60)), Err(Duration::from_millis(90)));
+    assert_eq!(w.try_acquire_at(2, at(100)), Ok(()));
+    assert_eq!(w.try_acquire_at(1, at(149)), Err(Duration::from_millis(1)));
+    assert_eq!(w.try_acquire_at(1, at(150)), Ok(()));
+  }
+}
@@ -0,0 +1,307 @@
+
+// one pinned worker thread per core, talking over spsc rings
+//
+// every link of the topology is its own ring under Policy::Block, so a
+// message is never lost: send_to() waits while the ring to that core is
+// full, try_send_to() hands the message back instead. two cores sending
+// to each other with both rings full wait for each other forever, a
+// worker that may get into that uses try_send_to() and polls in between.
+//
+// a worker gets its Core and either polls it itself or hands it a
+// handler through run(), which polls until the runtime is stopped. on
+// linux worker i is pinned to cpu i modulo the cpus there are, elsewhere
+// pinning does nothing
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use spsc::{self, Policy};
+use wait;
+
+// which cores get a ring to which
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+  // core i sends to core i+1, the last one to core 0
+  Ring,
+  // every core to every other one
+  Mesh,
+  // core 0 to and from every other core, those have no other links
+  Star,
+}
+
+impl Topology {
+  // the (from, to) pairs with a ring among `cores` cores, no core links
+  // to itself
+  pub fn links(self, cores : usize) -> Vec<(usize, usize)> {
+    let all = (0..cores).flat_map(|from| (0..cores).map(move |to| (from, to)));
+    all.filter(|&(from, to)| from != to && match self {
+      Topology::Ring => to == (from + 1) % cores,
+      Topology::Mesh => true,
+      Topology::Star => from == 0 || to == 0,
+    }).collect()
+  }
+}
+
+pub struct Builder {
+  cores    : usize,
+  topology : Topology,
+  capacity : usize,
+  pin      : bool,
+}
+
+impl Default for Builder {
+  fn default() -> Builder {
+    Builder::new()
+  }
+}
+
+impl Builder {
+  // a core per cpu there is, in a mesh of 1024 item rings
+  pub fn new() -> Builder {
+    Builder {
+      cores    : thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+      topology : Topology::Mesh,
+      capacity : 1024,
+      pin      : true,
+    }
+  }
+
+  pub fn cores(mut self, cores : usize) -> Builder {
+    if cores == 0 { panic!("the number of cores cannot be zero"); }
+    self.cores = cores;
+    self
+  }
+
+  pub fn topology(mut self, topology : Topology) -> Builder {
+    self.topology = topology;
+    self
+  }
+
+  // of every ring
+  pub fn capacity(mut self, capacity : usize) -> Builder {
+    if capacity == 0 { panic!("capacity cannot be zero"); }
+    self.capacity = capacity;
+    self
+  }
+
+  pub fn pin(mut self, pin : bool) -> Builder {
+    self.pin = pin;
+    self
+  }
+
+  // starts worker(core) on a thread per core, what it returns comes back
+  // from Runtime::join()
+  pub fn spawn<M, R, F>(self, worker : F) -> Runtime<R>
+    where M : Clone + Send + 'static,
+          R : Send + 'static,
+          F : Fn(&mut Core<M>) -> R + Send + Sync + 'static
+  {
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut cores : Vec<Core<M>> = (0..self.cores).map(|id| Core {
+      id,
+      outbound : (0..self.cores).map(|_| None).collect(),
+      inbound  : vec![],
+      stop     : stop.clone(),
+    }).collect();
+    for (from, to) in self.topology.links(self.cores) {
+      let (tx, rx) = spsc::Builder::new().capacity(self.capacity).overwrite(Policy::Block).build_with(None);
+      cores[from].outbound[to] = Some(tx);
+      cores[to].inbound.push((from, rx));
+    }
+
+    let worker = Arc::new(worker);
+    let cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let pin = self.pin;
+    let threads = cores.into_iter().map(|mut core| {
+      let worker = worker.clone();
+      thread::Builder::new()
+        .name(format!("core-{}", core.id))
+        .spawn(move || {
+          if pin { affinity::pin(core.id % cpus); }
+          worker(&mut core)
+        })
+        .expect("cannot spawn a core worker")
+    }).collect();
+
+    Runtime { stop, threads }
+  }
+}
+
+pub struct Runtime<R> {
+  stop    : Arc<AtomicBool>,
+  threads : Vec<JoinHandle<R>>,
+}
+
+impl <R> Runtime<R> {
+  pub fn cores(&self) -> usize {
+    self.threads.len()
+  }
+
+  // makes run() return once it finds nothing more to poll, see
+  // Core::is_stopping()
+  pub fn stop(&self) {
+    self.stop.store(true, Ordering::SeqCst);
+  }
+
+  // stop() and what every worker returned, in core order
+  pub fn join(self) -> Vec<thread::Result<R>> {
+    self.stop();
+    self.threads.into_iter().map(|t| t.join()).collect()
+  }
+}
+
+// a worker's end of the rings
+pub struct Core<M : Clone + Send> {
+  id       : usize,
+  outbound : Vec<Option<spsc::Sender<Option<M>>>>,    // per target core
+  inbound  : Vec<(usize, spsc::Receiver<Option<M>>)>, // with the sending core
+  stop     : Arc<AtomicBool>,
+}
+
+impl <M : Clone + Send> Core<M> {
+  pub fn id(&self) -> usize {
+    self.id
+  }
+
+  // the cores send_to() can reach from here
+  pub fn links(&self) -> Vec<usize> {
+    (0..self.outbound.len()).filter(|&to| self.outbound[to].is_some()).collect()
+  }
+
+  fn link(&self, core : usize) -> &spsc::Sender<Option<M>> {
+    match self.outbound.get(core) {
+      Some(Some(tx)) => tx,
+      _              => panic!("core {} has no link to core {}", self.id, core),
+    }
+  }
+
+  // waits while the ring to `core` is full, panics if the topology has
+  // no such ring
+  pub fn send_to(&self, core : usize, msg : M) {
+    let mut slot = self.link(core).reserve();
+    *slot = Some(msg);
+    slot.commit();
+  }
+
+  // hands `msg` back if the ring to `core` is full
+  pub fn try_send_to(&self, core : usize, msg : M) -> Result<(), M> {
+    let tx = self.link(core);
+    if tx.is_full() { return Err(msg); }
+    let mut slot = tx.reserve();
+    *slot = Some(msg);
+    slot.commit();
+    Ok(())
+  }
+
+  // handler(from, msg) for everything waiting on the inbound rings right
+  // now, returns how many that were
+  pub fn poll<F : FnMut(usize, M)>(&self, handler : F) -> usize {
+    let mut handler = handler;
+    let mut count = 0;
+    for &(from, ref rx) in self.inbound.iter() {
+      for msg in rx.try_iter().flatten() {
+        handler(from, msg);
+        count += 1;
+      }
+    }
+    count
+  }
+
+  pub fn is_stopping(&self) -> bool {
+    self.stop.load(Ordering::SeqCst)
+  }
+
+  // the poll loop: handler(core, from, msg) for every message until the
+  // runtime is stopping and a poll found nothing. backs off while idle
+  pub fn run<F : FnMut(&Core<M>, usize, M)>(&self, handler : F) {
+    let mut handler = handler;
+    let mut idle = 0;
+    loop {
+      let stopping = self.is_stopping();
+      if self.poll(|from, msg| handler(self, from, msg)) > 0 {
+        idle = 0;
+      } else if stopping {
+        return;
+      } else {
+        wait::backoff(idle);
+        idle += 1;
+      }
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod affinity {
+  use std::mem;
+  use std::os::raw::c_int;
+
+  // glibc's cpu_set_t, 1024 cpus
+  type CpuSet = [u64; 16];
+
+  extern "C" {
+    fn sched_setaffinity(pid : c_int, size : usize, mask : *const CpuSet) -> c_int;
+  }
+
+  // the calling thread onto `cpu` only, false if the kernel refused
+  pub fn pin(cpu : usize) -> bool {
+    let mut set : CpuSet = [0; 16];
+    if cpu >= set.len() * 64 { return false; }
+    set[cpu / 64] |= 1 << (cpu % 64);
+    unsafe { sched_setaffinity(0, mem::size_of::<CpuSet>(), &set) == 0 }
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod affinity {
+  pub fn pin(_cpu : usize) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Builder, Topology};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn topologies() {
+    assert_eq!(Topology::Ring.links(3), vec![(0, 1), (1, 2), (2, 0)]);
+    assert_eq!(Topology::Star.links(3), vec![(0, 1), (0, 2), (1, 0), (2, 0)]);
+    assert_eq!(Topology::Mesh.links(3).len(), 6);
+    assert!(Topology::Ring.links(1).is_empty());
+  }
+
+  #[test]
+  fn mesh_reaches_everyone() {
+    let rt = Builder::new().cores(3).capacity(2).spawn(|core| {
+      for to in core.links() { core.send_to(to, core.id() * 10); }
+      let mut got = vec![];
+      while got.len() < 2 { core.poll(|from, msg| got.push((from, msg))); }
+      got.sort();
+      got
+    });
+    let got : Vec<Vec<(usize, usize)>> = rt.join().into_iter().map(|r| r.unwrap()).collect();
+    assert_eq!(got, vec![vec![(1, 10), (2, 20)], vec![(0, 0), (2, 20)], vec![(0, 0), (1, 10)]]);
+  }
+
+  #[test]
+  fn token_goes_round_the_ring() {
+    let last = Arc::new(AtomicUsize::new(0));
+    let rt = {
+      let last = last.clone();
+      Builder::new().cores(3).topology(Topology::Ring).spawn(move |core| {
+        let next = (core.id() + 1) % 3;
+        if core.id() == 0 { core.send_to(next, 1usize); }
+        core.run(|core, _, token| {
+          if token < 30 { core.send_to(next, token + 1); } else { last.store(core.id() + 100, Ordering::SeqCst); }
+        });
+      })
+    };
+    while last.load(Ordering::SeqCst) == 0 { thread::sleep(Duration::from_millis(1)); }
+    assert_eq!(rt.join().into_iter().filter(|r| r.is_ok()).count(), 3);
+    // token n sits on core n % 3
+    assert_eq!(last.load(Ordering::SeqCst), 100);
+  }
+}
@@ -0,0 +1,360 @@
+
+// unbounded MPMC queue of linked fixed size segments
+//
+// head and tail are positions that count slots, LAP of them per segment
+// of which the last one is never used: a position there means "moving to
+// the next segment", set by the thread that took the segment's last real
+// slot while it links (push) or enters (pop) the next one. every slot has
+// a state stamp, WRITE once its value is in, READ once it was taken, so a
+// pop that won its position waits for the push that won the same one.
+//
+// a segment is freed by the pop that took its last slot, unless a reader
+// of an earlier slot is still copying the value out. it then gets DESTROY
+// stamped into its slot and frees the segment itself when done. low bit
+// of the head: the tail is in a later segment, so pop() need not look at
+// it. the layout follows crossbeam's SegQueue
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize, Ordering};
+
+use storage::CachePadded;
+use wait;
+
+const WRITE    : usize = 1;
+const READ     : usize = 2;
+const DESTROY  : usize = 4;
+
+// slots per segment, spsc/sim.rs sizes its runs by it
+pub(crate) const LAP : usize = 32;
+
+const BLOCK_CAP : usize = LAP - 1;
+const SHIFT     : usize = 1;              // positions leave the low bit free
+const HAS_NEXT  : usize = 1;
+
+struct Slot<T> {
+  value : UnsafeCell<MaybeUninit<T>>,
+  state : AtomicUsize,
+}
+
+struct Block<T> {
+  next  : AtomicPtr<Block<T>>,
+  slots : [Slot<T>; BLOCK_CAP],
+}
+
+struct Position<T> {
+  index : AtomicUsize,
+  block : AtomicPtr<Block<T>>,
+}
+
+// the two ends are on cache lines of their own
+pub struct SegQueue<T : Send> {
+  head    : CachePadded<Position<T>>,
+  tail    : CachePadded<Position<T>>,
+  _values : PhantomData<T>,
+}
+
+unsafe impl<T : Send> Send for SegQueue<T> { }
+unsafe impl<T : Send> Sync for SegQueue<T> { }
+
+impl <T> Slot<T> {
+  fn wait_write(&self) {
+    let mut attempt = 0;
+    while self.state.load(Ordering::Acquire) & WRITE == 0 {
+      wait::backoff(attempt);
+      attempt += 1;
+    }
+  }
+}
+
+impl <T> Block<T> {
+  fn new() -> Box<Block<T>> {
+    Box::new(Block {
+      next  : AtomicPtr::new(ptr::null_mut()),
+      slots : [(); BLOCK_CAP].map(|_| Slot { value : UnsafeCell::new(MaybeUninit::uninit()), state : AtomicUsize::new(0) }),
+    })
+  }
+
+  fn wait_next(&self) -> *mut Block<T> {
+    let mut attempt = 0;
+    loop {
+      let next = self.next.load(Ordering::Acquire);
+      if !next.is_null() { return next; }
+      wait::backoff(attempt);
+      attempt += 1;
+    }
+  }
+
+  // frees the segment unless a reader of a slot from `start` on is still
+  // at it, that one carries on then. the last slot needs no stamp, its
+  // reader is the one that started this
+  unsafe fn destroy(this : *mut Block<T>, start : usize) {
+    for i in start..BLOCK_CAP - 1 {
+      let slot = &(*this).slots[i];
+      if slot.state.load(Ordering::Acquire) & READ == 0 && slot.state.fetch_or(DESTROY, Ordering::AcqRel) & READ == 0 {
+        return;
+      }
+    }
+    drop(Box::from_raw(this));
+  }
+}
+
+impl <T : Send> SegQueue<T> {
+  // the first segment comes with the first push
+  pub fn new() -> SegQueue<T> {
+    SegQueue {
+      head    : CachePadded::new(Position { index : AtomicUsize::new(0), block : AtomicPtr::new(ptr::null_mut()) }),
+      tail    : CachePadded::new(Position { index : AtomicUsize::new(0), block : AtomicPtr::new(ptr::null_mut()) }),
+      _values : PhantomData,
+    }
+  }
+
+  pub fn push(&self, value : T) {
+    let mut attempt = 0;
+    let mut tail  = self.tail.index.load(Ordering::Acquire);
+    let mut block = self.tail.block.load(Ordering::Acquire);
+    let mut next_block = None;
+
+    loop {
+      let offset = (tail >> SHIFT) % LAP;
+
+      // another push is linking in the next segment
+      if offset == BLOCK_CAP {
+        wait::backoff(attempt);
+        attempt += 1;
+        tail  = self.tail.index.load(Ordering::Acquire);
+        block = self.tail.block.load(Ordering::Acquire);
+        continue;
+      }
+
+      // allocated before taking the last slot, so the others wait less
+      if offset + 1 == BLOCK_CAP && next_block.is_none() {
+        next_block = Some(Block::<T>::new());
+      }
+
+      if block.is_null() {
+        let new = Box::into_raw(Block::<T>::new());
+        if self.tail.block.compare_exchange(block, new, Ordering::Release, Ordering::Relaxed).is_ok() {
+          self.head.block.store(new, Ordering::Release);
+          block = new;
+        } else {
+          next_block = Some(unsafe { Box::from_raw(new) });
+          tail  = self.tail.index.load(Ordering::Acquire);
+          block = self.tail.block.load(Ordering::Acquire);
+          continue;
+        }
+      }
+
+      let new_tail = tail + (1 << SHIFT);
+      match self.tail.index.compare_exchange_weak(tail, new_tail, Ordering::SeqCst, Ordering::Acquire) {
+        Ok(_) => unsafe {
+          if offset + 1 == BLOCK_CAP {
+            let next = Box::into_raw(next_block.expect("next segment allocated above"));
+            self.tail.block.store(next, Ordering::Release);
+            self.tail.index.store(new_tail.wrapping_add(1 << SHIFT), Ordering::Release);
+            (*block).next.store(next, Ordering::Release);
+          }
+          let slot = &(*block).slots[offset];
+          ptr::write(slot.value.get(), MaybeUninit::new(value));
+          slot.state.fetch_or(WRITE, Ordering::Release);
+          return;
+        },
+        Err(current) => {
+          tail  = current;
+          block = self.tail.block.load(Ordering::Acquire);
+          wait::backoff(attempt);
+          attempt += 1;
+        },
+      }
+    }
+  }
+
+  // the oldest value, None if the queue is empty
+  pub fn pop(&self) -> Option<T> {
+    let mut attempt = 0;
+    let mut head  = self.head.index.load(Ordering::Acquire);
+    let mut block = self.head.block.load(Ordering::Acquire);
+
+    loop {
+      let offset = (head >> SHIFT) % LAP;
+
+      // another pop is moving to the next segment
+      if offset == BLOCK_CAP {
+        wait::backoff(attempt);
+        attempt += 1;
+        head  = self.head.index.load(Ordering::Acquire);
+        block = self.head.block.load(Ordering::Acquire);
+        continue;
+      }
+
+      let mut new_head = head + (1 << SHIFT);
+      if new_head & HAS_NEXT == 0 {
+        atomic::fence(Ordering::SeqCst);
+        let tail = self.tail.index.load(Ordering::Relaxed);
+        if head >> SHIFT == tail >> SHIFT { return None; }
+        if (head >> SHIFT) / LAP != (tail >> SHIFT) / LAP { new_head |= HAS_NEXT; }
+      }
+
+      // the first push has not put its segment in yet
+      if block.is_null() {
+        wait::backoff(attempt);
+        attempt += 1;
+        head  = self.head.index.load(Ordering::Acquire);
+        block = self.head.block.load(Ordering::Acquire);
+        continue;
+      }
+
+      match self.head.index.compare_exchange_weak(head, new_head, Ordering::SeqCst, Ordering::Acquire) {
+        Ok(_) => unsafe {
+          if offset + 1 == BLOCK_CAP {
+            let next = (*block).wait_next();
+            let mut next_index = (new_head & !HAS_NEXT).wrapping_add(1 << SHIFT);
+            if !(*next).next.load(Ordering::Relaxed).is_null() { next_index |= HAS_NEXT; }
+            self.head.block.store(next, Ordering::Release);
+            self.head.index.store(next_index, Ordering::Release);
+          }
+
+          let slot = &(*block).slots[offset];
+          slot.wait_write();
+          let value = ptr::read(slot.value.get()).assume_init();
+          if offset + 1 == BLOCK_CAP {
+            Block::destroy(block, 0);
+          } else if slot.state.fetch_or(READ, Ordering::AcqRel) & DESTROY != 0 {
+            Block::destroy(block, offset + 1);
+          }
+          return Some(value);
+        },
+        Err(current) => {
+          head  = current;
+          block = self.head.block.load(Ordering::Acquire);
+          wait::backoff(attempt);
+          attempt += 1;
+        },
+      }
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    let head = self.head.index.load(Ordering::SeqCst);
+    let tail = self.tail.index.load(Ordering::SeqCst);
+    head >> SHIFT == tail >> SHIFT
+  }
+
+  // values in the queue, a snapshot of head and tail taken together
+  pub fn len(&self) -> usize {
+    loop {
+      let mut tail = self.tail.index.load(Ordering::SeqCst);
+      let mut head = self.head.index.load(Ordering::SeqCst);
+      if self.tail.index.load(Ordering::SeqCst) != tail { continue; }
+
+      tail >>= SHIFT;
+      head >>= SHIFT;
+      // a position on the unused last slot counts as the next segment
+      if tail % LAP == BLOCK_CAP { tail += 1; }
+      if head % LAP == BLOCK_CAP { head += 1; }
+      return (tail - head) - (tail / LAP - head / LAP);
+    }
+  }
+}
+
+impl <T : Send> Default for SegQueue<T> {
+  fn default() -> SegQueue<T> {
+    SegQueue::new()
+  }
+}
+
+impl <T : Send> Drop for SegQueue<T> {
+  fn drop(&mut self) {
+    let mut head  = *self.head.index.get_mut() >> SHIFT;
+    let tail      = *self.tail.index.get_mut() >> SHIFT;
+    let mut block = *self.head.block.get_mut();
+    unsafe {
+      while head != tail {
+        let offset = head % LAP;
+        if offset < BLOCK_CAP {
+          ptr::drop_in_place((*(*block).slots[offset].value.get()).as_mut_ptr());
+        } else {
+          let next = *(*block).next.get_mut();
+          drop(Box::from_raw(block));
+          block = next;
+        }
+        head += 1;
+      }
+      if !block.is_null() { drop(Box::from_raw(block)); }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SegQueue, LAP};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  #[test]
+  fn fifo_across_segments() {
+    let q = SegQueue::new();
+    assert_eq!((q.pop(), q.len()), (None, 0));
+    for i in 0..3 * LAP { q.push(i); }
+    assert_eq!(q.len(), 3 * LAP);
+    for i in 0..LAP + 5 { assert_eq!(q.pop(), Some(i)); }
+    assert_eq!(q.len(), 2 * LAP - 5);
+    q.push(1000);
+    let rest : Vec<usize> = (0..2 * LAP - 4).map(|_| q.pop().unwrap()).collect();
+    assert_eq!(&rest[..2 * LAP - 5], &(LAP + 5..3 * LAP).collect::<Vec<usize>>()[..]);
+    assert_eq!(rest.last(), Some(&1000));
+    assert!(q.is_empty() && q.pop().is_none());
+  }
+
+  #[test]
+  fn values_left_are_dropped() {
+    let value = Arc::new(());
+    {
+      let q = SegQueue::new();
+      for _ in 0..100 { q.push(value.clone()); }
+      for _ in 0..40 { q.pop(); }
+    }
+    assert_eq!(Arc::strong_count(&value), 1);
+  }
+
+  #[test]
+  fn many_producers_many_consumers() {
+    const THREADS : usize = 3;
+    const ITEMS   : usize = 30_000;
+    let q = Arc::new(SegQueue::new());
+    let taken : Arc<Vec<AtomicUsize>> = Arc::new((0..THREADS * ITEMS).map(|_| AtomicUsize::new(0)).collect());
+    let done = Arc::new(AtomicUsize::new(0));
+
+    let mut threads = vec![];
+    for p in 0..THREADS {
+      let q = q.clone();
+      threads.push(thread::spawn(move || {
+        for i in 0..ITEMS { q.push(p * ITEMS + i); }
+      }));
+    }
+    for _ in 0..THREADS {
+      let (q, taken, done) = (q.clone(), taken.clone(), done.clone());
+      threads.push(thread::spawn(move || {
+        // per producer order holds for any one consumer
+        let mut last = [None; THREADS];
+        while done.load(Ordering::Relaxed) < THREADS * ITEMS {
+          match q.pop() {
+            Some(v) => {
+              assert!(last[v / ITEMS].is_none_or(|l| l < v));
+              last[v / ITEMS] = Some(v);
+              taken[v].fetch_add(1, Ordering::Relaxed);
+              done.fetch_add(1, Ordering::Relaxed);
+            },
+            None => thread::yield_now(),
+          }
+        }
+      }));
+    }
+    for t in threads { t.join().unwrap(); }
+    assert!(taken.iter().all(|t| t.load(Ordering::Relaxed) == 1));
+    assert!(q.is_empty());
+  }
+}
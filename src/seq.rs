@@ -0,0 +1,66 @@
+
+// sequence numbers handed out by the rings
+//
+// always 64 bits wide, whatever the pointer width, so they practically
+// never wrap and can be used for gap detection and replay. the rings keep
+// narrower counters inside and widen them on the way out
+//
+// comparisons go through before() and distance(), serial number
+// arithmetic as in RFC 1982, so a counter wrapping around is a non-event
+// as long as the two numbers are less than half the range apart
+
+pub type Seqno = u64;
+
+// the counter widths the rings use
+pub trait Serial : Copy {
+  fn wrapping_diff(self, earlier : Self) -> Self;
+  fn is_ahead(diff : Self) -> bool;       // diff lies in the lower half
+  fn to_u64(self) -> u64;
+}
+
+impl Serial for u64 {
+  fn wrapping_diff(self, earlier : u64) -> u64 { self.wrapping_sub(earlier) }
+  fn is_ahead(diff : u64) -> bool { diff != 0 && diff < (1 << 63) }
+  fn to_u64(self) -> u64 { self }
+}
+
+impl Serial for usize {
+  fn wrapping_diff(self, earlier : usize) -> usize { self.wrapping_sub(earlier) }
+  fn is_ahead(diff : usize) -> bool { diff != 0 && diff <= usize::MAX / 2 }
+  fn to_u64(self) -> u64 { self as u64 }
+}
+
+// true if `a` comes strictly before `b`
+pub fn before<S : Serial>(a : S, b : S) -> bool {
+  S::is_ahead(b.wrapping_diff(a))
+}
+
+// how far `b` is ahead of `a`, 0 if it is not
+pub fn distance<S : Serial>(a : S, b : S) -> u64 {
+  if before(a, b) { b.wrapping_diff(a).to_u64() } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{before, distance};
+
+  #[test]
+  fn plain_numbers() {
+    assert!(before(1u64, 2));
+    assert!(!before(2u64, 1));
+    assert!(!before(3u64, 3));
+    assert_eq!(distance(3u64, 10), 7);
+    assert_eq!(distance(10u64, 3), 0);
+  }
+
+  #[test]
+  fn across_the_wrap() {
+    assert!(before(usize::MAX, 0));
+    assert!(before(usize::MAX - 5, 4));
+    assert!(!before(4, usize::MAX - 5));
+    assert_eq!(distance(usize::MAX - 1, 2usize), 4);
+    assert_eq!(distance(u64::MAX, 0u64), 1);
+    // half the range apart is where it stops working
+    assert!(!before(0u64, 1 << 63));
+  }
+}
@@ -0,0 +1,112 @@
+
+// one stream split over independent spsc rings by key
+//
+// send() hashes the key to pick a shard, so all items of a key go through
+// the same ring, in order, while the shards are consumed in parallel.
+// every shard has its own Receiver, spawn_consumers() puts a thread on
+// each. the hash is std's DefaultHasher with fixed keys, a key lands on
+// the same shard in every run
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use seq::Seqno;
+use spsc;
+
+pub struct Sender<T : Clone + Send> {
+  shards : Vec<spsc::Sender<T>>,
+}
+
+// `shards` rings of `capacity` items each, the receivers in shard order
+pub fn channel<T : Clone + Send + Default>(shards : usize, capacity : usize) -> (Sender<T>, Vec<spsc::Receiver<T>>) {
+  if shards == 0 { panic!("a sharded channel needs at least one shard"); }
+  let (txs, rxs) = (0..shards).map(|_| spsc::channel(capacity, T::default())).unzip();
+  (Sender { shards : txs }, rxs)
+}
+
+impl <T : Clone + Send> Sender<T> {
+  // puts `value` on the shard of `key`, returns its seqno there
+  pub fn send<K : Hash + ?Sized>(&self, key : &K, value : T) -> Seqno {
+    let mut slot = self.shards[self.shard_of(key)].reserve();
+    *slot = value;
+    slot.commit()
+  }
+
+  // which shard items with `key` go to
+  pub fn shard_of<K : Hash + ?Sized>(&self, key : &K) -> usize {
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    (h.finish() % self.shards.len() as u64) as usize
+  }
+
+  pub fn shards(&self) -> usize {
+    self.shards.len()
+  }
+
+  pub fn shard(&self, index : usize) -> &spsc::Sender<T> {
+    &self.shards[index]
+  }
+}
+
+// a thread per receiver calling handler(shard, item) for every item until
+// the sender is gone and its shard drained
+pub fn spawn_consumers<T, F>(receivers : Vec<spsc::Receiver<T>>, handler : F) -> Vec<JoinHandle<()>>
+  where T : Clone + Send + 'static,
+        F : Fn(usize, T) + Send + Sync + 'static
+{
+  let handler = Arc::new(handler);
+  receivers.into_iter().enumerate().map(|(shard, rx)| {
+    let handler = handler.clone();
+    thread::Builder::new()
+      .name(format!("shard-{}", shard))
+      .spawn(move || {
+        for item in rx.iter() { handler(shard, item); }
+      })
+      .expect("cannot spawn a shard consumer")
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{channel, spawn_consumers};
+  use std::sync::{Arc, Mutex};
+
+  #[test]
+  fn keys_stay_on_their_shard() {
+    let (tx, rxs) = channel::<u32>(4, 16);
+    assert_eq!(tx.shards(), 4);
+    let shard = tx.shard_of("alice");
+    assert_eq!(tx.shard_of("alice"), shard);
+    for i in 0..3 { tx.send("alice", i); }
+    for (i, rx) in rxs.iter().enumerate() {
+      let got = rx.try_iter().collect::<Vec<u32>>();
+      if i == shard { assert_eq!(got, vec![0, 1, 2]); } else { assert!(got.is_empty()); }
+    }
+  }
+
+  #[test]
+  fn per_key_order_with_a_consumer_per_shard() {
+    const KEYS  : u64 = 16;
+    const ITEMS : u64 = 2_000;
+    // room for everything, nothing gets overwritten
+    let (tx, rxs) = channel::<(u64, u64)>(3, (KEYS * ITEMS) as usize);
+    let seen = Arc::new(Mutex::new(vec![vec![]; KEYS as usize]));
+    let shards : Vec<usize> = (0..KEYS).map(|k| tx.shard_of(&k)).collect();
+    let s = seen.clone();
+    let threads = spawn_consumers(rxs, move |shard, (key, i)| {
+      assert_eq!(shards[key as usize], shard);
+      s.lock().unwrap()[key as usize].push(i);
+    });
+
+    for i in 0..ITEMS {
+      for k in 0..KEYS { tx.send(&k, (k, i)); }
+    }
+    drop(tx);
+    for t in threads { t.join().unwrap(); }
+    for items in seen.lock().unwrap().iter() {
+      assert_eq!(*items, (0..ITEMS).collect::<Vec<u64>>());
+    }
+  }
+}
@@ -1,10 +1,61 @@
 
-struct CircularBuffer<T : Copy> {
-  seqno  : usize,
-  data   : Vec<T>,
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Index;
+use queue::RingQueue;
+use seq::Seqno;
+use storage::{AlignedBuf, RingStorage};
+
+/// A plain single threaded ring that keeps the last `n` items.
+///
+/// ```
+/// let mut ring = rpg::simple::CircularBuffer::new(2, 0i32);
+/// ring.put(|v| *v = 1);
+/// ring.put(|v| *v = 2);
+/// ring.put(|v| *v = 3);
+/// assert_eq!(ring.len(), 2);
+/// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![2, 3]);
+/// ```
+///
+/// A clone is an independent copy of the items and the count of puts,
+/// e.g. to look at a window in the background while the original moves on.
+#[derive(Clone)]
+pub struct CircularBuffer<T : Clone, S : RingStorage<T> = AlignedBuf<T>> {
+  seqno  : Seqno,           // puts so far
+  held   : usize,           // items since the last clear(), at most the size
+  data   : S,
+  _ty    : PhantomData<T>,
+}
+
+/// A [`CircularBuffer`] taken apart by
+/// [`into_raw_parts()`](struct.CircularBuffer.html#method.into_raw_parts),
+/// laid out so it can be kept on the other side of an FFI boundary.
+#[repr(C)]
+pub struct RawParts<T> {
+  pub ptr      : *mut T,      // `capacity` initialized slots
+  pub capacity : usize,
+  pub align    : usize,       // of the allocation
+  pub seqno    : Seqno,       // puts so far
+  pub held     : usize,       // items since the last clear()
 }
 
-struct CircularBufferIterator<'a, T: 'a + Copy> {
+/// Returned when a [`CircularBuffer`] is made from an empty `Vec`, a
+/// ring needs at least one slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmptyStorage;
+
+impl fmt::Display for EmptyStorage {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("a ring cannot be made from empty storage")
+  }
+}
+
+impl Error for EmptyStorage { }
+
+/// The items of a [`CircularBuffer`], oldest first.
+pub struct CircularBufferIterator<'a, T: 'a + Clone> {
   slice  : &'a [T],
   start  : usize,
   end    : usize,
@@ -12,42 +63,137 @@ struct CircularBufferIterator<'a, T: 'a + Copy> {
   wrap   : bool,
 }
 
-impl <T : Copy> CircularBuffer<T> {
-  fn new(size : usize, default_value : T) -> CircularBuffer<T> {
+impl <T : Clone> CircularBuffer<T> {
+  /// A ring for `size` items, every slot starts as a clone of
+  /// `default_value`. Panics if `size` is zero.
+  pub fn new(size : usize, default_value : T) -> CircularBuffer<T> {
+    // make sure there is enough place and fill it with the
+    // default value, the first slot starts on a cache line
+    CircularBuffer::with_storage(AlignedBuf::new(size, default_value))
+  }
 
-    if size == 0 { panic!("size cannot be zero"); }
+  /// Takes the ring apart without dropping anything, the slots and the
+  /// cursor come back together with [`from_raw_parts()`](#method.from_raw_parts).
+  ///
+  /// ```
+  /// use rpg::simple::CircularBuffer;
+  ///
+  /// let mut ring = CircularBuffer::new(2, 0i32);
+  /// ring.put(|v| *v = 1);
+  /// let parts = ring.into_raw_parts();
+  /// let ring = unsafe { CircularBuffer::from_raw_parts(parts) };
+  /// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![1]);
+  /// ```
+  pub fn into_raw_parts(self) -> RawParts<T> {
+    let (seqno, held) = (self.seqno, self.held);
+    let (ptr, capacity, align) = self.data.into_raw_parts();
+    RawParts { ptr, capacity, align, seqno, held }
+  }
 
-    let mut ret = CircularBuffer {
+  /// Puts a ring taken apart by [`into_raw_parts()`](#method.into_raw_parts)
+  /// back together, it owns the slots again.
+  ///
+  /// # Safety
+  ///
+  /// `ptr`, `capacity` and `align` must be unchanged from a single
+  /// `into_raw_parts()` call on a ring of the same `T`, and no other ring
+  /// may be rebuilt from them. The slots may have been written in the
+  /// meantime, as long as every one still holds a valid `T`. The cursor
+  /// may be changed too, but `held` must not exceed `seqno` or `capacity`.
+  pub unsafe fn from_raw_parts(parts : RawParts<T>) -> CircularBuffer<T> {
+    if parts.held as Seqno > parts.seqno.min(parts.capacity as Seqno) {
+      panic!("{} items held after {} puts into {} slots", parts.held, parts.seqno, parts.capacity);
+    }
+    CircularBuffer {
+      seqno : parts.seqno,
+      held  : parts.held,
+      data  : AlignedBuf::from_raw_parts(parts.ptr, parts.capacity, parts.align),
+      _ty   : PhantomData,
+    }
+  }
+}
+
+impl <T : Clone, S : RingStorage<T>> CircularBuffer<T, S> {
+  /// A ring over caller provided slots, one item per slot.
+  pub fn with_storage(storage : S) -> CircularBuffer<T, S> {
+
+    if storage.slots().is_empty() { panic!("size cannot be zero"); }
+
+    CircularBuffer {
       seqno : 0,
-      data  : vec![],
-    };
+      held  : 0,
+      data  : storage,
+      _ty   : PhantomData,
+    }
+  }
 
-    // make sure there is enough place and fill it with the
-    // default value
-    ret.data.resize(size, default_value);
-    ret
+  fn min_pos(&self) -> Seqno {
+    self.seqno - self.held as Seqno
   }
 
-  fn min_pos(&self) -> usize {
-    if self.seqno < self.data.len() {
-      0
-    } else {
-      (self.seqno - self.data.len()) as usize
+  // the slot of the item put as `seqno`
+  fn slot_of(&self, seqno : Seqno) -> usize {
+    (seqno % self.data.slots().len() as Seqno) as usize
+  }
+}
+
+impl <T : Clone, const N : usize> CircularBuffer<T, [T; N]> {
+  /// A ring over the slots of an array, without allocating. It can be
+  /// made in a `const` context, so it can live in a `static`. Fails to
+  /// compile there, and panics elsewhere, if `N` is zero.
+  ///
+  /// ```
+  /// use std::sync::Mutex;
+  /// use rpg::simple::CircularBuffer;
+  ///
+  /// static LAST : Mutex<CircularBuffer<u32, [u32; 4]>> = Mutex::new(CircularBuffer::from_array([0; 4]));
+  ///
+  /// for code in 1..6 { LAST.lock().unwrap().put(|v| *v = code); }
+  /// assert_eq!(LAST.lock().unwrap().to_vec(), vec![2, 3, 4, 5]);
+  /// ```
+  pub const fn from_array(slots : [T; N]) -> CircularBuffer<T, [T; N]> {
+    if N == 0 { panic!("size cannot be zero"); }
+    CircularBuffer {
+      seqno : 0,
+      held  : 0,
+      data  : slots,
+      _ty   : PhantomData,
     }
   }
+}
 
-  fn iter(&self) -> CircularBufferIterator<T> {
+impl <T : Clone, S : RingStorage<T>> CircularBuffer<T, S> {
+  /// How many items the ring holds, at most its capacity.
+  pub fn len(&self) -> usize {
+    self.held
+  }
 
-    let min  = self.min_pos();
-    let max  = self.seqno;
-    let sz   = self.data.len();
+  /// True before the first put and after [`clear()`](#method.clear).
+  pub fn is_empty(&self) -> bool {
+    self.held == 0
+  }
 
-    let min_pos  = min % sz;
-    let max_pos  = max % sz;
+  /// Forgets every item, the positions keep counting.
+  pub fn clear(&mut self) {
+    self.held = 0;
+  }
+
+  /// The number of slots.
+  pub fn capacity(&self) -> usize {
+    self.data.slots().len()
+  }
 
-    if self.seqno == 0 { // no data
+  /// The items the ring holds, oldest first.
+  pub fn iter(&self) -> CircularBufferIterator<'_, T> {
+
+    let min_pos  = self.slot_of(self.min_pos());
+    let max_pos  = self.slot_of(self.seqno);
+    let data = self.data.slots();
+    let sz   = data.len();
+
+    if self.held == 0 { // no data
       CircularBufferIterator {
-        slice  : self.data.as_slice(),
+        slice  : data,
         start  : 0,
         end    : 0,
         pos    : 1,
@@ -56,7 +202,7 @@ impl <T : Copy> CircularBuffer<T> {
     }
     else if min_pos < max_pos { // no wrap over
       CircularBufferIterator {
-        slice  : self.data.as_slice(),
+        slice  : data,
         start  : min_pos,
         end    : max_pos,
         pos    : min_pos,
@@ -64,23 +210,112 @@ impl <T : Copy> CircularBuffer<T> {
       }
     } else {
       CircularBufferIterator {
-        slice  : self.data.as_slice(),
+        slice  : data,
         start  : max_pos,
         end    : sz,
-        pos    : max_pos,
+        pos    : min_pos,
         wrap   : (max_pos != 0),
       }
     }
   }
 
-  fn put<F>(&mut self, setter: F) -> usize
+  /// [`iter()`](#method.iter) with the seqno of every item, the first
+  /// put is 0.
+  pub fn iter_enumerated(&self) -> impl Iterator<Item = (Seqno, T)> + '_ {
+    (self.min_pos()..).zip(self.iter())
+  }
+
+  /// Applies `f` to every item the ring holds, oldest first. Goes over
+  /// the two slices on either side of the wrap point, so it compiles to
+  /// plain loops without per-item index math.
+  ///
+  /// ```
+  /// let mut window = rpg::simple::CircularBuffer::new(3, 0.0f32);
+  /// for s in &[2.0, 4.0, 8.0, 6.0] { window.put(|v| *v = *s); }
+  /// let peak = window.iter().fold(0.0, f32::max);
+  /// window.map_in_place(|v| *v /= peak);
+  /// assert_eq!(window.to_vec(), vec![0.5, 1.0, 0.75]);
+  /// ```
+  pub fn map_in_place<F : FnMut(&mut T)>(&mut self, f : F) {
+    let mut f = f;
+    let (a, b) = self.as_mut_slices();
+    for v in a.iter_mut() { f(v); }
+    for v in b.iter_mut() { f(v); }
+  }
+
+  /// True if the ring holds an item equal to `item`.
+  pub fn contains(&self, item : &T) -> bool
+    where T : PartialEq
+  {
+    let (a, b) = self.as_slices();
+    a.contains(item) || b.contains(item)
+  }
+
+  /// How far back from the newest item the newest one matching `pred`
+  /// is, as taken by [`nth_back()`](#method.nth_back) and indexing.
+  ///
+  /// ```
+  /// let mut ring = rpg::simple::CircularBuffer::new(4, 0i32);
+  /// for i in &[3, 8, 5, 8, 1] { ring.put(|v| *v = *i); }
+  /// assert_eq!(ring.position(|v| *v == 8), Some(1));
+  /// assert_eq!(ring.position(|v| *v == 3), None);
+  /// assert!(ring.contains(&5));
+  /// ```
+  pub fn position<P : FnMut(&T) -> bool>(&self, pred : P) -> Option<usize> {
+    let (a, b) = self.as_slices();
+    b.iter().rev().chain(a.iter().rev()).position(pred)
+  }
+
+  /// Keeps only the items `keep` holds for, in order. `keep` sees them
+  /// oldest first, like `Vec::retain()`. The kept ones close up towards
+  /// the newest item, so the positions of older ones change while the
+  /// count of puts does not. Dropped items stay in their slots until they
+  /// are overwritten, as after [`clear()`](#method.clear).
+  ///
+  /// ```
+  /// let mut ring = rpg::simple::CircularBuffer::new(4, 0i32);
+  /// for i in 1..6 { ring.put(|v| *v = i); }
+  /// ring.retain(|v| v % 2 == 0);
+  /// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![2, 4]);
+  /// ```
+  pub fn retain<F : FnMut(&T) -> bool>(&mut self, keep : F) {
+    let mut keep = keep;
+    let kept : Vec<bool> = self.iter_mut().map(|v| keep(v)).collect();
+    let newest = self.seqno;
+
+    let mut held = 0;
+    for (i, _) in kept.iter().enumerate().rev().filter(|&(_, k)| *k) {
+      let from = self.slot_of(newest - (kept.len() - i) as Seqno);
+      let to   = self.slot_of(newest - 1 - held as Seqno);
+      self.data.slots_mut().swap(from, to);
+      held += 1;
+    }
+    self.held = held;
+  }
+
+  /// The items the ring holds, oldest first, to change them in place.
+  ///
+  /// ```
+  /// let mut window = rpg::simple::CircularBuffer::new(3, 0.0f64);
+  /// for s in &[1.0, 2.0, 4.0] { window.put(|v| *v = *s); }
+  /// for v in window.iter_mut() { *v *= 0.5; }
+  /// assert_eq!(window.iter().collect::<Vec<f64>>(), vec![0.5, 1.0, 2.0]);
+  /// ```
+  pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> + '_ {
+    let (a, b) = self.as_mut_slices();
+    a.iter_mut().chain(b.iter_mut())
+  }
+
+  /// Fills the next slot in place with `setter`, overwriting the oldest
+  /// item once the ring is full. Returns the number of puts so far.
+  pub fn put<F>(&mut self, setter: F) -> Seqno
     where F : FnMut(&mut T)
   {
     // calculate where to put the data
-    let pos = self.seqno % self.data.len();
+    let pos = self.slot_of(self.seqno);
 
     // get a reference to the data
-    let mut opt : Option<&mut T> = self.data.get_mut(pos);
+    let mut opt : Option<&mut T> = self.data.slots_mut().get_mut(pos);
 
     let mut setter = setter;
 
@@ -92,11 +327,178 @@ impl <T : Copy> CircularBuffer<T> {
 
     // increase sequence number
     self.seqno += 1;
+    self.held   = (self.held + 1).min(self.data.slots().len());
+    self.seqno
+  }
+
+  /// The items the ring holds in a `Vec`, oldest first.
+  pub fn to_vec(&self) -> Vec<T> {
+    let (a, b) = self.as_slices();
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    out.extend_from_slice(a);
+    out.extend_from_slice(b);
+    out
+  }
+
+  /// The item `n` puts back from the newest one, which is `n == 0`.
+  /// None if the ring holds no more than `n` items.
+  pub fn nth_back(&self, n : usize) -> Option<&T> {
+    if n >= self.held { return None; }
+    Some(&self.data.slots()[self.slot_of(self.seqno - 1 - n as Seqno)])
+  }
+
+  /// [`to_vec()`](#method.to_vec) of a ring that is not needed any more.
+  ///
+  /// ```
+  /// let mut ring = rpg::simple::CircularBuffer::new(3, 0.0f32);
+  /// for s in &[0.5, 0.25, 0.75, 1.0] { ring.put(|v| *v = *s); }
+  /// let samples : Vec<f32> = ring.into();
+  /// assert_eq!(samples, vec![0.25, 0.75, 1.0]);
+  /// ```
+  pub fn into_vec(self) -> Vec<T> {
+    self.to_vec()
+  }
+
+  // the logical contents oldest first, split at the wrap point
+  fn as_slices(&self) -> (&[T], &[T]) {
+    let start = self.slot_of(self.min_pos());
+    let data  = self.data.slots();
+    let sz    = data.len();
+
+    if start + self.held <= sz {
+      (&data[start..start+self.held], &[])
+    } else {
+      (&data[start..], &data[..start+self.held-sz])
+    }
+  }
+
+  fn as_mut_slices(&mut self) -> (&mut [T], &mut [T]) {
+    let (start, held) = (self.slot_of(self.min_pos()), self.held);
+    let data  = self.data.slots_mut();
+    let sz    = data.len();
+
+    if start + held <= sz {
+      (&mut data[start..start+held], &mut [])
+    } else {
+      let (wrapped, tail) = data.split_at_mut(start);
+      (tail, &mut wrapped[..start+held-sz])
+    }
+  }
+}
+
+/// Takes the `Vec` over as the ring's slots without copying, the ring
+/// starts full with the first item as the oldest.
+///
+/// ```
+/// use std::convert::TryFrom;
+/// use rpg::simple::CircularBuffer;
+///
+/// let mut window = CircularBuffer::try_from(vec![1, 2, 3]).unwrap();
+/// window.put(|v| *v = 4);
+/// assert_eq!(window.iter().collect::<Vec<i32>>(), vec![2, 3, 4]);
+/// assert!(CircularBuffer::try_from(Vec::<i32>::new()).is_err());
+/// ```
+impl <T : Clone> TryFrom<Vec<T>> for CircularBuffer<T, Vec<T>> {
+  type Error = EmptyStorage;
+
+  fn try_from(items : Vec<T>) -> Result<CircularBuffer<T, Vec<T>>, EmptyStorage> {
+    if items.is_empty() { return Err(EmptyStorage); }
+    Ok(CircularBuffer {
+      seqno : items.len() as Seqno,
+      held  : items.len(),
+      data  : items,
+      _ty   : PhantomData,
+    })
+  }
+}
+
+/// Indexes back from the newest item like [`nth_back()`](struct.CircularBuffer.html#method.nth_back),
+/// panicking where that returns None.
+///
+/// ```
+/// let mut x = rpg::simple::CircularBuffer::new(4, 0i32);
+/// for i in 1..6 { x.put(|v| *v = i); }
+/// // y[n] = x[n] - x[n-1]
+/// assert_eq!(x[0] - x[1], 1);
+/// assert_eq!(x[3], 2);
+/// ```
+impl <T : Clone, S : RingStorage<T>> Index<usize> for CircularBuffer<T, S> {
+  type Output = T;
+
+  fn index(&self, n : usize) -> &T {
+    match self.nth_back(n) {
+      Some(v) => v,
+      None    => panic!("index {} is out of range for {} items", n, self.held),
+    }
+  }
+}
+
+impl <T : Clone, S : RingStorage<T>> From<CircularBuffer<T, S>> for Vec<T> {
+  fn from(ring : CircularBuffer<T, S>) -> Vec<T> {
+    ring.into_vec()
+  }
+}
+
+// the bulk paths are plain memcpys, so they need Copy
+impl <T : Copy, S : RingStorage<T>> CircularBuffer<T, S> {
+
+  /// Bulk version of [`put()`](#method.put), copies the whole slice with
+  /// at most two memcpys. Items that would be overwritten anyway are skipped.
+  pub fn put_slice(&mut self, items : &[T]) -> Seqno {
+    let sz    = self.data.slots().len();
+    let skip  = items.len().saturating_sub(sz);
+    let src   = &items[skip..];
+    let pos   = self.slot_of(self.seqno + skip as Seqno);
+    let first = src.len().min(sz - pos);
+
+    {
+      let data = self.data.slots_mut();
+      data[pos..pos+first].copy_from_slice(&src[..first]);
+      data[..src.len()-first].copy_from_slice(&src[first..]);
+    }
+
+    self.seqno += items.len() as Seqno;
+    self.held   = (self.held + items.len()).min(sz);
     self.seqno
   }
+
+  /// Copies the oldest items into `out`, returns how many were copied.
+  pub fn read_into(&self, out : &mut [T]) -> usize {
+    let (a, b) = self.as_slices();
+    let first  = a.len().min(out.len());
+    let second = b.len().min(out.len() - first);
+
+    out[..first].copy_from_slice(&a[..first]);
+    out[first..first+second].copy_from_slice(&b[..second]);
+    first + second
+  }
 }
 
-impl <'_, T: '_ + Copy> Iterator for CircularBufferIterator<'_, T> {
+impl <T : Clone, S : RingStorage<T>> RingQueue<T> for CircularBuffer<T, S> {
+  fn push(&mut self, item : T) {
+    let mut item = Some(item);
+    self.put(|v| if let Some(n) = item.take() { *v = n; });
+  }
+
+  fn try_push(&mut self, item : T) -> Result<(), T> {
+    if self.held == self.capacity() { return Err(item); }
+    self.push(item);
+    Ok(())
+  }
+
+  fn drain<F : FnMut(T)>(&mut self, f : F) -> usize {
+    let mut f = f;
+    let count = self.held;
+    for v in self.iter() { f(v); }
+    self.clear();
+    count
+  }
+
+  fn len(&self) -> usize { self.held }
+  fn capacity(&self) -> usize { self.data.slots().len() }
+}
+
+impl <'a, T: 'a + Clone> Iterator for CircularBufferIterator<'a, T> {
   type Item = T;
 
   fn next(&mut self) -> Option<T> {
@@ -105,51 +507,39 @@ impl <'_, T: '_ + Copy> Iterator for CircularBufferIterator<'_, T> {
         self.pos    = 1;
         self.end    = self.start;
         self.wrap   = false;
-        Some(self.slice[0])
+        Some(self.slice[0].clone())
       } else {
         None
       }
     } else {
       let at     = self.pos;
       self.pos  += 1;
-      Some(self.slice[at])
+      Some(self.slice[at].clone())
     }
   }
 }
 
-pub fn tests() {
-  let mut x = CircularBuffer::new(2, 0 as i32);
-  x.put(|v| *v = 1);
-  let mut y : i32 = 2;
-  x.put(|v| { *v = y; y += 1; });
-  x.put(|v| { *v = y; y += 1; });
-
-  let it = x.iter();
-  for i in it {
-    println!("CB: {}", i);
-  }
-}
-
 #[cfg(test)]
 mod tests {
-  use super::CircularBuffer;
+  use super::{CircularBuffer, EmptyStorage};
+  use std::convert::TryFrom;
 
   #[test]
   #[should_panic]
   fn create_zero_sized() {
-    let _x = CircularBuffer::new(0, 0 as i32);
+    let _x = CircularBuffer::new(0, 0i32);
   }
 
   #[test]
   fn empty_buffer() {
-    let x = CircularBuffer::new(1, 0 as i32);
+    let x = CircularBuffer::new(1, 0i32);
     let count = x.iter().count();
     assert_eq!(count, 0);
   }
 
   #[test]
   fn overload_buffer() {
-    let mut x = CircularBuffer::new(2, 0 as i32);
+    let mut x = CircularBuffer::new(2, 0i32);
     x.put(|v| *v = 1);
     x.put(|v| *v = 2);
     x.put(|v| *v = 3);
@@ -160,20 +550,155 @@ mod tests {
 
   #[test]
   fn sum_available() {
-    let mut x = CircularBuffer::new(4, 0 as i32);
+    let mut x = CircularBuffer::new(4, 0i32);
     x.put(|v| *v = 2);
     x.put(|v| *v = 4);
     x.put(|v| *v = 6);
     x.put(|v| *v = 8);
     x.put(|v| *v = 10);
     assert_eq!(x.iter().count(), 4);
-    let sum = x.iter().take(3).fold(0, |acc, num| acc + num);
+    let sum = x.iter().take(3).sum::<i32>();
     assert_eq!(sum, 18);
   }
 
+  #[test]
+  fn boxed_storage() {
+    let mut x = CircularBuffer::with_storage(vec![0i32; 3].into_boxed_slice());
+    x.put(|v| *v = 1);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 3);
+    x.put(|v| *v = 4);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn seeded_from_vec() {
+    let items = vec![1i32, 2, 3];
+    let at = items.as_ptr();
+    let mut x = CircularBuffer::try_from(items).unwrap();
+    assert_eq!((x.len(), x.capacity()), (3, 3));
+    // the vec's own allocation
+    assert_eq!(x.as_slices().0.as_ptr(), at);
+    assert_eq!(x.put(|v| *v = 4), 4);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![2, 3, 4]);
+    assert_eq!(CircularBuffer::try_from(Vec::<i32>::new()).err(), Some(EmptyStorage));
+  }
+
+  #[test]
+  fn to_vec_unwraps() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    assert!(x.to_vec().is_empty());
+    for i in 0..5 { x.put(|v| *v = i); }
+    assert_eq!(x.to_vec(), vec![2, 3, 4]);
+    assert_eq!(Vec::from(x), vec![2, 3, 4]);
+  }
+
+  #[test]
+  fn indexes_from_the_newest() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    assert_eq!(x.nth_back(0), None);
+    for i in 0..5 { x.put(|v| *v = i); }
+    assert_eq!((x[0], x[1], x[2]), (4, 3, 2));
+    assert_eq!(x.nth_back(3), None);
+    x.clear();
+    x.put(|v| *v = 7);
+    assert_eq!((x.nth_back(0), x.nth_back(1)), (Some(&7), None));
+  }
+
+  #[test]
+  #[should_panic(expected = "index 1 is out of range for 1 items")]
+  fn index_past_the_oldest() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    x.put(|v| *v = 1);
+    let _ = x[1];
+  }
+
+  #[test]
+  fn iter_mut_across_the_wrap() {
+    let mut x = CircularBuffer::new(4, 0i32);
+    for i in 0..6 { x.put(|v| *v = i); }
+    for v in x.iter_mut() { *v *= 10; }
+    assert_eq!(x.to_vec(), vec![20, 30, 40, 50]);
+    x.clear();
+    assert_eq!(x.iter_mut().count(), 0);
+  }
+
+  #[test]
+  fn retain_closes_up() {
+    let mut x = CircularBuffer::new(5, 0i32);
+    for i in 0..8 { x.put(|v| *v = i); }
+    let mut seen = vec![];
+    x.retain(|&v| { seen.push(v); v != 4 && v != 6 });
+    assert_eq!(seen, vec![3, 4, 5, 6, 7]);
+    assert_eq!(x.iter_enumerated().collect::<Vec<_>>(), vec![(5, 3), (6, 5), (7, 7)]);
+    x.put(|v| *v = 8);
+    assert_eq!(x.to_vec(), vec![3, 5, 7, 8]);
+    x.retain(|_| false);
+    assert!(x.is_empty());
+  }
+
+  #[test]
+  fn searches_across_the_wrap() {
+    let mut x = CircularBuffer::new(4, 0i32);
+    assert!(!x.contains(&0));
+    for i in 0..6 { x.put(|v| *v = i); }
+    assert!(x.contains(&2) && x.contains(&5) && !x.contains(&1));
+    assert_eq!(x.position(|&v| v == 5), Some(0));
+    assert_eq!(x.position(|&v| v < 4), Some(2));
+    assert_eq!(x[x.position(|&v| v == 2).unwrap()], 2);
+  }
+
+  #[test]
+  fn map_in_place_in_order() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    for i in 0..5 { x.put(|v| *v = i); }
+    let mut n = 0;
+    x.map_in_place(|v| { *v = *v * 100 + n; n += 1; });
+    assert_eq!(x.to_vec(), vec![200, 301, 402]);
+  }
+
+  #[test]
+  fn clones_are_independent() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    for i in 0..4 { x.put(|v| *v = i); }
+    let copy = x.clone();
+    assert_eq!(x.put(|v| *v = 9), 5);
+    assert_eq!(copy.iter_enumerated().collect::<Vec<_>>(), vec![(1, 1), (2, 2), (3, 3)]);
+    assert_eq!(x.to_vec(), vec![2, 3, 9]);
+    assert_eq!(copy.data.align(), x.data.align());
+  }
+
+  #[test]
+  fn put_slice_wraps() {
+    let mut x = CircularBuffer::new(4, 0i32);
+    x.put(|v| *v = 1);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 3);
+    assert_eq!(x.put_slice(&[4, 5, 6]), 6);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![3, 4, 5, 6]);
+    assert_eq!(x.put_slice(&[7, 8, 9, 10, 11, 12]), 12);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![9, 10, 11, 12]);
+  }
+
+  #[test]
+  fn read_into_matches_iter() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    let mut out = [0i32; 5];
+    assert_eq!(x.read_into(&mut out), 0);
+    x.put_slice(&[1, 2]);
+    assert_eq!(x.read_into(&mut out), 2);
+    assert_eq!(&out[..2], &[1, 2]);
+    x.put_slice(&[3, 4]);
+    assert_eq!(x.read_into(&mut out), 3);
+    assert_eq!(&out[..3], &[2, 3, 4]);
+    let mut short = [0i32; 2];
+    assert_eq!(x.read_into(&mut short), 2);
+    assert_eq!(short, [2, 3]);
+  }
+
   #[test]
   fn can_put_with_env() {
-    let mut x = CircularBuffer::new(1, 0 as i32);
+    let mut x = CircularBuffer::new(1, 0i32);
     let mut y = 0;
     {
       let my_fn = |v : &mut i32| {
@@ -189,4 +714,74 @@ mod tests {
       //x.put(&my_fn);
     }
   }
+
+  #[test]
+  fn drops_overwritten_items() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use storage::Live;
+
+    let count = Arc::new(AtomicUsize::new(0));
+    {
+      let mut x = CircularBuffer::new(3, Live::new(&count));
+      for _ in 0..10 {
+        x.put(|v| *v = Live::new(&count));
+      }
+      assert_eq!(count.load(Ordering::SeqCst), 3);
+      assert_eq!(x.iter().count(), 3);
+      assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn cleared_buffer_wraps() {
+    let mut x = CircularBuffer::new(4, 0i32);
+    x.put_slice(&[1, 2, 3]);
+    x.clear();
+    assert_eq!(x.iter().count(), 0);
+    x.put_slice(&[4, 5, 6]);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![4, 5, 6]);
+    let mut out = [0i32; 4];
+    assert_eq!(x.read_into(&mut out), 3);
+    assert_eq!(&out[..3], &[4, 5, 6]);
+    assert_eq!(x.iter_enumerated().next(), Some((3, 4)));
+  }
+
+  #[test]
+  fn raw_parts_round_trip() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use storage::Live;
+
+    let mut x = CircularBuffer::new(3, 0i32);
+    x.put_slice(&[1, 2, 3, 4]);
+    let parts = x.into_raw_parts();
+    assert_eq!((parts.capacity, parts.seqno, parts.held), (3, 4, 3));
+    // the embedder writes the next slot itself
+    unsafe { *parts.ptr.add((parts.seqno % parts.capacity as u64) as usize) = 5; }
+    let parts = super::RawParts { seqno : 5, ..parts };
+    let mut x = unsafe { CircularBuffer::from_raw_parts(parts) };
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![3, 4, 5]);
+    x.put(|v| *v = 6);
+    assert_eq!(x.iter_enumerated().next(), Some((3, 4)));
+
+    // nothing is dropped while apart, everything once rebuilt
+    let count = Arc::new(AtomicUsize::new(0));
+    let x = CircularBuffer::new(4, Live::new(&count));
+    let parts = x.into_raw_parts();
+    assert_eq!(count.load(Ordering::SeqCst), 4);
+    drop(unsafe { CircularBuffer::from_raw_parts(parts) });
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn enumerated_positions() {
+    let mut x = CircularBuffer::new(3, 0i32);
+    assert_eq!(x.iter_enumerated().count(), 0);
+    x.put_slice(&[1, 2]);
+    assert_eq!(x.iter_enumerated().collect::<Vec<(u64, i32)>>(), vec![(0, 1), (1, 2)]);
+    x.put_slice(&[3, 4, 5]);
+    assert_eq!(x.iter_enumerated().collect::<Vec<(u64, i32)>>(), vec![(2, 3), (3, 4), (4, 5)]);
+  }
 }
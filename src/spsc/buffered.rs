@@ -0,0 +1,158 @@
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::Sender;
+use seq::Seqno;
+use storage::{AlignedBuf, RingStorage};
+
+// collects items on the writer's side and hands them to the ring in one
+// go once `max_items` are pending, on flush(), or once the oldest waited
+// for max_age. there is no timer thread, the age is checked by every
+// push() and by tick(), so an idle producer has to call the latter.
+//
+// every item still takes its own flag swap, what a batch saves is waking
+// the receiver (and writing its eventfd) once per item. whatever is
+// pending gets flushed when this is dropped
+pub struct BufferedSender<T: Clone + Send, S: RingStorage<T> = AlignedBuf<T>> {
+  tx        : Sender<T, S>,
+  pending   : Vec<T>,
+  max_items : usize,
+  max_age   : Option<Duration>,
+  oldest    : Option<Instant>,      // when the first pending item came in
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  // batches of up to `max_items`, see BufferedSender
+  pub fn buffered(self, max_items : usize) -> BufferedSender<T, S> {
+    if max_items == 0 { panic!("batch size cannot be zero"); }
+    BufferedSender {
+      tx        : self,
+      pending   : Vec::with_capacity(max_items),
+      max_items,
+      max_age   : None,
+      oldest    : None,
+    }
+  }
+
+  // buffered() with the batch size the channel was built with, see
+  // Builder::batch_size() and Profile
+  pub fn batched(self) -> BufferedSender<T, S> {
+    let max_items = self.batch;
+    self.buffered(max_items)
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> BufferedSender<T, S> {
+  // publishes pending items once the oldest is `max_age` old, even if
+  // the batch is not full
+  pub fn max_age(mut self, max_age : Duration) -> BufferedSender<T, S> {
+    self.max_age = Some(max_age);
+    self
+  }
+
+  // adds `item` to the batch, flushing if that fills it up or the batch
+  // got too old. returns the seqno of the last item published, if any
+  pub fn push(&mut self, item : T) -> Option<Seqno> {
+    if self.pending.is_empty() { self.oldest = Some(Instant::now()); }
+    self.pending.push(item);
+    if self.pending.len() >= self.max_items { self.flush() } else { self.tick() }
+  }
+
+  // flushes if the batch is older than max_age, for producers that may
+  // go quiet for a while
+  pub fn tick(&mut self) -> Option<Seqno> {
+    match (self.oldest, self.max_age) {
+      (Some(oldest), Some(max_age)) if oldest.elapsed() >= max_age => self.flush(),
+      _ => None,
+    }
+  }
+
+  // publishes everything pending, returns the seqno of the last item or
+  // None if there was nothing to publish
+  pub fn flush(&mut self) -> Option<Seqno> {
+    self.oldest = None;
+    self.tx.put_all(self.pending.drain(..))
+  }
+
+  // items pushed but not published yet
+  pub fn pending(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn sender(&self) -> &Sender<T, S> {
+    &self.tx
+  }
+}
+
+// a panicking producer drops its batch instead, flushing could block
+// forever under Policy::Block
+impl<T: Clone + Send, S: RingStorage<T>> Drop for BufferedSender<T, S> {
+  fn drop(&mut self) {
+    if !thread::panicking() { self.flush(); }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Duration;
+  use spsc::{self, Builder, Policy};
+
+  #[test]
+  fn publishes_full_batches() {
+    let (tx, rx) = spsc::channel(8, 0i32);
+    let mut tx = tx.buffered(3);
+    assert_eq!(tx.push(1), None);
+    assert_eq!(tx.push(2), None);
+    assert!(rx.is_empty());
+    assert_eq!(tx.push(3), Some(2));
+    assert_eq!(tx.pending(), 0);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+
+    tx.push(4);
+    assert_eq!(tx.flush(), Some(3));
+    assert_eq!(tx.flush(), None);
+    tx.push(5);
+    drop(tx);
+    assert_eq!(rx.iter().collect::<Vec<i32>>(), vec![4, 5]);
+  }
+
+  #[test]
+  fn batches_as_built() {
+    let (tx, rx) = Builder::new().capacity(8).batch_size(2).build::<i32>();
+    let mut tx = tx.batched();
+    assert_eq!(tx.push(1), None);
+    assert_eq!(tx.push(2), Some(1));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1, 2]);
+  }
+
+  #[test]
+  fn flushes_old_batches() {
+    let (tx, rx) = spsc::channel(8, 0i32);
+    let mut tx = tx.buffered(100).max_age(Duration::from_millis(10));
+    tx.push(1);
+    assert_eq!(tx.tick(), None);
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(tx.tick(), Some(0));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+
+    // a push past the age flushes by itself
+    tx.push(2);
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(tx.push(3), Some(2));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![2, 3]);
+  }
+
+  #[test]
+  fn batches_larger_than_a_blocking_ring() {
+    const ITEMS : u64 = 5000;
+    let (tx, rx) = Builder::new().capacity(2).overwrite(Policy::Block).build::<u64>();
+    let t = thread::spawn(move || {
+      let mut tx = tx.buffered(7);
+      for i in 0..ITEMS { tx.push(i); }
+    });
+    assert_eq!(rx.iter().collect::<Vec<u64>>(), (0..ITEMS).collect::<Vec<u64>>());
+    t.join().unwrap();
+  }
+}
@@ -0,0 +1,256 @@
+
+use std::sync::Arc;
+
+use super::{halves, CircularBuffer, Receiver, Sender};
+#[cfg(any(test, feature = "fault-injection"))]
+use super::Faults;
+use wait::{Blocking, BusySpin, Relax, Spin, SpinThenPark, WaitStrategy};
+
+// preset tunings for users who do not want to learn the internals: the
+// wait strategy, how often the sender publishes and how many items
+// Sender::batched() collects, picked together
+//
+// only Throughput holds puts back. there is no age or time bound on
+// that, a put stays invisible to the receiver until the rest of its
+// batch follows, Sender::flush() or the sender is dropped, so it is no
+// fit for request/response or low rate traffic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+  // burn a core, react within nanoseconds, every put is seen right away
+  LowLatency,
+  // spin a little, then park until the writer wakes us up. every put is
+  // seen right away as well
+  Balanced,
+  // give the cpu away right away, items are published and picked up in
+  // chunks of 64
+  Throughput,
+}
+
+impl Profile {
+  pub fn wait_strategy(self) -> Arc<dyn WaitStrategy> {
+    match self {
+      Profile::LowLatency => Arc::new(BusySpin),
+      Profile::Balanced   => Arc::new(SpinThenPark::default()),
+      Profile::Throughput => Arc::new(Blocking::default()),
+    }
+  }
+
+  // see Builder::publish_every(), the builder caps it at the capacity
+  pub fn publish_every(self) -> usize {
+    match self {
+      Profile::LowLatency => 1,
+      Profile::Balanced   => 1,
+      Profile::Throughput => 64,
+    }
+  }
+
+  // see Builder::batch_size()
+  pub fn batch_size(self) -> usize {
+    match self {
+      Profile::LowLatency => 1,
+      Profile::Balanced   => 16,
+      Profile::Throughput => 256,
+    }
+  }
+}
+
+// what put() does when the reader still has `capacity` unread items
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+  // evict the oldest unread item, the writer never waits (the default)
+  Overwrite,
+  // wait with the channel's strategy until the reader took something,
+  // nothing gets lost. a dropped receiver unblocks the writer for good
+  Block,
+}
+
+pub struct Builder {
+  capacity  : usize,
+  policy    : Policy,
+  wait      : Arc<dyn WaitStrategy>,
+  name      : Option<Arc<str>>,
+  profile   : Option<Profile>,
+  publish   : Option<usize>,        // publish_every(), else the profile's
+  batch     : Option<usize>,        // batch_size(), else the profile's
+  #[cfg(any(test, feature = "fault-injection"))]
+  faults    : Option<Arc<Faults>>,
+}
+
+impl Default for Builder {
+  fn default() -> Builder {
+    Builder::new()
+  }
+}
+
+impl Builder {
+  pub fn new() -> Builder {
+    Builder {
+      capacity : 1024,
+      policy   : Policy::Overwrite,
+      wait     : Profile::Balanced.wait_strategy(),
+      name     : None,
+      profile  : None,
+      publish  : None,
+      batch    : None,
+      #[cfg(any(test, feature = "fault-injection"))]
+      faults   : None,
+    }
+  }
+
+  pub fn capacity(mut self, capacity : usize) -> Builder {
+    self.capacity = capacity;
+    self
+  }
+
+  pub fn overwrite(mut self, policy : Policy) -> Builder {
+    self.policy = policy;
+    self
+  }
+
+  // the wait strategy of `profile`, and its publication interval and
+  // batch size unless publish_every() and batch_size() set them
+  pub fn profile(mut self, profile : Profile) -> Builder {
+    self.wait = profile.wait_strategy();
+    self.profile = Some(profile);
+    self
+  }
+
+  // spin-waits, relaxing between checks as given, e.g. sleeping with
+  // jitter on oversubscribed VMs. overrides the profile as well
+  pub fn relax(self, relax : Relax) -> Builder {
+    self.wait(Spin::new(relax))
+  }
+
+  // overrides whatever the profile picked
+  pub fn wait<W : WaitStrategy + 'static>(mut self, strategy : W) -> Builder {
+    self.wait = Arc::new(strategy);
+    self
+  }
+
+  // shown with the channel's id in Debug output and panic messages, see
+  // Label
+  pub fn name<N : Into<Arc<str>>>(mut self, name : N) -> Builder {
+    self.name = Some(name.into());
+    self
+  }
+
+  // the sender moves the shared seqno once every `puts` puts instead of
+  // on every one, so the receiver sees items in batches of that many and
+  // the cache line with the seqno bounces less. Sender::flush() publishes
+  // a partial batch, as do dropping the sender and a full ring under
+  // Policy::Block. at most the capacity
+  pub fn publish_every(mut self, puts : usize) -> Builder {
+    if puts == 0 { panic!("the publication interval cannot be zero"); }
+    self.publish = Some(puts);
+    self
+  }
+
+  // how many items Sender::batched() collects before it puts them, see
+  // BufferedSender
+  pub fn batch_size(mut self, items : usize) -> Builder {
+    if items == 0 { panic!("batch size cannot be zero"); }
+    self.batch = Some(items);
+    self
+  }
+
+  // what the channel gets, 1 and 1 without a profile
+  fn publish_interval(&self) -> usize {
+    match (self.publish, self.profile) {
+      (Some(puts), _)       => puts,
+      (None, Some(profile)) => profile.publish_every().min(self.capacity),
+      (None, None)          => 1,
+    }
+  }
+
+  fn batch_items(&self) -> usize {
+    self.batch.or(self.profile.map(Profile::batch_size)).unwrap_or(1)
+  }
+
+  // hooks both sides run into, see spsc::Point
+  #[cfg(any(test, feature = "fault-injection"))]
+  pub fn faults(mut self, faults : Arc<Faults>) -> Builder {
+    self.faults = Some(faults);
+    self
+  }
+
+  pub fn build<T: Clone + Send + Default>(self) -> (Sender<T>, Receiver<T>) {
+    self.build_with(T::default())
+  }
+
+  pub fn build_with<T: Clone + Send>(self, default_value : T) -> (Sender<T>, Receiver<T>) {
+    let publish = self.publish_interval();
+    if publish > self.capacity {
+      panic!("cannot publish every {} puts with a capacity of {}", publish, self.capacity);
+    }
+    let batch = self.batch_items();
+    let mut ring = CircularBuffer::new(self.capacity, default_value);
+    ring.backoff = Some(self.wait.clone());
+    ring.writer.get_mut().publish_at = publish;
+    #[cfg(any(test, feature = "fault-injection"))]
+    { ring.faults = self.faults; }
+    let (mut tx, mut rx) = halves(ring, self.wait);
+    tx.policy = self.policy;
+    tx.batch  = batch;
+    rx.policy = self.policy;
+    if let Some(name) = self.name {
+      tx.label.set_name(name.clone());
+      rx.label.set_name(name);
+    }
+    (tx, rx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Builder, Profile};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn profiles_pick_cadence_and_batches() {
+    let settings = |b : Builder| (b.publish_interval(), b.batch_items());
+    assert_eq!(settings(Builder::new()), (1, 1));
+    assert_eq!(settings(Builder::new().profile(Profile::LowLatency)), (1, 1));
+    assert_eq!(settings(Builder::new().profile(Profile::Balanced)), (1, 16));
+    assert_eq!(settings(Builder::new().profile(Profile::Throughput)), (64, 256));
+
+    // capped by a small ring, and what is set explicitly wins either way round
+    assert_eq!(settings(Builder::new().capacity(4).profile(Profile::Throughput)), (4, 256));
+    assert_eq!(settings(Builder::new().publish_every(2).profile(Profile::Throughput).batch_size(3)), (2, 3));
+    assert_eq!(settings(Builder::new().profile(Profile::LowLatency).publish_every(2)), (2, 1));
+  }
+
+  #[test]
+  fn profiles_end_up_in_the_channel() {
+    for &(profile, publish, batch) in &[(Profile::LowLatency, 1, 1), (Profile::Balanced, 1, 16), (Profile::Throughput, 64, 256)] {
+      let (tx, rx) = Builder::new().capacity(128).profile(profile).build::<usize>();
+      assert_eq!((unsafe { (*tx.inner.writer.get()).publish_at }, tx.batch), (publish, batch));
+
+      for i in 1..publish { tx.put(|v| *v = i); }
+      assert!(rx.is_empty());
+      tx.put(|v| *v = publish);
+      assert_eq!(rx.try_iter().collect::<Vec<usize>>(), (1..publish + 1).collect::<Vec<usize>>());
+    }
+  }
+
+  #[test]
+  fn a_lone_put_gets_through() {
+    for &profile in &[Profile::LowLatency, Profile::Balanced] {
+      let (tx, rx) = Builder::new().capacity(128).profile(profile).build::<u32>();
+      let t = thread::spawn(move || {
+        let seen = rx.wait(Some(Duration::from_secs(10)));
+        (seen, rx.try_iter().collect::<Vec<u32>>())
+      });
+      tx.put(|v| *v = 7);
+      assert_eq!(t.join().unwrap(), (true, vec![7]));
+    }
+
+    // Throughput holds it back until flushed
+    let (tx, rx) = Builder::new().capacity(128).profile(Profile::Throughput).build::<u32>();
+    tx.put(|v| *v = 7);
+    assert!(!rx.wait(Some(Duration::from_millis(20))));
+    tx.flush();
+    assert!(rx.wait(Some(Duration::from_secs(10))));
+    assert_eq!(rx.try_iter().collect::<Vec<u32>>(), vec![7]);
+  }
+}
@@ -0,0 +1,140 @@
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::Sender;
+use seq::Seqno;
+use storage::{AlignedBuf, RingStorage};
+use wait::{SpinThenPark, WaitStrategy};
+
+// a sender that spends one credit per item, credits come from downstream
+// through a Grant
+//
+// meant for chains of stages: a stage grants its upstream a credit for
+// every item it passed on (or finished), so a burst waits at the source
+// instead of piling up in, or being evicted from, whichever ring sits in
+// front of the slowest stage. with no more credit in flight than a ring's
+// capacity that ring never overwrites anything, whatever its policy
+pub struct CreditedSender<T: Clone + Send, S: RingStorage<T> = AlignedBuf<T>> {
+  tx      : Sender<T, S>,
+  credits : Arc<Credits>,
+}
+
+// hands credits to a CreditedSender, clone it for every place that does
+#[derive(Clone)]
+pub struct Grant {
+  credits : Arc<Credits>,
+}
+
+struct Credits {
+  left : AtomicUsize,
+  wait : SpinThenPark,
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  // starts out with `initial` credits, see CreditedSender
+  pub fn credited(self, initial : usize) -> (CreditedSender<T, S>, Grant) {
+    let credits = Arc::new(Credits { left : AtomicUsize::new(initial), wait : SpinThenPark::default() });
+    (CreditedSender { tx : self, credits : credits.clone() }, Grant { credits })
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> CreditedSender<T, S> {
+  // put() once there is a credit for it. a gone receiver grants nothing
+  // any more, puts then go through uncounted
+  pub fn put<F>(&self, setter : F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    self.take_credit(true);
+    self.tx.put(setter)
+  }
+
+  // put() only if there is a credit right now
+  pub fn try_put<F>(&self, setter : F) -> Option<Seqno>
+    where F : FnMut(&mut T)
+  {
+    if !self.take_credit(false) { return None; }
+    Some(self.tx.put(setter))
+  }
+
+  // credits left to spend
+  pub fn credits(&self) -> usize {
+    self.credits.left.load(Ordering::Acquire)
+  }
+
+  pub fn sender(&self) -> &Sender<T, S> {
+    &self.tx
+  }
+
+  // only we take credits, once there is one it stays until we take it
+  fn take_credit(&self, wait : bool) -> bool {
+    let left = &self.credits.left;
+    let ready = || left.load(Ordering::Acquire) > 0 || self.tx.is_closed();
+    if !ready() {
+      if !wait { return false; }
+      self.credits.wait.wait_for(&ready, None);
+    }
+    if left.load(Ordering::Acquire) > 0 { left.fetch_sub(1, Ordering::AcqRel); }
+    true
+  }
+}
+
+impl Grant {
+  pub fn grant(&self, credits : usize) {
+    self.credits.left.fetch_add(credits, Ordering::Release);
+    self.credits.wait.notify();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use spsc;
+
+  #[test]
+  fn spends_and_gets_credits() {
+    let (tx, rx) = spsc::channel(8, 0i32);
+    let (tx, grant) = tx.credited(2);
+    assert_eq!(tx.try_put(|v| *v = 1), Some(0));
+    assert_eq!(tx.try_put(|v| *v = 2), Some(1));
+    assert_eq!(tx.try_put(|v| *v = 3), None);
+    grant.grant(1);
+    assert_eq!(tx.credits(), 1);
+    assert_eq!(tx.try_put(|v| *v = 3), Some(2));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+
+    // a gone receiver does not leave the sender waiting
+    drop(rx);
+    tx.put(|v| *v = 4);
+  }
+
+  #[test]
+  fn bursts_wait_at_the_source() {
+    const ITEMS : u64 = 10_000;
+    // two overwriting stages, credits keep either from evicting
+    let (tx, rx1) = spsc::channel(4, 0u64);
+    let (tx, grant_src) = tx.credited(4);
+    let (tx2, rx2) = spsc::channel(4, 0u64);
+    let (tx2, grant_mid) = tx2.credited(4);
+
+    let src = thread::spawn(move || {
+      for i in 0..ITEMS { tx.put(|v| *v = i); }
+    });
+    let mid = thread::spawn(move || {
+      for i in rx1.iter() {
+        tx2.put(|v| *v = i * 2);
+        grant_src.grant(1);
+      }
+    });
+
+    let mut seen = 0;
+    for i in rx2.iter() {
+      assert_eq!(i, seen * 2);
+      seen += 1;
+      grant_mid.grant(1);
+    }
+    src.join().unwrap();
+    mid.join().unwrap();
+    assert_eq!(seen, ITEMS);
+  }
+}
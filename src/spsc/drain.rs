@@ -0,0 +1,88 @@
+
+use std::sync::atomic::Ordering;
+
+use super::{Receiver, Sender};
+use seq::Seqno;
+use storage::RingStorage;
+
+// an orderly shutdown: the sender finish()es instead of just going away,
+// the receiver drain_remaining()s what is still in the ring and learns
+// whether the stream was complete or cut short
+
+// what drain_remaining() returns
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Drained {
+  pub items    : u64,     // handed to the handler
+  pub lost     : u64,     // published but overwritten before the drain got them
+  pub finished : bool,    // the sender finish()ed, false if it was just dropped
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  // closes the channel as dropping the sender does, but marks the stream
+  // complete. returns how many items were put in all
+  pub fn finish(self) -> Seqno {
+    self.inner.finished.store(true, Ordering::SeqCst);
+    unsafe { (*self.inner.writer.get()).put_count }
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Receiver<T, S> {
+  // true once the sender finish()ed, see Drained
+  pub fn is_finished(&self) -> bool {
+    self.inner.finished.load(Ordering::SeqCst)
+  }
+
+  // hands every item to `handler` until the sender is gone and nothing is
+  // left, like iter(), then tells how it went. the counts start at the
+  // previous read, so lost also covers what was overwritten before
+  pub fn drain_remaining<F>(&self, handler : F) -> Drained
+    where F : FnMut(T)
+  {
+    let mut handler = handler;
+    let start = self.seen();
+    let mut items = 0;
+    for item in self.iter() {
+      handler(item);
+      items += 1;
+    }
+    Drained { items, lost : self.seen() - start - items, finished : self.is_finished() }
+  }
+
+  // the items published as of the last read
+  pub(crate) fn seen(&self) -> Seqno {
+    let r = unsafe { &*self.inner.reader.get() };
+    r.read_epoch.wrapping_add(r.max_read as Seqno)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Drained;
+  use spsc;
+  use std::thread;
+
+  #[test]
+  fn finish_then_drain() {
+    let (tx, rx) = spsc::channel(2, 0i32);
+    tx.put(|v| *v = 1);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+    for i in 2..6 { tx.put(|v| *v = i); }
+    assert_eq!(tx.finish(), 5);
+
+    let mut seen = vec![];
+    assert_eq!(rx.drain_remaining(|v| seen.push(v)), Drained { items : 2, lost : 2, finished : true });
+    assert_eq!(seen, vec![4, 5]);
+    assert!(rx.is_closed());
+  }
+
+  #[test]
+  fn dropped_sender_is_not_finished() {
+    let (tx, rx) = spsc::channel(4, 0u64);
+    let t = thread::spawn(move || {
+      for i in 0..3 { tx.put(|v| *v = i); }
+    });
+    let drained = rx.drain_remaining(|_| ());
+    t.join().unwrap();
+    assert_eq!(drained, Drained { items : 3, lost : 0, finished : false });
+  }
+}
@@ -0,0 +1,147 @@
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{flag, CircularBuffer, Receiver, Sender};
+use super::snapshot::idle_ring;
+use seq::Seqno;
+use storage::RingStorage;
+
+// the ring's bookkeeping as plain data, for debugging and for tests that
+// want to look at more than the items. slot numbers index the 2*n+1 data
+// slots, every one of them is either behind a flag, in read_priv or
+// write_tmp
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RingState {
+  pub capacity  : usize,
+  pub seqno     : usize,            // ctrl[0], the writer's seqno in a word
+  pub put_count : Seqno,
+  pub write_tmp : usize,            // the slot the next put fills
+  pub last_put  : usize,
+  pub max_read  : usize,            // ctrl[0] at the reader's last iter()
+  pub flags     : Vec<FlagState>,   // one per position
+  pub read_priv : Vec<usize>,       // the slots the reader holds
+}
+
+// one decoded control word
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlagState {
+  pub pos   : usize,                // data slot
+  pub seq   : usize,                // low bits of the seqno put there
+  pub taken : bool,
+}
+
+impl <T : Clone, S : RingStorage<T>, C : RingStorage<AtomicUsize>> CircularBuffer<T, S, C> {
+  /// The ring's counters, control words and slot positions, decoded.
+  ///
+  /// ```
+  /// let mut ring = rpg::spsc::CircularBuffer::new(2, 0i32);
+  /// ring.put(|v| *v = 1);
+  /// let state = ring.dump_state();
+  /// assert_eq!((state.seqno, state.flags[0].taken), (1, false));
+  /// ```
+  pub fn dump_state(&self) -> RingState {
+    // a shared ring is only dumped through dump_state() below, with both
+    // of its halves idle
+    let (w, r) = unsafe { (&*self.writer.get(), &*self.reader.get()) };
+    RingState {
+      capacity  : self.size,
+      seqno     : self.seqno().load(Ordering::Relaxed),
+      put_count : w.put_count,
+      write_tmp : w.write_tmp,
+      last_put  : w.last_put,
+      max_read  : r.max_read,
+      flags     : self.ctrl.slots()[flag::FIRST..].iter().map(|f| {
+        let f = f.load(Ordering::Relaxed);
+        FlagState { pos : flag::pos(f), seq : flag::seq(f), taken : flag::taken(f) }
+      }).collect(),
+      read_priv : r.read_priv.clone(),
+    }
+  }
+}
+
+// dump_state() of the channel `tx` and `rx` belong to, see snapshot()
+pub fn dump_state<T, S>(tx : &Sender<T, S>, rx : &Receiver<T, S>) -> RingState
+  where T : Clone + Send,
+        S : RingStorage<T>
+{
+  idle_ring(tx, rx).dump_state()
+}
+
+impl RingState {
+  pub fn to_json(&self) -> String {
+    let mut out = String::new();
+    let _ = write!(out, "{{\"capacity\": {}, \"seqno\": {}, \"put_count\": {}, \"write_tmp\": {}, \
+                         \"last_put\": {}, \"max_read\": {}, \"flags\": [",
+                   self.capacity, self.seqno, self.put_count, self.write_tmp, self.last_put, self.max_read);
+    for (i, f) in self.flags.iter().enumerate() {
+      if i > 0 { out.push_str(", "); }
+      let _ = write!(out, "{{\"pos\": {}, \"seq\": {}, \"taken\": {}}}", f.pos, f.seq, f.taken);
+    }
+    out.push_str("], \"read_priv\": [");
+    for (i, p) in self.read_priv.iter().enumerate() {
+      if i > 0 { out.push_str(", "); }
+      let _ = write!(out, "{}", p);
+    }
+    out.push_str("]}");
+    out
+  }
+
+  // a graphviz digraph of who holds which data slot, `dot -Tsvg` it
+  pub fn to_dot(&self) -> String {
+    let mut out = String::from("digraph ring {\n  rankdir=LR;\n  node [shape=box];\n");
+    let _ = writeln!(out, "  seqno [label=\"seqno {}\\nmax_read {}\"];", self.seqno, self.max_read);
+    for slot in 0..(self.capacity * 2) + 1 {
+      let _ = writeln!(out, "  slot{} [label=\"slot {}\", shape=ellipse];", slot, slot);
+    }
+    for (i, f) in self.flags.iter().enumerate() {
+      let style = if f.taken { "dashed" } else { "solid" };
+      let _ = writeln!(out, "  flag{} [label=\"flag {}\\nseq {}{}\"];", i, i, f.seq, if f.taken { " taken" } else { "" });
+      let _ = writeln!(out, "  flag{} -> slot{} [style={}];", i, f.pos, style);
+    }
+    for (i, p) in self.read_priv.iter().enumerate() {
+      let _ = writeln!(out, "  read{} [label=\"read_priv {}\"];", i, i);
+      let _ = writeln!(out, "  read{} -> slot{};", i, p);
+    }
+    let _ = writeln!(out, "  write_tmp -> slot{};", self.write_tmp);
+    out.push_str("}\n");
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{dump_state, FlagState};
+  use spsc::{self, CircularBuffer};
+
+  #[test]
+  fn every_slot_has_one_owner() {
+    let mut ring = CircularBuffer::new(3, 0i32);
+    for i in 0..5 { ring.put(|v| *v = i); }
+    assert_eq!(ring.iter().count(), 3);
+    ring.put(|v| *v = 5);
+
+    let state = ring.dump_state();
+    assert_eq!((state.seqno, state.put_count, state.max_read), (6, 6, 5));
+    let mut slots : Vec<usize> = state.flags.iter().map(|f| f.pos).chain(state.read_priv.iter().cloned()).collect();
+    slots.push(state.write_tmp);
+    slots.sort();
+    assert_eq!(slots, (0..7).collect::<Vec<usize>>());
+    // the newest item is the only unread one
+    assert_eq!(state.flags[5 % 3], FlagState { pos : state.last_put, seq : 5, taken : false });
+    assert_eq!(state.flags.iter().filter(|f| f.taken).count(), 2);
+  }
+
+  #[test]
+  fn json_and_dot() {
+    let (tx, rx) = spsc::channel(1, 0u8);
+    tx.put(|v| *v = 1);
+    let state = dump_state(&tx, &rx);
+    assert_eq!(state.to_json(),
+               "{\"capacity\": 1, \"seqno\": 1, \"put_count\": 1, \"write_tmp\": 1, \"last_put\": 0, \
+                \"max_read\": 0, \"flags\": [{\"pos\": 0, \"seq\": 0, \"taken\": false}], \"read_priv\": [2]}");
+    let dot = state.to_dot();
+    assert!(dot.starts_with("digraph ring {") && dot.ends_with("}\n"));
+    assert!(dot.contains("flag0 -> slot0 [style=solid];") && dot.contains("write_tmp -> slot1;"));
+  }
+}
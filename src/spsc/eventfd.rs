@@ -0,0 +1,50 @@
+
+// linux eventfd the sender bumps on every put, so the receiver can sit
+// in epoll/io_uring next to its other fds
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::raw::{c_int, c_uint};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+const EFD_CLOEXEC  : c_int = 0o2000000;
+const EFD_NONBLOCK : c_int = 0o4000;
+
+extern "C" {
+  fn eventfd(initval : c_uint, flags : c_int) -> c_int;
+}
+
+pub struct EventFd {
+  file : File,
+}
+
+impl EventFd {
+  pub fn new() -> io::Result<EventFd> {
+    let fd = unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(EventFd { file : unsafe { File::from_raw_fd(fd) } })
+  }
+
+  pub fn notify(&self) {
+    // only fails if the counter would overflow, it is readable then anyway
+    let _ = (&self.file).write(&1u64.to_ne_bytes());
+  }
+
+  // resets the counter, returns the number of notifications since the
+  // last reset
+  pub fn reset(&self) -> u64 {
+    let mut buf = [0u8; 8];
+    match (&self.file).read(&mut buf) {
+      Ok(8) => u64::from_ne_bytes(buf),
+      _     => 0,
+    }
+  }
+}
+
+impl AsRawFd for EventFd {
+  fn as_raw_fd(&self) -> RawFd {
+    self.file.as_raw_fd()
+  }
+}
@@ -0,0 +1,193 @@
+
+// fault injection at fixed points of put() and iter(), to reproduce slow
+// or stalled peers deterministically instead of hoping a stress test
+// hits the window. only compiled into tests and with the fault-injection
+// feature, otherwise every hook is an empty inline function
+//
+// a hook runs on the thread that reaches the point, so it can stall that
+// side (delay), make the other side run right there (call) or pretend the
+// flag CAS lost against the other side (fail_cas)
+
+#[cfg(any(test, feature = "fault-injection"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(test, feature = "fault-injection"))]
+use std::thread;
+#[cfg(any(test, feature = "fault-injection"))]
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Point {
+  // the writer filled its slot and is about to swap the flag
+  PutBeforeSwap,
+  // the flag is swapped, the seqno is not bumped yet
+  PutAfterSwap,
+  // the reader loaded a flag and is about to take it
+  IterBeforeTake,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+struct Hook {
+  point : Point,
+  skip  : usize,                       // hits to let pass first
+  times : usize,                       // hits it fires on, then it is done
+  fail  : bool,                        // the CAS at the point fails
+  call  : Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+#[derive(Default)]
+pub struct Faults {
+  hooks : Mutex<Vec<Hook>>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl Faults {
+  pub fn new() -> Faults {
+    Faults::default()
+  }
+
+  // runs `f` on `times` hits of `point`, after letting `skip` hits pass
+  pub fn call<F : Fn() + Send + Sync + 'static>(&self, point : Point, skip : usize, times : usize, f : F) {
+    self.add(Hook { point, skip, times, fail : false, call : Some(Arc::new(f)) });
+  }
+
+  pub fn delay(&self, point : Point, skip : usize, times : usize, pause : Duration) {
+    self.call(point, skip, times, move || thread::sleep(pause));
+  }
+
+  // the next `times` flag CAS at `point` fail, the flag itself stays as
+  // it is. the writer just retries, the reader takes it for a merge and
+  // retries as well. PutAfterSwap has no CAS to fail
+  pub fn fail_cas(&self, point : Point, times : usize) {
+    self.add(Hook { point, skip : 0, times, fail : true, call : None });
+  }
+
+  fn add(&self, hook : Hook) {
+    self.hooks.lock().unwrap().push(hook);
+  }
+
+  // true if the CAS at `point` has to fail. the calls run without the
+  // lock held, they may well reach another point themselves
+  pub(crate) fn hit(&self, point : Point) -> bool {
+    let mut fail  = false;
+    let mut calls = vec![];
+    for hook in self.hooks.lock().unwrap().iter_mut() {
+      if hook.point != point || hook.times == 0 { continue; }
+      if hook.skip > 0 { hook.skip -= 1; continue; }
+      hook.times -= 1;
+      fail |= hook.fail;
+      if let Some(ref call) = hook.call { calls.push(call.clone()); }
+    }
+    for call in calls { call(); }
+    fail
+  }
+}
+
+// what the ring keeps, nothing at all without the feature
+#[cfg(any(test, feature = "fault-injection"))]
+pub(crate) type Slot = Option<Arc<Faults>>;
+#[cfg(not(any(test, feature = "fault-injection")))]
+pub(crate) type Slot = NoFaults;
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+#[derive(Default)]
+pub(crate) struct NoFaults;
+
+// what a ring starts with, for const context
+#[cfg(any(test, feature = "fault-injection"))]
+pub(crate) const NONE : Slot = None;
+#[cfg(not(any(test, feature = "fault-injection")))]
+pub(crate) const NONE : Slot = NoFaults;
+
+pub(crate) trait Inject {
+  // true if the CAS at `point` has to fail
+  fn fires(&self, point : Point) -> bool;
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl Inject for Option<Arc<Faults>> {
+  fn fires(&self, point : Point) -> bool {
+    self.as_ref().is_some_and(|f| f.hit(point))
+  }
+}
+
+#[cfg(not(any(test, feature = "fault-injection")))]
+impl Inject for NoFaults {
+  #[inline(always)]
+  fn fires(&self, _point : Point) -> bool { false }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+  use super::{Faults, Point};
+  use spsc::{Builder, TryPutError};
+
+  #[test]
+  fn forced_cas_failures() {
+    let faults = Arc::new(Faults::new());
+    let (tx, rx) = Builder::new().capacity(4).faults(faults.clone()).build::<i32>();
+
+    faults.fail_cas(Point::PutBeforeSwap, 3);
+    assert_eq!(tx.try_put(|v| *v = 1, 2), Err(TryPutError::Contended));
+    assert_eq!(tx.cas_retries(), 2);
+    assert_eq!(tx.try_put(|v| *v = 1, 2), Ok(0));
+
+    faults.fail_cas(Point::IterBeforeTake, 1);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+    assert_eq!(rx.cas_retries(), 1);
+  }
+
+  #[test]
+  fn reader_lapped_mid_acquisition() {
+    let faults = Arc::new(Faults::new());
+    let (tx, rx) = Builder::new().capacity(4).faults(faults.clone()).build::<u64>();
+    for i in 0..4 { tx.put(|v| *v = i); }
+
+    // the reader took item 3 and is about to take 2 when the writer
+    // goes around the whole ring
+    let tx = Arc::new(Mutex::new(tx));
+    {
+      let tx = tx.clone();
+      faults.call(Point::IterBeforeTake, 1, 1, move || {
+        for i in 4..9 { tx.lock().unwrap().put(|v| *v = i); }
+      });
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<u64>>(), vec![3]);
+    // 4 got evicted before anyone saw it
+    assert_eq!(rx.try_iter_enumerated().collect::<Vec<(u64, u64)>>(), vec![(5, 5), (6, 6), (7, 7), (8, 8)]);
+  }
+
+  #[test]
+  fn writer_stalls_after_flag_swap() {
+    let faults = Arc::new(Faults::new());
+    let (tx, rx) = Builder::new().capacity(4).faults(faults.clone()).build::<u64>();
+
+    // the third put swapped its flag but has not bumped the seqno yet
+    let rx = Arc::new(Mutex::new(rx));
+    let seen = Arc::new(Mutex::new(vec![]));
+    {
+      let (rx, seen) = (rx.clone(), seen.clone());
+      faults.call(Point::PutAfterSwap, 2, 1, move || {
+        seen.lock().unwrap().extend(rx.lock().unwrap().try_iter());
+      });
+    }
+    for i in 0..4 { tx.put(|v| *v = i); }
+    assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+    assert_eq!(rx.lock().unwrap().try_iter().collect::<Vec<u64>>(), vec![2, 3]);
+  }
+
+  #[test]
+  fn delayed_writer() {
+    let faults = Arc::new(Faults::new());
+    let (tx, _rx) = Builder::new().capacity(4).faults(faults.clone()).build::<i32>();
+    faults.delay(Point::PutBeforeSwap, 1, 1, Duration::from_millis(20));
+
+    let at = Instant::now();
+    tx.put(|v| *v = 1);
+    assert!(at.elapsed() < Duration::from_millis(20));
+    tx.put(|v| *v = 2);
+    assert!(at.elapsed() >= Duration::from_millis(20));
+  }
+}
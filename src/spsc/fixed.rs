@@ -0,0 +1,216 @@
+use std::cell::Cell;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use super::{flag, CircularBuffer, CircularBufferIterator};
+use seq::Seqno;
+use storage::RingStorage;
+
+// an spsc channel that is made without allocating, in const context, so
+// it can live in a static instead of behind a OnceLock:
+//
+//   static EVENTS : StaticChannel<u32, 8> = StaticChannel::new(0);
+//   let (tx, rx) = EVENTS.split();
+//
+// it is the ring channel() uses, with the 2*N+1 slots and the control
+// words inline. split() hands out the two halves once, at runtime, which
+// is also when the reader's list of its N slots gets allocated. the
+// halves only put and try_iter(), there is no wait strategy and no
+// Policy::Block. items are Copy, the slots are filled in const context
+pub struct StaticChannel<T : Copy, const N : usize> {
+  ring  : CircularBuffer<T, InlineSlots<T, N>, InlineCtrl<N>>,
+  split : AtomicBool,
+}
+
+// the halves only ever touch their own side of the ring, as the ones of
+// channel() do
+unsafe impl<T : Copy + Send, const N : usize> Sync for StaticChannel<T, N> { }
+
+pub struct StaticSender<'a, T : 'a + Copy, const N : usize> {
+  ring    : &'a CircularBuffer<T, InlineSlots<T, N>, InlineCtrl<N>>,
+  writing : Cell<bool>,
+}
+
+unsafe impl<'a, T : Copy + Send, const N : usize> Send for StaticSender<'a, T, N> { }
+
+pub struct StaticReceiver<'a, T : 'a + Copy, const N : usize> {
+  ring    : &'a CircularBuffer<T, InlineSlots<T, N>, InlineCtrl<N>>,
+  reading : Cell<bool>,
+}
+
+unsafe impl<'a, T : Copy + Send, const N : usize> Send for StaticReceiver<'a, T, N> { }
+
+// the slots back to back, repr(C) leaves no gaps between them
+#[repr(C)]
+struct InlineSlots<T, const N : usize> {
+  published : [T; N],
+  taken     : [T; N],
+  spare     : T,
+}
+
+// the seqno and its padding, then the flags, see flag.rs
+#[repr(C)]
+struct InlineCtrl<const N : usize> {
+  seqno : [AtomicUsize; flag::FIRST],
+  flags : [AtomicUsize; N],
+}
+
+impl <T, const N : usize> RingStorage<T> for InlineSlots<T, N> {
+  fn slots(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self as *const InlineSlots<T, N> as *const T, 2*N + 1) }
+  }
+
+  fn slots_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self as *mut InlineSlots<T, N> as *mut T, 2*N + 1) }
+  }
+
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T {
+    this as *mut T
+  }
+}
+
+impl <const N : usize> RingStorage<AtomicUsize> for InlineCtrl<N> {
+  fn slots(&self) -> &[AtomicUsize] {
+    unsafe { slice::from_raw_parts(self as *const InlineCtrl<N> as *const AtomicUsize, flag::ctrl_words(N)) }
+  }
+
+  fn slots_mut(&mut self) -> &mut [AtomicUsize] {
+    unsafe { slice::from_raw_parts_mut(self as *mut InlineCtrl<N> as *mut AtomicUsize, flag::ctrl_words(N)) }
+  }
+}
+
+impl <T : Copy, const N : usize> StaticChannel<T, N> {
+  // a channel for N items, every slot a copy of `default_value`. fails
+  // to compile in a static, and panics elsewhere, if N is zero or too
+  // large for the flags
+  pub const fn new(default_value : T) -> StaticChannel<T, N> {
+    if N == 0 { panic!("size cannot be zero"); }
+    if N > flag::MAX_SIZE { panic!("too many slots for the flag layout on this target"); }
+
+    let slots = InlineSlots { published : [default_value; N], taken : [default_value; N], spare : default_value };
+    let ctrl  = InlineCtrl { seqno : [const { AtomicUsize::new(0) }; flag::FIRST], flags : [const { AtomicUsize::new(0) }; N] };
+    StaticChannel {
+      ring  : CircularBuffer::uninit(slots, ctrl, N),
+      split : AtomicBool::new(false),
+    }
+  }
+
+  // the two halves. panics if the channel was split before, even if
+  // those halves are gone by now
+  pub fn split(&self) -> (StaticSender<'_, T, N>, StaticReceiver<'_, T, N>) {
+    if self.split.swap(true, Ordering::AcqRel) { panic!("a static channel can only be split once"); }
+    // nobody had the ring so far
+    unsafe { self.ring.set_up(true); }
+    (StaticSender { ring : &self.ring, writing : Cell::new(false) },
+     StaticReceiver { ring : &self.ring, reading : Cell::new(false) })
+  }
+
+  pub fn capacity(&self) -> usize {
+    N
+  }
+}
+
+impl <'a, T : Copy + Send, const N : usize> StaticSender<'a, T, N> {
+  // Sender::put(), evicts the oldest unread item once the ring is full
+  pub fn put<F>(&self, setter : F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    struct Done<'b>(&'b Cell<bool>);
+    impl<'b> Drop for Done<'b> {
+      fn drop(&mut self) { self.0.set(false); }
+    }
+
+    let mut setter = setter;
+    if self.writing.replace(true) { panic!("static sender used from inside its own setter"); }
+    let _done = Done(&self.writing);
+    unsafe { self.ring.put_unbounded(|_, v| setter(v)).0 }
+  }
+
+  // true while the next put would evict an item the receiver has not
+  // taken yet
+  pub fn is_full(&self) -> bool {
+    !self.ring.has_room()
+  }
+}
+
+impl <'a, T : Copy, const N : usize> Drop for StaticSender<'a, T, N> {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.ring.poisoned.store(true, Ordering::SeqCst);
+    }
+    self.ring.writer_gone.store(true, Ordering::SeqCst);
+  }
+}
+
+impl <'a, T : Copy + Send, const N : usize> StaticReceiver<'a, T, N> {
+  // Receiver::try_iter(), one iterator at a time
+  pub fn try_iter(&self) -> CircularBufferIterator<'_, T> {
+    if self.reading.replace(true) { panic!("static receiver: try_iter() called while an earlier iterator is alive"); }
+    let mut it = unsafe { self.ring.take() };
+    it.reading = Some(&self.reading);
+    it
+  }
+
+  pub fn is_empty(&self) -> bool {
+    !self.ring.has_unread()
+  }
+
+  // true once the sender was dropped
+  pub fn is_closed(&self) -> bool {
+    self.ring.writer_gone.load(Ordering::SeqCst)
+  }
+}
+
+impl <'a, T : Copy, const N : usize> Drop for StaticReceiver<'a, T, N> {
+  fn drop(&mut self) {
+    self.ring.reader_gone.store(true, Ordering::SeqCst);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::StaticChannel;
+  use std::thread;
+
+  static EVENTS : StaticChannel<u32, 4> = StaticChannel::new(0);
+
+  #[test]
+  fn lives_in_a_static() {
+    let (tx, rx) = EVENTS.split();
+    assert_eq!(EVENTS.capacity(), 4);
+    let t = thread::spawn(move || {
+      for i in 1..1001 { tx.put(|v| *v = i); }
+    });
+
+    // overwritten items are missing, the rest comes in order
+    let mut got = vec![];
+    loop {
+      let closed = rx.is_closed();
+      got.extend(rx.try_iter());
+      if closed && rx.is_empty() { break; }
+      thread::yield_now();
+    }
+    t.join().unwrap();
+    assert!(got.windows(2).all(|w| w[0] < w[1]), "out of order: {:?}", got);
+    assert_eq!(got.last(), Some(&1000));
+  }
+
+  #[test]
+  fn keeps_the_newest() {
+    let channel = StaticChannel::<u8, 3>::new(0);
+    let (tx, rx) = channel.split();
+    for i in 1..6 { tx.put(|v| *v = i); }
+    assert!(tx.is_full());
+    assert_eq!(rx.try_iter().collect::<Vec<u8>>(), vec![3, 4, 5]);
+    assert!(rx.is_empty() && !tx.is_full());
+  }
+
+  #[test]
+  #[should_panic(expected = "only be split once")]
+  fn splits_once() {
+    let channel = StaticChannel::<u8, 2>::new(0);
+    let _halves = channel.split();
+    channel.split();
+  }
+}
@@ -0,0 +1,99 @@
+
+// the per position control words of the spsc ring
+//
+//   | TAKEN (top bit) | data slot index | low SEQ_BITS of the seqno |
+//
+// everything derives from usize::BITS, so 64 bit targets get 47 bits for
+// the index and 32 bit ones (armv7, wasm32) 15, i.e. at most MAX_SLOTS
+// data slots. the seq bits cap rings further, at MAX_SIZE items. the
+// constructors refuse anything bigger
+//
+// the flags come after ctrl[0], the seqno, but a cache line further on:
+// the writer bumps the seqno on every publish and the reader CASes flags
+// on every take, on one line they would keep stealing it from each other
+
+use std::mem;
+
+use storage::CACHE_LINE;
+
+pub(crate) const SEQ_BITS  : u32   = 16;
+pub(crate) const SEQ_MASK  : usize = (1 << SEQ_BITS) - 1;
+
+// set once the reader swapped the item out, or if it never held one.
+// without it the writer evicts an item nobody has seen
+pub(crate) const TAKEN     : usize = 1 << (usize::BITS - 1);
+
+pub(crate) const MAX_SLOTS : usize = 1 << (usize::BITS - 1 - SEQ_BITS);
+
+// the most items a ring takes. a position's next item comes `size`
+// seqnos later, in a bigger ring a lap could leave the seq bits as they
+// were (any multiple of 2^SEQ_BITS would) and a lapped reader's CAS
+// would take the newer item for the one it was after
+pub(crate) const MAX_SIZE  : usize = if (MAX_SLOTS - 1) / 2 < 1 << (SEQ_BITS - 1) { (MAX_SLOTS - 1) / 2 } else { 1 << (SEQ_BITS - 1) };
+
+// index of the first flag in the control words
+pub(crate) const FIRST     : usize = CACHE_LINE / mem::size_of::<usize>();
+
+const _ : () = assert!(usize::BITS >= 32, "the flag packing needs at least 32 bit words");
+const _ : () = assert!(MAX_SLOTS >= (1 << 15), "too few index bits left in a flag");
+const _ : () = assert!(MAX_SIZE < 1 << SEQ_BITS, "a lap has to move the seq bits");
+
+pub(crate) fn pack(pos : usize, seqno : usize) -> usize {
+  debug_assert!(pos < MAX_SLOTS, "slot index {} does not fit a flag", pos);
+  (pos << SEQ_BITS) | (seqno & SEQ_MASK)
+}
+
+pub(crate) fn pos(flag : usize) -> usize {
+  (flag & !TAKEN) >> SEQ_BITS
+}
+
+pub(crate) fn seq(flag : usize) -> usize {
+  flag & SEQ_MASK
+}
+
+pub(crate) fn taken(flag : usize) -> bool {
+  flag & TAKEN != 0
+}
+
+// control words of a ring for `size` items, padding included
+pub(crate) fn ctrl_words(size : usize) -> usize {
+  FIRST + size
+}
+
+// panics unless `len` data slots make a valid ring
+pub(crate) fn check_slots(len : usize) {
+  if len < 3 || len.is_multiple_of(2) { panic!("storage must hold 2*size+1 elements, got {}", len); }
+  if len > 2 * MAX_SIZE + 1 { panic!("at most {} slots fit the flag layout on this target, got {}", 2 * MAX_SIZE + 1, len); }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn round_trip() {
+    for &p in [0, 1, 12345, MAX_SLOTS - 1].iter() {
+      for &s in [0, 1, SEQ_MASK, SEQ_MASK + 1, usize::MAX].iter() {
+        let f = pack(p, s);
+        assert_eq!(pos(f), p);
+        assert_eq!(seq(f), s & SEQ_MASK);
+        assert!(!taken(f));
+        assert_eq!(pos(f | TAKEN), p);
+        assert_eq!(seq(f | TAKEN), s & SEQ_MASK);
+        assert!(taken(f | TAKEN));
+      }
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "fit the flag layout")]
+  fn too_many_slots() {
+    check_slots(MAX_SLOTS + 1);
+  }
+
+  #[test]
+  fn a_lap_moves_the_seq() {
+    check_slots(2 * MAX_SIZE + 1);
+    assert_ne!(seq(MAX_SIZE), seq(0));
+  }
+}
@@ -0,0 +1,158 @@
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use super::{CircularBufferIterator, Label, TryPutError, Receiver, Sender};
+use seq::Seqno;
+use storage::RingStorage;
+
+// a sender or receiver with hooks around its calls, to look closely at
+// one suspect channel while the others run as they are. the halves
+// themselves do not change, only calls made through the wrapper are seen
+//
+// every call is counted. one in `sample_every` calls is also timed and
+// handed to the on_sample() callback, which runs on the calling thread
+// right after the call, so whatever it does is paid for there
+pub struct Instrumented<H> {
+  inner     : H,
+  every     : usize,
+  calls     : Cell<u64>,
+  items     : Cell<u64>,
+  on_sample : Option<Hook>,
+}
+
+type Hook = Box<dyn Fn(&Event<'_>) + Send>;
+
+// one sampled call
+#[derive(Clone, Copy, Debug)]
+pub struct Event<'a> {
+  pub label : &'a Label,
+  pub seqno : Option<Seqno>,    // of the item put or the first one taken
+  pub items : usize,            // put or taken
+  pub took  : Duration,         // inside the call, waiting for room included
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+  pub calls : u64,              // puts or try_iter()s, failed ones included
+  pub items : u64,
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  // every call counted, none sampled until on_sample() is set
+  pub fn instrumented(self) -> Instrumented<Sender<T, S>> {
+    Instrumented::new(self)
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Receiver<T, S> {
+  pub fn instrumented(self) -> Instrumented<Receiver<T, S>> {
+    Instrumented::new(self)
+  }
+}
+
+impl<H> Instrumented<H> {
+  fn new(inner : H) -> Instrumented<H> {
+    Instrumented { inner, every : 1, calls : Cell::new(0), items : Cell::new(0), on_sample : None }
+  }
+
+  // the first call and every n-th after it, 1 samples all of them
+  pub fn sample_every(mut self, n : usize) -> Instrumented<H> {
+    if n == 0 { panic!("sampling interval cannot be zero"); }
+    self.every = n;
+    self
+  }
+
+  pub fn on_sample<F : Fn(&Event<'_>) + Send + 'static>(mut self, f : F) -> Instrumented<H> {
+    self.on_sample = Some(Box::new(f));
+    self
+  }
+
+  pub fn counts(&self) -> Counts {
+    Counts { calls : self.calls.get(), items : self.items.get() }
+  }
+
+  pub fn inner(&self) -> &H {
+    &self.inner
+  }
+
+  pub fn into_inner(self) -> H {
+    self.inner
+  }
+
+  // runs `call`, counting what `done` says it did and timing it if it
+  // is sampled. the clock is read for sampled calls only
+  fn record<R, C, D>(&self, label : &Label, call : C, done : D) -> R
+    where C : FnOnce() -> R,
+          D : Fn(&R) -> (Option<Seqno>, usize)
+  {
+    let hook = match self.on_sample {
+      Some(ref hook) if self.calls.get().is_multiple_of(self.every as u64) => Some(hook),
+      _ => None,
+    };
+    let start = hook.map(|_| Instant::now());
+    let ret = call();
+    let took = start.map(|s| s.elapsed());
+
+    let (seqno, items) = done(&ret);
+    self.calls.set(self.calls.get() + 1);
+    self.items.set(self.items.get() + items as u64);
+    if let (Some(hook), Some(took)) = (hook, took) {
+      hook(&Event { label, seqno, items, took });
+    }
+    ret
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Instrumented<Sender<T, S>> {
+  pub fn put<F>(&self, setter : F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    self.record(self.inner.label(), || self.inner.put(setter), |&seqno| (Some(seqno), 1))
+  }
+
+  pub fn try_put<F>(&self, setter : F, max_retries : usize) -> Result<Seqno, TryPutError>
+    where F : FnMut(&mut T)
+  {
+    self.record(self.inner.label(), || self.inner.try_put(setter, max_retries), |r| match *r {
+      Ok(seqno) => (Some(seqno), 1),
+      Err(_)    => (None, 0),
+    })
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Instrumented<Receiver<T, S>> {
+  // the items are taken by the call, iterating them is not timed
+  pub fn try_iter(&self) -> CircularBufferIterator<'_, T> {
+    self.record(self.inner.label(), || self.inner.try_iter(), |batch| match batch.count {
+      0     => (None, 0),
+      count => (Some(batch.seqno), count),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use spsc;
+  use super::Counts;
+
+  #[test]
+  fn counts_all_samples_some() {
+    let (tx, rx) = spsc::channel(8, 0i32);
+    let seen = Arc::new(Mutex::new(vec![]));
+    let tx = {
+      let seen = seen.clone();
+      tx.instrumented().sample_every(2).on_sample(move |e| seen.lock().unwrap().push((e.seqno, e.items)))
+    };
+    let rx = rx.instrumented();
+    for i in 0..5 { tx.put(|v| *v = i); }
+    assert_eq!(*seen.lock().unwrap(), vec![(Some(0), 1), (Some(2), 1), (Some(4), 1)]);
+    assert_eq!(tx.counts(), Counts { calls : 5, items : 5 });
+
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![0, 1, 2, 3, 4]);
+    assert_eq!(rx.try_iter().count(), 0);
+    assert_eq!(rx.counts(), Counts { calls : 2, items : 5 });
+    assert_eq!(rx.inner().label(), tx.inner().label());
+  }
+}
@@ -0,0 +1,87 @@
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{flag, CircularBuffer};
+use storage::RingStorage;
+
+// structural checks after every put and iter, see the debug-invariants
+// feature. each side only checks what the other one cannot change under
+// it, so they hold with both halves running:
+//
+//  - the writer: the flag it swapped in last carries its seqno, every flag
+//    names a valid data slot and none of them write_tmp
+//  - the reader: read_priv names distinct valid slots, none of them behind
+//    a flag, and max_read is not past ctrl[0]
+//
+// together the slots behind the flags, in read_priv and write_tmp make up
+// every data slot exactly once. a violation panics with the control words
+// and the checking side's own state, the other side's may be changing
+impl <T : Clone, S : RingStorage<T>, C : RingStorage<AtomicUsize>> CircularBuffer<T, S, C> {
+  pub(super) fn check_writer(&self) {
+    let len = 2*self.size + 1;
+    let w = unsafe { &*self.writer.get() };
+    // a take keeps the seq bits, so the reader cannot change this one
+    let last = self.write_seqno().wrapping_sub(1);
+    if flag::seq(self.flag(self.pos(last)).load(Ordering::Relaxed)) != flag::seq(last) {
+      self.broken("the latest flag does not carry the writer's seqno", w);
+    }
+    if w.write_tmp >= len { self.broken("write_tmp is out of bounds", w); }
+    for f in self.flags() {
+      if flag::pos(f) >= len { self.broken("a flag points out of bounds", w); }
+      if flag::pos(f) == w.write_tmp { self.broken("a flag points to write_tmp", w); }
+    }
+  }
+
+  pub(super) fn check_reader(&self) {
+    let len = 2*self.size + 1;
+    let r = unsafe { &*self.reader.get() };
+    let mut owned = vec![false; len];
+    for &p in r.read_priv.iter() {
+      if p >= len { self.broken("read_priv is out of bounds", r); }
+      if owned[p] { self.broken("read_priv holds a slot twice", r); }
+      owned[p] = true;
+    }
+    if self.flags().any(|f| owned[flag::pos(f)]) { self.broken("a flag points to a slot in read_priv", r); }
+    let seqno = self.seqno().load(Ordering::Relaxed);
+    if r.max_read != seqno && !::seq::before(r.max_read, seqno) {
+      self.broken("max_read is past ctrl[0]", r);
+    }
+  }
+
+  fn flags(&self) -> impl Iterator<Item = usize> + '_ {
+    self.ctrl.slots()[flag::FIRST..].iter().map(|f| f.load(Ordering::Relaxed))
+  }
+
+  fn broken(&self, what : &str, side : &dyn Debug) -> ! {
+    let ctrl = self.ctrl.slots();
+    let flags : Vec<usize> = self.flags().collect();
+    panic!("spsc ring invariant broken, {}: seqno {}, flags {:?}, {:?}", what, ctrl[0].load(Ordering::Relaxed), flags, side);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use spsc::CircularBuffer;
+
+  #[test]
+  fn holds_through_puts_and_reads() {
+    let mut ring = CircularBuffer::new(3, 0i32);
+    for i in 0..20 {
+      ring.put(|v| *v = i);
+      if i % 3 == 0 { ring.iter().count(); }
+      ring.check_writer();
+      ring.check_reader();
+    }
+  }
+
+  #[test]
+  #[should_panic(expected = "spsc ring invariant broken")]
+  fn catches_a_doubly_owned_slot() {
+    let mut ring = CircularBuffer::new(2, 0i32);
+    ring.put(|v| *v = 1);
+    let r = ring.reader.get_mut();
+    r.read_priv[1] = r.read_priv[0];
+    ring.iter().count();
+  }
+}
@@ -0,0 +1,62 @@
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// which channel a half belongs to, for Debug output, panic messages and
+// whoever reports stats or events per channel. every channel gets a
+// process wide id when it is made, Builder::name() adds a name to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Label {
+  id   : u64,
+  name : Option<Arc<str>>,
+}
+
+static NEXT_ID : AtomicU64 = AtomicU64::new(1);
+
+impl Label {
+  pub(crate) fn next() -> Label {
+    Label { id : NEXT_ID.fetch_add(1, Ordering::Relaxed), name : None }
+  }
+
+  pub(crate) fn set_name(&mut self, name : Arc<str>) {
+    self.name = Some(name);
+  }
+
+  // unique among the channels of this process, counting from 1
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_deref()
+  }
+}
+
+// `channel "orders" (#3)`, or `channel #3` without a name
+impl fmt::Display for Label {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.name {
+      Some(ref name) => write!(f, "channel {:?} (#{})", name, self.id),
+      None           => write!(f, "channel #{}", self.id),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use spsc::{self, Builder};
+
+  #[test]
+  fn both_halves_carry_it() {
+    let (tx, rx) = Builder::new().capacity(2).name("orders").build::<i32>();
+    assert_eq!(tx.label(), rx.label());
+    assert_eq!(tx.label().name(), Some("orders"));
+    assert_eq!(tx.label().to_string(), format!("channel \"orders\" (#{})", tx.label().id()));
+
+    let (other, _) = spsc::channel(2, 0i32);
+    assert!(other.label().id() > tx.label().id());
+    assert_eq!(other.label().to_string(), format!("channel #{}", other.label().id()));
+    assert!(format!("{:?}", rx).contains("\"orders\""));
+  }
+}
@@ -0,0 +1,88 @@
+
+// litmus shapes the ring relies on, run as plain stress tests
+//
+// loom is not available to this crate, so these can only catch a broken
+// ordering on hardware that actually reorders (arm, power). on x86 they
+// still catch a missing fence that the compiler took advantage of
+//
+// message passing: data written before the release fence in put must be
+// seen whole by the reader that takes the flag, and the reader must be
+// done cloning a slot before the writer gets it back
+//
+// store buffer: the receiver announcing that it parks and the sender
+// publishing must not both miss each other, or a wakeup is lost
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::channel_with_wait;
+use wait::{Blocking, SpinThenPark, WaitStrategy};
+
+const WORDS : usize = 8;
+
+fn whole(item : &[u64; WORDS]) -> bool {
+  item.iter().all(|w| *w == item[0])
+}
+
+#[test]
+fn message_passing() {
+  const ITEMS : u64 = 200_000;
+
+  let (tx, rx) = channel_with_wait(4, [0u64; WORDS], Arc::new(SpinThenPark::default()));
+  let t = thread::spawn(move || {
+    for i in 1..ITEMS+1 { tx.put(|v| *v = [i; WORDS]); }
+  });
+
+  let mut last = 0;
+  while last < ITEMS {
+    for item in rx.try_iter() {
+      assert!(whole(&item), "torn item {:?}", item);
+      assert!(item[0] > last);
+      last = item[0];
+    }
+  }
+  t.join().unwrap();
+}
+
+// one item at a time with an ack going back, so every wait() really parks.
+// a lost wakeup shows up as a wait() running into its timeout, max_park
+// is longer than that so the safety net cannot hide it
+fn ping_pong(strategy : &dyn Fn() -> Arc<dyn WaitStrategy>) {
+  const ROUNDS : u64 = 2000;
+  let timeout = Some(Duration::from_secs(5));
+
+  let (ping_tx, ping_rx) = channel_with_wait(1, 0u64, strategy());
+  let (pong_tx, pong_rx) = channel_with_wait(1, 0u64, strategy());
+
+  let t = thread::spawn(move || {
+    let mut seen = 0;
+    while seen < ROUNDS {
+      assert!(ping_rx.wait(timeout), "lost wakeup after {}", seen);
+      for v in ping_rx.try_iter() {
+        seen = v;
+        pong_tx.put(|p| *p = v);
+      }
+    }
+  });
+
+  for i in 1..ROUNDS+1 {
+    ping_tx.put(|v| *v = i);
+    let mut acked = false;
+    while !acked {
+      assert!(pong_rx.wait(timeout), "lost wakeup at {}", i);
+      acked = pong_rx.try_iter().any(|v| v == i);
+    }
+  }
+  t.join().unwrap();
+}
+
+#[test]
+fn store_buffer_spin_then_park() {
+  ping_pong(&|| Arc::new(SpinThenPark::new(0, Duration::from_secs(10))));
+}
+
+#[test]
+fn store_buffer_blocking() {
+  ping_pong(&|| Arc::new(Blocking::default()));
+}
@@ -1,121 +1,783 @@
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+mod buffered;
+mod builder;
+mod credit;
+mod drain;
+mod dump;
+#[cfg(target_os = "linux")]
+mod eventfd;
+mod fault;
+mod fixed;
+pub(crate) mod flag;
+mod instrumented;
+#[cfg(all(debug_assertions, any(test, feature = "debug-invariants")))]
+mod invariants;
+mod label;
+#[cfg(test)]
+mod litmus;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod sim;
+mod snapshot;
+mod stats;
+mod timing;
+
+pub use self::buffered::BufferedSender;
+pub use self::builder::{Builder, Policy, Profile};
+pub use self::credit::{CreditedSender, Grant};
+pub use self::drain::Drained;
+pub use self::dump::{dump_state, FlagState, RingState};
+#[cfg(any(test, feature = "fault-injection"))]
+pub use self::fault::{Faults, Point};
+pub use self::fixed::{StaticChannel, StaticReceiver, StaticSender};
+pub use self::instrumented::{Counts, Event, Instrumented};
+pub use self::label::Label;
+pub use self::snapshot::{restore, restore_with_storage, snapshot, ChannelState};
+pub use self::stats::{ReceiverStats, SenderStats};
+pub use self::timing::TimingStats;
 
-struct CircularBuffer<T : Copy> {
-  seqno       : AtomicUsize,        // the ID of the last written item
-  data        : Vec<T>,             // (2*n)+1 preallocated elements
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use self::fault::Inject;
+#[cfg(not(any(test, feature = "fault-injection")))]
+use self::fault::Point;
+use fence;
+use queue::RingQueue;
+use seq::{self, Seqno};
+use storage::{AlignedBuf, CachePadded, RingStorage};
+
+/// The ring behind [`channel()`], usable on its own from one thread.
+///
+/// It holds `2*n+1` slots for a capacity of `n`: the `n` published items,
+/// the `n` the reader took last time and one the writer fills. [`put()`]
+/// overwrites the oldest item once the ring is full, [`iter()`] hands out
+/// whatever was published since the previous call, newest `n` at most.
+///
+/// ```
+/// let mut ring = rpg::spsc::CircularBuffer::new(2, 0i32);
+/// ring.put(|v| *v = 1);
+/// ring.put(|v| *v = 2);
+/// ring.put(|v| *v = 3);
+/// assert_eq!(ring.len(), 2);
+/// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![2, 3]);
+/// assert!(ring.is_empty());
+/// ```
+///
+/// [`put()`]: #method.put
+/// [`iter()`]: #method.iter
+pub struct CircularBuffer<T : Clone, S : RingStorage<T> = AlignedBuf<T>, C : RingStorage<AtomicUsize> = Vec<AtomicUsize>> {
+  data        : UnsafeCell<S>,      // (2*n)+1 preallocated elements, see slot()
   size        : usize,              // n
+  mask        : usize,              // n-1 if n is a power of two, else 0
 
-  buffer      : Vec<AtomicUsize>,   // (positions+seqno)[]
-  read_priv   : Vec<usize>,         // positions belong to the reader
+  ctrl        : C,                  // seqno, padding, then (positions+seqno)[], see flag.rs
+  writer      : CachePadded<UnsafeCell<Writer>>,
+  reader      : CachePadded<UnsafeCell<Reader>>,
+  poisoned    : AtomicBool,         // the writer side died in a panic
+  reader_gone : AtomicBool,         // the Receiver was dropped
+  writer_gone : AtomicBool,         // the Sender was dropped
+  finished    : AtomicBool,         // ... through Sender::finish()
+  backoff     : Option<Arc<dyn WaitStrategy>>, // between failed CAS, wait::backoff() if None
+  faults      : fault::Slot,        // injected stalls and CAS failures, tests only
+  _ty         : PhantomData<T>,
+}
+
+// each side's own bookkeeping sits on cache lines of its own, so a put
+// does not invalidate the line the reader is updating and the other way
+// round. what both touch are the control words and the slots, and the
+// flags they hand each other. the rest is set up once and only read
+//
+// the Sender and the Receiver share the ring, so it is never borrowed
+// mutably as a whole: each side's state is in a cell only that side
+// borrows, and a data slot is only borrowed by whoever owns it as of the
+// flags, see slot()
+#[derive(Debug)]
+struct Writer {
   write_tmp   : usize,              // temporary position where the writer writes first
+  last_put    : usize,              // where the latest published item lives
+  put_count   : Seqno,              // the writer's seqno, ctrl[0] is just its low bits
+  unpublished : usize,              // puts not in ctrl[0] yet, see publish()
+  publish_at  : usize,              // publish() once that many are held back, 1 unless batched
+  put_retries : usize,              // failed flag CAS in put
+  put_gave_up : usize,              // puts that ran out of retries
+  evictions   : u64,                // unread items overwritten
+}
+
+#[derive(Debug)]
+struct Reader {
+  read_priv   : Vec<usize>,         // positions belong to the reader
   max_read    : usize,              // reader's last read seqno
+  read_epoch  : Seqno,              // what the reader adds to ctrl[0] for its wraps
+  iter_misses : usize,              // failed flag CAS in iter
+  iter_cut    : usize,              // iters a lost CAS ended early
+  reads       : usize,              // iters that took something
+  taken       : u64,                // items those took
 }
 
-pub struct CircularBufferIterator<'a, T: 'a + Copy> {
-  data   : &'a [T],
-  revpos : &'a [usize],
-  count  : usize,
+/// A [`CircularBuffer`] taken apart by
+/// [`into_raw_parts()`](struct.CircularBuffer.html#method.into_raw_parts),
+/// laid out so it can be kept on the other side of an FFI boundary.
+///
+/// The fields are the ring's own, to be handed back unchanged.
+#[repr(C)]
+pub struct RawParts<T> {
+  pub data       : *mut T,            // 2*capacity+1 initialized slots
+  pub align      : usize,             // of the data allocation
+  pub capacity   : usize,
+  pub ctrl       : *mut AtomicUsize,  // the seqno, a cache line later capacity flags
+  pub read_priv  : *mut usize,        // capacity positions
+  pub write_tmp  : usize,
+  pub last_put   : usize,
+  pub max_read   : usize,
+  pub put_count  : Seqno,
+  pub read_epoch : Seqno,
+  pub poisoned   : bool,
 }
 
-impl <T : Copy> CircularBuffer<T> {
-  fn new(size : usize, default_value : T) -> CircularBuffer<T> {
+/// The items one [`CircularBuffer::iter()`] call took, oldest first.
+pub struct CircularBufferIterator<'a, T: 'a + Clone> {
+  data              : *const T,     // the ring's first slot
+  pub(crate) revpos : &'a [usize],
+  pub(crate) count  : usize,
+  pub(crate) seqno  : Seqno,        // of the next item returned
+  reading           : Option<&'a Cell<bool>>, // the Receiver's, cleared on drop
+}
 
-    if size == 0 { panic!("size cannot be zero"); }
+// yields (seqno, item), the seqno counts every put since the start
+pub struct EnumeratedIterator<'a, T: 'a + Clone> {
+  inner : CircularBufferIterator<'a, T>,
+}
 
-    let mut ret = CircularBuffer {
-      seqno      : AtomicUsize::new(0),
-      data       : vec![],
-      size       : size,
-      buffer     : vec![],
-      read_priv  : vec![],
-      write_tmp  : 0,
-      max_read   : 0,
-    };
+impl <T : Clone> CircularBuffer<T> {
+  /// A ring for `size` items, every slot starts as a clone of
+  /// `default_value`. Panics if `size` is zero or too large for the flags.
+  pub fn new(size : usize, default_value : T) -> CircularBuffer<T> {
+
+    if size == 0 { panic!("size cannot be zero"); }
+    flag::check_slots(size.checked_mul(2).and_then(|n| n.checked_add(1)).unwrap_or(usize::MAX));
 
     // make sure there is enough place and fill it with the
-    // default value
-    ret.data.resize((size*2)+1, default_value);
+    // default value, the first slot starts on a cache line
+    CircularBuffer::with_storage(AlignedBuf::new((size*2)+1, default_value))
+  }
+
+  /// Takes the ring apart without dropping anything, it comes back with
+  /// [`from_raw_parts()`](#method.from_raw_parts). The CAS retry counters,
+  /// the backoff strategy and any injected faults are not part of it.
+  ///
+  /// ```
+  /// use rpg::spsc::CircularBuffer;
+  ///
+  /// let mut ring = CircularBuffer::new(2, 0i32);
+  /// ring.put(|v| *v = 1);
+  /// let parts = ring.into_raw_parts();
+  /// let mut ring = unsafe { CircularBuffer::from_raw_parts(parts) };
+  /// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![1]);
+  /// ```
+  pub fn into_raw_parts(self) -> RawParts<T> {
+    // the backoff strategy and the faults are dropped here
+    let CircularBuffer { data, size, ctrl, writer, reader, poisoned, .. } = self;
+    let (writer, reader) = (writer.into_inner().into_inner(), reader.into_inner().into_inner());
+
+    let (data, _, align) = data.into_inner().into_raw_parts();
+    RawParts {
+      data,
+      align,
+      capacity   : size,
+      ctrl       : Box::into_raw(ctrl.into_boxed_slice()) as *mut AtomicUsize,
+      read_priv  : Box::into_raw(reader.read_priv.into_boxed_slice()) as *mut usize,
+      write_tmp  : writer.write_tmp,
+      last_put   : writer.last_put,
+      max_read   : reader.max_read,
+      put_count  : writer.put_count,
+      read_epoch : reader.read_epoch,
+      poisoned   : poisoned.into_inner(),
+    }
+  }
+
+  /// Puts a ring taken apart by [`into_raw_parts()`](#method.into_raw_parts)
+  /// back together, it owns the slots and the control words again.
+  ///
+  /// # Safety
+  ///
+  /// Every field must be unchanged from a single `into_raw_parts()` call
+  /// on a ring of the same `T`, and no other ring may be rebuilt from
+  /// them. Unlike the simple ring nothing may be written in the meantime,
+  /// the slots a position points to belong to whoever holds the flag.
+  pub unsafe fn from_raw_parts(parts : RawParts<T>) -> CircularBuffer<T> {
+    let size = parts.capacity;
+    let ctrl      = Box::from_raw(ptr::slice_from_raw_parts_mut(parts.ctrl, flag::ctrl_words(size)));
+    let read_priv = Box::from_raw(ptr::slice_from_raw_parts_mut(parts.read_priv, size));
+
+    CircularBuffer {
+      data        : UnsafeCell::new(AlignedBuf::from_raw_parts(parts.data, (size*2)+1, parts.align)),
+      size,
+      mask        : if size.is_power_of_two() { size - 1 } else { 0 },
+      ctrl        : ctrl.into_vec(),
+      writer      : CachePadded::new(UnsafeCell::new(Writer {
+        write_tmp   : parts.write_tmp,
+        last_put    : parts.last_put,
+        put_count   : parts.put_count,
+        unpublished : 0,
+        publish_at  : 1,
+        put_retries : 0,
+        put_gave_up : 0,
+        evictions   : 0,
+      })),
+      reader      : CachePadded::new(UnsafeCell::new(Reader {
+        read_priv   : read_priv.into_vec(),
+        max_read    : parts.max_read,
+        read_epoch  : parts.read_epoch,
+        iter_misses : 0,
+        iter_cut    : 0,
+        reads       : 0,
+        taken       : 0,
+      })),
+      poisoned    : AtomicBool::new(parts.poisoned),
+      reader_gone : AtomicBool::new(false),
+      writer_gone : AtomicBool::new(false),
+      finished    : AtomicBool::new(false),
+      backoff     : None,
+      faults      : Default::default(),
+      _ty         : PhantomData,
+    }
+  }
+}
+
+impl <T : Clone, S : RingStorage<T>> CircularBuffer<T, S> {
+  /// A ring over caller provided slots, which must hold `2*n+1` elements
+  /// for a capacity of `n`.
+  pub fn with_storage(storage : S) -> CircularBuffer<T, S> {
+
+    let len = storage.slots().len();
+    flag::check_slots(len);
+
+    let ctrl = (0..flag::ctrl_words((len-1)/2)).map(|_| AtomicUsize::new(0)).collect();
+    CircularBuffer::with_parts(storage, ctrl, true)
+  }
+}
+
+impl <T : Clone, S : RingStorage<T>, C : RingStorage<AtomicUsize>> CircularBuffer<T, S, C> {
+  // the control words may already be set up by the other side
+  // (e.g. another process), in that case `init` must be false
+  pub(crate) fn with_parts(storage : S, ctrl : C, init : bool) -> CircularBuffer<T, S, C> {
+
+    let len = storage.slots().len();
+    flag::check_slots(len);
 
-    for i in 0..size {
-      ret.buffer.push(AtomicUsize::new((1+i) << 16));
-      ret.read_priv.push(1+size+i);
+    let size = (len-1)/2;
+    if ctrl.slots().len() != flag::ctrl_words(size) {
+      panic!("control storage must hold {} words for {} items, got {}", flag::ctrl_words(size), size, ctrl.slots().len());
     }
 
+    let ret = CircularBuffer::uninit(storage, ctrl, size);
+    // nobody else has the ring yet
+    unsafe { ret.set_up(init); }
     ret
   }
 
-  fn put<F>(&mut self, setter: F) -> usize
+  // a ring for `size` items that set_up() still has to be called on, so
+  // it can be made in const context. the storage sizes are not checked
+  pub(crate) const fn uninit(storage : S, ctrl : C, size : usize) -> CircularBuffer<T, S, C> {
+    CircularBuffer {
+      data        : UnsafeCell::new(storage),
+      size,
+      mask        : if size.is_power_of_two() { size - 1 } else { 0 },
+      ctrl,
+      writer      : CachePadded::new(UnsafeCell::new(Writer {
+        write_tmp   : 0,
+        last_put    : 0,
+        put_count   : 0,
+        unpublished : 0,
+        publish_at  : 1,
+        put_retries : 0,
+        put_gave_up : 0,
+        evictions   : 0,
+      })),
+      reader      : CachePadded::new(UnsafeCell::new(Reader {
+        read_priv   : Vec::new(),
+        max_read    : 0,
+        read_epoch  : 0,
+        iter_misses : 0,
+        iter_cut    : 0,
+        reads       : 0,
+        taken       : 0,
+      })),
+      poisoned    : AtomicBool::new(false),
+      reader_gone : AtomicBool::new(false),
+      writer_gone : AtomicBool::new(false),
+      finished    : AtomicBool::new(false),
+      backoff     : None,
+      faults      : fault::NONE,
+      _ty         : PhantomData,
+    }
+  }
+
+  // hands the reader its slots and, if `init`, sets up the control words
+  // (see with_parts()). unsafe: only once, before either side uses the ring
+  unsafe fn set_up(&self, init : bool) {
+    if init {
+      self.seqno().store(0, Ordering::SeqCst);
+    }
+
+    let r = self.reader();
+    for i in 0..self.size {
+      if init {
+        self.flag(i).store(flag::pack(1+i, 0) | flag::TAKEN, Ordering::SeqCst);
+      }
+      r.read_priv.push(1+self.size+i);
+    }
+  }
+
+  fn backoff(&self, attempt : usize) {
+    match self.backoff {
+      Some(ref strategy) => strategy.backoff(attempt),
+      None               => wait::backoff(attempt),
+    }
+  }
+
+  // seqno % n, without dividing for a power of two n
+  fn pos(&self, seqno : usize) -> usize {
+    if self.mask != 0 { seqno & self.mask } else { seqno % self.size }
+  }
+
+  fn seqno(&self) -> &AtomicUsize {
+    &self.ctrl.slots()[0]
+  }
+
+  // the control word of position `pos`
+  fn flag(&self, pos : usize) -> &AtomicUsize {
+    &self.ctrl.slots()[flag::FIRST + pos]
+  }
+
+  // data slot `at`, 2*n+1 of them. the ring never borrows all of them:
+  // the writer owns write_tmp, the reader what is in read_priv, and a
+  // slot behind a flag is only ever read, by both sides
+  fn slot(&self, at : usize) -> *mut T {
+    if at > 2*self.size { panic!("data index is out of bounds {}", at); }
+    unsafe { S::slots_ptr(self.data.get()).add(at) }
+  }
+
+  // the writer's own state. unsafe: only the writing side may call it,
+  // and not while another borrow of it is alive
+  #[allow(clippy::mut_from_ref)]
+  unsafe fn writer(&self) -> &mut Writer {
+    &mut *self.writer.get()
+  }
+
+  // the same for the reader
+  #[allow(clippy::mut_from_ref)]
+  unsafe fn reader(&self) -> &mut Reader {
+    &mut *self.reader.get()
+  }
+
+  // where the writer is, ctrl[0] plus the puts it holds back
+  fn write_seqno(&self) -> usize {
+    self.seqno().load(Ordering::Relaxed).wrapping_add(unsafe { (*self.writer.get()).unpublished })
+  }
+
+  // moves ctrl[0] past the held back puts in one store, false if there
+  // were none. their flags are already swapped in, until then the reader
+  // just does not look at them
+  //
+  // this and the other unsafe puts below are the writer side, shared with
+  // a reader that may be in iter() meanwhile. unsafe: at most one of them
+  // runs at a time, see Sender::with_ring()
+  pub(crate) unsafe fn publish(&self) -> bool {
+    let w = self.writer();
+    if w.unpublished == 0 { return false; }
+    self.seqno().fetch_add(w.unpublished, Ordering::Relaxed);
+    w.unpublished = 0;
+    true
+  }
+
+  /// The most items one [`iter()`](#method.iter) can return.
+  pub fn capacity(&self) -> usize {
+    self.size
+  }
+
+  /// How many items the next [`iter()`](#method.iter) would return.
+  pub fn len(&self) -> usize {
+    let max_read = unsafe { (*self.reader.get()).max_read };
+    (seq::distance(max_read, self.seqno().load(Ordering::Relaxed)) as usize).min(self.size)
+  }
+
+  /// True if nothing was put since the last [`iter()`](#method.iter).
+  pub fn is_empty(&self) -> bool {
+    !self.has_unread()
+  }
+
+  /// Clones of what the next [`iter()`](#method.iter) would return,
+  /// oldest first, without taking anything.
+  ///
+  /// ```
+  /// let mut ring = rpg::spsc::CircularBuffer::new(2, 0i32);
+  /// for i in 1..4 { ring.put(|v| *v = i); }
+  /// assert_eq!(ring.to_vec(), vec![2, 3]);
+  /// assert_eq!(ring.iter().count(), 2);
+  /// ```
+  pub fn to_vec(&self) -> Vec<T> {
+    let seqno = self.seqno().load(Ordering::Relaxed);
+    let unread = self.len();
+    (0..unread).map(|i| {
+      let at = seqno.wrapping_sub(unread - i);
+      let f = self.flag(self.pos(at)).load(Ordering::Relaxed);
+      debug_assert!(!flag::taken(f) && flag::seq(f) == flag::seq(at));
+      unsafe { (*self.slot(flag::pos(f))).clone() }
+    }).collect()
+  }
+
+  /// [`to_vec()`](#method.to_vec) of a ring that is not needed any more.
+  pub fn into_vec(self) -> Vec<T> {
+    self.to_vec()
+  }
+
+  // false if the next put would evict an item the reader has not taken,
+  // the reader marks every flag it takes
+  fn has_room(&self) -> bool {
+    let pos = self.pos(self.write_seqno());
+    flag::taken(self.flag(pos).load(Ordering::Relaxed)) ||
+      self.reader_gone.load(Ordering::SeqCst)
+  }
+
+  // how many puts in a row would find has_room(), i.e. the run of taken
+  // flags from the next slot on. a reader halfway through an iter() takes
+  // newest first and leaves a gap the count stops at, it is never too high
+  fn room(&self) -> usize {
+    if self.reader_gone.load(Ordering::SeqCst) { return self.size; }
+    let pos = self.pos(self.write_seqno());
+    (0..self.size)
+      .take_while(|i| flag::taken(self.flag(self.pos(pos + i)).load(Ordering::Relaxed)))
+      .count()
+  }
+
+  // true if the writer published past the reader's last iter()
+  pub(crate) fn has_unread(&self) -> bool {
+    self.seqno().load(Ordering::Relaxed) != unsafe { (*self.reader.get()).max_read }
+  }
+
+  /// Fills the next item in place with `setter` and publishes it,
+  /// evicting the oldest one if the ring is full. Returns its seqno,
+  /// counting from 0.
+  pub fn put<F>(&mut self, setter: F) -> Seqno
     where F : FnMut(&mut T)
   {
     let mut setter = setter;
+    self.put_at(|_, v| setter(v))
+  }
+
+  // like put() but hands back the item it evicted, if the reader
+  // has not taken it yet
+  unsafe fn put_replace(&self, value : T) -> Option<T> {
+    let mut value = Some(value);
+    let mut stale = None;
+    let (_, unread) = self.put_unbounded(|_, v| stale = value.take().map(|n| mem::replace(v, n)));
+
+    // the evicted item is now in write_tmp, park the stale one there instead
+    match (unread, stale) {
+      (true, Some(stale)) => Some(mem::replace(&mut *self.slot(self.writer().write_tmp), stale)),
+      _                   => None,
+    }
+  }
+
+  // puts only if pred() holds for the latest published item (the default
+  // value before the first put). only the writer ever changes data slots,
+  // so the reader cannot get between the check and the write
+  unsafe fn put_if<P, F>(&self, pred : P, setter : F) -> Option<Seqno>
+    where P : FnOnce(&T) -> bool,
+          F : FnMut(&mut T)
+  {
+    let mut setter = setter;
+    if pred(&*self.slot(self.writer().last_put)) {
+      Some(self.put_unbounded(|_, v| setter(v)).0)
+    } else {
+      None
+    }
+  }
+
+  // folds `value` into the latest item if the reader has not taken it yet.
+  // the merged copy is built in write_tmp and swapped in for the old one,
+  // so the reader gets either version whole. None if the reader has it,
+  // put_value() then puts `value` as a new item
+  unsafe fn try_merge<M>(&self, value : &T, merge : M) -> Option<Seqno>
+    where M : FnOnce(&mut T, &T)
+  {
+    let latest = self.write_seqno().wrapping_sub(1);
+    let (put_count, last_put, write_tmp) = {
+      let w = self.writer();
+      (w.put_count, w.last_put, w.write_tmp)
+    };
+    if put_count == 0 { return None; }
+
+    let pos      = self.pos(latest);
+    let old_flag = flag::pack(last_put, latest);
+    let new_flag = flag::pack(write_tmp, latest);
+    if self.flag(pos).load(Ordering::Relaxed) != old_flag { return None; }
+
+    let mut merged = (*self.slot(last_put)).clone();
+    merge(&mut merged, value);
+    *self.slot(write_tmp) = merged;
+
+    // publishes the merged copy, pairs with the fence at the end of iter().
+    // last_put was never taken, so nothing needs acquiring on success
+    fence::release();
+    let order = fence::on(Ordering::Relaxed, Ordering::Release);
+    let w = self.writer();
+    if self.flag(pos).compare_exchange(old_flag, new_flag, order, Ordering::Relaxed).is_ok() {
+      mem::swap(&mut w.last_put, &mut w.write_tmp);
+      return Some(w.put_count - 1);
+    }
+    // the reader took it meanwhile
+    w.put_retries += 1;
+    None
+  }
 
-    // get a reference to the data
-    let mut opt : Option<&mut T> = self.data.get_mut(self.write_tmp);
+  // puts a ready value as a new item
+  unsafe fn put_value(&self, value : T) -> Seqno {
+    let mut value = Some(value);
+    self.put_unbounded(|_, v| if let Some(n) = value.take() { *v = n; }).0
+  }
+
+  // like put() but the setter also learns which data slot it writes,
+  // so callers can keep per-slot side data
+  pub(crate) fn put_at<F>(&mut self, setter: F) -> Seqno
+    where F : FnMut(usize, &mut T)
+  {
+    // borrowed mutably, there is no other side
+    unsafe { self.put_unbounded(setter).0 }
+  }
+
+  // the slot the next put publishes, private to the writer until then
+  #[allow(clippy::mut_from_ref)]
+  unsafe fn reserved(&self) -> &mut T {
+    &mut *self.slot(self.writer().write_tmp)
+  }
+
+  unsafe fn put_unbounded<F>(&self, setter: F) -> (Seqno, bool)
+    where F : FnMut(usize, &mut T)
+  {
+    match self.put_evicting(setter, usize::MAX) {
+      Ok(ret) => ret,
+      Err(_)  => unreachable!("unbounded put gave up"),
+    }
+  }
+
+  // returns the seqno and whether the item in the slot now owned by
+  // write_tmp was still unread. gives up with nothing published after
+  // `max_retries` failed flag CAS
+  unsafe fn put_evicting<F>(&self, setter: F, max_retries : usize) -> Result<(Seqno, bool), TryPutError>
+    where F : FnMut(usize, &mut T)
+  {
+    let ret = self.swap_in(setter, max_retries)?;
+    // released by the fence in swap_in() as well. batched, the reader
+    // gets to see it with the last put of the batch
+    let due = { let w = self.writer(); w.unpublished >= w.publish_at };
+    if due { self.publish(); }
+    Ok(ret)
+  }
+
+  /// Puts a clone of every item and publishes them together, with one
+  /// update of the seqno, so a reader gets either all of them or none.
+  /// Returns the seqno of the last one, `None` for an empty batch.
+  /// Panics if the batch is larger than the capacity.
+  ///
+  /// ```
+  /// let mut ring = rpg::spsc::CircularBuffer::new(4, 0i32);
+  /// assert_eq!(ring.put_many(&[1, 2, 3]), Some(2));
+  /// assert_eq!(ring.iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+  /// ```
+  pub fn put_many(&mut self, items : &[T]) -> Option<Seqno> {
+    unsafe { self.put_batch(items) }
+  }
 
-    // write the data to the temporary writer buffer
-    match opt.as_mut() {
-      Some(v) => setter(v),
-      None    => { panic!("write tmp pos is out of bounds {}", self.write_tmp); }
+  unsafe fn put_batch(&self, items : &[T]) -> Option<Seqno> {
+    self.check_batch(items.len());
+    let mut last = None;
+    for item in items {
+      match self.swap_in(|_, v| v.clone_from(item), usize::MAX) {
+        Ok((seqno, _)) => last = Some(seqno),
+        Err(_)         => unreachable!("unbounded put gave up"),
+      }
     }
+    // with whatever publish_every() held back, if that is due
+    let due = { let w = self.writer(); w.unpublished >= w.publish_at };
+    if due { self.publish(); }
+    last
+  }
+
+  fn check_batch(&self, len : usize) {
+    if len > self.size { panic!("a batch of {} items does not fit a ring of {}", len, self.size); }
+  }
+
+  // the item into the next position, without moving ctrl[0]
+  unsafe fn swap_in<F>(&self, setter: F, max_retries : usize) -> Result<(Seqno, bool), TryPutError>
+    where F : FnMut(usize, &mut T)
+  {
+    let mut setter = setter;
+    let at = self.writer().write_tmp;
+
+    // write the data to the temporary writer buffer. the setter runs before
+    // any shared word is touched, so if it panics write_tmp, the flags and
+    // the seqno are as they were and the slot stays private to the writer.
+    // nothing of the writer's is borrowed meanwhile, the setter may look
+    // at the sender's stats
+    setter(at, &mut *self.slot(at));
+
+    // calculate writer flag position, only the writer stores ctrl[0]
+    let seqno  = self.write_seqno();
+    let pos    = self.pos(seqno);
 
-    // calculate writer flag position
-    let seqno  = self.seqno.load(Ordering::SeqCst);
-    let pos    = seqno % self.size;
+    // publish point: the data written above happens before any reader
+    // that takes the flag (pairs with the acquire fence at the end of iter())
+    fence::release();
 
     // get a reference to the writer flag
-    match self.buffer.get_mut(pos) {
+    let unread = match self.ctrl.slots().get(flag::FIRST + pos) {
       Some(v) => {
-        let mut old_flag : usize = (*v).load(Ordering::SeqCst);
-        let mut old_pos  : usize = old_flag >> 16;
-        let new_flag     : usize = (self.write_tmp << 16) + (seqno & 0xffff);
+        let mut old_flag : usize = (*v).load(Ordering::Relaxed);
+        let mut old_pos  : usize = flag::pos(old_flag);
+        let new_flag     : usize = flag::pack(at, seqno);
+        let mut retries  : usize = 0;
 
         loop {
-          let result = (*v).compare_and_swap(old_flag,
-                                             new_flag,
-                                             Ordering::SeqCst);
-          if result == old_flag {
-            self.write_tmp = old_pos;
-            break;
+          let cas = if self.faults.fires(Point::PutBeforeSwap) {
+            Err((*v).load(Ordering::Relaxed))
           } else {
-            old_flag = result;
-            old_pos  = old_flag >> 16;
+            (*v).compare_exchange(old_flag, new_flag, fence::on(Ordering::Relaxed, Ordering::AcqRel), Ordering::Relaxed)
           };
-        };
+          match cas {
+            Ok(_) => {
+              // old_pos may be a slot the reader handed back, its reads of
+              // it happen before we write there (pairs with the release
+              // fence at the start of iter())
+              fence::acquire();
+              let w = self.writer();
+              w.last_put  = at;
+              w.write_tmp = old_pos;
+              break !flag::taken(old_flag);
+            },
+            Err(result) => {
+              if retries >= max_retries {
+                self.writer().put_gave_up += 1;
+                return Err(TryPutError::Contended);
+              }
+              self.backoff(retries);
+              retries += 1;
+              old_flag = result;
+              old_pos  = flag::pos(old_flag);
+              self.writer().put_retries += 1;
+            },
+          };
+        }
       },
       None => { panic!("buffer index is out of bounds {}", pos); }
-    }
+    };
+
+    self.faults.fires(Point::PutAfterSwap);
 
-    // increase sequence number
-    self.seqno.fetch_add(1, Ordering::SeqCst)
+    let w = self.writer();
+    w.unpublished += 1;
+    w.put_count += 1;
+    if unread { w.evictions += 1; }
+    let put = w.put_count - 1;
+    #[cfg(all(debug_assertions, any(test, feature = "debug-invariants")))]
+    self.check_writer();
+    Ok((put, unread))
   }
 
-  fn iter(&mut self) -> CircularBufferIterator<T> {
-    let mut seqno : usize = self.seqno.load(Ordering::SeqCst);
+  /// Takes everything put since the previous call, at most
+  /// [`capacity()`](#method.capacity) of the newest items.
+  pub fn iter(&mut self) -> CircularBufferIterator<'_, T> {
+    // borrowed mutably, there is no other side
+    unsafe { self.take() }
+  }
+
+  // iter() for the reader side, shared with a writer that may be putting
+  // meanwhile. unsafe: at most one take() runs at a time, and not while
+  // the iterator of the previous one is alive, see Receiver::try_iter()
+  pub(crate) unsafe fn take(&self) -> CircularBufferIterator<'_, T> {
+    let mut seqno : usize = self.seqno().load(Ordering::Relaxed);
+    let data = self.slot(0) as *const T;
+    let r = self.reader();
+
+    // nothing new: an idle reader polling in a loop only reads the
+    // seqno's line, max_read is in the reader's own block
+    if seqno == r.max_read {
+      return CircularBufferIterator {
+        data,
+        revpos  : r.read_priv.as_slice(),
+        count   : 0,
+        seqno   : r.read_epoch.wrapping_add(seqno as Seqno),
+        reading : None,
+      };
+    }
+
     let mut count : usize = 0;
-    let max_read : usize = self.max_read;
-    self.max_read = seqno;
+    let mut merges: usize = 0;
+    let max_read : usize = r.max_read;
+    r.max_read = seqno;
+
+    // ctrl[0] went around since the last call (only ever on 32 bit)
+    if seqno < max_read && seq::before(max_read, seqno) {
+      r.read_epoch = r.read_epoch.wrapping_add((usize::MAX as Seqno).wrapping_add(1));
+    }
+    let top = r.read_epoch + seqno as Seqno;
+
+    // the previous iterator is gone, its reads of the read_priv slots
+    // happen before the writer reuses them (pairs with the acquire fence
+    // after the flag CAS in put_evicting())
+    fence::release();
 
     loop {
-      if count >= self.size || seqno <= max_read || seqno == 0 { break; }
-      let pos = (seqno-1) % self.size;
+      if count >= self.size || !seq::before(max_read, seqno) { break; }
+      let pos = self.pos(seqno.wrapping_sub(1));
 
-      match self.read_priv.get_mut(count) {
-        Some(r) => {
-          match self.buffer.get_mut(pos) {
+      match r.read_priv.get_mut(count) {
+        Some(rp) => {
+          match self.ctrl.slots().get(flag::FIRST + pos) {
             Some(v) => {
-              let old_flag : usize = (*v).load(Ordering::SeqCst);
-              let old_pos  : usize = old_flag >> 16;
-              let old_seq  : usize = old_flag & 0xffff;
-              let chk_flag : usize = (old_pos << 16) + ((seqno-1) & 0xffff);
-              let new_flag : usize = (*r << 16) + (old_seq & 0xffff);
-
-              if chk_flag == (*v).compare_and_swap(chk_flag, new_flag, Ordering::SeqCst) {
-                *r = old_pos;
-                seqno -=1;
-                count += 1;
+              let old_flag : usize = (*v).load(Ordering::Relaxed);
+              let old_pos  : usize = flag::pos(old_flag);
+              let chk_flag : usize = flag::pack(old_pos, seqno.wrapping_sub(1));
+              let new_flag : usize = flag::pack(*rp, flag::seq(old_flag)) | flag::TAKEN;
+
+              let cas = if self.faults.fires(Point::IterBeforeTake) {
+                Err((*v).load(Ordering::Relaxed))
               } else {
-                break;
+                (*v).compare_exchange(chk_flag, new_flag, fence::on(Ordering::Relaxed, Ordering::AcqRel), Ordering::Relaxed)
+              };
+              match cas {
+                Ok(_) => {
+                  *rp = old_pos;
+                  seqno = seqno.wrapping_sub(1);
+                  count += 1;
+                },
+                // same item, the writer merged into it, take the new version
+                // (bounded, a writer merging nonstop must not keep us here)
+                Err(now) if !flag::taken(now) && flag::seq(now) == flag::seq(seqno.wrapping_sub(1)) && merges < self.size => {
+                  self.backoff(merges);
+                  merges += 1;
+                  r.iter_misses += 1;
+                },
+                // still merged into after all those tries. only the writer's
+                // latest item is, so nothing was taken yet: leave it unread
+                // for the next call instead of skipping it
+                Err(now) if !flag::taken(now) && flag::seq(now) == flag::seq(seqno.wrapping_sub(1)) && count == 0 => {
+                  r.iter_misses += 1;
+                  r.max_read = seqno.wrapping_sub(1);
+                  // ctrl[0] just went around, the next call counts that
+                  // lap again
+                  if seqno == 0 {
+                    r.read_epoch = r.read_epoch.wrapping_sub((usize::MAX as Seqno).wrapping_add(1));
+                  }
+                  break;
+                },
+                Err(_) => {
+                  r.iter_misses += 1;
+                  r.iter_cut += 1;
+                  break;
+                },
               }
             },
             None => { panic!("buffer index is out of bounds {}", pos); }
@@ -125,138 +787,1657 @@ impl <T : Copy> CircularBuffer<T> {
       }
     }
 
+    // consume point: what the writer put into the slots we took is
+    // visible from here on (pairs with the release fences in the put paths)
+    if count > 0 {
+      fence::acquire();
+      r.reads += 1;
+      r.taken += count as u64;
+    }
+    #[cfg(all(debug_assertions, any(test, feature = "debug-invariants")))]
+    self.check_reader();
+
     CircularBufferIterator {
-      data    : self.data.as_slice(),
-      revpos  : self.read_priv.as_slice(),
-      count   : count,
+      data,
+      revpos  : (*self.reader.get()).read_priv.as_slice(),
+      count,
+      seqno   : top.wrapping_sub(count as Seqno),
+      reading : None,
     }
   }
 }
 
-impl <'_, T: '_ + Copy> Iterator for CircularBufferIterator<'_, T> {
+// try_push() fails where Policy::Block would wait
+impl <T : Clone, S : RingStorage<T>, C : RingStorage<AtomicUsize>> RingQueue<T> for CircularBuffer<T, S, C> {
+  fn push(&mut self, item : T) {
+    let mut item = Some(item);
+    self.put(|v| if let Some(n) = item.take() { *v = n; });
+  }
+
+  fn try_push(&mut self, item : T) -> Result<(), T> {
+    if !self.has_room() { return Err(item); }
+    self.push(item);
+    Ok(())
+  }
+
+  fn drain<F : FnMut(T)>(&mut self, f : F) -> usize {
+    let mut f = f;
+    let mut count = 0;
+    for v in self.iter() { f(v); count += 1; }
+    count
+  }
+
+  fn len(&self) -> usize { CircularBuffer::len(self) }
+  fn capacity(&self) -> usize { self.size }
+}
+
+impl <'a, T: 'a + Clone> CircularBufferIterator<'a, T> {
+  /// The newest item of the batch and its seqno, without cloning it.
+  /// None once everything was returned.
+  pub fn newest(&self) -> Option<(Seqno, &'a T)> {
+    if self.count == 0 { return None; }
+    let seqno = self.seqno.wrapping_add(self.count as Seqno - 1);
+    Some((seqno, self.slot(self.revpos[0])))
+  }
+
+  // a slot in revpos, the reader owns it until the iterator is dropped
+  pub(crate) fn slot(&self, pos : usize) -> &'a T {
+    unsafe { &*self.data.add(pos) }
+  }
+}
+
+impl <'a, T: 'a + Clone> Iterator for CircularBufferIterator<'a, T> {
   type Item = T;
 
   fn next(&mut self) -> Option<T> {
     if self.count > 0 {
       self.count -= 1;
+      self.seqno = self.seqno.wrapping_add(1);
       let pos : usize = self.revpos[self.count];
-      Some(self.data[pos])
+      Some(self.slot(pos).clone())
     } else {
       None
     }
   }
 }
 
+impl <'a, T: 'a + Clone> Drop for CircularBufferIterator<'a, T> {
+  fn drop(&mut self) {
+    if let Some(reading) = self.reading { reading.set(false); }
+  }
+}
+
+impl <'a, T: 'a + Clone> Iterator for EnumeratedIterator<'a, T> {
+  type Item = (Seqno, T);
+
+  fn next(&mut self) -> Option<(Seqno, T)> {
+    let seqno = self.inner.seqno;
+    self.inner.next().map(|v| (seqno, v))
+  }
+}
+
 // integrate into Rust multithreading
-use std::cell::UnsafeCell;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::error::Error;
+use std::fmt;
+use std::io::IoSlice;
+use std::iter::FusedIterator;
+use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use wait::{self, CancelToken, Canceled, SpinThenPark, WaitStrategy};
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use self::eventfd::EventFd;
+use self::timing::Timing;
+
+// both halves only need &self and share the ring through &, each only
+// goes into the cell of its own side's state (see Writer). neither is
+// Sync, so a half shared across threads has to sit behind a Mutex, and
+// the Cell guards catch the remaining misuse: a setter putting into its
+// own sender, or a second try_iter() while the first iterator is still
+// alive. the doc tests below keep it so
 
-pub struct Sender<T: Copy> {
-  inner: Arc<UnsafeCell<CircularBuffer<T>>>,
+/// The writing half of a channel. It can move to another thread, but it
+/// cannot be cloned or shared between threads:
+///
+/// ```
+/// let (tx, _rx) = rpg::spsc::channel(4, 0u32);
+/// std::thread::spawn(move || { tx.put(|v| *v = 1); }).join().unwrap();
+/// ```
+///
+/// ```compile_fail,E0599
+/// let (tx, _rx) = rpg::spsc::channel(4, 0u32);
+/// let _second_writer = tx.clone();
+/// ```
+///
+/// ```compile_fail,E0277
+/// fn shared<T : Sync>(_ : &T) { }
+/// let (tx, _rx) = rpg::spsc::channel(4, 0u32);
+/// shared(&tx);
+/// ```
+///
+/// Items must be `Send`, they cross threads:
+///
+/// ```compile_fail,E0277
+/// let (_tx, _rx) = rpg::spsc::channel(4, std::rc::Rc::new(0u32));
+/// ```
+pub struct Sender<T: Clone, S: RingStorage<T> = AlignedBuf<T>> {
+  inner: Arc<CircularBuffer<T, S>>,
+  label: Label,
+  writing: Cell<bool>,
+  policy: Policy,
+  batch: usize,
+  wait: Arc<dyn WaitStrategy>,
+  #[cfg(target_os = "linux")]
+  signal: Option<Arc<EventFd>>,
 }
 
-unsafe impl<T: Copy> Send for Sender<T> { }
+unsafe impl<T: Clone + Send, S: RingStorage<T>> Send for Sender<T, S> { }
 
-pub struct Receiver<T: Copy> {
-  inner: Arc<UnsafeCell<CircularBuffer<T>>>,
+// Sender::reserve(), the next slot to be published, written in place for
+// as long as needed. commit() publishes it, dropping it publishes nothing
+// and the next put or reserve gets the same slot with whatever is in it
+pub struct Reservation<'a, T: 'a + Clone + Send, S: 'a + RingStorage<T> = AlignedBuf<T>> {
+  tx   : &'a Sender<T, S>,
+  slot : &'a mut T,
 }
 
-unsafe impl<T: Copy> Send for Receiver<T> { }
+/// The reading half of a channel, `Send` but neither `Clone` nor `Sync`
+/// like the [`Sender`].
+///
+/// ```
+/// let (tx, rx) = rpg::spsc::channel(4, 0u32);
+/// tx.put(|v| *v = 1);
+/// assert_eq!(std::thread::spawn(move || rx.try_iter().count()).join().unwrap(), 1);
+/// ```
+///
+/// ```compile_fail,E0599
+/// let (_tx, rx) = rpg::spsc::channel(4, 0u32);
+/// let _second_reader = rx.clone();
+/// ```
+///
+/// ```compile_fail,E0277
+/// let (_tx, rx) = rpg::spsc::channel(4, 0u32);
+/// let rx = std::sync::Arc::new(rx);
+/// let shared = rx.clone();
+/// std::thread::spawn(move || shared.try_iter().count());
+/// ```
+pub struct Receiver<T: Clone, S: RingStorage<T> = AlignedBuf<T>> {
+  inner: Arc<CircularBuffer<T, S>>,
+  label: Label,
+  reading: Cell<bool>,
+  policy: Policy,
+  wait: Arc<dyn WaitStrategy>,
+  timing: RefCell<Option<Timing>>,
+  #[cfg(target_os = "linux")]
+  signal: Option<Arc<EventFd>>,
+}
+
+unsafe impl<T: Clone + Send, S: RingStorage<T>> Send for Receiver<T, S> { }
+
+// Receiver::iter(), every item as it arrives until the sender is gone
+// and everything it published was taken. waits in between with the
+// channel's strategy, at most until the deadline of iter_deadline() or
+// the token of iter_cancelable() is canceled
+pub struct Iter<'a, T: 'a + Clone, S: 'a + RingStorage<T> = AlignedBuf<T>> {
+  rx       : &'a Receiver<T, S>,
+  batch    : Option<CircularBufferIterator<'a, T>>,
+  deadline : Option<Instant>,
+  token    : Option<&'a CancelToken>,
+}
+
+// Receiver::iter_enumerated(), Iter with the seqno of every item
+pub struct IterEnumerated<'a, T: 'a + Clone, S: 'a + RingStorage<T> = AlignedBuf<T>> {
+  inner : Iter<'a, T, S>,
+}
+
+// why try_put() published nothing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryPutError {
+  // Policy::Block and the receiver has not taken anything since the
+  // ring filled up, retrying helps once it catches up
+  Full,
+  // the retry budget ran out, the reader kept changing the flag under
+  // the writer. retrying soon is likely to work
+  Contended,
+}
+
+impl fmt::Display for TryPutError {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    match *self {
+      TryPutError::Full      => f.write_str("the ring is full, the receiver is behind"),
+      TryPutError::Contended => f.write_str("too many failed flag updates"),
+    }
+  }
+}
+
+impl Error for TryPutError { }
+
+// the sender was dropped while its thread was panicking, whatever it
+// published before is still delivered, but nothing more will come
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Poisoned;
+
+impl fmt::Display for Poisoned {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("sender panicked")
+  }
+}
+
+impl Error for Poisoned { }
 
-pub fn channel<T: Copy + Send>(size : usize,
+pub fn channel<T: Clone + Send>(size : usize,
                                default_value : T) -> (Sender<T>, Receiver<T>) {
-    let a = Arc::new(UnsafeCell::new(CircularBuffer::new(size, default_value)));
-    (Sender::new(a.clone()), Receiver::new(a))
+    channel_with_wait(size, default_value, Arc::new(SpinThenPark::default()))
+}
+
+// same as channel() but `size` is rounded up to the next power of two,
+// the ring then finds its positions with a mask instead of a division
+pub fn channel_pow2<T: Clone + Send>(size : usize,
+                                    default_value : T) -> (Sender<T>, Receiver<T>) {
+    if size == 0 { panic!("size cannot be zero"); }
+    channel(size.next_power_of_two(), default_value)
+}
+
+// both sides wait and back off with `w`
+pub(crate) fn channel_with_wait<T: Clone + Send>(size : usize,
+                                                default_value : T,
+                                                w : Arc<dyn WaitStrategy>) -> (Sender<T>, Receiver<T>) {
+    let mut ring = CircularBuffer::new(size, default_value);
+    ring.backoff = Some(w.clone());
+    halves(ring, w)
+}
+
+// the two halves sharing `ring`, both waiting with `w`
+fn halves<T: Clone + Send, S: RingStorage<T>>(ring : CircularBuffer<T, S>,
+                                              w : Arc<dyn WaitStrategy>) -> (Sender<T, S>, Receiver<T, S>) {
+    let a = Arc::new(ring);
+    let label = Label::next();
+    (Sender::new(a.clone(), label.clone(), w.clone()), Receiver::new(a, label, w))
+}
+
+// same as channel() but the sender also bumps an eventfd on every put,
+// the receiver's as_raw_fd() can then be registered with epoll
+#[cfg(target_os = "linux")]
+pub fn channel_eventfd<T: Clone + Send>(size : usize,
+                                       default_value : T) -> io::Result<(Sender<T>, Receiver<T>)> {
+    let signal = Some(Arc::new(EventFd::new()?));
+    let (mut tx, mut rx) = channel(size, default_value);
+    tx.signal = signal.clone();
+    rx.signal = signal;
+    Ok((tx, rx))
+}
+
+// same as channel() but every slot is aligned to `align` bytes,
+// the element size must be a multiple of it
+pub fn channel_aligned<T: Clone + Send>(size : usize,
+                                       align : usize,
+                                       default_value : T) -> (Sender<T>, Receiver<T>) {
+    if size == 0 { panic!("size cannot be zero"); }
+    channel_with_storage(AlignedBuf::with_slot_align((size*2)+1, align, default_value))
+}
+
+// same as channel() but the slots live in the given storage,
+// which must hold 2*size+1 elements
+pub fn channel_with_storage<T: Clone + Send, S: RingStorage<T>>(storage : S) -> (Sender<T, S>, Receiver<T, S>) {
+    halves(CircularBuffer::with_storage(storage), Arc::new(SpinThenPark::default()))
 }
 
-impl<T: Copy + Send> Sender<T> {
-  fn new(inner: Arc<UnsafeCell<CircularBuffer<T>>>) -> Sender<T> {
-    Sender { inner: inner, }
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  fn new(inner: Arc<CircularBuffer<T, S>>, label: Label, wait: Arc<dyn WaitStrategy>) -> Sender<T, S> {
+    Sender {
+      inner,
+      label,
+      writing : Cell::new(false),
+      policy  : Policy::Overwrite,
+      batch   : 1,
+      wait,
+      #[cfg(target_os = "linux")]
+      signal : None,
+    }
   }
 
-  pub fn put<F>(&mut self, setter: F) -> usize
-    where F : FnMut(&mut T)
+  // the writer half of the ring, for the duration of f. the guard is
+  // what makes the ring's unsafe puts safe to call from f, one at a time
+  fn with_ring<R, F>(&self, f : F) -> R
+    where F : FnOnce(&CircularBuffer<T, S>) -> R
   {
-    unsafe { (*self.inner.get()).put(setter) }
+    struct Done<'a>(&'a Cell<bool>);
+    impl<'a> Drop for Done<'a> {
+      fn drop(&mut self) { self.0.set(false); }
+    }
+
+    if self.writing.replace(true) { panic!("{}: sender used from inside its own setter", self.label); }
+    let _done = Done(&self.writing);
+    f(&self.inner)
   }
-}
 
-impl<T: Copy + Send> Receiver<T> {
-  fn new(inner: Arc<UnsafeCell<CircularBuffer<T>>>) -> Receiver<T> {
-    Receiver { inner: inner, }
+  // Policy::Block: waits until the next put evicts nothing
+  fn wait_for_room(&self) {
+    if self.policy == Policy::Block {
+      // the receiver makes room only for what it can see
+      if self.is_full() { self.flush(); }
+      self.wait.wait_for(&|| self.inner.has_room(), None);
+    }
   }
 
-  pub fn iter(&mut self) -> CircularBufferIterator<T> {
-    unsafe { (*self.inner.get()).iter() }
+  // wait_for_room() that gives up once `token` is canceled, room that is
+  // there wins over the cancellation
+  fn wait_for_room_cancelable(&self, token : &CancelToken) -> Result<(), Canceled> {
+    if self.policy == Policy::Block {
+      if self.is_full() { self.flush(); }
+      token.wait_for(&self.wait, &|| self.inner.has_room(), None)?;
+    }
+    Ok(())
   }
-}
 
-pub fn tests() {
-  let mut x = CircularBuffer::new(4, 0 as i32);
+  pub fn put<F>(&self, setter: F) -> Seqno
+    where F : FnMut(&mut T)
+  {
+    let mut setter = setter;
+    self.wait_for_room();
+    let seqno = self.with_ring(|ring| unsafe { ring.put_unbounded(|_, v| setter(v)).0 });
+    self.wake();
+    seqno
+  }
 
+  // put() that another thread can abort while it waits for room under
+  // Policy::Block, nothing is published then. the setter only runs once
+  // there is room
+  pub fn put_cancelable<F>(&self, setter : F, token : &CancelToken) -> Result<Seqno, Canceled>
+    where F : FnMut(&mut T)
   {
-    x.put(|v| *v = 1);
-    x.put(|v| *v = 2);
-    x.put(|v| *v = 3);
-    x.put(|v| *v = 4);
-    x.put(|v| *v = 5);
-    x.put(|v| *v = 6);
-    x.put(|v| *v = 7);
+    let mut setter = setter;
+    self.wait_for_room_cancelable(token)?;
+    let seqno = self.with_ring(|ring| unsafe { ring.put_unbounded(|_, v| setter(v)).0 });
+    self.wake();
+    Ok(seqno)
   }
 
+  // put() with at most `max_retries` failed flag CAS, for callers that
+  // rather take a fallback path than spin. under Policy::Block a full
+  // ring fails right away with Full instead of waiting. the setter may
+  // run again on the next put, as nothing got published
+  pub fn try_put<F>(&self, setter : F, max_retries : usize) -> Result<Seqno, TryPutError>
+    where F : FnMut(&mut T)
   {
-    for i in x.iter() {
-      println!("--: {}", i);
+    let mut setter = setter;
+    if self.policy == Policy::Block && self.is_full() {
+      return Err(TryPutError::Full);
     }
+    let seqno = self.with_ring(|ring| unsafe { ring.put_evicting(|_, v| setter(v), max_retries) })?.0;
+    self.wake();
+    Ok(seqno)
   }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::CircularBuffer;
+  // put() with a setter that may fail, an Err publishes nothing and is
+  // handed back. the slot keeps what the setter left in it, the next put
+  // gets it as it is
+  pub fn try_put_with<E, F>(&self, setter : F) -> Result<Seqno, E>
+    where F : FnOnce(&mut T) -> Result<(), E>
+  {
+    let mut slot = self.reserve();
+    setter(&mut slot)?;
+    Ok(slot.commit())
+  }
 
-  #[test]
-  #[should_panic]
-  fn create_zero_sized() {
-    let _x = CircularBuffer::new(0, 0 as i32);
+  // the slot the next put would fill, to write it directly instead of
+  // through a setter. the sender cannot be used until the reservation is
+  // gone. waits for room first under Policy::Block
+  pub fn reserve(&self) -> Reservation<'_, T, S> {
+    self.wait_for_room();
+    if self.writing.replace(true) { panic!("{}: sender used from inside its own setter", self.label); }
+    let slot = unsafe { self.inner.reserved() };
+    Reservation { tx : self, slot }
   }
 
-  #[test]
-  fn empty_buffer() {
-    let mut x = CircularBuffer::new(1, 0 as i32);
-    assert_eq!(x.iter().count(), 0);
+  // put() of a ready value, returns the item it overwrote if the
+  // receiver never saw it (never under Policy::Block)
+  pub fn put_replace(&self, value : T) -> Option<T> {
+    self.wait_for_room();
+    let evicted = self.with_ring(|ring| unsafe { ring.put_replace(value) });
+    self.wake();
+    evicted
   }
 
-  #[test]
-  fn sum_available() {
-    let mut x = CircularBuffer::new(4, 0 as i32);
-    x.put(|v| *v = 2);
-    x.put(|v| *v = 4);
-    x.put(|v| *v = 6);
-    x.put(|v| *v = 8);
-    x.put(|v| *v = 10);
-    let sum = x.iter().take(3).fold(0, |acc, num| acc + num);
-    assert_eq!(sum, 18);
+  // conflates `value` into the latest item with merge(item, value) while
+  // the receiver has not taken that item yet, e.g. to sum up counters.
+  // returns the seqno of the item that holds the data
+  pub fn put_merge<M>(&self, value : T, merge : M) -> Seqno
+    where M : FnOnce(&mut T, &T)
+  {
+    // merging evicts nothing, only the fallback put waits for room
+    let seqno = match self.with_ring(|ring| unsafe { ring.try_merge(&value, merge) }) {
+      Some(seqno) => seqno,
+      None        => {
+        self.wait_for_room();
+        self.with_ring(|ring| unsafe { ring.put_value(value) })
+      },
+    };
+    self.wake();
+    seqno
   }
 
-  #[test]
-  fn overload_buffer() {
-    let mut x = CircularBuffer::new(2, 0 as i32);
-    x.put(|v| *v = 1);
-    x.put(|v| *v = 2);
-    x.put(|v| *v = 3);
-    assert_eq!(x.iter().count(), 2);
+  // put() guarded by a predicate over the latest published item, e.g.
+  // to keep a last value cache monotonic. None if nothing was written
+  pub fn put_if<P, F>(&self, pred : P, setter : F) -> Option<Seqno>
+    where P : FnOnce(&T) -> bool,
+          F : FnMut(&mut T)
+  {
+    self.wait_for_room();
+    let seqno = self.with_ring(|ring| unsafe { ring.put_if(pred, setter) });
+    if seqno.is_some() { self.wake(); }
+    seqno
   }
 
-  #[test]
-  fn read_twice() {
-    let mut x = CircularBuffer::new(2, 0 as i32);
-    x.put(|v| *v = 1);
-    assert_eq!(x.iter().count(), 1);
-    assert_eq!(x.iter().count(), 0);
-    x.put(|v| *v = 2);
-    x.put(|v| *v = 3);
-    assert_eq!(x.iter().count(), 2);
-    assert_eq!(x.iter().count(), 0);
+  // true while the next put would evict an item the receiver has not
+  // taken yet, i.e. would wait under Policy::Block. never true once the
+  // receiver is gone
+  pub fn is_full(&self) -> bool {
+    !self.inner.has_room()
+  }
+
+  // how many items fit before the next put would evict an unread one or,
+  // under Policy::Block, wait. the receiver may make more room meanwhile,
+  // never less
+  pub fn spare_capacity(&self) -> usize {
+    self.inner.room()
+  }
+
+  // true once the receiver was dropped, nothing put from then on is read
+  pub fn is_closed(&self) -> bool {
+    self.inner.reader_gone.load(Ordering::SeqCst)
+  }
+
+  // the same as the receiver's
+  pub fn label(&self) -> &Label {
+    &self.label
+  }
+
+  // true if both write into the same ring. a channel has one sender, so
+  // that is the same handle, seen e.g. through two references. see
+  // Receiver::same_channel() for pairing a sender with its receiver
+  pub fn same_channel(&self, other : &Sender<T, S>) -> bool {
+    Arc::ptr_eq(&self.inner, &other.inner)
+  }
+
+  // puts every item, waking the receiver once at the end. under
+  // Policy::Block wait_for_room() wakes it before waiting as well, it may
+  // be parked on the items of this very batch
+  pub(crate) fn put_all<I : Iterator<Item = T>>(&self, items : I) -> Option<Seqno> {
+    let mut last = None;
+    for item in items {
+      self.wait_for_room();
+      let mut item = Some(item);
+      last = Some(self.with_ring(|ring| unsafe { ring.put_unbounded(|_, v| if let Some(n) = item.take() { *v = n; }).0 }));
+    }
+    if last.is_some() { self.wake(); }
+    last
+  }
+
+  // put_many() of the ring, a reader sees all of the batch or none of it.
+  // under Policy::Block it first waits until the whole batch fits
+  pub fn put_many(&self, items : &[T]) -> Option<Seqno> {
+    let ring = &self.inner;
+    ring.check_batch(items.len());
+    if self.policy == Policy::Block && ring.room() < items.len() {
+      self.flush();
+      self.wait.wait_for(&|| ring.room() >= items.len(), None);
+    }
+    let last = self.with_ring(|ring| unsafe { ring.put_batch(items) });
+    if last.is_some() { self.wake(); }
+    last
+  }
+
+  // publishes the puts held back by Builder::publish_every() and wakes
+  // the receiver for them
+  pub fn flush(&self) {
+    self.with_ring(|ring| unsafe { ring.publish() });
+    self.wake();
+  }
+
+  pub(crate) fn cas_retries(&self) -> usize {
+    unsafe { (*self.inner.writer.get()).put_retries }
+  }
+}
+
+impl<T: Clone, S: RingStorage<T>> Sender<T, S> {
+  fn wake(&self) {
+    // nothing new to see while puts are held back
+    if unsafe { (*self.inner.writer.get()).unpublished } > 0 { return; }
+    self.wait.notify();
+    #[cfg(target_os = "linux")]
+    {
+      if let Some(ref signal) = self.signal { signal.notify(); }
+    }
+  }
+}
+
+// byte messages put together from several buffers, e.g. a header and a
+// body kept apart, without concatenating them first. the slot's vector is
+// reused, so this only allocates while slots grow to the largest message
+impl<S: RingStorage<Vec<u8>>> Sender<Vec<u8>, S> {
+  pub fn put_vectored(&self, bufs : &[IoSlice<'_>]) -> Seqno {
+    self.put(|v| fill_vectored(v, bufs))
+  }
+
+  // reserve() with the slot already holding `bufs`, more can be appended
+  // before the commit
+  pub fn reserve_vectored(&self, bufs : &[IoSlice<'_>]) -> Reservation<'_, Vec<u8>, S> {
+    let mut slot = self.reserve();
+    fill_vectored(&mut slot, bufs);
+    slot
+  }
+}
+
+fn fill_vectored(v : &mut Vec<u8>, bufs : &[IoSlice<'_>]) {
+  v.clear();
+  for b in bufs { v.extend_from_slice(b); }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> Reservation<'a, T, S> {
+  // publishes the slot, returns its seqno
+  pub fn commit(self) -> Seqno {
+    self.commit_evicting().0
+  }
+
+  // commit() that also tells whether an unread item got evicted for it
+  pub(crate) fn commit_evicting(self) -> (Seqno, bool) {
+    let tx = self.tx;
+    drop(self);
+    let ret = tx.with_ring(|ring| unsafe { ring.put_unbounded(|_, _| ()) });
+    tx.wake();
+    ret
+  }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> Deref for Reservation<'a, T, S> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    self.slot
+  }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> DerefMut for Reservation<'a, T, S> {
+  fn deref_mut(&mut self) -> &mut T {
+    self.slot
+  }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> Drop for Reservation<'a, T, S> {
+  fn drop(&mut self) {
+    self.tx.writing.set(false);
+  }
+}
+
+// the channel and its state, not the items
+impl<T: Clone + Send, S: RingStorage<T>> fmt::Debug for Sender<T, S> {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Sender")
+      .field("id", &self.label.id())
+      .field("name", &self.label.name())
+      .field("policy", &self.policy)
+      .field("closed", &self.is_closed())
+      .finish()
+  }
+}
+
+// put() only publishes after the setter returned, so a panic in the setter
+// never leaves a half written item behind. what remains is telling the
+// receiver that the producer is gone, and whether it died
+impl<T: Clone, S: RingStorage<T>> Drop for Sender<T, S> {
+  fn drop(&mut self) {
+    let ring = &self.inner;
+    // nothing else of the writer's can be alive, this is its last use
+    unsafe { ring.publish(); }
+    if thread::panicking() {
+      ring.poisoned.store(true, Ordering::SeqCst);
+    }
+    ring.writer_gone.store(true, Ordering::SeqCst);
+    self.wake();
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Receiver<T, S> {
+  fn new(inner: Arc<CircularBuffer<T, S>>, label: Label, wait: Arc<dyn WaitStrategy>) -> Receiver<T, S> {
+    Receiver {
+      inner,
+      label,
+      reading : Cell::new(false),
+      policy  : Policy::Overwrite,
+      wait,
+      timing  : RefCell::new(None),
+      #[cfg(target_os = "linux")]
+      signal : None,
+    }
+  }
+
+  // clears the eventfd before draining, returns the number of puts
+  // signalled since the last call (0 without an eventfd)
+  #[cfg(target_os = "linux")]
+  pub fn reset_eventfd(&self) -> u64 {
+    self.signal.as_ref().map_or(0, |s| s.reset())
+  }
+
+  // whatever was published since the last call, without waiting. the
+  // items are handed to the writer again once the iterator is dropped,
+  // so only one may be alive at a time
+  pub fn try_iter(&self) -> CircularBufferIterator<'_, T> {
+    if self.reading.replace(true) { panic!("{}: iter() called while an earlier iterator is alive", self.label); }
+    let mut it = unsafe { self.inner.take() };
+    it.reading = Some(&self.reading);
+    if let Some(ref mut timing) = *self.timing.borrow_mut() {
+      timing.record(it.seqno, it.count);
+    }
+    // the taken slots are free for a blocked writer already
+    if self.policy == Policy::Block && it.count > 0 { self.wait.notify(); }
+    it
+  }
+
+  // starts keeping receive timestamps and lag for the last `window`
+  // items, restarting if it was on already
+  pub fn record_timing(&mut self, window : usize) {
+    *self.timing.get_mut() = Some(Timing::new(window));
+  }
+
+  // None unless record_timing() was called
+  pub fn timing(&self) -> Option<TimingStats> {
+    self.timing.borrow().as_ref().map(|t| t.stats())
+  }
+
+  // every item from now on, blocking until the next one arrives. ends
+  // once the sender is dropped (panicking or not) and nothing is left,
+  // so a consumer thread can simply loop over it
+  pub fn iter(&self) -> Iter<'_, T, S> {
+    Iter { rx : self, batch : None, deadline : None, token : None }
+  }
+
+  // iter() that also ends once `deadline` passed, e.g. to get back to
+  // periodic work in between
+  pub fn iter_deadline(&self, deadline : Instant) -> Iter<'_, T, S> {
+    Iter { rx : self, batch : None, deadline : Some(deadline), token : None }
+  }
+
+  // iter() that another thread can end through the token, e.g. to shut a
+  // consumer down while the sender is still around. token.is_canceled()
+  // tells that apart from a closed channel
+  pub fn iter_cancelable<'a>(&'a self, token : &'a CancelToken) -> Iter<'a, T, S> {
+    Iter { rx : self, batch : None, deadline : None, token : Some(token) }
+  }
+
+  // iter_deadline() `timeout` from now
+  pub fn iter_timeout(&self, timeout : Duration) -> Iter<'_, T, S> {
+    self.iter_deadline(Instant::now() + timeout)
+  }
+
+  // iter() with the seqno of every item
+  pub fn iter_enumerated(&self) -> IterEnumerated<'_, T, S> {
+    IterEnumerated { inner : self.iter() }
+  }
+
+  // try_iter() that also tells the seqno of every item, the same number
+  // Sender::put() returned for it
+  pub fn try_iter_enumerated(&self) -> EnumeratedIterator<'_, T> {
+    EnumeratedIterator { inner : self.try_iter() }
+  }
+
+  // try_iter() that fails once the sender panicked and everything it
+  // published has been seen
+  pub fn checked_iter(&self) -> Result<CircularBufferIterator<'_, T>, Poisoned> {
+    if self.is_poisoned() && self.is_empty() {
+      return Err(Poisoned);
+    }
+    Ok(self.try_iter())
+  }
+
+  // true if try_iter() would return nothing, without taking any flags
+  pub fn is_empty(&self) -> bool {
+    !self.inner.has_unread()
+  }
+
+  // true once the sender was dropped, nothing gets published after that
+  pub fn is_closed(&self) -> bool {
+    self.inner.writer_gone.load(Ordering::SeqCst)
+  }
+
+  pub fn label(&self) -> &Label {
+    &self.label
+  }
+
+  // true if both read from the same ring, i.e. are the same handle
+  pub fn same_channel(&self, other : &Receiver<T, S>) -> bool {
+    Arc::ptr_eq(&self.inner, &other.inner)
+  }
+
+  // true if `tx` writes into the ring this reads from
+  pub fn is_fed_by(&self, tx : &Sender<T, S>) -> bool {
+    Arc::ptr_eq(&self.inner, &tx.inner)
+  }
+
+  pub fn is_poisoned(&self) -> bool {
+    self.inner.poisoned.load(Ordering::SeqCst)
+  }
+
+  // blocks until there is something try_iter() has not seen yet, using
+  // the channel's wait strategy. returns false if the timeout expired
+  // first. a closed channel wakes it up as well
+  pub fn wait(&self, timeout : Option<Duration>) -> bool {
+    self.wait_until(timeout.map(|t| Instant::now() + t))
+  }
+
+  fn wait_until(&self, deadline : Option<Instant>) -> bool {
+    self.wait.wait_for(&|| self.inner.has_unread() || self.is_closed(), deadline)
+  }
+
+  // wait() that another thread can abort through the token
+  pub fn wait_cancelable(&self, timeout : Option<Duration>, token : &CancelToken) -> Result<bool, Canceled> {
+    self.wait_until_cancelable(timeout.map(|t| Instant::now() + t), token)
+  }
+
+  fn wait_until_cancelable(&self, deadline : Option<Instant>, token : &CancelToken) -> Result<bool, Canceled> {
+    token.wait_for(&self.wait, &|| self.inner.has_unread() || self.is_closed(), deadline)
+  }
+
+  pub(crate) fn cas_retries(&self) -> usize {
+    unsafe { (*self.inner.reader.get()).iter_misses }
+  }
+}
+
+// a writer blocked under Policy::Block would otherwise wait forever
+impl<'a, T: Clone + Send, S: RingStorage<T>> Iter<'a, T, S> {
+  fn next_enumerated(&mut self) -> Option<(Seqno, T)> {
+    loop {
+      if let Some(ref mut batch) = self.batch {
+        let seqno = batch.seqno;
+        if let Some(v) = batch.next() { return Some((seqno, v)); }
+      }
+      // hands the taken slots back before asking for more
+      self.batch = None;
+
+      // a taken batch is always handed out in full, what arrives after
+      // the deadline stays in the ring for the next call
+      if self.deadline.is_some_and(|d| Instant::now() >= d) { return None; }
+      if self.token.is_some_and(|t| t.is_canceled()) { return None; }
+
+      // checked first, so whatever the sender published before it went
+      // away is still picked up below
+      let closed = self.rx.is_closed();
+      let batch  = self.rx.try_iter();
+      if batch.count > 0 {
+        self.batch = Some(batch);
+      } else if closed {
+        return None;
+      } else {
+        drop(batch);
+        let woken = match self.token {
+          Some(token) => self.rx.wait_until_cancelable(self.deadline, token).unwrap_or(false),
+          None        => self.rx.wait_until(self.deadline),
+        };
+        if !woken { return None; }
+      }
+    }
+  }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> Iterator for Iter<'a, T, S> {
+  type Item = T;
+
+  fn next(&mut self) -> Option<T> {
+    self.next_enumerated().map(|(_, v)| v)
+  }
+}
+
+impl<'a, T: Clone + Send, S: RingStorage<T>> Iterator for IterEnumerated<'a, T, S> {
+  type Item = (Seqno, T);
+
+  fn next(&mut self) -> Option<(Seqno, T)> {
+    self.inner.next_enumerated()
+  }
+}
+
+// a closed channel stays closed, a passed deadline stays passed and a
+// canceled token stays canceled, so all of them keep returning None
+impl<'a, T: Clone + Send, S: RingStorage<T>> FusedIterator for Iter<'a, T, S> { }
+impl<'a, T: Clone + Send, S: RingStorage<T>> FusedIterator for IterEnumerated<'a, T, S> { }
+
+impl<T: Clone + Send, S: RingStorage<T>> fmt::Debug for Receiver<T, S> {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Receiver")
+      .field("id", &self.label.id())
+      .field("name", &self.label.name())
+      .field("policy", &self.policy)
+      .field("closed", &self.is_closed())
+      .finish()
+  }
+}
+
+impl<T: Clone, S: RingStorage<T>> Drop for Receiver<T, S> {
+  fn drop(&mut self) {
+    self.inner.reader_gone.store(true, Ordering::SeqCst);
+    self.wait.notify();
+  }
+}
+
+// -1 unless the channel was made by channel_eventfd(), which makes
+// epoll_ctl() fail loudly instead of watching a random fd
+#[cfg(target_os = "linux")]
+impl<T: Clone + Send, S: RingStorage<T>> AsRawFd for Receiver<T, S> {
+  fn as_raw_fd(&self) -> RawFd {
+    self.signal.as_ref().map_or(-1, |s| s.as_raw_fd())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::CircularBuffer;
+
+  #[test]
+  #[should_panic]
+  fn create_zero_sized() {
+    let _x = CircularBuffer::new(0, 0i32);
+  }
+
+  #[test]
+  fn sides_on_lines_of_their_own() {
+    use storage::CACHE_LINE;
+
+    let x = CircularBuffer::new(4, 0i32);
+    let writer = &*x.writer as *const _ as usize;
+    let reader = &*x.reader as *const _ as usize;
+    assert_eq!((writer % CACHE_LINE, reader % CACHE_LINE), (0, 0));
+    assert!(writer.abs_diff(reader) >= CACHE_LINE);
+
+    // the flags start a line after the seqno, wherever the words begin
+    let seqno = x.seqno() as *const _ as usize;
+    assert_eq!(x.flag(0) as *const _ as usize - seqno, CACHE_LINE);
+    assert_eq!(x.ctrl.len(), CACHE_LINE / std::mem::size_of::<usize>() + 4);
+  }
+
+  #[test]
+  fn empty_buffer() {
+    let mut x = CircularBuffer::new(1, 0i32);
+    assert_eq!(x.iter().count(), 0);
+  }
+
+  #[test]
+  fn sum_available() {
+    let mut x = CircularBuffer::new(4, 0i32);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 4);
+    x.put(|v| *v = 6);
+    x.put(|v| *v = 8);
+    x.put(|v| *v = 10);
+    let sum = x.iter().take(3).sum::<i32>();
+    assert_eq!(sum, 18);
+  }
+
+  #[test]
+  fn overload_buffer() {
+    let mut x = CircularBuffer::new(2, 0i32);
+    x.put(|v| *v = 1);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 3);
+    assert_eq!(x.iter().count(), 2);
+  }
+
+  #[test]
+  fn control_word_wraps() {
+    use std::sync::atomic::Ordering;
+
+    // as if the ring had been running for 2^usize::BITS puts
+    let mut x = CircularBuffer::new(4, 0i32);
+    x.seqno().store(usize::MAX - 1, Ordering::SeqCst);
+    x.reader.get_mut().max_read = usize::MAX - 1;
+    for i in 1..4 { x.put(|v| *v = i); }
+    assert_eq!(x.seqno().load(Ordering::SeqCst), 1);
+    assert_eq!(x.to_vec(), vec![1, 2, 3]);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![1, 2, 3]);
+    x.put(|v| *v = 4);
+    assert_eq!(x.into_vec(), vec![4]);
+  }
+
+  #[test]
+  fn publishes_in_batches() {
+    use std::thread;
+    use super::{Builder, Policy};
+
+    let (tx, rx) = Builder::new().capacity(8).publish_every(4).build::<u32>();
+    for i in 0..3 { tx.put(|v| *v = i); }
+    assert!(rx.is_empty());
+    tx.put(|v| *v = 3);
+    assert_eq!(rx.try_iter().collect::<Vec<u32>>(), vec![0, 1, 2, 3]);
+    tx.put(|v| *v = 4);
+    tx.flush();
+    tx.put(|v| *v = 5);
+    assert_eq!(rx.try_iter().collect::<Vec<u32>>(), vec![4]);
+    drop(tx);
+    assert_eq!(rx.iter().collect::<Vec<u32>>(), vec![5]);
+
+    // a full ring publishes what it holds back before it waits
+    const ITEMS : u32 = 5000;
+    let (tx, rx) = Builder::new().capacity(4).publish_every(4).overwrite(Policy::Block).build::<u32>();
+    let t = thread::spawn(move || {
+      for i in 0..ITEMS { tx.put(|v| *v = i); }
+    });
+    assert_eq!(rx.iter().collect::<Vec<u32>>(), (0..ITEMS).collect::<Vec<u32>>());
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn idle_polls_change_nothing() {
+    let (tx, rx) = super::channel(4, 0i32);
+    tx.put(|v| *v = 1);
+    assert_eq!(rx.try_iter().count(), 1);
+    let before = super::dump_state(&tx, &rx);
+    for _ in 0..3 { assert_eq!(rx.try_iter().count(), 0); }
+    assert_eq!(super::dump_state(&tx, &rx), before);
+    assert_eq!(rx.stats().reads, 1);
+    tx.put(|v| *v = 2);
+    assert_eq!(rx.try_iter_enumerated().collect::<Vec<(u64, i32)>>(), vec![(1, 2)]);
+  }
+
+  #[test]
+  fn batches_show_up_whole() {
+    use std::thread;
+    use super::{Builder, Policy};
+
+    let (tx, rx) = super::channel(4, 0u32);
+    assert_eq!(tx.put_many(&[]), None);
+    assert_eq!(tx.put_many(&[1, 2, 3]), Some(2));
+    assert_eq!(rx.try_iter().collect::<Vec<u32>>(), vec![1, 2, 3]);
+
+    // every record is 3 items, first of them the record id
+    const RECORDS : u32 = 2000;
+    let (tx, rx) = Builder::new().capacity(6).overwrite(Policy::Block).build::<u32>();
+    let t = thread::spawn(move || {
+      for r in 0..RECORDS { tx.put_many(&[r, r, r]); }
+    });
+    let mut got = 0;
+    loop {
+      let closed = rx.is_closed();
+      let batch : Vec<u32> = rx.try_iter().collect();
+      if batch.is_empty() && closed { break; }
+      assert_eq!(batch.len() % 3, 0);
+      for record in batch.chunks(3) { assert_eq!(record, &[got, got, got]); got += 1; }
+    }
+    assert_eq!(got, RECORDS);
+    t.join().unwrap();
+  }
+
+  #[test]
+  #[should_panic(expected = "a batch of 3 items does not fit a ring of 2")]
+  fn batch_too_large() {
+    let (tx, _rx) = super::Builder::new().capacity(2).overwrite(super::Policy::Block).build::<u32>();
+    tx.put(|v| *v = 0);
+    tx.put_many(&[1, 2, 3]);
+  }
+
+  #[test]
+  fn pow2_capacity() {
+    let (tx, rx) = super::channel_pow2(5, 0usize);
+    assert_eq!(tx.inner.capacity(), 8);
+    for i in 0..20 { tx.put(|v| *v = i); }
+    assert_eq!(rx.try_iter().collect::<Vec<usize>>(), (12..20).collect::<Vec<usize>>());
+    assert!(tx.spare_capacity() == 8 && !tx.is_full());
+  }
+
+  #[test]
+  fn failed_setter_publishes_nothing() {
+    let (tx, rx) = super::channel(4, 0u32);
+    assert_eq!(tx.try_put_with(|v| "7".parse().map(|n| *v = n)), Ok(0));
+    assert!(tx.try_put_with(|v| "x".parse().map(|n| *v = n)).is_err());
+    assert_eq!(tx.try_put_with(|v| "8".parse().map(|n| *v = n)), Ok(1));
+    assert_eq!(rx.try_iter().collect::<Vec<u32>>(), vec![7, 8]);
+  }
+
+  #[test]
+  fn spare_capacity() {
+    let (tx, rx) = super::channel(4, 0i32);
+    assert_eq!(tx.spare_capacity(), 4);
+    for i in 0..3 { tx.put(|v| *v = i); }
+    assert_eq!(tx.spare_capacity(), 1);
+    assert_eq!(rx.try_iter().count(), 3);
+    assert_eq!(tx.spare_capacity(), 4);
+    for i in 0..6 { tx.put(|v| *v = i); }
+    assert!(tx.is_full() && tx.spare_capacity() == 0);
+    drop(rx);
+    assert_eq!(tx.spare_capacity(), 4);
+  }
+
+  #[test]
+  fn same_channel() {
+    let (tx, rx) = super::channel(2, 0i32);
+    let (tx2, rx2) = super::channel(2, 0i32);
+    let txs = [&tx, &tx2];
+    assert!(txs[0].same_channel(&tx) && !tx.same_channel(&tx2));
+    assert!(rx.same_channel(&rx) && !rx.same_channel(&rx2));
+    assert!(rx.is_fed_by(&tx) && !rx2.is_fed_by(&tx));
+  }
+
+  #[test]
+  fn blocking_iter_until_closed() {
+    use std::thread;
+    use super::{Builder, Policy};
+
+    const ITEMS : u64 = 5000;
+    let (tx, rx) = Builder::new().capacity(4).overwrite(Policy::Block).build::<u64>();
+    let t = thread::spawn(move || {
+      for i in 0..ITEMS { tx.put(|v| *v = i); }
+    });
+    assert_eq!(rx.iter().collect::<Vec<u64>>(), (0..ITEMS).collect::<Vec<u64>>());
+    assert!(rx.is_closed() && !rx.is_poisoned());
+    t.join().unwrap();
+
+    // whatever was left when the sender went away still comes out
+    let (tx, rx) = super::channel(4, 0u64);
+    for i in 0..6 { tx.put(|v| *v = i); }
+    assert!(!rx.is_closed());
+    drop(tx);
+    assert_eq!(rx.iter_enumerated().collect::<Vec<(u64, u64)>>(), vec![(2, 2), (3, 3), (4, 4), (5, 5)]);
+    assert_eq!(rx.iter().count(), 0);
+  }
+
+  #[test]
+  fn dropped_sender_ends_parked_iter() {
+    use std::thread;
+    use std::time::Duration;
+    use wait::Blocking;
+    use super::Builder;
+
+    // the consumer parks in iter() long before the producer leaves
+    let (tx, rx) = Builder::new().capacity(4).wait(Blocking::default()).build::<u32>();
+    let t = thread::spawn(move || {
+      let mut it = rx.iter();
+      let first = it.next();
+      (first, it.next(), it.next())
+    });
+    thread::sleep(Duration::from_millis(20));
+    tx.put(|v| *v = 7);
+    thread::sleep(Duration::from_millis(20));
+    drop(tx);
+    assert_eq!(t.join().unwrap(), (Some(7), None, None));
+  }
+
+  #[test]
+  fn iter_stops_at_deadline() {
+    use std::time::{Duration, Instant};
+
+    let (tx, rx) = super::channel(4, 0i32);
+    let started = Instant::now();
+    assert_eq!(rx.iter_timeout(Duration::from_millis(20)).count(), 0);
+    assert!(started.elapsed() >= Duration::from_millis(20));
+
+    // a passed deadline leaves the items for later, a taken batch is
+    // handed out in full
+    tx.put(|v| *v = 1);
+    assert_eq!(rx.iter_deadline(started).count(), 0);
+    let mut it = rx.iter_timeout(Duration::from_millis(200));
+    assert_eq!(it.next(), Some(1));
+    tx.put(|v| *v = 2);
+    tx.put(|v| *v = 3);
+    assert_eq!(it.next(), Some(2));
+    assert_eq!(it.next(), Some(3));
+    assert_eq!(it.next(), None);
+    assert_eq!(it.next(), None);
+    drop(it);
+
+    drop(tx);
+    assert_eq!(rx.iter_timeout(Duration::from_secs(10)).count(), 0);
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn reserve_and_commit() {
+    let (tx, rx) = super::channel(2, vec![0u8; 4]);
+    {
+      let mut slot = tx.reserve();
+      slot[0] = 1;
+      slot.push(9);
+      assert_eq!(slot.commit(), 0);
+    }
+    // an abandoned reservation publishes nothing
+    tx.reserve()[0] = 2;
+    let mut slot = tx.reserve();
+    slot[1] = 3;
+    slot.commit();
+
+    let batch = rx.try_iter();
+    assert_eq!(batch.newest(), Some((1, &vec![2, 3, 0, 0])));
+    assert_eq!(batch.collect::<Vec<Vec<u8>>>(), vec![vec![1, 0, 0, 0, 9], vec![2, 3, 0, 0]]);
+  }
+
+  #[test]
+  fn vectored_puts() {
+    use std::io::IoSlice;
+    let (tx, rx) = super::channel(2, Vec::new());
+    let (head, body) = (&b"hdr:"[..], &b"body"[..]);
+    tx.put_vectored(&[IoSlice::new(head), IoSlice::new(body)]);
+    let mut slot = tx.reserve_vectored(&[IoSlice::new(body)]);
+    slot.push(b'!');
+    slot.commit();
+    assert_eq!(rx.try_iter().collect::<Vec<Vec<u8>>>(), vec![b"hdr:body".to_vec(), b"body!".to_vec()]);
+  }
+
+  #[test]
+  #[should_panic(expected = "sender used from inside its own setter")]
+  fn put_while_reserved() {
+    let (tx, _rx) = super::channel(2, 0i32);
+    let _slot = tx.reserve();
+    tx.put(|v| *v = 1);
+  }
+
+  #[test]
+  fn occupancy_predicates() {
+    let (tx, rx) = super::channel(2, 0i32);
+    assert!(rx.is_empty() && !tx.is_full());
+    tx.put(|v| *v = 1);
+    assert!(!rx.is_empty() && !tx.is_full());
+    tx.put(|v| *v = 2);
+    assert!(tx.is_full());
+    assert_eq!(rx.try_iter().count(), 2);
+    assert!(rx.is_empty() && !tx.is_full());
+
+    // overwriting keeps it full until the receiver drains or leaves
+    for i in 0..5 { tx.put(|v| *v = i); }
+    assert!(tx.is_full());
+    drop(rx);
+    assert!(!tx.is_full());
+  }
+
+  #[test]
+  fn raw_parts_round_trip() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use storage::Live;
+
+    // taken apart with items pending and the reader's slots swapped
+    let mut x = CircularBuffer::new(3, 0i32);
+    for i in 1..5 { x.put(|v| *v = i); }
+    assert_eq!(x.iter().count(), 3);
+    x.put(|v| *v = 5);
+    x.put(|v| *v = 6);
+    let parts = x.into_raw_parts();
+    assert_eq!((parts.capacity, parts.put_count), (3, 6));
+    let mut x = unsafe { CircularBuffer::from_raw_parts(parts) };
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![5, 6]);
+    for i in 7..12 { x.put(|v| *v = i); }
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![9, 10, 11]);
+
+    // nothing is dropped while apart, everything once rebuilt
+    let count = Arc::new(AtomicUsize::new(0));
+    let mut x = CircularBuffer::new(2, Live::new(&count));
+    x.put(|v| *v = Live::new(&count));
+    let parts = x.into_raw_parts();
+    assert_eq!(count.load(Ordering::SeqCst), 5);
+    drop(unsafe { CircularBuffer::from_raw_parts(parts) });
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn static_storage() {
+    let slots : &'static mut [i32] = Box::leak(vec![0i32; 5].into_boxed_slice());
+    let (tx, rx) = super::channel_with_storage(slots);
+    tx.put(|v| *v = 1);
+    tx.put(|v| *v = 2);
+    tx.put(|v| *v = 3);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![2, 3]);
+  }
+
+  #[test]
+  fn aligned_slots() {
+    let (tx, rx) = super::channel_aligned(3, 32, [0f32; 8]);
+    tx.put(|v| *v = [1.0; 8]);
+    tx.put(|v| *v = [2.0; 8]);
+    assert_eq!(rx.try_iter().map(|v| v[7]).collect::<Vec<f32>>(), vec![1.0, 2.0]);
+  }
+
+  #[test]
+  #[should_panic]
+  fn even_sized_storage() {
+    let _x = CircularBuffer::with_storage(vec![0i32; 4]);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn eventfd_counts_puts() {
+    use std::os::unix::io::AsRawFd;
+    let (tx, rx) = super::channel_eventfd(2, 0i32).unwrap();
+    assert!(rx.as_raw_fd() >= 0);
+    assert_eq!(rx.reset_eventfd(), 0);
+    tx.put(|v| *v = 1);
+    tx.put(|v| *v = 2);
+    tx.put(|v| *v = 3);
+    assert_eq!(rx.reset_eventfd(), 3);
+    assert_eq!(rx.reset_eventfd(), 0);
+    assert_eq!(rx.try_iter().count(), 2);
+
+    let (_tx, rx) = super::channel(2, 0i32);
+    assert_eq!(rx.as_raw_fd(), -1);
+  }
+
+  #[test]
+  fn wait_for_writer() {
+    use std::thread;
+    use std::time::Duration;
+    use super::{Builder, Profile};
+
+    let (tx, rx) = Builder::new().capacity(4).profile(Profile::Throughput).build::<i32>();
+    assert!(!rx.wait(Some(Duration::from_millis(5))));
+    let t = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(10));
+      tx.put(|v| *v = 3);
+      // the profile holds puts back
+      tx.flush();
+    });
+    assert!(rx.wait(None));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![3]);
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn blocking_wait() {
+    use std::thread;
+    use std::time::Duration;
+    use wait::Blocking;
+
+    let (tx, rx) = super::Builder::new().capacity(4).wait(Blocking::default()).build::<i32>();
+    let t = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(10));
+      tx.put(|v| *v = 3);
+    });
+    assert!(rx.wait(Some(Duration::from_secs(10))));
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn block_policy_loses_nothing() {
+    use std::thread;
+    use wait::SpinThenPark;
+    use super::{Builder, Policy, TryPutError};
+
+    const ITEMS : u64 = 20000;
+    let (tx, rx) = Builder::new().capacity(4)
+                                 .overwrite(Policy::Block)
+                                 .wait(SpinThenPark::default())
+                                 .build::<u64>();
+    for i in 0..4 { tx.put(|v| *v = i); }
+    assert_eq!(tx.try_put(|v| *v = 4, 10), Err(TryPutError::Full));
+
+    let t = thread::spawn(move || {
+      for i in 4..ITEMS { tx.put(|v| *v = i); }
+    });
+    let mut next = 0;
+    while next < ITEMS {
+      for v in rx.try_iter() {
+        assert_eq!(v, next);
+        next += 1;
+      }
+    }
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn dropped_receiver_unblocks_writer() {
+    use std::thread;
+    use std::time::Duration;
+    use super::{Builder, Policy};
+
+    let (tx, rx) = Builder::new().capacity(2).overwrite(Policy::Block).build::<i32>();
+    let t = thread::spawn(move || {
+      for i in 0..10 { tx.put(|v| *v = i); }
+    });
+    thread::sleep(Duration::from_millis(10));
+    drop(rx);
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn cancel_parked_receiver() {
+    use std::thread;
+    use std::time::Duration;
+    use wait::{CancelToken, Canceled};
+
+    let (_tx, rx) = super::channel(4, 0i32);
+    let token = CancelToken::new();
+    let t = {
+      let token = token.clone();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+      })
+    };
+    assert_eq!(rx.wait_cancelable(None, &token), Err(Canceled));
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn cancel_blocked_sender() {
+    use std::thread;
+    use std::time::Duration;
+    use super::{Builder, Policy};
+    use wait::{CancelToken, Canceled};
+
+    let (tx, rx) = Builder::new().capacity(1).overwrite(Policy::Block).build::<i32>();
+    tx.put(|v| *v = 1);
+    let token = CancelToken::new();
+    let t = {
+      let token = token.clone();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+      })
+    };
+    assert_eq!(tx.put_cancelable(|_| panic!("no room, the setter must not run"), &token), Err(Canceled));
+    t.join().unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+
+    // with room it puts, canceled or not
+    assert_eq!(tx.put_cancelable(|v| *v = 2, &token), Ok(1));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![2]);
+  }
+
+  #[test]
+  fn cancel_blocking_iter() {
+    use std::thread;
+    use std::time::Duration;
+    use wait::CancelToken;
+
+    let (tx, rx) = super::channel(4, 0i32);
+    tx.put(|v| *v = 1);
+    tx.put(|v| *v = 2);
+    let token = CancelToken::new();
+    let t = {
+      let token = token.clone();
+      thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+      })
+    };
+    // what is there comes out, then the wait for more ends with the token
+    let mut it = rx.iter_cancelable(&token);
+    assert_eq!(it.by_ref().collect::<Vec<i32>>(), vec![1, 2]);
+    assert!(token.is_canceled() && !rx.is_closed());
+    t.join().unwrap();
+
+    // and stays ended
+    tx.put(|v| *v = 3);
+    assert_eq!(it.next(), None);
+    drop(it);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![3]);
+  }
+
+  #[test]
+  fn read_twice() {
+    let mut x = CircularBuffer::new(2, 0i32);
+    x.put(|v| *v = 1);
+    assert_eq!(x.iter().count(), 1);
+    assert_eq!(x.iter().count(), 0);
+    x.put(|v| *v = 2);
+    x.put(|v| *v = 3);
+    assert_eq!(x.iter().count(), 2);
+    assert_eq!(x.iter().count(), 0);
+  }
+
+  #[test]
+  fn drops_unread_and_pending_items() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use storage::Live;
+
+    let count = Arc::new(AtomicUsize::new(0));
+    {
+      let (tx, rx) = super::channel(2, Live::new(&count));
+      // 2*2+1 slots, the default value got dropped
+      assert_eq!(count.load(Ordering::SeqCst), 5);
+
+      // items overwritten while unread are dropped when their slot is reused
+      for _ in 0..20 {
+        tx.put(|v| *v = Live::new(&count));
+      }
+      assert_eq!(count.load(Ordering::SeqCst), 5);
+
+      let read : Vec<Live> = rx.try_iter().collect();
+      assert_eq!(read.len(), 2);
+      assert_eq!(count.load(Ordering::SeqCst), 7);
+      drop(read);
+
+      // pending at drop time
+      tx.put(|v| *v = Live::new(&count));
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+
+  #[test]
+  fn panicking_sender_poisons() {
+    use std::thread;
+    use super::Poisoned;
+
+    let (tx, rx) = super::channel(4, 0i32);
+    let t = thread::spawn(move || {
+      tx.put(|v| *v = 1);
+      tx.put(|_| panic!("setter failed"));
+    });
+    assert!(t.join().is_err());
+
+    assert!(rx.wait(None));
+    assert!(rx.is_poisoned());
+    assert_eq!(rx.checked_iter().unwrap().collect::<Vec<i32>>(), vec![1]);
+    assert_eq!(rx.checked_iter().err(), Some(Poisoned));
+  }
+
+  #[test]
+  fn dropped_sender_is_not_poisoned() {
+    let (tx, rx) = super::channel(4, 0i32);
+    drop(tx);
+    assert!(!rx.is_poisoned());
+    assert_eq!(rx.checked_iter().unwrap().count(), 0);
+  }
+
+  #[test]
+  fn panicking_setter_keeps_writer_state() {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::Ordering;
+
+    let mut x = CircularBuffer::new(3, 0i32);
+    x.put(|v| *v = 1);
+
+    let flags = |x : &CircularBuffer<i32>| x.ctrl.iter().map(|f| f.load(Ordering::SeqCst)).collect::<Vec<usize>>();
+    let before = (x.writer.get_mut().write_tmp, flags(&x));
+    let ret = panic::catch_unwind(AssertUnwindSafe(|| {
+      x.put(|v| { *v = 99; panic!("setter failed"); });
+    }));
+    assert!(ret.is_err());
+    assert_eq!((x.writer.get_mut().write_tmp, flags(&x)), before);
+
+    x.put(|v| *v = 2);
+    assert_eq!(x.iter().collect::<Vec<i32>>(), vec![1, 2]);
+  }
+
+  #[test]
+  fn put_replace_returns_unread() {
+    let (tx, rx) = super::channel(2, 0i32);
+    assert_eq!(tx.put_replace(1), None);
+    assert_eq!(tx.put_replace(2), None);
+    assert_eq!(tx.put_replace(3), Some(1));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![2, 3]);
+    assert_eq!(tx.put_replace(4), None);
+    assert_eq!(tx.put_replace(5), None);
+    assert_eq!(tx.put_replace(6), Some(4));
+    assert_eq!(tx.put_replace(7), Some(5));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![6, 7]);
+  }
+
+  #[test]
+  fn put_if_keeps_increasing() {
+    let (tx, rx) = super::channel(2, 0i32);
+    for v in [3, 1, 5, 4, 5, 7].iter() {
+      tx.put_if(|cur| *v > *cur, |slot| *slot = *v);
+    }
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![5, 7]);
+    // the reader taking the items does not change what the writer compares to
+    assert_eq!(tx.put_if(|cur| *cur < 7, |slot| *slot = 6), None);
+    assert!(tx.put_if(|cur| *cur < 8, |slot| *slot = 8).is_some());
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![8]);
+  }
+
+  #[test]
+  fn put_merge_conflates_unread() {
+    let (tx, rx) = super::channel(2, 0i32);
+    assert_eq!(tx.put_merge(1, |a, b| *a += *b), 0);
+    assert_eq!(tx.put_merge(2, |a, b| *a += *b), 0);
+    assert_eq!(tx.put_merge(3, |a, b| *a += *b), 0);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![6]);
+    assert_eq!(tx.put_merge(4, |a, b| *a += *b), 1);
+    assert_eq!(tx.put_merge(5, |a, b| *a += *b), 1);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![9]);
+  }
+
+  #[test]
+  fn put_merge_loses_nothing() {
+    use std::thread;
+
+    const ITEMS : u64 = 100000;
+    let (tx, rx) = super::channel(4, 0u64);
+    let t = thread::spawn(move || {
+      for _ in 0..ITEMS { tx.put_merge(1, |a, b| *a += *b); }
+    });
+    let mut sum = 0;
+    while sum < ITEMS {
+      sum += rx.try_iter().sum::<u64>();
+    }
+    t.join().unwrap();
+    assert_eq!(sum, ITEMS);
+  }
+
+  #[test]
+  fn put_merge_needs_no_room() {
+    use super::{Builder, Policy};
+
+    let (tx, rx) = Builder::new().capacity(1).overwrite(Policy::Block).build::<i32>();
+    tx.put(|v| *v = 1);
+    assert!(tx.is_full());
+    // folds into the unread item instead of waiting for the receiver
+    assert_eq!(tx.put_merge(2, |a, b| *a += *b), 0);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![3]);
+  }
+
+  #[test]
+  fn iter_leaves_an_item_merged_into_nonstop() {
+    use super::{Builder, Faults, Point};
+    use std::sync::{Arc, Mutex};
+
+    let faults = Arc::new(Faults::new());
+    let (tx, rx) = Builder::new().capacity(2).faults(faults.clone()).build::<u64>();
+    tx.put_merge(1, |a, b| *a += *b);
+
+    // a merge before every take attempt, one more than iter() retries
+    let tx     = Arc::new(Mutex::new(Some(tx)));
+    let merger = tx.clone();
+    faults.call(Point::IterBeforeTake, 0, 3, move || {
+      if let Some(ref tx) = *merger.lock().unwrap() { tx.put_merge(1, |a, b| *a += *b); }
+    });
+    assert_eq!(rx.try_iter().count(), 0);
+    assert!(!rx.is_empty());
+    assert_eq!(rx.try_iter().collect::<Vec<u64>>(), vec![4]);
+    let stats = rx.stats();
+    assert_eq!((stats.items, stats.cut_short), (1, 0));
+    tx.lock().unwrap().take();
+  }
+
+  #[test]
+  #[should_panic(expected = "fit the flag layout")]
+  fn no_ring_of_65536() {
+    // a lap would leave every flag's seq as it was
+    super::channel_pow2(40_000, 0u8);
+  }
+
+  #[test]
+  fn enumerated_seqnos() {
+    let (tx, rx) = super::channel(3, 0i32);
+    for i in 0..5 { assert_eq!(tx.put(|v| *v = i * 10), i as u64); }
+    assert_eq!(rx.try_iter_enumerated().collect::<Vec<(u64, i32)>>(), vec![(2, 20), (3, 30), (4, 40)]);
+    tx.put(|v| *v = 50);
+    assert_eq!(rx.try_iter_enumerated().collect::<Vec<(u64, i32)>>(), vec![(5, 50)]);
+  }
+
+  #[test]
+  fn receiver_timing() {
+    let (tx, mut rx) = super::channel(2, 0i32);
+    assert!(rx.timing().is_none());
+    rx.record_timing(8);
+
+    for i in 0..5 { tx.put(|v| *v = i); }
+    assert_eq!(rx.try_iter().count(), 2);
+    tx.put(|v| *v = 5);
+    assert_eq!(rx.try_iter().count(), 1);
+
+    let t = rx.timing().unwrap();
+    assert_eq!((t.samples, t.max_lag, t.lost), (3, 1, 0));
+    for i in 0..4 { tx.put(|v| *v = i); }
+    rx.try_iter().count();
+    assert_eq!(rx.timing().unwrap().lost, 2);
+  }
+
+  #[test]
+  fn try_put_without_contention() {
+    let x = CircularBuffer::new(2, 0i32);
+    unsafe {
+      assert_eq!(x.put_evicting(|_, v| *v = 1, 0), Ok((0, false)));
+      assert_eq!(x.put_evicting(|_, v| *v = 2, 0), Ok((1, false)));
+      assert_eq!(x.put_evicting(|_, v| *v = 3, 0), Ok((2, true)));
+    }
+
+    let (tx, rx) = super::channel(2, 0i32);
+    assert_eq!(tx.try_put(|v| *v = 7, 0), Ok(0));
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![7]);
+  }
+  #[test]
+  fn halves_shared_by_reference() {
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // neither half is Sync, across threads one goes behind a Mutex
+    let (tx, rx) = super::channel(4, 0i32);
+    let tx = Arc::new(Mutex::new(tx));
+    let t = { let tx = tx.clone(); thread::spawn(move || { tx.lock().unwrap().put(|x| *x = 1); }) };
+    t.join().unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+
+    let tx = Rc::new(Arc::try_unwrap(tx).ok().unwrap().into_inner().unwrap());
+    let rx = Rc::new(rx);
+    let put = { let tx = tx.clone(); move |v| tx.put(|x| *x = v) };
+    put(2);
+    put(3);
+    let mut seen = vec![];
+    for v in rx.try_iter() {
+      seen.push(v);
+      // the receiver is only borrowed, the other methods stay usable
+      assert!(!rx.is_poisoned());
+    }
+    assert_eq!(seen, vec![2, 3]);
+    assert_eq!(rx.try_iter().count(), 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "earlier iterator")]
+  fn second_iter_while_iterating() {
+    let (tx, rx) = super::channel(4, 0i32);
+    tx.put(|v| *v = 1);
+    let _first = rx.try_iter();
+    let _second = rx.try_iter();
+  }
+
+  #[test]
+  #[should_panic(expected = "own setter")]
+  fn put_from_inside_setter() {
+    let (tx, _rx) = super::channel(4, 0i32);
+    tx.put(|_| { tx.put(|v| *v = 2); });
   }
 }
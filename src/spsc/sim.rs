@@ -0,0 +1,375 @@
+
+// deterministic simulation of a producer and a consumer on one thread
+//
+// a seeded scheduler picks which side runs the next operation, and at
+// every fault point (see fault.rs) it may let the other side run whole
+// operations right there, e.g. a writer going around the ring while the
+// reader sits between loading a flag and taking it. the same seed gives
+// the same interleaving, so a failure is replayed by its seed
+//
+// unlike loom this doesn't search every interleaving and doesn't model
+// weak memory, it only preempts at the fault points, one level deep. in
+// exchange it runs long scenarios in milliseconds
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use mpsc;
+use segqueue::SegQueue;
+use seq::Seqno;
+use super::{Builder, Faults, Point, Receiver, Sender};
+
+// splitmix64, good enough to pick interleavings
+struct Rng(u64);
+
+impl Rng {
+  fn next(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  // true with a chance of `percent` in 100
+  fn chance(&mut self, percent : u64) -> bool {
+    self.next() % 100 < percent
+  }
+
+  fn upto(&mut self, max : u64) -> u64 {
+    1 + self.next() % max
+  }
+
+  // what the stress runs do at a fault point or between operations:
+  // mostly nothing, sometimes yield, now and then a nap
+  fn perturb(&mut self) {
+    match self.next() % 100 {
+      0..=1  => thread::sleep(Duration::from_micros(50)),
+      2..=19 => thread::yield_now(),
+      _      => {},
+    }
+  }
+}
+
+// what the consumer checks everything it gets against
+#[derive(Default)]
+struct Model {
+  started   : Seqno,            // puts begun, the newest may be half done
+  last_seen : Option<Seqno>,
+  received  : usize,
+  preempted : usize,            // operations run inside the other side
+}
+
+struct World {
+  tx       : Mutex<Sender<Seqno>>,
+  rx       : Mutex<Receiver<Seqno>>,
+  model    : Mutex<Model>,
+  rng      : Mutex<Rng>,
+  capacity : usize,
+  nested   : AtomicBool,        // a side is running inside the other one
+}
+
+impl World {
+  fn produce(&self) {
+    let tx = self.tx.lock().unwrap();
+    let seqno = {
+      let mut model = self.model.lock().unwrap();
+      model.started += 1;
+      model.started - 1
+    };
+    assert_eq!(tx.put(|v| *v = seqno), seqno);
+  }
+
+  fn consume(&self) {
+    let rx = self.rx.lock().unwrap();
+    for (seqno, v) in rx.try_iter_enumerated() {
+      let mut model = self.model.lock().unwrap();
+      assert_eq!(v, seqno, "item does not match its seqno");
+      assert!(seqno < model.started, "seqno {} was never put", seqno);
+      assert!(model.last_seen.is_none_or(|l| l < seqno), "seqno {} after {:?}", seqno, model.last_seen);
+      model.last_seen = Some(seqno);
+      model.received += 1;
+    }
+  }
+
+  // runs up to `max` operations of one side inside the other one
+  fn preempt(&self, percent : u64, max : u64, op : fn(&World)) {
+    if self.nested.swap(true, Ordering::SeqCst) { return; }
+    let steps = {
+      let mut rng = self.rng.lock().unwrap();
+      if rng.chance(percent) { rng.upto(max) } else { 0 }
+    };
+    self.model.lock().unwrap().preempted += steps as usize;
+    for _ in 0..steps { op(self); }
+    self.nested.store(false, Ordering::SeqCst);
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+  pub puts      : Seqno,
+  pub received  : usize,
+  pub preempted : usize,
+}
+
+// one seeded scenario of `ops` top level operations, panics on a broken
+// invariant
+pub fn run(seed : u64, ops : usize, capacity : usize) -> Report {
+  let faults = Arc::new(Faults::new());
+  let (tx, rx) = Builder::new().capacity(capacity).faults(faults.clone()).build::<Seqno>();
+  let world = Arc::new(World {
+    tx       : Mutex::new(tx),
+    rx       : Mutex::new(rx),
+    model    : Mutex::new(Model::default()),
+    rng      : Mutex::new(Rng(seed)),
+    capacity,
+    nested   : AtomicBool::new(false),
+  });
+
+  // the hooks only hold a weak reference, the ring owns the hooks
+  let lap = world.capacity as u64 + 2;
+  for point in &[Point::PutBeforeSwap, Point::PutAfterSwap] {
+    let w = Arc::downgrade(&world);
+    faults.call(*point, 0, usize::MAX, move || {
+      if let Some(w) = w.upgrade() { w.preempt(20, 2, World::consume); }
+    });
+  }
+  {
+    let w = Arc::downgrade(&world);
+    faults.call(Point::IterBeforeTake, 0, usize::MAX, move || {
+      if let Some(w) = w.upgrade() { w.preempt(20, lap, World::produce); }
+    });
+  }
+
+  for _ in 0..ops {
+    let produce = world.rng.lock().unwrap().chance(60);
+    if produce { world.produce(); } else { world.consume(); }
+  }
+
+  // the newest item always gets through in the end, once nothing
+  // preempts the consumer any more
+  world.nested.store(true, Ordering::SeqCst);
+  world.consume();
+  let model = world.model.lock().unwrap();
+  if model.started > 0 {
+    assert_eq!(model.last_seen, Some(model.started - 1), "the newest item never arrived");
+  }
+  Report { puts : model.started, received : model.received, preempted : model.preempted }
+}
+
+// run() for every seed, on failure the seed is printed for a replay with
+// RPG_SIM_SEED=<seed>, which then runs just that one
+pub fn explore(seeds : u64, ops : usize, capacity : usize) {
+  let seeds : Vec<u64> = match env::var("RPG_SIM_SEED").ok().and_then(|s| s.parse().ok()) {
+    Some(seed) => vec![seed],
+    None       => (0..seeds).collect(),
+  };
+  for seed in seeds {
+    if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| run(seed, ops, capacity))) {
+      eprintln!("spsc simulation failed, replay with RPG_SIM_SEED={}", seed);
+      panic::resume_unwind(panic);
+    }
+  }
+}
+
+// the same invariants with a real producer and consumer thread, the hooks
+// make either side yield or nap at random fault points. this stands in
+// for a randomized scheduler like shuttle, which the crate can't depend
+// on: the os still schedules, so a seed picks the perturbations but
+// doesn't replay a run. cheap enough for big rings and long runs
+pub fn stress(seed : u64, ops : usize, capacity : usize) -> Report {
+  let faults = Arc::new(Faults::new());
+  let rng    = Arc::new(Mutex::new(Rng(seed)));
+  for point in &[Point::PutBeforeSwap, Point::PutAfterSwap, Point::IterBeforeTake] {
+    let rng = rng.clone();
+    faults.call(*point, 0, usize::MAX, move || rng.lock().unwrap().perturb());
+  }
+
+  let (tx, rx) = Builder::new().capacity(capacity).faults(faults).build::<Seqno>();
+  let puts = ops as Seqno;
+  let producer = thread::spawn(move || {
+    for i in 0..puts { assert_eq!(tx.put(|v| *v = i), i); }
+  });
+
+  let mut model = Model::default();
+  while puts > 0 && model.last_seen != Some(puts - 1) {
+    for (seqno, v) in rx.try_iter_enumerated() {
+      assert_eq!(v, seqno, "item does not match its seqno");
+      assert!(seqno < puts, "seqno {} was never put", seqno);
+      assert!(model.last_seen.is_none_or(|l| l < seqno), "seqno {} after {:?}", seqno, model.last_seen);
+      model.last_seen = Some(seqno);
+      model.received += 1;
+    }
+    thread::yield_now();
+  }
+  producer.join().unwrap();
+  Report { puts, received : model.received, preempted : 0 }
+}
+
+// the newest item of each of several producers, the mpsc and segqueue
+// runs put (producer, i) items
+struct Order {
+  last     : Vec<Option<usize>>,
+  ops      : usize,               // items per producer
+  received : usize,
+}
+
+impl Order {
+  fn new(producers : usize, ops : usize) -> Order {
+    Order { last : vec![None; producers], ops, received : 0 }
+  }
+
+  fn see(&mut self, (p, i) : (usize, usize)) {
+    assert!(p < self.last.len() && i < self.ops, "({}, {}) was never put", p, i);
+    assert!(self.last[p].is_none_or(|l| l < i), "producer {} went from {:?} to {}", p, self.last[p], i);
+    self.last[p] = Some(i);
+    self.received += 1;
+  }
+}
+
+// stress() for mpsc::channel_combining(): `producers` threads put their
+// numbered items with random pauses in between, so the combiner lock
+// changes hands mid drain. every producer's items must arrive in order.
+// with a ring big enough for everything none may be lost either, and all
+// must be in the shared ring once the puts returned, before the senders
+// go and their final drain could hide an item left in a staging ring
+pub fn stress_combining(seed : u64, producers : usize, ops : usize, capacity : usize) -> Report {
+  let (tx, rx) = mpsc::channel_combining(capacity, (0usize, 0usize));
+  let threads : Vec<_> = (0..producers).map(|p| {
+    let tx = tx.clone();
+    let mut rng = Rng(seed ^ (p as u64) << 32);
+    thread::spawn(move || {
+      for i in 0..ops {
+        tx.put(|v| *v = (p, i));
+        rng.perturb();
+      }
+      tx
+    })
+  }).collect();
+  drop(tx);
+
+  // read while the producers run, then what is left once they are done
+  let mut order = Order::new(producers, ops);
+  while !threads.iter().all(|t| t.is_finished()) {
+    rx.try_iter().for_each(|item| order.see(item));
+    thread::yield_now();
+  }
+  let senders : Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+  rx.try_iter().for_each(|item| order.see(item));
+  let lossless = capacity >= producers * ops;
+  if lossless {
+    assert!(order.last.iter().all(|l| *l == ops.checked_sub(1)), "items stranded in a staging ring: {:?}", order.last);
+  }
+  drop(senders);
+  rx.iter().for_each(|item| order.see(item));
+  if lossless {
+    assert_eq!(order.received, producers * ops, "items came twice");
+  }
+  Report { puts : (producers * ops) as Seqno, received : order.received, preempted : 0 }
+}
+
+// the SegQueue under `producers` pushing and `consumers` popping threads
+// with random pauses, so the queue runs empty and fills again and head
+// and tail cross segment boundaries, LAP slots apart, in either order.
+// every item must come out exactly once, a consumer gets the items of a
+// producer in the order they were pushed
+pub fn stress_segqueue(seed : u64, producers : usize, consumers : usize, ops : usize) -> Report {
+  let queue  = Arc::new(SegQueue::new());
+  let popped = Arc::new(AtomicUsize::new(0));
+  let total  = producers * ops;
+
+  let pushers : Vec<_> = (0..producers).map(|p| {
+    let queue   = queue.clone();
+    let mut rng = Rng(seed ^ (p as u64) << 32);
+    thread::spawn(move || {
+      for i in 0..ops {
+        queue.push((p, i));
+        rng.perturb();
+      }
+    })
+  }).collect();
+
+  let poppers : Vec<_> = (0..consumers).map(|c| {
+    let queue   = queue.clone();
+    let popped  = popped.clone();
+    let mut rng = Rng(!seed ^ (c as u64) << 32);
+    thread::spawn(move || {
+      let mut got   = Vec::new();
+      let mut order = Order::new(producers, ops);
+      while popped.load(Ordering::Acquire) < total {
+        match queue.pop() {
+          Some(item) => {
+            order.see(item);
+            got.push(item);
+            popped.fetch_add(1, Ordering::AcqRel);
+          },
+          None => thread::yield_now(),
+        }
+        rng.perturb();
+      }
+      got
+    })
+  }).collect();
+
+  for t in pushers { t.join().unwrap(); }
+  let mut seen = vec![vec![false; ops]; producers];
+  for t in poppers {
+    for (p, i) in t.join().unwrap() {
+      assert!(!seen[p][i], "({}, {}) came out twice", p, i);
+      seen[p][i] = true;
+    }
+  }
+  assert!(queue.is_empty() && queue.pop().is_none(), "items left after {} pops", total);
+  Report { puts : total as Seqno, received : popped.load(Ordering::Acquire), preempted : 0 }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{explore, run, stress, stress_combining, stress_segqueue};
+  use segqueue::LAP;
+
+  #[test]
+  fn same_seed_same_run() {
+    assert_eq!(run(7, 300, 3), run(7, 300, 3));
+    let r = run(7, 300, 3);
+    assert!(r.puts > 0 && r.received > 0 && r.received <= r.puts as usize);
+    assert!(r.preempted > 0);
+  }
+
+  #[test]
+  fn many_seeds() {
+    explore(200, 500, 1);
+    explore(200, 500, 4);
+  }
+
+  #[test]
+  fn threads_with_random_perturbations() {
+    for (seed, capacity) in [1, 3, 64, 4096].iter().enumerate() {
+      let r = stress(seed as u64, 20_000, *capacity);
+      assert!(r.received > 0 && r.received <= 20_000);
+    }
+  }
+
+  #[test]
+  fn combining_producers() {
+    // big enough to keep everything, then small enough to evict
+    for seed in 0..4 {
+      let r = stress_combining(seed, 4, 600, 2_400);
+      assert_eq!(r.received, 2_400);
+    }
+    let r = stress_combining(9, 6, 5_000, 16);
+    assert!(r.received > 0 && r.received <= 30_000);
+  }
+
+  #[test]
+  fn segqueue_across_segments() {
+    for (seed, &(producers, consumers)) in [(1, 1), (1, 3), (3, 1), (4, 4)].iter().enumerate() {
+      let r = stress_segqueue(seed as u64, producers, consumers, 200 * LAP);
+      assert_eq!(r.received, producers * 200 * LAP);
+    }
+  }
+}
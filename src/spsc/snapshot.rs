@@ -0,0 +1,134 @@
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use super::{halves, CircularBuffer, Policy, Receiver, Sender};
+use wait::SpinThenPark;
+use seq::{self, Seqno};
+use storage::{AlignedBuf, RingStorage};
+
+// a channel's contents and counters as plain data, to checkpoint a
+// pipeline and pick it up again after a restart
+//
+// there is no serialization here, the fields are public for whatever
+// format the caller writes them in. restoring gives a fresh channel that
+// goes on where the old one stopped: the unread items come out first with
+// their old seqnos, the next put gets the seqno after them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelState<T> {
+  pub capacity  : usize,
+  pub policy    : Policy,
+  pub published : Seqno,      // items put in all, the seqno of the next one
+  pub read      : Seqno,      // what had been published at the reader's last read
+  pub items     : Vec<T>,     // unread, oldest first, the last published - items.len() seqnos
+}
+
+// the state of the channel `tx` and `rx` belong to. holding both in one
+// thread means neither side is busy, so the snapshot is consistent
+pub fn snapshot<T, S>(tx : &Sender<T, S>, rx : &Receiver<T, S>) -> ChannelState<T>
+  where T : Clone + Send,
+        S : RingStorage<T>
+{
+  let ring = idle_ring(tx, rx);
+  let published = unsafe { (*ring.writer.get()).put_count };
+  let read = rx.seen();
+  let items = ring.to_vec();
+  debug_assert_eq!(items.len() as Seqno, seq::distance(read, published).min(ring.size as Seqno));
+
+  ChannelState { capacity : ring.size, policy : tx.policy, published, read, items }
+}
+
+// the ring behind both halves, neither of which may be in the middle of
+// a put or an iteration
+pub(super) fn idle_ring<'a, T, S>(tx : &'a Sender<T, S>, rx : &Receiver<T, S>) -> &'a CircularBuffer<T, S>
+  where T : Clone + Send,
+        S : RingStorage<T>
+{
+  if !Arc::ptr_eq(&tx.inner, &rx.inner) { panic!("the sender of {} and the receiver of {} belong to different channels", tx.label, rx.label); }
+  if tx.writing.get() || rx.reading.get() { panic!("{} looked at while a reservation or an iterator is alive", tx.label); }
+  &tx.inner
+}
+
+// a new channel holding `state`, every other slot a clone of `default_value`
+pub fn restore<T : Clone + Send>(state : ChannelState<T>, default_value : T) -> (Sender<T>, Receiver<T>) {
+  if state.capacity == 0 { panic!("size cannot be zero"); }
+  restore_with_storage(AlignedBuf::new((state.capacity * 2) + 1, default_value), state)
+}
+
+// restore() into the given storage, e.g. a fresh mapping, which must hold
+// 2*capacity+1 elements
+pub fn restore_with_storage<T, S>(storage : S, state : ChannelState<T>) -> (Sender<T, S>, Receiver<T, S>)
+  where T : Clone + Send,
+        S : RingStorage<T>
+{
+  let mut ring = CircularBuffer::with_storage(storage);
+  if ring.size != state.capacity { panic!("storage holds {} items, the state {}", ring.size, state.capacity); }
+  if state.items.len() > state.capacity || seq::distance(state.read, state.published) < state.items.len() as Seqno {
+    panic!("state holds more items than were published since the last read");
+  }
+
+  // the counters as if everything before the items had been read, then
+  // the items are published again under their old seqnos
+  let start = state.published - state.items.len() as Seqno;
+  ring.seqno().store(start as usize, Ordering::Relaxed);
+  ring.writer.get_mut().put_count  = start;
+  ring.reader.get_mut().max_read   = state.read as usize;
+  ring.reader.get_mut().read_epoch = state.read.wrapping_sub(state.read as usize as Seqno);
+  for item in state.items {
+    let mut item = Some(item);
+    ring.put(|v| if let Some(n) = item.take() { *v = n; });
+  }
+
+  // the same as channel_with_storage()
+  let (mut tx, mut rx) = halves(ring, Arc::new(SpinThenPark::default()));
+  tx.policy = state.policy;
+  rx.policy = state.policy;
+  (tx, rx)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{restore, snapshot};
+  use spsc::{self, Builder, Policy};
+
+  #[test]
+  fn round_trip() {
+    let (tx, rx) = spsc::channel(3, 0i32);
+    tx.put(|v| *v = 1);
+    assert_eq!(rx.try_iter().collect::<Vec<i32>>(), vec![1]);
+    for i in 2..7 { tx.put(|v| *v = i); }
+
+    let state = snapshot(&tx, &rx);
+    assert_eq!((state.published, state.read, state.capacity), (6, 1, 3));
+    assert_eq!(state.items, vec![4, 5, 6]);
+    // taking it changes nothing
+    assert_eq!(snapshot(&tx, &rx), state);
+    drop((tx, rx));
+
+    let (tx, rx) = restore(state, 0);
+    assert_eq!(tx.put(|v| *v = 7), 6);
+    let got : Vec<_> = rx.try_iter_enumerated().collect();
+    assert_eq!(got, vec![(4, 5), (5, 6), (6, 7)]);
+    assert!(rx.is_empty());
+  }
+
+  #[test]
+  fn restores_policy_and_lost_items() {
+    let (tx, rx) = Builder::new().capacity(2).overwrite(Policy::Block).build::<u64>();
+    tx.put(|v| *v = 10);
+    tx.put(|v| *v = 11);
+    let mut state = snapshot(&tx, &rx);
+    assert_eq!(state.items, vec![10, 11]);
+
+    // pretend a few more were published and overwritten before, the lost
+    // count goes on from the old read position
+    state.published += 3;
+    state.items = vec![14, 15];
+    let (tx, rx) = restore(state, 0);
+    assert!(tx.is_full());
+    drop(tx);
+    let mut seen = vec![];
+    let drained = rx.drain_remaining(|v| seen.push(v));
+    assert_eq!((seen, drained.items, drained.lost), (vec![14, 15], 2, 3));
+  }
+}
@@ -0,0 +1,72 @@
+
+use super::{Receiver, Sender};
+use seq::Seqno;
+use storage::RingStorage;
+
+// what each half of a channel went through since it was made, to tell
+// contention (lost flag CAS) from capacity (evictions) and, together with
+// TimingStats, from a lagging consumer. every half counts on its own, in
+// plain words it alone writes, so reading them costs nothing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SenderStats {
+  pub puts        : Seqno,    // items published
+  pub cas_retries : usize,    // flag CAS lost to the reader and tried again
+  pub gave_up     : usize,    // try_put()s that ran out of retries for it
+  pub evicted     : u64,      // unread items overwritten by a put
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReceiverStats {
+  pub reads       : usize,    // try_iter()s that took something
+  pub items       : u64,      // what those took
+  pub cas_retries : usize,    // items taken again as the writer merged into them
+  pub cut_short   : usize,    // try_iter()s the writer lapped halfway
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Sender<T, S> {
+  pub fn stats(&self) -> SenderStats {
+    if self.writing.get() { panic!("{}: sender used from inside its own setter", self.label); }
+    // the guard above: no put is in the middle of changing these
+    let w = unsafe { &*self.inner.writer.get() };
+    SenderStats {
+      puts        : w.put_count,
+      cas_retries : w.put_retries,
+      gave_up     : w.put_gave_up,
+      evicted     : w.evictions,
+    }
+  }
+}
+
+impl<T: Clone + Send, S: RingStorage<T>> Receiver<T, S> {
+  pub fn stats(&self) -> ReceiverStats {
+    let r = unsafe { &*self.inner.reader.get() };
+    ReceiverStats {
+      reads       : r.reads,
+      items       : r.taken,
+      cas_retries : r.iter_misses - r.iter_cut,
+      cut_short   : r.iter_cut,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use super::{ReceiverStats, SenderStats};
+  use spsc::{Builder, Faults, Point};
+
+  #[test]
+  fn counts_each_side() {
+    let faults = Arc::new(Faults::new());
+    let (tx, rx) = Builder::new().capacity(2).faults(faults.clone()).build::<i32>();
+    for i in 0..3 { tx.put(|v| *v = i); }
+    faults.fail_cas(Point::PutBeforeSwap, 2);
+    assert!(tx.try_put(|v| *v = 3, 1).is_err());
+    assert_eq!(tx.stats(), SenderStats { puts : 3, cas_retries : 1, gave_up : 1, evicted : 1 });
+
+    faults.fail_cas(Point::IterBeforeTake, 1);
+    assert_eq!(rx.try_iter().count(), 2);
+    assert_eq!(rx.try_iter().count(), 0);
+    assert_eq!(rx.stats(), ReceiverStats { reads : 1, items : 2, cas_retries : 1, cut_short : 0 });
+  }
+}
@@ -0,0 +1,114 @@
+
+// receive side timing over the last `window` items
+//
+// every item gets the instant the receiver's try_iter() handed it out and how
+// many newer items were already there (try_iter() always reaches up to the
+// latest item, so that is how far the receiver lagged). items that were
+// overwritten before the receiver got to them only show up as lost
+
+use std::time::{Duration, Instant};
+
+use seq::{self, Seqno};
+use simple::CircularBuffer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingStats {
+  pub samples  : usize,
+  pub min_gap  : Duration,   // between two consecutive receives
+  pub mean_gap : Duration,
+  pub max_gap  : Duration,
+  pub mean_lag : f64,        // items the writer was ahead
+  pub max_lag  : usize,
+  pub lost     : u64,        // overwritten unread, since recording started
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+  at  : Instant,
+  lag : usize,
+}
+
+pub(crate) struct Timing {
+  samples : CircularBuffer<Sample>,
+  next    : Option<Seqno>,   // seqno expected next, to spot lost items
+  lost    : u64,
+}
+
+impl Timing {
+  pub(crate) fn new(window : usize) -> Timing {
+    Timing {
+      samples : CircularBuffer::new(window, Sample { at : Instant::now(), lag : 0 }),
+      next    : None,
+      lost    : 0,
+    }
+  }
+
+  // a batch of `count` items starting at `first`
+  pub(crate) fn record(&mut self, first : Seqno, count : usize) {
+    if count == 0 { return; }
+
+    if let Some(next) = self.next {
+      self.lost += seq::distance(next, first);
+    }
+    self.next = Some(first + count as Seqno);
+
+    let at = Instant::now();
+    for lag in (0..count).rev() {
+      self.samples.put(|s| *s = Sample { at, lag });
+    }
+  }
+
+  pub(crate) fn stats(&self) -> TimingStats {
+    let mut ret = TimingStats {
+      samples  : 0,
+      min_gap  : Duration::from_secs(0),
+      mean_gap : Duration::from_secs(0),
+      max_gap  : Duration::from_secs(0),
+      mean_lag : 0.0,
+      max_lag  : 0,
+      lost     : self.lost,
+    };
+
+    let mut prev : Option<Instant> = None;
+    let mut gaps = Duration::from_secs(0);
+    let mut lags = 0;
+
+    for s in self.samples.iter() {
+      if let Some(p) = prev {
+        let gap = s.at - p;
+        if ret.samples == 1 || gap < ret.min_gap { ret.min_gap = gap; }
+        if gap > ret.max_gap { ret.max_gap = gap; }
+        gaps += gap;
+      }
+      prev = Some(s.at);
+      lags += s.lag;
+      ret.max_lag = ret.max_lag.max(s.lag);
+      ret.samples += 1;
+    }
+
+    if ret.samples > 1 { ret.mean_gap = gaps / (ret.samples as u32 - 1); }
+    if ret.samples > 0 { ret.mean_lag = lags as f64 / ret.samples as f64; }
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Timing;
+
+  #[test]
+  fn lag_and_lost() {
+    let mut t = Timing::new(4);
+    assert_eq!(t.stats().samples, 0);
+
+    t.record(0, 3);
+    let s = t.stats();
+    assert_eq!((s.samples, s.max_lag, s.lost), (3, 2, 0));
+    assert_eq!(s.mean_lag, 1.0);
+
+    t.record(6, 3);
+    let s = t.stats();
+    assert_eq!((s.samples, s.max_lag, s.lost), (4, 2, 3));
+    assert!(s.min_gap <= s.mean_gap && s.mean_gap <= s.max_gap);
+  }
+}
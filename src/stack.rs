@@ -0,0 +1,182 @@
+
+// bounded lock-free LIFO (Treiber stack)
+//
+// the values live in a fixed array of nodes, what the stack links up are
+// node indices. two of those index stacks make a Stack: one of nodes that
+// hold a value and one of free nodes, push() moves a node from the free
+// one to the other and try_pop() back. nothing is allocated after new().
+//
+// a head word packs the top index with a tag that counts every change to
+// it, so a CAS cannot succeed on a head that was popped and pushed back
+// in between (ABA): that pop would have read a stale link. the tag has
+// half a word, it would have to wrap around exactly between a load and
+// the CAS to fool it
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const INDEX_BITS : u32   = usize::BITS / 2;
+const INDEX_MASK : usize = (1 << INDEX_BITS) - 1;
+const NIL        : usize = INDEX_MASK;      // an empty stack
+
+// most indices a head word can point at
+pub(crate) const MAX_LEN : usize = NIL - 1;
+
+fn pack(index : usize, tag : usize) -> usize {
+  (tag << INDEX_BITS) | index
+}
+
+// a stack of the indices below `len`, each on it at most once. whoever
+// pops an index owns whatever it stands for until pushing it again
+pub(crate) struct IndexStack {
+  head : AtomicUsize,                       // top index and its tag
+  next : Box<[AtomicUsize]>,                // the index below each one
+}
+
+impl IndexStack {
+  // holds every index, 0 on top, if `full`. empty otherwise
+  pub(crate) fn new(len : usize, full : bool) -> IndexStack {
+    if len > MAX_LEN { panic!("at most {} entries fit the stack on this target, got {}", MAX_LEN, len); }
+    IndexStack {
+      head : AtomicUsize::new(pack(if full && len > 0 { 0 } else { NIL }, 0)),
+      next : (0..len).map(|i| AtomicUsize::new(if i + 1 < len { i + 1 } else { NIL })).collect(),
+    }
+  }
+
+  // the owner's writes to what `index` stands for happen before the
+  // next pop() of it
+  pub(crate) fn push(&self, index : usize) {
+    let mut head = self.head.load(Ordering::Relaxed);
+    loop {
+      self.next[index].store(head & INDEX_MASK, Ordering::Relaxed);
+      let tag = (head >> INDEX_BITS).wrapping_add(1);
+      match self.head.compare_exchange_weak(head, pack(index, tag), Ordering::Release, Ordering::Relaxed) {
+        Ok(_)        => return,
+        Err(current) => head = current,
+      }
+    }
+  }
+
+  pub(crate) fn pop(&self) -> Option<usize> {
+    let mut head = self.head.load(Ordering::Acquire);
+    loop {
+      let index = head & INDEX_MASK;
+      if index == NIL { return None; }
+
+      // may read the link of an index someone else just popped and
+      // pushed somewhere else, the tag makes the CAS fail then
+      let next = self.next[index].load(Ordering::Relaxed);
+      let tag  = (head >> INDEX_BITS).wrapping_add(1);
+      match self.head.compare_exchange_weak(head, pack(next, tag), Ordering::Acquire, Ordering::Acquire) {
+        Ok(_)        => return Some(index),
+        Err(current) => head = current,
+      }
+    }
+  }
+}
+
+pub struct Stack<T : Send> {
+  values : Box<[UnsafeCell<MaybeUninit<T>>]>,
+  full   : IndexStack,                      // nodes holding a value
+  free   : IndexStack,
+}
+
+// a value is only touched by whoever popped its node off either stack
+unsafe impl<T : Send> Sync for Stack<T> { }
+
+impl <T : Send> Stack<T> {
+  // an empty stack for up to `capacity` values
+  pub fn new(capacity : usize) -> Stack<T> {
+    if capacity == 0 { panic!("capacity cannot be zero"); }
+    Stack {
+      values : (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+      full   : IndexStack::new(capacity, false),
+      free   : IndexStack::new(capacity, true),
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.values.len()
+  }
+
+  // puts `value` on top, hands it back if the stack is full
+  pub fn push(&self, value : T) -> Result<(), T> {
+    let node = match self.free.pop() {
+      Some(node) => node,
+      None       => return Err(value),
+    };
+    unsafe { ptr::write(self.values[node].get(), MaybeUninit::new(value)); }
+    self.full.push(node);
+    Ok(())
+  }
+
+  // the value pushed last, None if there is none
+  pub fn try_pop(&self) -> Option<T> {
+    let node = self.full.pop()?;
+    let value = unsafe { ptr::read(self.values[node].get()).assume_init() };
+    self.free.push(node);
+    Some(value)
+  }
+}
+
+impl <T : Send> Drop for Stack<T> {
+  fn drop(&mut self) {
+    while self.try_pop().is_some() { }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Stack;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn last_in_first_out() {
+    let s = Stack::new(3);
+    assert_eq!(s.try_pop(), None);
+    for i in 0..3 { assert_eq!(s.push(i), Ok(())); }
+    assert_eq!(s.push(3), Err(3));
+    assert_eq!(s.try_pop(), Some(2));
+    assert_eq!(s.push(4), Ok(()));
+    assert_eq!((s.try_pop(), s.try_pop(), s.try_pop(), s.try_pop()), (Some(4), Some(1), Some(0), None));
+  }
+
+  #[test]
+  fn values_left_are_dropped() {
+    let value = Arc::new(());
+    {
+      let s = Stack::new(4);
+      for _ in 0..3 { s.push(value.clone()).unwrap(); }
+    }
+    assert_eq!(Arc::strong_count(&value), 1);
+  }
+
+  #[test]
+  fn no_value_lost_or_doubled() {
+    const THREADS : usize = 4;
+    const ROUNDS  : usize = 20_000;
+    let s = Arc::new(Stack::new(8));
+    for i in 0..8 { s.push(i).unwrap(); }
+
+    // every thread pops and pushes back, the same 8 values must remain
+    let threads : Vec<_> = (0..THREADS).map(|_| {
+      let s = s.clone();
+      thread::spawn(move || {
+        for _ in 0..ROUNDS {
+          if let Some(v) = s.try_pop() {
+            s.push(v).unwrap();
+          }
+        }
+      })
+    }).collect();
+    for t in threads { t.join().unwrap(); }
+
+    let mut left : Vec<usize> = (0..8).map(|_| s.try_pop().unwrap()).collect();
+    left.sort_unstable();
+    assert_eq!(left, (0..8).collect::<Vec<usize>>());
+    assert_eq!(s.try_pop(), None);
+  }
+}
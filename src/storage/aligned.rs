@@ -0,0 +1,151 @@
+
+use std::alloc::{self, Layout};
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use super::RingStorage;
+
+pub const CACHE_LINE : usize = 64;
+
+// a value on cache lines of its own, so what one thread keeps writing
+// does not drag the line another one reads along with it. repr(align)
+// only takes a literal, the storage tests keep it at CACHE_LINE
+#[repr(align(64))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CachePadded<T>(T);
+
+impl <T> CachePadded<T> {
+  pub const fn new(value : T) -> CachePadded<T> {
+    CachePadded(value)
+  }
+
+  pub fn into_inner(self) -> T {
+    self.0
+  }
+}
+
+impl <T> Deref for CachePadded<T> {
+  type Target = T;
+  fn deref(&self) -> &T { &self.0 }
+}
+
+impl <T> DerefMut for CachePadded<T> {
+  fn deref_mut(&mut self) -> &mut T { &mut self.0 }
+}
+
+// heap slots whose first element starts on a cache line
+//
+// with_slot_align() additionally guarantees every slot is aligned to the
+// requested boundary, which needs the element size to be a multiple of it
+// (e.g. [f32; 8] for 32 byte AVX loads)
+pub struct AlignedBuf<T> {
+  ptr     : NonNull<T>,
+  len     : usize,
+  layout  : Layout,
+}
+
+unsafe impl<T : Send> Send for AlignedBuf<T> { }
+unsafe impl<T : Sync> Sync for AlignedBuf<T> { }
+
+impl <T : Clone> AlignedBuf<T> {
+  pub fn new(len : usize, default_value : T) -> AlignedBuf<T> {
+    AlignedBuf::allocate(len, CACHE_LINE, |_| default_value.clone())
+  }
+
+  pub fn with_slot_align(len : usize, align : usize, default_value : T) -> AlignedBuf<T> {
+    if !align.is_power_of_two() { panic!("alignment must be a power of two, got {}", align); }
+    if !mem::size_of::<T>().is_multiple_of(align) {
+      panic!("slot size {} is not a multiple of the alignment {}, wrap the element in a #[repr(align)] type",
+             mem::size_of::<T>(), align);
+    }
+    AlignedBuf::allocate(len, align, |_| default_value.clone())
+  }
+
+  pub fn align(&self) -> usize {
+    self.layout.align()
+  }
+
+  // slot i starts as fill(i)
+  fn allocate<F : FnMut(usize) -> T>(len : usize, align : usize, fill : F) -> AlignedBuf<T> {
+    let mut fill = fill;
+    let align = align.max(CACHE_LINE).max(mem::align_of::<T>());
+    let bytes = mem::size_of::<T>().checked_mul(len).expect("capacity overflow");
+    let layout = Layout::from_size_align(bytes, align).expect("invalid layout");
+
+    let ptr = if bytes == 0 {
+      // nothing to allocate, any well aligned address will do
+      unsafe { NonNull::new_unchecked(align as *mut T) }
+    } else {
+      let p = unsafe { alloc::alloc(layout) } as *mut T;
+      match NonNull::new(p) {
+        Some(p) => p,
+        None    => alloc::handle_alloc_error(layout),
+      }
+    };
+
+    for i in 0..len {
+      unsafe { ptr::write(ptr.as_ptr().add(i), fill(i)); }
+    }
+
+    AlignedBuf { ptr, len, layout }
+  }
+}
+
+impl <T> AlignedBuf<T> {
+  /// The allocation, its length in elements and its alignment. The
+  /// elements are not dropped and the memory is not freed, hand all
+  /// three back to [`from_raw_parts()`](#method.from_raw_parts) for that.
+  pub fn into_raw_parts(self) -> (*mut T, usize, usize) {
+    let ret = (self.ptr.as_ptr(), self.len, self.layout.align());
+    mem::forget(self);
+    ret
+  }
+
+  /// Takes back an allocation given out by
+  /// [`into_raw_parts()`](#method.into_raw_parts).
+  ///
+  /// # Safety
+  ///
+  /// `ptr`, `len` and `align` must be exactly what `into_raw_parts()`
+  /// returned for an `AlignedBuf<T>` of the same `T`, every element must
+  /// still be initialized, and the parts must be taken back only once.
+  pub unsafe fn from_raw_parts(ptr : *mut T, len : usize, align : usize) -> AlignedBuf<T> {
+    let bytes  = mem::size_of::<T>() * len;
+    let layout = Layout::from_size_align_unchecked(bytes, align);
+    AlignedBuf { ptr : NonNull::new_unchecked(ptr), len, layout }
+  }
+}
+
+impl <T> RingStorage<T> for AlignedBuf<T> {
+  fn slots(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+  }
+
+  fn slots_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+  }
+
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T {
+    (*this).ptr.as_ptr()
+  }
+}
+
+// a fresh allocation with the same alignment and clones of every slot
+impl <T : Clone> Clone for AlignedBuf<T> {
+  fn clone(&self) -> AlignedBuf<T> {
+    let slots = self.slots();
+    AlignedBuf::allocate(self.len, self.layout.align(), |i| slots[i].clone())
+  }
+}
+
+// every slot always holds a live element, so all of them get dropped
+impl <T> Drop for AlignedBuf<T> {
+  fn drop(&mut self) {
+    unsafe { ptr::drop_in_place(self.slots_mut()); }
+    if self.layout.size() != 0 {
+      unsafe { alloc::dealloc(self.ptr.as_ptr() as *mut u8, self.layout); }
+    }
+  }
+}
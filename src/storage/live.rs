@@ -0,0 +1,27 @@
+
+// counts its live instances, the drop tests of the buffers check
+// the count goes back to zero
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub(crate) struct Live(Arc<AtomicUsize>);
+
+impl Live {
+  pub(crate) fn new(count : &Arc<AtomicUsize>) -> Live {
+    count.fetch_add(1, Ordering::SeqCst);
+    Live(count.clone())
+  }
+}
+
+impl Clone for Live {
+  fn clone(&self) -> Live {
+    Live::new(&self.0)
+  }
+}
+
+impl Drop for Live {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
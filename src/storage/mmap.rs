@@ -0,0 +1,130 @@
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use super::RingStorage;
+
+#[cfg(target_os = "linux")]
+type OffT = isize;
+#[cfg(not(target_os = "linux"))]
+type OffT = i64;
+
+const PROT_READ     : c_int = 1;
+const PROT_WRITE    : c_int = 2;
+const MAP_SHARED    : c_int = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const MAP_ANONYMOUS : c_int = 0x20;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const MAP_ANONYMOUS : c_int = 0x1000;
+
+extern "C" {
+  fn mmap(addr : *mut c_void, len : usize, prot : c_int,
+          flags : c_int, fd : c_int, offset : OffT) -> *mut c_void;
+  fn munmap(addr : *mut c_void, len : usize) -> c_int;
+}
+
+// a shared memory mapping used as ring storage
+//
+// anonymous mappings survive fork(), file backed mappings can be attached
+// by unrelated processes, either way the slots are plain memory and are
+// never dropped
+pub struct MmapRegion<T> {
+  ptr    : *mut T,
+  len    : usize,
+  _ty    : PhantomData<T>,
+}
+
+unsafe impl<T : Send> Send for MmapRegion<T> { }
+
+impl <T : Copy> MmapRegion<T> {
+  pub fn anonymous(len : usize, default_value : T) -> io::Result<MmapRegion<T>> {
+    let mut ret = unsafe { MmapRegion::map(len, MAP_SHARED | MAP_ANONYMOUS, -1, 0)? };
+    for v in ret.slots_mut() {
+      *v = default_value;
+    }
+    Ok(ret)
+  }
+}
+
+impl <T> MmapRegion<T> {
+  /// Maps `len` elements of the given file, growing it if needed.
+  ///
+  /// # Safety
+  ///
+  /// The existing bytes are reinterpreted as `T`, so `T` must be valid
+  /// for any bit pattern stored there (including all zeros).
+  pub unsafe fn open<P : AsRef<Path>>(path : P, len : usize) -> io::Result<MmapRegion<T>> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    MmapRegion::from_file(&file, 0, len)
+  }
+
+  /// Maps `len` elements of `file` starting at byte `offset`, growing
+  /// the file if needed. The mapping stays valid after `file` is closed.
+  ///
+  /// # Safety
+  ///
+  /// Same as `open()`, additionally `offset` must be a multiple of the
+  /// page size.
+  pub unsafe fn from_file(file : &File, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+    let end = offset.checked_add(byte_len::<T>(len)? as u64).ok_or_else(too_big)?;
+    if file.metadata()?.len() < end {
+      file.set_len(end)?;
+    }
+    MmapRegion::map(len, MAP_SHARED, file.as_raw_fd(), offset)
+  }
+
+  unsafe fn map(len : usize, flags : c_int, fd : c_int, offset : u64) -> io::Result<MmapRegion<T>> {
+    if len == 0 || mem::size_of::<T>() == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map zero bytes"));
+    }
+
+    let bytes = byte_len::<T>(len)?;
+    let p = mmap(ptr::null_mut(), bytes, PROT_READ | PROT_WRITE, flags, fd, offset as OffT);
+    if p as isize == -1 {
+      return Err(io::Error::last_os_error());
+    }
+
+    Ok(MmapRegion {
+      ptr   : p as *mut T,
+      len,
+      _ty   : PhantomData,
+    })
+  }
+}
+
+// the bytes `len` elements take, an error instead of a wrapped product
+// that would map a short region
+fn byte_len<T>(len : usize) -> io::Result<usize> {
+  len.checked_mul(mem::size_of::<T>()).ok_or_else(too_big)
+}
+
+fn too_big() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "mapping size overflows")
+}
+
+impl <T> RingStorage<T> for MmapRegion<T> {
+  fn slots(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.ptr, self.len) }
+  }
+
+  fn slots_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+  }
+
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T {
+    (*this).ptr
+  }
+}
+
+impl <T> Drop for MmapRegion<T> {
+  fn drop(&mut self) {
+    unsafe { munmap(self.ptr as *mut c_void, self.len * mem::size_of::<T>()); }
+  }
+}
@@ -0,0 +1,210 @@
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use super::RingStorage;
+
+type Handle = *mut c_void;
+
+const INVALID_HANDLE_VALUE : Handle = !0usize as Handle;
+const PAGE_READWRITE       : u32 = 0x04;
+const FILE_MAP_ALL_ACCESS  : u32 = 0x000f_001f;
+const ERROR_ALREADY_EXISTS : i32 = 183;
+
+#[link(name = "kernel32")]
+extern "system" {
+  fn CreateFileMappingW(file : Handle, attributes : *mut c_void, protect : u32,
+                        size_high : u32, size_low : u32, name : *const u16) -> Handle;
+  fn OpenFileMappingW(access : u32, inherit : i32, name : *const u16) -> Handle;
+  fn MapViewOfFile(mapping : Handle, access : u32, offset_high : u32,
+                   offset_low : u32, bytes : usize) -> *mut c_void;
+  fn UnmapViewOfFile(addr : *const c_void) -> i32;
+  fn CloseHandle(handle : Handle) -> i32;
+  fn SetLastError(code : u32);
+}
+
+// the windows flavour of the shared mapping, same interface as on unix
+//
+// views are created with CreateFileMapping/MapViewOfFile, anonymous
+// regions are backed by the page file
+pub struct MmapRegion<T> {
+  ptr    : *mut T,
+  len    : usize,
+  _ty    : PhantomData<T>,
+}
+
+unsafe impl<T : Send> Send for MmapRegion<T> { }
+
+impl <T : Copy> MmapRegion<T> {
+  pub fn anonymous(len : usize, default_value : T) -> io::Result<MmapRegion<T>> {
+    let mut ret = unsafe { MmapRegion::map(INVALID_HANDLE_VALUE, 0, len)? };
+    for v in ret.slots_mut() {
+      *v = default_value;
+    }
+    Ok(ret)
+  }
+}
+
+impl <T> MmapRegion<T> {
+  /// Maps `len` elements of the given file, growing it if needed.
+  ///
+  /// # Safety
+  ///
+  /// The existing bytes are reinterpreted as `T`, so `T` must be valid
+  /// for any bit pattern stored there (including all zeros).
+  pub unsafe fn open<P : AsRef<Path>>(path : P, len : usize) -> io::Result<MmapRegion<T>> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    MmapRegion::from_file(&file, 0, len)
+  }
+
+  /// Maps `len` elements of `file` starting at byte `offset`, growing
+  /// the file if needed. The mapping stays valid after `file` is closed.
+  ///
+  /// # Safety
+  ///
+  /// Same as `open()`, additionally `offset` must be a multiple of the
+  /// allocation granularity (64k).
+  pub unsafe fn from_file(file : &File, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+    let end = offset.checked_add(byte_len::<T>(len)? as u64).ok_or_else(too_big)?;
+    if file.metadata()?.len() < end {
+      file.set_len(end)?;
+    }
+    MmapRegion::map(file.as_raw_handle() as Handle, offset, len)
+  }
+
+  unsafe fn map(file : Handle, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+    if len == 0 || mem::size_of::<T>() == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map zero bytes"));
+    }
+
+    let bytes = byte_len::<T>(len)?;
+
+    // file mappings take their size from the file, anonymous ones need it
+    let size = if file == INVALID_HANDLE_VALUE { bytes as u64 } else { 0 };
+    let mapping = CreateFileMappingW(file, ptr::null_mut(), PAGE_READWRITE,
+                                     (size >> 32) as u32, size as u32, ptr::null());
+    if mapping.is_null() {
+      return Err(io::Error::last_os_error());
+    }
+
+    // the view keeps the mapping object alive
+    let view = MmapRegion::view(mapping, offset, len);
+    CloseHandle(mapping);
+    view
+  }
+
+  unsafe fn view(mapping : Handle, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+    if len == 0 || mem::size_of::<T>() == 0 {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map zero bytes"));
+    }
+
+    let bytes = byte_len::<T>(len)?;
+    let p = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS,
+                          (offset >> 32) as u32, offset as u32, bytes);
+    if p.is_null() {
+      return Err(io::Error::last_os_error());
+    }
+
+    Ok(MmapRegion {
+      ptr   : p as *mut T,
+      len,
+      _ty   : PhantomData,
+    })
+  }
+}
+
+// the bytes `len` elements take, an error instead of a wrapped product
+// that would map a short region
+fn byte_len<T>(len : usize) -> io::Result<usize> {
+  len.checked_mul(mem::size_of::<T>()).ok_or_else(too_big)
+}
+
+fn too_big() -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, "mapping size overflows")
+}
+
+// a mapping object with a name, over a whole file, that another process
+// opens by that name instead of going through the file
+//
+// the views are MmapRegions, they keep the object alive after this
+// handle is gone, until the last view of either process is unmapped
+pub struct NamedMapping {
+  handle : Handle,
+}
+
+unsafe impl Send for NamedMapping { }
+
+impl NamedMapping {
+  // over the first `bytes` of `file`, growing it if needed. fails with
+  // ErrorKind::AlreadyExists while a mapping of that name is around
+  pub fn create(file : &File, bytes : u64, name : &[u16]) -> io::Result<NamedMapping> {
+    if file.metadata()?.len() < bytes {
+      file.set_len(bytes)?;
+    }
+    let handle = unsafe {
+      SetLastError(0);
+      CreateFileMappingW(file.as_raw_handle() as Handle, ptr::null_mut(), PAGE_READWRITE, 0, 0, name.as_ptr())
+    };
+    if handle.is_null() {
+      return Err(io::Error::last_os_error());
+    }
+    // the handle is to the other one's object then
+    if io::Error::last_os_error().raw_os_error() == Some(ERROR_ALREADY_EXISTS) {
+      unsafe { CloseHandle(handle); }
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a mapping of that name exists already"));
+    }
+    Ok(NamedMapping { handle })
+  }
+
+  // the mapping another process created, ErrorKind::NotFound if nobody
+  // has it open
+  pub fn open(name : &[u16]) -> io::Result<NamedMapping> {
+    let handle = unsafe { OpenFileMappingW(FILE_MAP_ALL_ACCESS, 0, name.as_ptr()) };
+    if handle.is_null() {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(NamedMapping { handle })
+  }
+
+  /// Maps `len` elements starting at byte `offset`.
+  ///
+  /// # Safety
+  ///
+  /// Same as `MmapRegion::from_file()`.
+  pub unsafe fn view<T>(&self, offset : u64, len : usize) -> io::Result<MmapRegion<T>> {
+    MmapRegion::view(self.handle, offset, len)
+  }
+}
+
+impl Drop for NamedMapping {
+  fn drop(&mut self) {
+    unsafe { CloseHandle(self.handle); }
+  }
+}
+
+impl <T> RingStorage<T> for MmapRegion<T> {
+  fn slots(&self) -> &[T] {
+    unsafe { slice::from_raw_parts(self.ptr, self.len) }
+  }
+
+  fn slots_mut(&mut self) -> &mut [T] {
+    unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+  }
+
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T {
+    (*this).ptr
+  }
+}
+
+impl <T> Drop for MmapRegion<T> {
+  fn drop(&mut self) {
+    unsafe { UnmapViewOfFile(self.ptr as *const c_void); }
+  }
+}
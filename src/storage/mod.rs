@@ -0,0 +1,180 @@
+
+// backing storage for the ring buffers
+//
+// the buffers only need a fixed size slice of slots, where that slice lives
+// (heap, static memory, a shared mapping) is up to the storage
+
+use std::ptr;
+
+pub trait RingStorage<T> {
+  fn slots(&self) -> &[T];
+  fn slots_mut(&mut self) -> &mut [T];
+
+  /// The first slot, for a ring whose two sides use different slots at
+  /// the same time from two threads, neither of which may borrow all of
+  /// them. The storages here get there without going through a slice,
+  /// the default does.
+  ///
+  /// # Safety
+  ///
+  /// `this` must be valid, and the slots may only be touched through the
+  /// pointer for as long as nobody else borrows the storage.
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T {
+    (*this).slots_mut().as_mut_ptr()
+  }
+}
+
+impl <T> RingStorage<T> for Vec<T> {
+  fn slots(&self) -> &[T] { self.as_slice() }
+  fn slots_mut(&mut self) -> &mut [T] { self.as_mut_slice() }
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T { (*this).as_mut_ptr() }
+}
+
+impl <T> RingStorage<T> for Box<[T]> {
+  fn slots(&self) -> &[T] { self }
+  fn slots_mut(&mut self) -> &mut [T] { self }
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T { ptr::addr_of_mut!(**this) as *mut T }
+}
+
+impl <T> RingStorage<T> for &'static mut [T] {
+  fn slots(&self) -> &[T] { self }
+  fn slots_mut(&mut self) -> &mut [T] { self }
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T { ptr::addr_of_mut!(**this) as *mut T }
+}
+
+// inline, e.g. for a ring that lives in a static
+impl <T, const N : usize> RingStorage<T> for [T; N] {
+  fn slots(&self) -> &[T] { self }
+  fn slots_mut(&mut self) -> &mut [T] { self }
+  unsafe fn slots_ptr(this : *mut Self) -> *mut T { this as *mut T }
+}
+
+mod aligned;
+
+pub use self::aligned::{AlignedBuf, CachePadded, CACHE_LINE};
+
+#[cfg(test)]
+mod live;
+#[cfg(test)]
+pub(crate) use self::live::Live;
+
+#[cfg(unix)]
+mod mmap;
+#[cfg(windows)]
+mod mmap_windows;
+
+#[cfg(unix)]
+pub use self::mmap::MmapRegion;
+#[cfg(windows)]
+pub use self::mmap_windows::{MmapRegion, NamedMapping};
+
+#[cfg(test)]
+mod tests {
+  use super::RingStorage;
+
+  fn fill<S : RingStorage<i32>>(s : &mut S) -> i32 {
+    for (i, v) in s.slots_mut().iter_mut().enumerate() {
+      *v = i as i32;
+    }
+    s.slots().iter().sum()
+  }
+
+  #[test]
+  fn heap_storage() {
+    let mut v = vec![0i32; 4];
+    assert_eq!(fill(&mut v), 6);
+    let mut b = vec![0i32; 4].into_boxed_slice();
+    assert_eq!(fill(&mut b), 6);
+  }
+
+  // the same first slot as slots() gives
+  #[test]
+  fn slot_pointers() {
+    fn first<S : RingStorage<i32>>(mut s : S) {
+      let ptr = unsafe { S::slots_ptr(&mut s) };
+      assert_eq!(ptr as *const i32, s.slots().as_ptr());
+      unsafe { *ptr.add(1) = 7; }
+      assert_eq!(s.slots()[1], 7);
+    }
+    first(vec![0i32; 3]);
+    first(vec![0i32; 3].into_boxed_slice());
+    first::<&'static mut [i32]>(Box::leak(vec![0i32; 3].into_boxed_slice()));
+    first([0i32; 3]);
+    first(super::AlignedBuf::new(3, 0i32));
+  }
+
+  #[test]
+  fn static_storage() {
+    let mut s : &'static mut [i32] = Box::leak(vec![0i32; 5].into_boxed_slice());
+    assert_eq!(fill(&mut s), 10);
+  }
+
+  #[test]
+  fn array_storage() {
+    let mut a = [0i32; 4];
+    assert_eq!(fill(&mut a), 6);
+  }
+
+  #[test]
+  fn cache_line_aligned() {
+    let mut b = super::AlignedBuf::new(4, 0i32);
+    assert!((b.slots().as_ptr() as usize).is_multiple_of(super::CACHE_LINE));
+    assert_eq!(fill(&mut b), 6);
+  }
+
+  #[test]
+  fn padded_to_a_line() {
+    use std::mem;
+    use super::{CachePadded, CACHE_LINE};
+
+    assert_eq!((mem::align_of::<CachePadded<u8>>(), mem::size_of::<CachePadded<u8>>()), (CACHE_LINE, CACHE_LINE));
+    let pair = [CachePadded::new(1u64), CachePadded::new(2u64)];
+    assert_eq!(&pair[1] as *const _ as usize - &pair[0] as *const _ as usize, CACHE_LINE);
+    assert_eq!(*pair[0] + pair[1].into_inner(), 3);
+  }
+
+  #[test]
+  fn slot_aligned() {
+    let b = super::AlignedBuf::with_slot_align(3, 32, [0f32; 8]);
+    assert!(b.slots().iter().all(|s| (s.as_ptr() as usize).is_multiple_of(32)));
+  }
+
+  #[test]
+  #[should_panic]
+  fn misaligned_slots() {
+    let _b = super::AlignedBuf::with_slot_align(3, 32, [0f32; 3]);
+  }
+
+  #[cfg(any(unix, windows))]
+  #[test]
+  fn anonymous_mapping() {
+    let mut m = super::MmapRegion::anonymous(1024, 7i32).unwrap();
+    assert_eq!(m.slots().len(), 1024);
+    assert!(m.slots().iter().all(|v| *v == 7));
+    assert_eq!(fill(&mut m), 523776);
+  }
+
+  #[cfg(any(unix, windows))]
+  #[test]
+  fn mapping_size_overflow() {
+    use std::io::ErrorKind;
+
+    // 8 byte elements, the product would wrap around to 8 bytes
+    let err = super::MmapRegion::anonymous(usize::MAX / 8 + 2, 0u64).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  fn aligned_drops_slots() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let mut b = super::AlignedBuf::new(5, super::Live::new(&count));
+    assert_eq!(count.load(Ordering::SeqCst), 5);
+    b.slots_mut()[0] = super::Live::new(&count);
+    assert_eq!(count.load(Ordering::SeqCst), 5);
+    drop(b);
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+  }
+}
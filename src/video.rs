@@ -0,0 +1,199 @@
+
+// newest-frame-wins handoff between a capture thread and a consumer
+//
+// an spsc ring of capacity 1, so three frame slots (see
+// spsc::CircularBuffer): the writer fills one in place through reserve(),
+// commit() swaps it for the published one, and the reader takes whatever
+// was published last. a frame the reader never got to before the next
+// commit is dropped and counted, the writer never waits.
+//
+// the slots are allocated once, cache line aligned, and reused for every
+// frame, so big frames are never cloned on the way
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rate::WindowCounter;
+use seq::Seqno;
+use spsc::{self, CircularBufferIterator, Reservation};
+
+pub struct FrameWriter<T : Clone + Send> {
+  tx      : spsc::Sender<T>,
+  dropped : Arc<AtomicU64>,
+}
+
+pub struct FrameReader<T : Clone + Send> {
+  rx        : spsc::Receiver<T>,
+  dropped   : Arc<AtomicU64>,
+  counted   : u64,              // of dropped, already in drops
+  delivered : u64,
+  deliveries: WindowCounter,
+  drops     : WindowCounter,
+}
+
+// frame being written, see FrameWriter::reserve()
+pub struct Frame<'a, T : 'a + Clone + Send> {
+  slot    : Reservation<'a, T>,
+  dropped : &'a AtomicU64,
+}
+
+// the latest frame, the writer cannot reuse its slot while this is alive
+pub struct Latest<'a, T : 'a + Clone> {
+  frame  : &'a T,
+  seqno  : Seqno,
+  _batch : CircularBufferIterator<'a, T>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+  pub delivered            : u64,   // since the start
+  pub dropped              : u64,
+  pub delivered_per_second : u64,   // over the last second
+  pub dropped_per_second   : u64,
+}
+
+// every slot starts out as a clone of `blank`, which sets the frame size
+pub fn channel<T : Clone + Send>(blank : T) -> (FrameWriter<T>, FrameReader<T>) {
+  let (tx, rx)  = spsc::channel(1, blank);
+  let dropped   = Arc::new(AtomicU64::new(0));
+  let second    = Duration::from_secs(1);
+  let writer = FrameWriter { tx, dropped : dropped.clone() };
+  let reader = FrameReader {
+    rx,
+    dropped,
+    counted    : 0,
+    delivered  : 0,
+    deliveries : WindowCounter::new(second, 10),
+    drops      : WindowCounter::new(second, 10),
+  };
+  (writer, reader)
+}
+
+impl <T : Clone + Send> FrameWriter<T> {
+  // the slot for the next frame. it still holds an older frame, so the
+  // camera should overwrite all of it
+  pub fn reserve(&mut self) -> Frame<'_, T> {
+    Frame { slot : self.tx.reserve(), dropped : &self.dropped }
+  }
+
+  // frames overwritten before the reader got to them
+  pub fn dropped(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+}
+
+impl <'a, T : Clone + Send> Frame<'a, T> {
+  // hands the frame to the reader, dropping the one it did not get to
+  // yet. returns the frame's seqno
+  pub fn commit(self) -> Seqno {
+    let (seqno, evicted) = self.slot.commit_evicting();
+    if evicted { self.dropped.fetch_add(1, Ordering::Relaxed); }
+    seqno
+  }
+}
+
+impl <'a, T : Clone + Send> Deref for Frame<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    &self.slot
+  }
+}
+
+impl <'a, T : Clone + Send> DerefMut for Frame<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    &mut self.slot
+  }
+}
+
+impl <T : Clone + Send> FrameReader<T> {
+  // the newest frame committed since the last call, None if there is none
+  pub fn latest(&mut self) -> Option<Latest<'_, T>> {
+    let now = Instant::now();
+    let dropped = self.dropped.load(Ordering::Relaxed);
+    if dropped > self.counted {
+      self.drops.record_at(dropped - self.counted, now);
+      self.counted = dropped;
+    }
+
+    let batch = self.rx.try_iter();
+    let (seqno, frame) = batch.newest()?;
+    self.delivered += 1;
+    self.deliveries.record_at(1, now);
+    Some(Latest { frame, seqno, _batch : batch })
+  }
+
+  // latest() that waits up to `timeout` for a frame
+  pub fn wait_latest(&mut self, timeout : Duration) -> Option<Latest<'_, T>> {
+    self.rx.wait(Some(timeout));
+    self.latest()
+  }
+
+  pub fn stats(&self) -> FrameStats {
+    FrameStats {
+      delivered            : self.delivered,
+      dropped              : self.dropped.load(Ordering::Relaxed),
+      delivered_per_second : self.deliveries.count(),
+      dropped_per_second   : self.drops.count(),
+    }
+  }
+}
+
+impl <'a, T : Clone> Latest<'a, T> {
+  pub fn seqno(&self) -> Seqno {
+    self.seqno
+  }
+}
+
+impl <'a, T : Clone> Deref for Latest<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    self.frame
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::channel;
+
+  #[test]
+  fn newest_frame_wins() {
+    let (mut tx, mut rx) = channel(vec![0u8; 16]);
+    assert!(rx.latest().is_none());
+
+    for i in 1..4 {
+      let mut frame = tx.reserve();
+      for b in frame.iter_mut() { *b = i; }
+      frame.commit();
+    }
+    {
+      let latest = rx.latest().unwrap();
+      assert_eq!((latest.seqno(), latest[15]), (2, 3));
+    }
+    assert!(rx.latest().is_none());
+    assert_eq!(tx.dropped(), 2);
+
+    let stats = rx.stats();
+    assert_eq!((stats.delivered, stats.dropped), (1, 2));
+    assert_eq!(stats.delivered_per_second, 1);
+    // drops are seen with the next call
+    rx.latest();
+    assert_eq!(rx.stats().dropped_per_second, 2);
+  }
+
+  #[test]
+  fn abandoned_frames_are_not_sent() {
+    let (mut tx, mut rx) = channel([0u32; 1024]);
+    tx.reserve()[0] = 1;
+    assert!(rx.latest().is_none());
+    let mut frame = tx.reserve();
+    frame[1] = 2;
+    frame.commit();
+    let latest = rx.latest().unwrap();
+    assert_eq!(&latest[..2], &[1, 2]);
+    assert_eq!(tx.dropped(), 0);
+  }
+}
@@ -0,0 +1,431 @@
+
+// how a side of a channel waits for the other one
+//
+// the waiting side calls wait_for() with a readiness check (data arrived,
+// a slot got free), the other side calls notify() after every change. the
+// strategies trade cpu burn for wakeup latency, LMAX disruptor style
+
+use std::error::Error;
+use std::fmt;
+use std::hint;
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+pub trait WaitStrategy : Send + Sync {
+  // blocks until ready() holds or the deadline passed, returns ready()
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool;
+
+  // called by the other side after it made progress
+  fn notify(&self) { }
+
+  // called between retries of a failed flag CAS, `attempt` counts from 0
+  fn backoff(&self, attempt : usize) {
+    backoff(attempt);
+  }
+}
+
+// spin hints doubling from 1 to 64, then yields, so a contended cache
+// line gets some rest without giving up the core right away
+pub fn backoff(attempt : usize) {
+  if attempt < 7 {
+    for _ in 0..(1 << attempt) { hint::spin_loop(); }
+  } else {
+    thread::yield_now();
+  }
+}
+
+fn expired(deadline : Option<Instant>) -> bool {
+  deadline.is_some_and(|d| Instant::now() >= d)
+}
+
+// time left until the deadline, capped at `max`
+fn remaining(deadline : Option<Instant>, max : Duration) -> Duration {
+  match deadline {
+    Some(d) => d.saturating_duration_since(Instant::now()).min(max),
+    None    => max,
+  }
+}
+
+// a point in time an operation gives up at, or never
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+  pub fn never() -> Deadline {
+    Deadline(None)
+  }
+
+  pub fn at(when : Instant) -> Deadline {
+    Deadline(Some(when))
+  }
+
+  pub fn after(timeout : Duration) -> Deadline {
+    Deadline(Some(Instant::now() + timeout))
+  }
+
+  pub fn instant(&self) -> Option<Instant> {
+    self.0
+  }
+
+  pub fn expired(&self) -> bool {
+    expired(self.0)
+  }
+
+  pub fn remaining(&self, max : Duration) -> Duration {
+    remaining(self.0, max)
+  }
+}
+
+// lowest latency, keeps a core at 100%
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BusySpin;
+
+impl WaitStrategy for BusySpin {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    while !ready() {
+      if expired(deadline) { return false; }
+      hint::spin_loop();
+    }
+    true
+  }
+
+  // never yields the core
+  fn backoff(&self, attempt : usize) {
+    for _ in 0..(1 << attempt.min(6)) { hint::spin_loop(); }
+  }
+}
+
+// lets other threads on the core run between checks
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Yield;
+
+impl WaitStrategy for Yield {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    while !ready() {
+      if expired(deadline) { return false; }
+      thread::yield_now();
+    }
+    true
+  }
+
+  fn backoff(&self, _attempt : usize) {
+    thread::yield_now();
+  }
+}
+
+// spins for a while, then parks the thread until notify() unparks it.
+// both halves of a channel may wait on it at the same time (the writer
+// under Policy::Block), so it keeps every parked thread
+pub struct SpinThenPark {
+  spins    : u32,
+  max_park : Duration,
+  parked   : Mutex<Vec<Thread>>,
+  waiting  : AtomicUsize,
+}
+
+impl SpinThenPark {
+  // max_park bounds a single park, as a safety net only
+  pub fn new(spins : u32, max_park : Duration) -> SpinThenPark {
+    SpinThenPark {
+      spins,
+      max_park,
+      parked   : Mutex::new(Vec::new()),
+      waiting  : AtomicUsize::new(0),
+    }
+  }
+}
+
+impl Default for SpinThenPark {
+  fn default() -> SpinThenPark {
+    SpinThenPark::new(1000, Duration::from_millis(1))
+  }
+}
+
+impl WaitStrategy for SpinThenPark {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    for _ in 0..self.spins {
+      if ready() { return true; }
+      hint::spin_loop();
+    }
+
+    let me = thread::current();
+    self.parked.lock().unwrap().push(me.clone());
+    // store buffer shape with notify(): one of us must see the other's
+    // store, whatever orderings ready() and the notifier's change use
+    self.waiting.fetch_add(1, Ordering::SeqCst);
+    let ret = loop {
+      atomic::fence(Ordering::SeqCst);
+      if ready() { break true; }
+      if expired(deadline) { break false; }
+      thread::park_timeout(remaining(deadline, self.max_park));
+    };
+    self.waiting.fetch_sub(1, Ordering::SeqCst);
+    self.parked.lock().unwrap().retain(|t| t.id() != me.id());
+    ret
+  }
+
+  fn notify(&self) {
+    atomic::fence(Ordering::SeqCst);
+    if self.waiting.load(Ordering::SeqCst) > 0 {
+      for t in self.parked.lock().unwrap().iter() {
+        t.unpark();
+      }
+    }
+  }
+}
+
+// what a spinning loop does between two checks. Sleep keeps oversubscribed
+// machines usable, the jitter stops several spinners waking in lockstep
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Relax {
+  SpinLoop,
+  Yield,
+  Sleep { base : Duration, jitter : Duration },
+}
+
+impl Relax {
+  pub fn pause(&self) {
+    match *self {
+      Relax::SpinLoop => hint::spin_loop(),
+      Relax::Yield    => thread::yield_now(),
+      Relax::Sleep { base, jitter } => thread::sleep(base + jittered(jitter)),
+    }
+  }
+}
+
+// a random duration in [0, max), splitmix64 over a shared counter is
+// plenty for spreading out wakeups
+fn jittered(max : Duration) -> Duration {
+  static STATE : AtomicU64 = AtomicU64::new(0);
+  let nanos = max.as_nanos() as u64;
+  if nanos == 0 { return max; }
+
+  let mut z = STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+  z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  Duration::from_nanos((z ^ (z >> 31)) % nanos)
+}
+
+// checks in a loop, relaxing in between as configured. BusySpin and
+// Yield are the SpinLoop and Yield cases of it
+#[derive(Clone, Copy, Debug)]
+pub struct Spin {
+  relax : Relax,
+}
+
+impl Spin {
+  pub fn new(relax : Relax) -> Spin {
+    Spin { relax }
+  }
+}
+
+impl WaitStrategy for Spin {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    while !ready() {
+      if expired(deadline) { return false; }
+      self.relax.pause();
+    }
+    true
+  }
+
+  fn backoff(&self, attempt : usize) {
+    match self.relax {
+      Relax::SpinLoop => BusySpin.backoff(attempt),
+      relax           => relax.pause(),
+    }
+  }
+}
+
+// sleeps on a condition variable, cheapest on cpu, slowest to wake up
+#[derive(Default)]
+pub struct Blocking {
+  lock     : Mutex<()>,
+  cond     : Condvar,
+  waiters  : AtomicUsize,
+}
+
+impl WaitStrategy for Blocking {
+  fn wait_for(&self, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> bool {
+    let mut guard = self.lock.lock().unwrap();
+    // the same store buffer shape as in SpinThenPark, notify() only
+    // takes the lock if it sees a waiter
+    self.waiters.fetch_add(1, Ordering::SeqCst);
+    atomic::fence(Ordering::SeqCst);
+    let ret = loop {
+      if ready() { break true; }
+      if expired(deadline) { break false; }
+      // the timeout only matters with a deadline, notify() does the rest
+      let nap = remaining(deadline, Duration::from_secs(1));
+      guard = self.cond.wait_timeout(guard, nap).unwrap().0;
+    };
+    self.waiters.fetch_sub(1, Ordering::SeqCst);
+    ret
+  }
+
+  fn notify(&self) {
+    atomic::fence(Ordering::SeqCst);
+    if self.waiters.load(Ordering::SeqCst) > 0 {
+      let _guard = self.lock.lock().unwrap();
+      self.cond.notify_all();
+    }
+  }
+}
+
+// lets another thread abort blocking waits, e.g. from a Ctrl-C handler.
+// clones share the same state, once canceled it stays canceled
+#[derive(Clone, Default)]
+pub struct CancelToken {
+  inner : Arc<CancelState>,
+}
+
+#[derive(Default)]
+struct CancelState {
+  canceled : AtomicBool,
+  next_id  : AtomicUsize,
+  // strategies with a wait in progress, notified by cancel()
+  waiting  : Mutex<Vec<(usize, Arc<dyn WaitStrategy>)>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("wait canceled")
+  }
+}
+
+impl Error for Canceled { }
+
+impl CancelToken {
+  pub fn new() -> CancelToken {
+    CancelToken::default()
+  }
+
+  pub fn cancel(&self) {
+    self.inner.canceled.store(true, Ordering::SeqCst);
+    for (_, strategy) in self.inner.waiting.lock().unwrap().iter() {
+      strategy.notify();
+    }
+  }
+
+  pub fn is_canceled(&self) -> bool {
+    self.inner.canceled.load(Ordering::SeqCst)
+  }
+
+  // like strategy.wait_for(), but gives up with Canceled once cancel() is
+  // called. a ready() that holds wins over the cancellation
+  pub fn wait_for(&self, strategy : &Arc<dyn WaitStrategy>, ready : &dyn Fn() -> bool, deadline : Option<Instant>) -> Result<bool, Canceled> {
+    let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+    self.inner.waiting.lock().unwrap().push((id, strategy.clone()));
+
+    let ret = strategy.wait_for(&|| ready() || self.is_canceled(), deadline);
+    self.inner.waiting.lock().unwrap().retain(|&(i, _)| i != id);
+
+    match ret {
+      true if ready()            => Ok(true),
+      _ if self.is_canceled()    => Err(Canceled),
+      _                          => Ok(false),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Blocking, BusySpin, CancelToken, Canceled, Relax, Spin, SpinThenPark, WaitStrategy, Yield};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  fn wakes_up(strategy : Arc<dyn WaitStrategy>) {
+    let flag = Arc::new(AtomicBool::new(false));
+    let deadline = Some(Instant::now() + Duration::from_millis(5));
+    assert!(!strategy.wait_for(&|| flag.load(Ordering::SeqCst), deadline));
+
+    let (s, f) = (strategy.clone(), flag.clone());
+    let t = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(10));
+      f.store(true, Ordering::SeqCst);
+      s.notify();
+    });
+    assert!(strategy.wait_for(&|| flag.load(Ordering::SeqCst), None));
+    t.join().unwrap();
+  }
+
+  #[test]
+  fn all_strategies_wake_up() {
+    wakes_up(Arc::new(BusySpin));
+    wakes_up(Arc::new(Yield));
+    wakes_up(Arc::new(SpinThenPark::new(10, Duration::from_secs(10))));
+    wakes_up(Arc::new(Blocking::default()));
+  }
+
+  #[test]
+  fn parked_waiters_all_wake_up() {
+    // two threads parked at once, the max park is far beyond the test
+    let strategy = Arc::new(SpinThenPark::new(0, Duration::from_secs(10)));
+    let flag = Arc::new(AtomicBool::new(false));
+    let waiters : Vec<_> = (0..2).map(|_| {
+      let (s, f) = (strategy.clone(), flag.clone());
+      thread::spawn(move || s.wait_for(&|| f.load(Ordering::SeqCst), None))
+    }).collect();
+    thread::sleep(Duration::from_millis(20));
+
+    let started = Instant::now();
+    flag.store(true, Ordering::SeqCst);
+    strategy.notify();
+    for w in waiters { assert!(w.join().unwrap()); }
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[test]
+  fn cancel_aborts_all_strategies() {
+    let strategies : Vec<Arc<dyn WaitStrategy>> = vec![
+      Arc::new(SpinThenPark::new(10, Duration::from_secs(1))),
+      Arc::new(Blocking::default()),
+    ];
+    for strategy in strategies {
+      let token = CancelToken::new();
+      let t = {
+        let token = token.clone();
+        thread::spawn(move || {
+          thread::sleep(Duration::from_millis(10));
+          token.cancel();
+        })
+      };
+      let started = Instant::now();
+      assert_eq!(token.wait_for(&strategy, &|| false, None), Err(Canceled));
+      assert!(started.elapsed() < Duration::from_secs(1));
+      t.join().unwrap();
+      // ready wins over an earlier cancel
+      assert_eq!(token.wait_for(&strategy, &|| true, None), Ok(true));
+    }
+  }
+
+  #[test]
+  fn backoff_grows_into_yields() {
+    // mostly checking none of them blocks for long
+    let started = Instant::now();
+    for attempt in 0..20 {
+      super::backoff(attempt);
+      BusySpin.backoff(attempt);
+      Yield.backoff(attempt);
+      Blocking::default().backoff(attempt);
+    }
+    assert!(started.elapsed().as_secs() < 1);
+  }
+
+  #[test]
+  fn relaxed_spinning() {
+    let sleep = Relax::Sleep { base : Duration::from_micros(50), jitter : Duration::from_micros(50) };
+    for relax in [Relax::SpinLoop, Relax::Yield, sleep].iter() {
+      wakes_up(Arc::new(Spin::new(*relax)));
+    }
+    for _ in 0..100 {
+      assert!(super::jittered(Duration::from_micros(10)) < Duration::from_micros(10));
+    }
+  }
+}
@@ -0,0 +1,182 @@
+
+// a fixed set of threads running one handler over submitted items
+//
+// shutdown() stops the intake, lets the workers drain what is queued until
+// the deadline, joins them and hands back whatever was left over, so
+// nothing buffered gets lost silently
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use wait::Deadline;
+
+struct State<T> {
+  queue     : VecDeque<T>,
+  in_flight : usize,
+  open      : bool,     // submit() accepts items
+  abort     : bool,     // workers stop taking items
+}
+
+struct Shared<T> {
+  state : Mutex<State<T>>,
+  cond  : Condvar,
+}
+
+pub struct Pool<T : Send + 'static> {
+  shared  : Arc<Shared<T>>,
+  threads : Vec<JoinHandle<()>>,
+}
+
+// what shutdown() found
+#[derive(Debug)]
+pub struct Report<T> {
+  pub unprocessed : Vec<T>,   // queued items the deadline cut off
+  pub panicked    : usize,    // workers that died in the handler
+}
+
+// keeps the in-flight count right even if the handler panics
+struct InFlight<'a, T : 'a>(&'a Shared<T>);
+
+impl <'a, T> Drop for InFlight<'a, T> {
+  fn drop(&mut self) {
+    let mut state = self.0.state.lock().unwrap_or_else(|e| e.into_inner());
+    state.in_flight -= 1;
+    self.0.cond.notify_all();
+  }
+}
+
+impl <T : Send + 'static> Pool<T> {
+  pub fn new<F>(threads : usize, handler : F) -> Pool<T>
+    where F : Fn(T) + Send + Sync + 'static
+  {
+    if threads == 0 { panic!("a pool needs at least one thread"); }
+
+    let shared = Arc::new(Shared {
+      state : Mutex::new(State { queue : VecDeque::new(), in_flight : 0, open : true, abort : false }),
+      cond  : Condvar::new(),
+    });
+    let handler = Arc::new(handler);
+
+    let threads = (0..threads).map(|_| {
+      let shared  = shared.clone();
+      let handler = handler.clone();
+      thread::spawn(move || Pool::work(&shared, &*handler))
+    }).collect();
+
+    Pool { shared, threads }
+  }
+
+  fn work<F : Fn(T)>(shared : &Shared<T>, handler : &F) {
+    loop {
+      let item = {
+        let mut state = shared.state.lock().unwrap();
+        loop {
+          if state.abort { return; }
+          if let Some(item) = state.queue.pop_front() {
+            state.in_flight += 1;
+            break item;
+          }
+          if !state.open { return; }
+          state = shared.cond.wait(state).unwrap();
+        }
+      };
+      let _guard = InFlight(shared);
+      handler(item);
+    }
+  }
+
+  // hands the item back once shutdown started
+  pub fn submit(&self, item : T) -> Result<(), T> {
+    let mut state = self.shared.state.lock().unwrap();
+    if !state.open { return Err(item); }
+    state.queue.push_back(item);
+    self.shared.cond.notify_one();
+    Ok(())
+  }
+
+  // queued plus in-flight items
+  pub fn pending(&self) -> usize {
+    let state = self.shared.state.lock().unwrap();
+    state.queue.len() + state.in_flight
+  }
+
+  // later calls find nothing left and return right away
+  pub fn shutdown(&mut self, deadline : Deadline) -> Report<T> {
+    let unprocessed = {
+      let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+      state.open = false;
+      self.shared.cond.notify_all();
+
+      while (!state.queue.is_empty() || state.in_flight > 0) && !deadline.expired() {
+        let timeout = deadline.remaining(Duration::from_millis(100));
+        state = self.shared.cond.wait_timeout(state, timeout).unwrap_or_else(|e| e.into_inner()).0;
+      }
+
+      // items already handed to a worker still run to completion
+      state.abort = true;
+      self.shared.cond.notify_all();
+      state.queue.drain(..).collect()
+    };
+
+    let panicked = self.threads.drain(..).map(|t| t.join()).filter(|r| r.is_err()).count();
+    Report { unprocessed, panicked }
+  }
+}
+
+// dropping the pool drains it completely
+impl <T : Send + 'static> Drop for Pool<T> {
+  fn drop(&mut self) {
+    if !self.threads.is_empty() {
+      self.shutdown(Deadline::never());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Pool;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+  use wait::Deadline;
+
+  #[test]
+  fn drains_before_joining() {
+    let sum = Arc::new(AtomicUsize::new(0));
+    let mut pool = {
+      let sum = sum.clone();
+      Pool::new(4, move |v : usize| { sum.fetch_add(v, Ordering::SeqCst); })
+    };
+    for i in 0..1000 { pool.submit(i).unwrap(); }
+
+    let report = pool.shutdown(Deadline::never());
+    assert!(report.unprocessed.is_empty());
+    assert_eq!(report.panicked, 0);
+    assert_eq!(sum.load(Ordering::SeqCst), 999 * 1000 / 2);
+    assert_eq!(pool.submit(1), Err(1));
+  }
+
+  #[test]
+  fn reports_what_the_deadline_cut_off() {
+    let mut pool = Pool::new(1, |_ : usize| thread::sleep(Duration::from_millis(20)));
+    for i in 0..50 { pool.submit(i).unwrap(); }
+
+    let report = pool.shutdown(Deadline::after(Duration::from_millis(50)));
+    assert!(!report.unprocessed.is_empty());
+    assert!(report.unprocessed.len() < 50);
+    assert_eq!(*report.unprocessed.last().unwrap(), 49);
+  }
+
+  #[test]
+  fn counts_panicked_workers() {
+    let mut pool = Pool::new(2, |v : usize| if v == 3 { panic!("boom") });
+    for i in 0..10 { pool.submit(i).unwrap(); }
+    // the other worker drains the rest
+    let report = pool.shutdown(Deadline::after(Duration::from_secs(5)));
+    assert_eq!(report.panicked, 1);
+    assert!(report.unprocessed.is_empty());
+  }
+}
@@ -0,0 +1,187 @@
+
+// heavy channel and queue churn under a counting allocator, everything
+// allocated must be given back once the channels and queues are gone
+//
+// the allocator counts for the whole process, so all the scenarios run
+// from a single test one after the other
+
+extern crate rpg;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::thread;
+
+use rpg::disruptor;
+use rpg::mpsc;
+use rpg::prelude::{channel, Deadline};
+use rpg::segqueue::SegQueue;
+use rpg::workers::Pool;
+
+struct Counting;
+
+static BLOCKS : AtomicIsize = AtomicIsize::new(0);
+static BYTES  : AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+  unsafe fn alloc(&self, layout : Layout) -> *mut u8 {
+    BLOCKS.fetch_add(1, Ordering::SeqCst);
+    BYTES.fetch_add(layout.size() as isize, Ordering::SeqCst);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr : *mut u8, layout : Layout) {
+    BLOCKS.fetch_sub(1, Ordering::SeqCst);
+    BYTES.fetch_sub(layout.size() as isize, Ordering::SeqCst);
+    System.dealloc(ptr, layout)
+  }
+
+  unsafe fn realloc(&self, ptr : *mut u8, layout : Layout, new_size : usize) -> *mut u8 {
+    BYTES.fetch_add(new_size as isize - layout.size() as isize, Ordering::SeqCst);
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+#[global_allocator]
+static ALLOC : Counting = Counting;
+
+fn net() -> (isize, isize) {
+  (BLOCKS.load(Ordering::SeqCst), BYTES.load(Ordering::SeqCst))
+}
+
+fn churn_copy() {
+  for size in 1..1000 {
+    let (tx, rx) = channel(size, 0u64);
+    for i in 0..(size as u64 * 3) { tx.put(|v| *v = i); }
+    assert!(rx.try_iter().count() <= size);
+  }
+}
+
+fn churn_owned() {
+  for size in 1..500 {
+    let (tx, rx) = channel(size, Vec::new());
+    for i in 0..size * 3 {
+      tx.put(|v| *v = vec![i as u8; i % 64]);
+      if i % 7 == 0 { rx.try_iter().count(); }
+    }
+    // the rest stays pending
+  }
+}
+
+fn across_threads() {
+  for _ in 0..50 {
+    let (tx, rx) = channel(16, String::new());
+    let t = thread::spawn(move || {
+      for i in 0..1000 { tx.put(|v| *v = i.to_string()); }
+    });
+    let mut seen = 0;
+    while seen < 10 { seen += rx.try_iter().count(); }
+    t.join().unwrap();
+  }
+}
+
+fn pipelines() {
+  for size in 1..200 {
+    let mut b = disruptor::Builder::new();
+    let first = b.stage(&[]);
+    b.stage(&[first]);
+    let (mut tx, mut rx) = b.build(size, Vec::new());
+    for i in 0..size { tx.put(|v| *v = vec![0u32; i]); }
+    rx[0].process(|_, _| ());
+  }
+}
+
+fn pools() {
+  for _ in 0..50 {
+    let mut pool = Pool::new(2, |v : Vec<u8>| drop(v));
+    for i in 0..100 { pool.submit(vec![0u8; i]).unwrap(); }
+    let report = pool.shutdown(Deadline::never());
+    assert!(report.unprocessed.is_empty());
+  }
+}
+
+// the queue allocates a segment per 31 items, whatever is still in it
+// and the segments holding it go with the queue
+fn segments() {
+  for n in 0..200 {
+    let q = SegQueue::new();
+    for i in 0..n { q.push(vec![i as u8; i % 48]); }
+    for _ in 0..n / 3 { q.pop(); }
+  }
+
+  let q = std::sync::Arc::new(SegQueue::new());
+  let pushers : Vec<_> = (0..4).map(|t| {
+    let q = q.clone();
+    thread::spawn(move || for i in 0..2000 { q.push(format!("{}-{}", t, i)); })
+  }).collect();
+  let mut popped = 0;
+  while popped < 1000 { if q.pop().is_some() { popped += 1; } }
+  for t in pushers { t.join().unwrap(); }
+}
+
+// clones putting into one ring, dropped with items still unread
+fn many_producers() {
+  for size in 1..100 {
+    let (tx, rx) = mpsc::channel(size, String::new());
+    let senders : Vec<_> = (0..3).map(|_| tx.clone()).collect();
+    for (n, s) in senders.iter().enumerate() {
+      for i in 0..size * 2 { s.put(|v| *v = format!("{}:{}", n, i)); }
+    }
+    rx.try_iter().take(size / 2).count();
+  }
+
+  let (tx, rx) = mpsc::channel(64, Vec::new());
+  let producers : Vec<_> = (0..4).map(|_| {
+    let tx = tx.clone();
+    thread::spawn(move || for i in 0..1000 { tx.put(|v| *v = vec![0u16; i % 32]); })
+  }).collect();
+  drop(tx);
+  for t in producers { t.join().unwrap(); }
+  rx.try_iter().take(10).count();
+}
+
+// the same with a staging ring per clone, some of them gone and some
+// items left in the rings of the others
+fn combining() {
+  for size in 1..100 {
+    let (tx, rx) = mpsc::channel_combining(size, String::new());
+    let weak = tx.downgrade();
+    let senders : Vec<_> = (0..3).map(|_| tx.clone()).collect();
+    for (n, s) in senders.iter().enumerate() {
+      for i in 0..size * 2 { s.put(|v| *v = format!("{}:{}", n, i)); }
+    }
+    drop(senders);
+    rx.try_iter().take(size / 2).count();
+    weak.upgrade().unwrap().put(|v| v.push('x'));
+  }
+
+  let (tx, rx) = mpsc::channel_combining(64, Vec::new());
+  let producers : Vec<_> = (0..4).map(|_| {
+    let tx = tx.clone();
+    thread::spawn(move || for i in 0..1000 { tx.put(|v| *v = vec![0u16; i % 32]); })
+  }).collect();
+  for t in producers { t.join().unwrap(); }
+  drop(rx);
+  tx.put(|v| v.push(1));
+}
+
+#[test]
+fn channel_churn_does_not_leak() {
+  let scenarios : Vec<(&str, fn())> = vec![
+    ("churn_copy",     churn_copy),
+    ("churn_owned",    churn_owned),
+    ("across_threads", across_threads),
+    ("pipelines",      pipelines),
+    ("pools",          pools),
+    ("segments",       segments),
+    ("many_producers", many_producers),
+    ("combining",      combining),
+  ];
+
+  for &(name, scenario) in scenarios.iter() {
+    // the first run may set up lazily allocated runtime state
+    scenario();
+    let before = net();
+    scenario();
+    assert_eq!(net(), before, "{} leaked (blocks, bytes)", name);
+  }
+}
@@ -0,0 +1,87 @@
+
+// the producer records every item it sent, the consumer every batch it
+// got, and the two are compared afterwards. "values never go down" lets
+// duplicates, mixed up slots and unjustified loss through, this doesn't:
+//
+// - every item matches what the oracle says was sent under its seqno
+// - seqnos strictly increase across batches, so no duplicates
+// - a batch is a run of consecutive seqnos, at most capacity long
+// - an item may only be missing if the writer went a whole ring past it,
+//   and under Policy::Block nothing may be missing at all
+
+extern crate rpg;
+
+use std::thread;
+
+use rpg::prelude::{Builder, Policy, Seqno};
+
+// something that is not the seqno itself, so slot mixups show
+fn value_of(seqno : Seqno) -> u64 {
+  let z = seqno.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+  z ^ (z >> 29)
+}
+
+fn check(capacity : usize, items : usize, policy : Policy) {
+  let (tx, rx) = Builder::new().capacity(capacity).overwrite(policy).build::<u64>();
+
+  let producer = thread::spawn(move || {
+    let mut sent = Vec::with_capacity(items);
+    for i in 0..items as Seqno {
+      let seqno = tx.put(|v| *v = value_of(i));
+      sent.push((seqno, value_of(i)));
+    }
+    sent
+  });
+
+  // runs until the producer is gone and its last batch was taken, the
+  // count of items is only used for checking
+  let last = items as Seqno - 1;
+  let mut batches : Vec<Vec<(Seqno, u64)>> = vec![];
+  loop {
+    let closed = rx.is_closed();
+    let batch : Vec<(Seqno, u64)> = rx.try_iter_enumerated().collect();
+    if !batch.is_empty() { batches.push(batch); }
+    else if closed { break; }
+    else { rx.wait(None); }
+  }
+  assert_eq!(batches.last().and_then(|b| b.last()).map(|i| i.0), Some(last), "the latest item never arrived");
+  let sent = producer.join().unwrap();
+
+  let tag = format!("capacity {} {:?}", capacity, policy);
+  let mut prev : Option<Seqno> = None;
+  let mut lost = 0;
+  for batch in &batches {
+    assert!(batch.len() <= capacity, "{}: batch of {}", tag, batch.len());
+    for (i, &(seqno, value)) in batch.iter().enumerate() {
+      assert_eq!(sent[seqno as usize], (seqno, value), "{}: item {} differs from what was sent", tag, seqno);
+      if i > 0 { assert_eq!(seqno, batch[i-1].0 + 1, "{}: hole inside a batch", tag); }
+    }
+
+    let first = batch[0].0;
+    let expected = prev.map_or(0, |p| p + 1);
+    assert!(first >= expected, "{}: seqno {} seen twice or out of order", tag, first);
+    for missing in expected..first {
+      assert!(policy == Policy::Overwrite, "{}: seqno {} lost under Block", tag, missing);
+      assert!(missing + capacity as Seqno <= last, "{}: seqno {} lost without being overwritten", tag, missing);
+      lost += 1;
+    }
+    prev = Some(batch[batch.len()-1].0);
+  }
+
+  let received : usize = batches.iter().map(|b| b.len()).sum();
+  assert_eq!(received + lost, items, "{}", tag);
+}
+
+#[test]
+fn overwrite_matches_oracle() {
+  for capacity in &[1, 2, 7, 64] {
+    check(*capacity, 50_000, Policy::Overwrite);
+  }
+}
+
+#[test]
+fn block_matches_oracle() {
+  for capacity in &[1, 7, 64] {
+    check(*capacity, 20_000, Policy::Block);
+  }
+}